@@ -0,0 +1,115 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of what coroutines cost: spawning one, switching in and out of
+//! it, and canceling it. Run them with `cargo bench`.
+
+#![cfg_attr(has_generator_trait, feature(generator_trait))]
+
+use core::pin::Pin;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use frenetic::{
+    Control, Coroutine, CoroutineError, Finished, Generator, GeneratorState, STACK_MINIMUM,
+};
+
+// Enough for the deepest benchmark, and for unwinding.
+const STACK_SIZE: usize = STACK_MINIMUM * 16;
+
+/// Spawns a coroutine and drops it before it starts.
+fn spawn(c: &mut Criterion) {
+    let mut stack = vec![0u8; STACK_SIZE];
+
+    let _ = c.bench_function("spawn", |b| {
+        b.iter(|| {
+            let coro = Coroutine::new(&mut stack[..], |c: Control<'_, (), (), u32>, ()| c.done(1));
+            black_box(coro);
+        })
+    });
+}
+
+/// Spawns a coroutine and runs it to completion: a switch in and back out.
+fn spawn_run(c: &mut Criterion) {
+    let mut stack = vec![0u8; STACK_SIZE];
+
+    let _ = c.bench_function("spawn_run", |b| {
+        b.iter(|| {
+            let mut coro =
+                Coroutine::new(&mut stack[..], |c: Control<'_, (), (), u32>, ()| c.done(1));
+            black_box(Pin::new(&mut coro).resume());
+        })
+    });
+}
+
+/// Resumes a coroutine which yields straight back.
+fn resume_yield(c: &mut Criterion) {
+    let mut stack = vec![0u8; STACK_SIZE];
+    let mut coro = Coroutine::new(&mut stack[..], |mut c, ()| loop {
+        c = c.r#yield(1u32)?.0;
+    });
+    let _: GeneratorState<u32, ()> = Pin::new(&mut coro).resume();
+
+    let _ = c.bench_function("resume_yield", |b| {
+        b.iter(|| black_box(Pin::new(&mut coro).resume()))
+    });
+}
+
+/// Calls itself `depth` times, then keeps yielding from the innermost call.
+fn recurse(mut c: Control<'_, (), u32, ()>, depth: u32) -> Result<Finished<()>, CoroutineError> {
+    if depth == 0 {
+        loop {
+            c = c.r#yield(0)?.0;
+        }
+    }
+
+    black_box(recurse(c, depth - 1))
+}
+
+/// Resumes a coroutine which yields from deep down a call stack. A switch
+/// doesn't depend on the depth of the stack, so this should cost as much as
+/// `resume_yield`.
+fn deep_resume_yield(c: &mut Criterion) {
+    let mut stack = vec![0u8; STACK_SIZE];
+    let mut coro = Coroutine::new(&mut stack[..], |c, ()| recurse(c, 100));
+    let _ = Pin::new(&mut coro).resume();
+
+    let _ = c.bench_function("deep_resume_yield", |b| {
+        b.iter(|| black_box(Pin::new(&mut coro).resume()))
+    });
+}
+
+/// Cancels a suspended coroutine, which unwinds its stack.
+fn cancel(c: &mut Criterion) {
+    let mut stack = vec![0u8; STACK_SIZE];
+
+    let _ = c.bench_function("cancel", |b| {
+        b.iter(|| {
+            let mut coro = Coroutine::new(&mut stack[..], |c, ()| {
+                let (c, ()) = c.r#yield(1u32)?;
+                c.done(())
+            });
+            let _ = Pin::new(&mut coro).resume();
+            black_box(coro.cancel())
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    spawn,
+    spawn_run,
+    resume_yield,
+    deep_resume_yield,
+    cancel
+);
+criterion_main!(benches);
@@ -19,16 +19,234 @@ fn main() {
         std::env::set_var("CC", "clang");
     }
 
-    cc::Build::new()
-        .file("src/jump.ll")
-        .flag("-x")
-        .flag("ir")
-        .flag("-Wno-override-module")
-        .compile("jump");
-
-    if probe("#![feature(generator_trait)] fn main() {}") {
+    // The thread backend is written in Rust; there is nothing to compile.
+    // Miri can't run foreign code, so it always uses the thread backend.
+    let threads = std::env::var_os("CARGO_FEATURE_THREADS").is_some()
+        || std::env::var_os("CARGO_CFG_MIRI").is_some();
+
+    // The asm backend is written in Rust too, with global_asm!. Where it is
+    // supported, it also stands in for the others when there is no C
+    // compiler to build them with.
+    let asm = std::env::var_os("CARGO_FEATURE_ASM").is_some();
+
+    // Both promise a context switch which confidential computing
+    // environments can run: nothing an SGX enclave can't execute, nothing
+    // that makes an SEV-SNP guest exit to the hypervisor.
+    if std::env::var_os("CARGO_FEATURE_SGX").is_some() {
+        check_restricted("sgx", &["threads", "cet", "guard", "valgrind", "tsan"]);
+    }
+    if std::env::var_os("CARGO_FEATURE_SNP").is_some() {
+        check_restricted("snp", &["threads", "cet"]);
+    }
+
+    if !threads {
+        if asm && !asm_supported() {
+            let target = std::env::var("TARGET").expect("target");
+            panic!("the asm feature is not supported on {}", target);
+        }
+
+        if asm {
+            println!("cargo:rustc-cfg=frenetic_asm");
+        } else if !have_compiler() && asm_supported() {
+            println!("cargo:warning=no C compiler found, using the asm backend");
+            println!("cargo:rustc-cfg=frenetic_asm");
+        } else {
+            compile();
+        }
+
+        if std::env::var_os("CARGO_FEATURE_VALGRIND").is_some() {
+            compile_valgrind();
+        }
+    }
+
+    // The nightly trait has had three shapes: `Generator` without a resume
+    // argument, which we re-export, then `Generator<R>` and `Coroutine<R>`
+    // with one, which we implement next to our own `Generator`.
+    if probe(
+        "#![feature(generator_trait)]
+        use std::ops::{Generator, GeneratorState};
+        pub fn f<G: Generator>(g: std::pin::Pin<&mut G>) -> GeneratorState<G::Yield, G::Return> {
+            g.resume()
+        }
+        fn main() {}",
+    ) {
         println!("cargo:rustc-cfg=has_generator_trait");
+    } else if probe(
+        "#![feature(generator_trait)]
+        use std::ops::{Generator, GeneratorState};
+        pub fn f<G: Generator<u8>>(g: std::pin::Pin<&mut G>) -> GeneratorState<G::Yield, G::Return> {
+            g.resume(0)
+        }
+        fn main() {}",
+    ) {
+        println!("cargo:rustc-cfg=has_generator_resume_arg");
+    } else if probe(
+        "#![feature(coroutine_trait)]
+        use std::ops::{Coroutine, CoroutineState};
+        pub fn f<G: Coroutine<u8>>(g: std::pin::Pin<&mut G>) -> CoroutineState<G::Yield, G::Return> {
+            g.resume(0)
+        }
+        fn main() {}",
+    ) {
+        println!("cargo:rustc-cfg=has_coroutine_trait");
     }
+
+    if probe("#[cfg(any(panic = \"unwind\", panic = \"abort\"))] fn main() {}") {
+        println!("cargo:rustc-cfg=has_cfg_panic");
+    }
+
+    // Const generics, with a panic in an associated const (Rust 1.57).
+    if probe(
+        "struct S<const N: usize>;
+        impl<const N: usize> S<N> { const CHECK: () = assert!(N > 0); }
+        fn main() { let () = S::<1>::CHECK; }",
+    ) {
+        println!("cargo:rustc-cfg=has_const_generics");
+    }
+
+    // Generic associated types (Rust 1.65), for `LendingGenerator`.
+    if probe("trait T { type A<'a> where Self: 'a; } fn main() {}") {
+        println!("cargo:rustc-cfg=has_gats");
+    }
+
+    // Embeds the GDB script in `debug/` in binaries (Rust 1.71). The probe
+    // reads stdin, so it needs the script's full path.
+    let manifest = std::env::var("CARGO_MANIFEST_DIR").expect("manifest dir");
+    let script = std::path::Path::new(&manifest).join("debug/frenetic_gdb.py");
+    if probe(&format!(
+        "#![debugger_visualizer(gdb_script_file = {:?})] fn main() {{}}",
+        script
+    )) {
+        println!("cargo:rustc-cfg=has_debugger_visualizer");
+    }
+}
+
+/// Compile the native context-switch backend
+fn compile() {
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").expect("target arch");
+    let os = std::env::var("CARGO_CFG_TARGET_OS").expect("target os");
+    let target = std::env::var("TARGET").expect("target");
+    let endian = std::env::var("CARGO_CFG_TARGET_ENDIAN").expect("target endian");
+    let mut build = cc::Build::new();
+
+    // The 32-bit ARM backend needs ARMv7 (Thumb-2 in Thumb state).
+    let armv7 = ["armv7", "thumbv7", "armv8", "thumbv8m.main"]
+        .iter()
+        .any(|prefix| target.starts_with(prefix));
+
+    // The assembly backends carry the floating-point control state along
+    // with each context on request.
+    if std::env::var_os("CARGO_FEATURE_FPSTATE").is_some() {
+        build.define("FRENETIC_FPSTATE", None);
+    }
+
+    // The x86_64 backend supports Intel CET shadow stacks on request.
+    if std::env::var_os("CARGO_FEATURE_CET").is_some() {
+        build.define("FRENETIC_CET", None);
+    }
+
+    // Use the portable LLVM IR implementation unless the target needs a
+    // hand-written one (LLVM cannot lower the sjlj intrinsics there, they
+    // don't preserve everything the platform ABI requires, or we want to
+    // describe the frames to unwinders).
+    match (arch.as_str(), os.as_str()) {
+        ("aarch64", _) => build.file("src/jump/aarch64.S"),
+        ("arm", _) if armv7 => build.file("src/jump/arm.S"),
+        ("powerpc64", _) if endian == "little" => build.file("src/jump/powerpc64.S"),
+        ("riscv64", _) => build.file("src/jump/riscv64.S"),
+        ("s390x", _) => build.file("src/jump/s390x.S"),
+        // WebAssembly can't switch stacks by itself: the host does it.
+        ("wasm32", _) => build
+            .file("src/jump/wasm32.ll")
+            .flag("-x")
+            .flag("ir")
+            .flag("-Wno-override-module"),
+        ("x86", "windows") => build.file("src/jump/x86-windows.S"),
+        ("x86", _) => build.file("src/jump/x86.S"),
+        ("x86_64", "windows") => build.file("src/jump/x86_64-windows.S"),
+        ("x86_64", _) => build.file("src/jump/x86_64.S"),
+        _ => {
+            // The sjlj intrinsics need a bigger jump buffer.
+            println!("cargo:rustc-cfg=frenetic_sjlj");
+            build
+                .file("src/jump.ll")
+                .flag("-x")
+                .flag("ir")
+                .flag("-Wno-override-module")
+        }
+    };
+
+    build.compile("jump");
+}
+
+/// Check that a feature restricting the context switch to the x86_64
+/// assembly backend can keep its promise, which the features in `conflicts`
+/// would break. See `src/sgx.rs` and `src/snp.rs`.
+fn check_restricted(feature: &str, conflicts: &[&str]) {
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").expect("target arch");
+    let os = std::env::var("CARGO_CFG_TARGET_OS").expect("target os");
+
+    if arch != "x86_64" || os == "windows" {
+        let target = std::env::var("TARGET").expect("target");
+        panic!("the {} feature is not supported on {}", feature, target);
+    }
+
+    for conflict in conflicts {
+        let var = format!("CARGO_FEATURE_{}", conflict.to_uppercase());
+        if std::env::var_os(var).is_some() {
+            panic!(
+                "the {} feature can't be combined with {}",
+                feature, conflict
+            );
+        }
+    }
+}
+
+/// Test if the global_asm! backend supports the target
+fn asm_supported() -> bool {
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").expect("target arch");
+    let os = std::env::var("CARGO_CFG_TARGET_OS").expect("target os");
+    let target = std::env::var("TARGET").expect("target");
+
+    match (arch.as_str(), os.as_str()) {
+        (_, "windows") => false,
+        ("aarch64", _) => !target.starts_with("arm64e"),
+        ("x86_64", _) => true,
+        _ => false,
+    }
+}
+
+/// Test if the C compiler can be run
+fn have_compiler() -> bool {
+    use std::process::Stdio;
+
+    let compiler = match cc::Build::new().try_get_compiler() {
+        Ok(compiler) => compiler,
+        Err(_) => return false,
+    };
+
+    compiler
+        .to_command()
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Compile the Valgrind client request helper
+fn compile_valgrind() {
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").expect("target arch");
+    let os = std::env::var("CARGO_CFG_TARGET_OS").expect("target os");
+
+    match (arch.as_str(), os.as_str()) {
+        (_, "windows") => panic!("the valgrind feature is not supported on Windows"),
+        ("x86_64", _) | ("aarch64", _) => {}
+        _ => panic!("the valgrind feature is not supported on {}", arch),
+    }
+
+    cc::Build::new().file("src/valgrind.S").compile("valgrind");
 }
 
 /// Test if a code snippet can be compiled
@@ -0,0 +1,153 @@
+# Copyright 2019 Red Hat
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+# GDB commands for the coroutines of a program using frenetic:
+#
+#   frenetic coroutines    lists the live coroutines (needs the `registry`
+#                          feature)
+#   frenetic bt ID         backtraces the suspended coroutine ID
+#
+# The layout they rely on is described by the `frenetic_debug` symbol; see
+# `src/debug.rs`. This script is embedded in binaries built with Rust 1.71 or
+# later, and loaded automatically if GDB's auto-load safe-path allows it.
+# Otherwise, `source` it.
+
+import struct
+
+import gdb
+
+VERSION = 1
+STATES = ["created", "suspended", "finished", "running"]
+
+# The registers to point at a suspended context, per architecture.
+REGISTERS = {
+    "i386:x86-64": ("rsp", "rip", "rbp"),
+    "aarch64": ("sp", "pc", "x29"),
+}
+
+
+def read(addr, size):
+    return bytes(gdb.selected_inferior().read_memory(addr, size))
+
+
+def word(addr):
+    return struct.unpack("<Q", read(addr, 8))[0]
+
+
+class Info(object):
+    def __init__(self):
+        try:
+            addr = int(gdb.parse_and_eval("(unsigned long) &frenetic_debug"))
+        except gdb.error:
+            raise gdb.GdbError("frenetic_debug not found; is frenetic linked in?")
+
+        fields = struct.unpack("<5I", read(addr, 20))
+        (self.version, self.word, self.child, self.frame, self.resumed) = fields
+        if self.version != VERSION:
+            raise gdb.GdbError("unsupported frenetic_debug version %d" % self.version)
+        if self.word != 8:
+            raise gdb.GdbError("only 64-bit targets are supported")
+
+        self.coroutines = word(addr + 24)
+
+    def nodes(self):
+        node = self.coroutines
+        while node:
+            (nxt, _, ident, ctx, state) = struct.unpack("<QQQQI", read(node, 36))
+            (name, length) = struct.unpack("<QQ", read(node + 40, 16))
+            name = read(name, length).decode("utf-8", "replace") if name else ""
+            state = STATES[state] if state < len(STATES) else "?"
+            yield (ident, ctx, state, name)
+            node = nxt
+
+
+class Frenetic(gdb.Command):
+    """Commands for frenetic coroutines."""
+
+    def __init__(self):
+        super(Frenetic, self).__init__("frenetic", gdb.COMMAND_STACK, prefix=True)
+
+
+class Coroutines(gdb.Command):
+    """List the live coroutines.
+
+Usage: frenetic coroutines"""
+
+    def __init__(self):
+        super(Coroutines, self).__init__("frenetic coroutines", gdb.COMMAND_STACK)
+
+    def invoke(self, arg, from_tty):
+        info = Info()
+        gdb.write("%-6s %-10s %-18s %s\n" % ("ID", "STATE", "CONTEXT", "NAME"))
+        for (ident, ctx, state, name) in sorted(info.nodes()):
+            gdb.write("%-6d %-10s 0x%016x %s\n" % (ident, state, ctx, name))
+
+
+class Backtrace(gdb.Command):
+    """Backtrace a suspended coroutine.
+
+Usage: frenetic bt ID
+
+The registers of the selected thread are pointed at the suspended context
+for the backtrace, and restored afterwards."""
+
+    def __init__(self):
+        super(Backtrace, self).__init__("frenetic bt", gdb.COMMAND_STACK)
+
+    def invoke(self, arg, from_tty):
+        info = Info()
+        if not info.frame:
+            raise gdb.GdbError("suspended contexts can't be walked on this target")
+
+        arch = gdb.selected_frame().architecture().name()
+        if arch not in REGISTERS:
+            raise gdb.GdbError("unsupported architecture %s" % arch)
+
+        try:
+            ident = int(arg)
+        except ValueError:
+            raise gdb.GdbError("usage: frenetic bt ID")
+
+        for (i, ctx, state, _) in info.nodes():
+            if i == ident:
+                break
+        else:
+            raise gdb.GdbError("no coroutine %d" % ident)
+
+        if state != "suspended":
+            raise gdb.GdbError("coroutine %d is %s, not suspended" % (ident, state))
+
+        sp = word(ctx + info.child)
+        fp = word(sp + info.frame)
+        pc = word(sp + info.frame + 8)
+        if arch == "aarch64":
+            pc &= (1 << 48) - 1  # Strip the pointer authentication code.
+        values = (sp + info.resumed, pc, fp)
+
+        regs = REGISTERS[arch]
+        gdb.execute("select-frame 0")
+        saved = [int(gdb.parse_and_eval("(unsigned long) $" + r)) for r in regs]
+        try:
+            for (r, v) in zip(regs, values):
+                gdb.execute("set $%s = %d" % (r, v))
+            gdb.execute("bt", from_tty)
+        finally:
+            gdb.execute("select-frame 0")
+            for (r, v) in zip(regs, saved):
+                gdb.execute("set $%s = %d" % (r, v))
+
+
+Frenetic()
+Coroutines()
+Backtrace()
@@ -0,0 +1,162 @@
+# Copyright 2019 Red Hat
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+# LLDB commands for the coroutines of a program using frenetic:
+#
+#   frenetic-coroutines    lists the live coroutines (needs the `registry`
+#                          feature)
+#   frenetic-bt ID         backtraces the suspended coroutine ID
+#
+# Load it with `command script import path/to/frenetic_lldb.py`. The layout
+# it relies on is described by the `frenetic_debug` symbol; see
+# `src/debug.rs`.
+
+import struct
+
+import lldb
+
+VERSION = 1
+STATES = ["created", "suspended", "finished", "running"]
+
+# The registers to point at a suspended context, per architecture.
+REGISTERS = {
+    "x86_64": ("rsp", "rip", "rbp"),
+    "aarch64": ("sp", "pc", "fp"),
+    "arm64": ("sp", "pc", "fp"),
+    "arm64e": ("sp", "pc", "fp"),
+}
+
+
+class Error(Exception):
+    pass
+
+
+def read(process, addr, size):
+    if size == 0:
+        return b""
+
+    error = lldb.SBError()
+    data = process.ReadMemory(addr, size, error)
+    if not error.Success():
+        raise Error("can't read 0x%x: %s" % (addr, error))
+    return data
+
+
+def word(process, addr):
+    return struct.unpack("<Q", read(process, addr, 8))[0]
+
+
+class Info(object):
+    def __init__(self, target):
+        symbols = target.FindSymbols("frenetic_debug")
+        if not symbols.GetSize():
+            raise Error("frenetic_debug not found; is frenetic linked in?")
+
+        addr = symbols[0].GetSymbol().GetStartAddress().GetLoadAddress(target)
+        self.process = target.GetProcess()
+
+        fields = struct.unpack("<5I", read(self.process, addr, 20))
+        (self.version, self.word, self.child, self.frame, self.resumed) = fields
+        if self.version != VERSION:
+            raise Error("unsupported frenetic_debug version %d" % self.version)
+        if self.word != 8:
+            raise Error("only 64-bit targets are supported")
+
+        self.coroutines = word(self.process, addr + 24)
+
+    def nodes(self):
+        node = self.coroutines
+        while node:
+            data = read(self.process, node, 56)
+            (nxt, _, ident, ctx, state) = struct.unpack_from("<QQQQI", data)
+            (name, length) = struct.unpack_from("<QQ", data, 40)
+            name = read(self.process, name, length) if name else b""
+            name = name.decode("utf-8", "replace")
+            state = STATES[state] if state < len(STATES) else "?"
+            yield (ident, ctx, state, name)
+            node = nxt
+
+
+def coroutines(debugger, command, result, internal_dict):
+    """List the live coroutines."""
+    try:
+        info = Info(debugger.GetSelectedTarget())
+    except Error as e:
+        result.SetError(str(e))
+        return
+
+    result.AppendMessage("%-6s %-10s %-18s %s" % ("ID", "STATE", "CONTEXT", "NAME"))
+    for (ident, ctx, state, name) in sorted(info.nodes()):
+        result.AppendMessage("%-6d %-10s 0x%016x %s" % (ident, state, ctx, name))
+
+
+def backtrace(debugger, command, result, internal_dict):
+    """Backtrace a suspended coroutine: frenetic-bt ID
+
+    The registers of the selected thread are pointed at the suspended context
+    for the backtrace, and restored afterwards."""
+    target = debugger.GetSelectedTarget()
+    try:
+        info = Info(target)
+        if not info.frame:
+            raise Error("suspended contexts can't be walked on this target")
+
+        arch = target.GetTriple().split("-")[0]
+        if arch not in REGISTERS:
+            raise Error("unsupported architecture %s" % arch)
+
+        try:
+            ident = int(command)
+        except ValueError:
+            raise Error("usage: frenetic-bt ID")
+
+        for (i, ctx, state, _) in info.nodes():
+            if i == ident:
+                break
+        else:
+            raise Error("no coroutine %d" % ident)
+
+        if state != "suspended":
+            raise Error("coroutine %d is %s, not suspended" % (ident, state))
+
+        sp = word(info.process, ctx + info.child)
+        fp = word(info.process, sp + info.frame)
+        pc = word(info.process, sp + info.frame + 8)
+        if arch != "x86_64":
+            pc &= (1 << 48) - 1  # Strip the pointer authentication code.
+    except Error as e:
+        result.SetError(str(e))
+        return
+
+    thread = info.process.GetSelectedThread()
+    frame = thread.GetFrameAtIndex(0)
+    regs = REGISTERS[arch]
+    saved = [frame.FindRegister(r).GetValueAsUnsigned() for r in regs]
+
+    def write(values):
+        for (r, v) in zip(regs, values):
+            debugger.HandleCommand("register write %s 0x%x" % (r, v))
+
+    write((sp + info.resumed, pc, fp))
+    try:
+        debugger.HandleCommand("thread backtrace")
+    finally:
+        write(saved)
+
+
+def __lldb_init_module(debugger, internal_dict):
+    debugger.HandleCommand(
+        "command script add -f frenetic_lldb.coroutines frenetic-coroutines"
+    )
+    debugger.HandleCommand("command script add -f frenetic_lldb.backtrace frenetic-bt")
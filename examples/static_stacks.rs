@@ -0,0 +1,82 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Green threads without a heap: a few tasks on statically allocated stacks,
+//! run round-robin until all of them are done.
+//!
+//! Nothing here needs the standard library or an allocator but `main()` and
+//! `println!()`. On a Cortex-M microcontroller, build frenetic with
+//! `default-features = false`, call `run()` from the entry point once the FPU
+//! is enabled, and report the results some other way. Run it on the host with
+//! `cargo run --example static_stacks`.
+
+#![cfg_attr(has_generator_trait, feature(generator_trait))]
+
+use core::pin::Pin;
+use frenetic::{AlignedStack, Coroutine, Generator, GeneratorState, StaticStack, STACK_MINIMUM};
+
+const TASKS: usize = 3;
+const STACK_SIZE: usize = STACK_MINIMUM * 2;
+
+static STACKS: [StaticStack<STACK_SIZE>; TASKS] =
+    [StaticStack::new(), StaticStack::new(), StaticStack::new()];
+
+type Task = Coroutine<'static, (), (usize, u32), u32, &'static mut AlignedStack<STACK_SIZE>>;
+
+/// Runs the tasks in turn until they have all returned, calling `step` for
+/// every step of every task. Returns what each task returned.
+fn run(mut step: impl FnMut(usize, u32)) -> [u32; TASKS] {
+    let mut tasks: [Option<Task>; TASKS] = [None, None, None];
+    for (id, slot) in tasks.iter_mut().enumerate() {
+        let stack = STACKS[id].take().expect("the stack is in use");
+        let steps = id as u32 + 2;
+
+        // Counts to `steps`, yielding after every step, and returns the sum.
+        *slot = Some(Coroutine::new(stack, move |mut c, ()| {
+            let mut sum = 0;
+
+            for step in 1..=steps {
+                sum += step;
+                c = c.r#yield((id, step))?.0;
+            }
+
+            c.done(sum)
+        }));
+    }
+
+    let mut results = [0; TASKS];
+    let mut running = TASKS;
+    while running > 0 {
+        for (id, slot) in tasks.iter_mut().enumerate() {
+            if let Some(coro) = slot {
+                match Pin::new(coro).resume() {
+                    GeneratorState::Yielded((id, n)) => step(id, n),
+                    GeneratorState::Complete(sum) => {
+                        results[id] = sum;
+                        running -= 1;
+                        *slot = None;
+                    }
+                }
+            }
+        }
+    }
+
+    results
+}
+
+fn main() {
+    let results = run(|id, n| println!("task {} step {}", id, n));
+    println!("results: {:?}", results);
+    assert_eq!(results, [3, 6, 10]);
+}
@@ -0,0 +1,142 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Procedural macros for frenetic. Use them through the `macros` feature of
+//! the `frenetic` crate rather than depending on this crate directly.
+
+#![deny(
+    warnings,
+    absolute_paths_not_starting_with_crate,
+    deprecated_in_future,
+    keyword_idents,
+    macro_use_extern_crate,
+    missing_debug_implementations,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results,
+    unreachable_pub,
+    rust_2018_idioms,
+    rust_2018_compatibility
+)]
+
+// Required before Rust 1.42.
+#[allow(unused_extern_crates)]
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span};
+use quote::quote;
+use syn::fold::{self, Fold};
+use syn::parse::{ParseStream, Parser};
+use syn::{Block, Expr, ExprReturn, ExprYield, Item, Stmt};
+
+/// Rewrites `yield` and `return` expressions in terms of a `Control` which is
+/// threaded through a mutable binding.
+struct Rewrite {
+    control: Ident,
+}
+
+impl Fold for Rewrite {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        let control = self.control.clone();
+
+        match expr {
+            // `yield e` moves the `Control` out, yields `e` and moves the
+            // returned `Control` back in. Its value is the resume argument.
+            Expr::Yield(ExprYield { expr, .. }) => {
+                let expr = expr.map(|e| self.fold_expr(*e));
+                let expr = expr.map_or_else(|| quote!(()), |e| quote!(#e));
+                syn::parse_quote! {{
+                    let (__frenetic_next, __frenetic_arg) = #control.r#yield(#expr)?;
+                    #control = __frenetic_next;
+                    __frenetic_arg
+                }}
+            }
+
+            // `return e` finishes the coroutine with `e`.
+            Expr::Return(ExprReturn { expr, .. }) => {
+                let expr = expr.map(|e| self.fold_expr(*e));
+                let expr = expr.map_or_else(|| quote!(()), |e| quote!(#e));
+                syn::parse_quote!(return #control.done(#expr))
+            }
+
+            // `yield` and `return` in a nested closure or async block belong
+            // to that closure or block.
+            Expr::Closure(_) | Expr::Async(_) => expr,
+
+            expr => fold::fold_expr(self, expr),
+        }
+    }
+
+    // Nested items are not part of the coroutine either.
+    fn fold_item(&mut self, item: Item) -> Item {
+        item
+    }
+}
+
+/// Builds a coroutine closure from a block of statements.
+///
+/// Inside the block, `yield e` pauses the coroutine, passing `e` to the
+/// parent. It evaluates to the argument the coroutine is resumed with. The
+/// value of the block is the coroutine's return value, as is the operand of
+/// `return`. The result can be passed to `Coroutine::new()`.
+///
+/// ```ignore
+/// let coro = Coroutine::new(&mut stack, coroutine! {
+///     yield 1;
+///     yield 2;
+///     "done"
+/// });
+/// ```
+///
+/// If the coroutine is canceled, `yield` returns from the block early.
+#[proc_macro]
+pub fn coroutine(input: TokenStream) -> TokenStream {
+    let parser = |input: ParseStream<'_>| Block::parse_within(input);
+    let mut stmts = match parser.parse(input) {
+        Ok(stmts) => stmts,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let mut rewrite = Rewrite {
+        control: Ident::new("__frenetic_control", Span::call_site()),
+    };
+
+    // The trailing expression, if any, is the return value.
+    let tail = match stmts.pop() {
+        Some(Stmt::Expr(expr)) => rewrite.fold_expr(expr),
+        Some(stmt) => {
+            stmts.push(stmt);
+            syn::parse_quote!(())
+        }
+        None => syn::parse_quote!(()),
+    };
+
+    let stmts: Vec<_> = stmts.into_iter().map(|s| rewrite.fold_stmt(s)).collect();
+    let control = &rewrite.control;
+
+    let output = quote! {
+        move |#control, _| {
+            #[allow(unused_mut)]
+            let mut #control = #control;
+            #(#stmts)*
+            let __frenetic_return = #tail;
+            #control.done(__frenetic_return)
+        }
+    };
+
+    output.into()
+}
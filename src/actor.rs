@@ -0,0 +1,370 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Actors: coroutines which own some state and handle messages sent to them,
+//! one at a time.
+//!
+//! An actor receives its messages through a `Mailbox`, a bounded channel (see
+//! `sync::mpsc`), from any number of `Address`es. `actor()` makes a coroutine
+//! which runs an `Actor` for the `scheduler::Executor`: it waits for the next
+//! message, and hands it to `Actor::handle()`.
+//!
+//! Should the handler panic, the actor is supervised according to its
+//! `Supervision`: either it stops, or it is replaced with a fresh one and
+//! carries on with the next message. `Address::stop()` stops it gracefully,
+//! once it has handled the messages sent before, as does dropping every
+//! address. Either way, the coroutine returns how the actor `Exit`ed. An actor
+//! canceled with its coroutine is dropped where it waits, like any other
+//! value on a coroutine's stack.
+//!
+//! ```
+//! # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+//! use frenetic::actor::{actor, mailbox, Actor, Exit, Supervision};
+//! use frenetic::scheduler::Executor;
+//! use frenetic::sync::oneshot;
+//! use frenetic::{GeneratorState, STACK_MINIMUM};
+//!
+//! enum Message {
+//!     Add(i32),
+//!     Get(oneshot::Sender<i32>),
+//! }
+//!
+//! #[derive(Default)]
+//! struct Counter(i32);
+//!
+//! impl Actor for Counter {
+//!     type Message = Message;
+//!
+//!     fn handle(&mut self, msg: Message) {
+//!         match msg {
+//!             Message::Add(n) => self.0 += n,
+//!             Message::Get(reply) => drop(reply.send(self.0)),
+//!         }
+//!     }
+//! }
+//!
+//! let (addr, mailbox) = mailbox(8);
+//! let (tx, mut rx) = oneshot::channel();
+//! assert!(addr.try_send(Message::Add(2)).is_ok());
+//! assert!(addr.try_send(Message::Add(3)).is_ok());
+//! assert!(addr.try_send(Message::Get(tx)).is_ok());
+//! assert!(addr.try_stop().is_ok());
+//!
+//! let mut stack = [0u8; STACK_MINIMUM * 16];
+//! let mut executor = Executor::<(), Exit, _>::new();
+//! let _ = executor.spawn(actor(&mut stack, mailbox, Supervision::Stop, Counter::default));
+//! executor.run(|_, state| assert!(matches!(state, GeneratorState::Complete(Exit::Stopped))));
+//!
+//! assert_eq!(rx.try_recv(), Ok(5));
+//! ```
+
+use crate::sync::mpsc::{self, SendError, TrySendError};
+use crate::sync::WaitResult;
+use crate::{Control, Coroutine, Stack};
+use core::any::Any;
+use core::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// What an actor does.
+pub trait Actor {
+    /// The messages it handles.
+    type Message;
+
+    /// Handles a message. A panic in here is caught, and the actor is
+    /// supervised. See `Supervision`.
+    fn handle(&mut self, msg: Self::Message);
+
+    /// Called once the actor has been stopped gracefully, after the last
+    /// message it handles.
+    fn stopped(&mut self) {}
+}
+
+/// What to do with an actor whose handler panics.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Supervision {
+    /// Stop it, without handling the messages left in its mailbox. Its
+    /// coroutine returns `Exit::Panicked`.
+    Stop,
+
+    /// Drop it and make a fresh one, which carries on with the next message,
+    /// up to this many times. Once it panics again, stop it.
+    Restart(usize),
+}
+
+/// How an actor has exited: what its coroutine returns.
+#[derive(Debug)]
+pub enum Exit {
+    /// It has been stopped gracefully, by `Address::stop()` or because every
+    /// address has been dropped.
+    Stopped,
+
+    /// Its handler panicked with this payload, and it has been stopped. See
+    /// `Supervision`.
+    Panicked(Box<dyn Any + Send>),
+}
+
+/// What goes through a mailbox.
+enum Letter<M> {
+    Message(M),
+    Stop,
+}
+
+impl<M> Letter<M> {
+    /// Returns the message in a letter which could not be delivered.
+    fn into_message(self) -> M {
+        match self {
+            Letter::Message(msg) => msg,
+            Letter::Stop => unreachable!("stop letter returned as a message"),
+        }
+    }
+}
+
+/// Creates a mailbox which holds up to `capacity` messages, along with an
+/// address to send them to it.
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn mailbox<M>(capacity: usize) -> (Address<M>, Mailbox<M>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (Address(tx), Mailbox(rx))
+}
+
+/// Where to send messages to an actor. It can be cloned to send from several
+/// places.
+pub struct Address<M>(mpsc::Sender<Letter<M>>);
+
+impl<M> Address<M> {
+    /// Sends `msg`, suspending the coroutine while the mailbox is full.
+    ///
+    /// Hands back the `Control`, along with the message if the actor has
+    /// exited.
+    pub fn send<'a, Y, R>(
+        &self,
+        c: Control<'a, (), Y, R>,
+        msg: M,
+    ) -> WaitResult<'a, Y, R, Result<(), SendError<M>>> {
+        let (c, result) = self.0.send(c, Letter::Message(msg))?;
+        Ok((
+            c,
+            result.map_err(|SendError(l)| SendError(l.into_message())),
+        ))
+    }
+
+    /// Sends `msg` if the mailbox has room for it, without waiting.
+    pub fn try_send(&self, msg: M) -> Result<(), TrySendError<M>> {
+        self.0.try_send(Letter::Message(msg)).map_err(|e| match e {
+            TrySendError::Full(l) => TrySendError::Full(l.into_message()),
+            TrySendError::Disconnected(l) => TrySendError::Disconnected(l.into_message()),
+        })
+    }
+
+    /// Asks the actor to stop once it has handled the messages sent before,
+    /// suspending the coroutine while the mailbox is full.
+    ///
+    /// Hands back the `Control`, along with an error if the actor has exited
+    /// already.
+    pub fn stop<'a, Y, R>(
+        &self,
+        c: Control<'a, (), Y, R>,
+    ) -> WaitResult<'a, Y, R, Result<(), SendError<()>>> {
+        let (c, result) = self.0.send(c, Letter::Stop)?;
+        Ok((c, result.map_err(|_| SendError(()))))
+    }
+
+    /// Asks the actor to stop like `stop()`, if the mailbox has room for
+    /// that, without waiting.
+    pub fn try_stop(&self) -> Result<(), TrySendError<()>> {
+        self.0.try_send(Letter::Stop).map_err(|e| match e {
+            TrySendError::Full(_) => TrySendError::Full(()),
+            TrySendError::Disconnected(_) => TrySendError::Disconnected(()),
+        })
+    }
+}
+
+impl<M> Clone for Address<M> {
+    fn clone(&self) -> Self {
+        Address(self.0.clone())
+    }
+}
+
+impl<M> fmt::Debug for Address<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Address").finish()
+    }
+}
+
+/// Where the messages sent to an actor wait for it.
+pub struct Mailbox<M>(mpsc::Receiver<Letter<M>>);
+
+impl<M> Mailbox<M> {
+    /// Receives the next message, suspending the coroutine while there is
+    /// none.
+    ///
+    /// Hands back the `Control`, along with the message, or `None` once the
+    /// actor has been asked to stop, or every address has been dropped and
+    /// the mailbox is empty.
+    pub fn recv<'a, Y, R>(&self, c: Control<'a, (), Y, R>) -> WaitResult<'a, Y, R, Option<M>> {
+        let (c, letter) = self.0.recv(c)?;
+        match letter {
+            Ok(Letter::Message(msg)) => Ok((c, Some(msg))),
+            Ok(Letter::Stop) | Err(_) => Ok((c, None)),
+        }
+    }
+}
+
+impl<M> fmt::Debug for Mailbox<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mailbox").finish()
+    }
+}
+
+/// Makes a coroutine which runs the actor `new()` makes, handling the
+/// messages in `mailbox` until it exits, for an executor to drive.
+///
+/// Whenever the actor is replaced after a panic (see `Supervision`),
+/// `new()` makes its replacement.
+pub fn actor<'a, A, F, Y, S>(
+    stack: S,
+    mailbox: Mailbox<A::Message>,
+    supervision: Supervision,
+    mut new: F,
+) -> Coroutine<'a, (), Y, Exit, S>
+where
+    A: Actor,
+    F: FnMut() -> A + 'a,
+    A::Message: 'a,
+    S: Stack + 'a,
+{
+    Coroutine::new(stack, move |mut c, ()| {
+        let mut actor = new();
+        let mut restarts = 0;
+
+        loop {
+            let (next, msg) = mailbox.recv(c)?;
+            c = next;
+
+            let msg = match msg {
+                Some(msg) => msg,
+                None => {
+                    actor.stopped();
+                    return c.done(Exit::Stopped);
+                }
+            };
+
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(|| actor.handle(msg))) {
+                match supervision {
+                    Supervision::Restart(max) if restarts < max => {
+                        restarts += 1;
+                        actor = new();
+                    }
+                    _ => return c.done(Exit::Panicked(payload)),
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::{GeneratorState, STACK_MINIMUM};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Logs what it handles, and panics on negative numbers.
+    struct Logger(Rc<RefCell<Vec<String>>>);
+
+    impl Actor for Logger {
+        type Message = i32;
+
+        fn handle(&mut self, msg: i32) {
+            assert!(msg >= 0, "negative");
+            self.0.borrow_mut().push(msg.to_string());
+        }
+
+        fn stopped(&mut self) {
+            self.0.borrow_mut().push(String::from("stopped"));
+        }
+    }
+
+    #[test]
+    fn graceful_stop() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (addr, mailbox) = mailbox(1);
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut executor = Executor::<(), Exit, _>::new();
+
+        let logger = log.clone();
+        let _ = executor.spawn(actor(&mut a, mailbox, Supervision::Stop, move || {
+            Logger(logger.clone())
+        }));
+
+        // Sends more than the mailbox holds, so it waits for the actor.
+        let _ = executor.spawn(Coroutine::new(&mut b, move |mut c, ()| {
+            for i in 0..3 {
+                c = addr.send(c, i)?.0;
+            }
+            let (c, result) = addr.stop(c)?;
+            assert_eq!(result, Ok(()));
+            c.done(Exit::Stopped)
+        }));
+
+        let mut stopped = 0;
+        executor.run(|_, state| {
+            assert!(matches!(state, GeneratorState::Complete(Exit::Stopped)));
+            stopped += 1;
+        });
+        assert_eq!(stopped, 2);
+        assert_eq!(*log.borrow(), ["0", "1", "2", "stopped"]);
+    }
+
+    #[test]
+    fn restart() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (addr, mailbox) = mailbox(8);
+        for &i in [1, -1, 2, -2, 3].iter() {
+            addr.try_send(i).unwrap();
+        }
+
+        // Unwinding from the handler takes more stack.
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+        let mut executor = Executor::<(), Exit, _>::new();
+
+        let logger = log.clone();
+        let _ = executor.spawn(actor(
+            &mut stack,
+            mailbox,
+            Supervision::Restart(1),
+            move || {
+                logger.borrow_mut().push(String::from("new"));
+                Logger(logger.clone())
+            },
+        ));
+
+        executor.run(|_, state| match state {
+            GeneratorState::Complete(Exit::Panicked(payload)) => {
+                assert_eq!(payload.downcast_ref(), Some(&"negative"))
+            }
+            _ => panic!("unexpected exit"),
+        });
+        assert_eq!(*log.borrow(), ["new", "1", "new", "2"]);
+
+        // The actor has exited, and dropped its mailbox.
+        assert_eq!(addr.try_send(4), Err(TrySendError::Disconnected(4)));
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Adapters which transform generators, like those of `Iterator`: the values
+//! a generator yields, the value it returns, or two generators run one after
+//! the other. They take any `Generator` which is `Unpin`, such as a
+//! `Coroutine`, and are generators themselves, so they compose. `pipe()`
+//! feeds what one yields to a coroutine which takes resume arguments, so
+//! stages can be chained into a streaming pipeline.
+
+use crate::{Coroutine, Generator, GeneratorState};
+use core::pin::Pin;
+
+/// Methods to adapt a generator. See `adapter`.
+pub trait GeneratorExt: Generator + Sized {
+    /// Passes every value the generator yields through `f`.
+    fn map_yield<F, Z>(self, f: F) -> MapYield<Self, F>
+    where
+        F: FnMut(Self::Yield) -> Z,
+    {
+        MapYield { gen: self, f }
+    }
+
+    /// Passes the value the generator returns through `f`.
+    fn map_return<F, T>(self, f: F) -> MapReturn<Self, F>
+    where
+        F: FnOnce(Self::Return) -> T,
+    {
+        MapReturn {
+            gen: self,
+            f: Some(f),
+        }
+    }
+
+    /// Runs `other` once this generator has completed, yielding what both
+    /// yield, and returning what both return.
+    fn chain<G>(self, other: G) -> Chain<Self, G>
+    where
+        G: Generator<Yield = Self::Yield>,
+    {
+        Chain {
+            first: self,
+            second: other,
+            returned: None,
+        }
+    }
+}
+
+impl<G: Generator> GeneratorExt for G {}
+
+/// A generator which maps the values another one yields. See
+/// `GeneratorExt::map_yield()`.
+#[derive(Debug)]
+pub struct MapYield<G, F> {
+    gen: G,
+    f: F,
+}
+
+// The closure is never pinned.
+impl<G: Unpin, F> Unpin for MapYield<G, F> {}
+
+impl<G, F, Z> Generator for MapYield<G, F>
+where
+    G: Generator + Unpin,
+    F: FnMut(G::Yield) -> Z,
+{
+    type Yield = Z;
+    type Return = G::Return;
+
+    fn resume(self: Pin<&mut Self>) -> GeneratorState<Z, G::Return> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.gen).resume() {
+            GeneratorState::Yielded(y) => GeneratorState::Yielded((this.f)(y)),
+            GeneratorState::Complete(r) => GeneratorState::Complete(r),
+        }
+    }
+}
+
+/// A generator which maps the value another one returns. See
+/// `GeneratorExt::map_return()`.
+#[derive(Debug)]
+pub struct MapReturn<G, F> {
+    gen: G,
+    f: Option<F>,
+}
+
+impl<G: Unpin, F> Unpin for MapReturn<G, F> {}
+
+impl<G, F, T> Generator for MapReturn<G, F>
+where
+    G: Generator + Unpin,
+    F: FnOnce(G::Return) -> T,
+{
+    type Yield = G::Yield;
+    type Return = T;
+
+    fn resume(self: Pin<&mut Self>) -> GeneratorState<G::Yield, T> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.gen).resume() {
+            GeneratorState::Yielded(y) => GeneratorState::Yielded(y),
+            GeneratorState::Complete(r) => {
+                let f = this.f.take().expect("generator resumed after completion");
+                GeneratorState::Complete(f(r))
+            }
+        }
+    }
+}
+
+/// A generator which runs two others, one after the other. See
+/// `GeneratorExt::chain()`.
+///
+/// It holds on to what the first one returns until the second one has
+/// completed too, and then returns both.
+#[derive(Debug)]
+pub struct Chain<G: Generator, H> {
+    first: G,
+    second: H,
+    returned: Option<G::Return>,
+}
+
+impl<G: Generator + Unpin, H: Unpin> Unpin for Chain<G, H> {}
+
+impl<G, H> Generator for Chain<G, H>
+where
+    G: Generator + Unpin,
+    H: Generator<Yield = G::Yield> + Unpin,
+{
+    type Yield = G::Yield;
+    type Return = (G::Return, H::Return);
+
+    fn resume(self: Pin<&mut Self>) -> GeneratorState<G::Yield, (G::Return, H::Return)> {
+        let this = self.get_mut();
+        if this.returned.is_none() {
+            match Pin::new(&mut this.first).resume() {
+                GeneratorState::Yielded(y) => return GeneratorState::Yielded(y),
+                GeneratorState::Complete(r) => this.returned = Some(r),
+            }
+        }
+
+        match Pin::new(&mut this.second).resume() {
+            GeneratorState::Yielded(y) => GeneratorState::Yielded(y),
+            GeneratorState::Complete(r) => {
+                let first = this
+                    .returned
+                    .take()
+                    .expect("generator resumed after completion");
+                GeneratorState::Complete((first, r))
+            }
+        }
+    }
+}
+
+/// Feeds every value `producer` yields to `consumer` as its resume argument,
+/// and yields what `consumer` yields in turn.
+///
+/// The first value is passed to the consumer's closure, and each one after
+/// that is returned from the `Control::r#yield()` it is paused in, so the
+/// consumer yields once for every value it takes. The pipe completes as soon
+/// as either of the two does, returning which one it was and what it
+/// returned; the other is left suspended, and is canceled when the pipe is
+/// dropped. Pipes are generators themselves, so a pipe can be the producer of
+/// the next stage.
+pub fn pipe<'a, G, U, R, S>(
+    producer: G,
+    consumer: Coroutine<'a, G::Yield, U, R, S>,
+) -> Pipe<'a, G, U, R, S>
+where
+    G: Generator + Unpin,
+{
+    Pipe { producer, consumer }
+}
+
+/// Which stage of a `Pipe` completed it, with what that stage returned.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Debug, Hash)]
+pub enum Piped<P, C> {
+    /// The producer ran out, and returned this.
+    Producer(P),
+
+    /// The consumer completed, and returned this.
+    Consumer(C),
+}
+
+/// A generator which feeds what one generator yields to a coroutine. See
+/// `pipe()`.
+#[derive(Debug)]
+pub struct Pipe<'a, G: Generator, U, R, S> {
+    producer: G,
+    consumer: Coroutine<'a, G::Yield, U, R, S>,
+}
+
+impl<'a, G: Generator + Unpin, U, R, S> Unpin for Pipe<'a, G, U, R, S> {}
+
+impl<'a, G, U, R, S> Generator for Pipe<'a, G, U, R, S>
+where
+    G: Generator + Unpin,
+{
+    type Yield = U;
+    type Return = Piped<G::Return, R>;
+
+    fn resume(self: Pin<&mut Self>) -> GeneratorState<U, Piped<G::Return, R>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.producer).resume() {
+            GeneratorState::Yielded(t) => match Pin::new(&mut this.consumer).resume_with(t) {
+                GeneratorState::Yielded(u) => GeneratorState::Yielded(u),
+                GeneratorState::Complete(r) => GeneratorState::Complete(Piped::Consumer(r)),
+            },
+            GeneratorState::Complete(r) => GeneratorState::Complete(Piped::Producer(r)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Control, Coroutine, CoroutineError, Finished, STACK_MINIMUM};
+
+    fn count<'a>(
+        stack: &'a mut [u8],
+        n: i32,
+        r: &'static str,
+    ) -> Coroutine<'a, (), i32, &'static str> {
+        Coroutine::new(stack, move |mut c: Control<'_, (), i32, &str>, ()| {
+            for i in 1..=n {
+                c = c.r#yield(i)?.0;
+            }
+            c.done(r)
+        })
+    }
+
+    fn drive<G: Generator + Unpin>(mut gen: G) -> (Vec<G::Yield>, G::Return) {
+        let mut yielded = Vec::new();
+        loop {
+            match Pin::new(&mut gen).resume() {
+                GeneratorState::Yielded(y) => yielded.push(y),
+                GeneratorState::Complete(r) => return (yielded, r),
+            }
+        }
+    }
+
+    #[test]
+    fn map_yield() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let gen = count(&mut stack, 2, "foo").map_yield(|y| y * 10);
+        assert_eq!(drive(gen), (vec![10, 20], "foo"));
+    }
+
+    #[test]
+    fn map_return() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let gen = count(&mut stack, 2, "foo").map_return(str::len);
+        assert_eq!(drive(gen), (vec![1, 2], 3));
+    }
+
+    #[test]
+    fn chain() {
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let gen = count(&mut a, 2, "foo")
+            .chain(count(&mut b, 1, "bar"))
+            .map_yield(|y| y.to_string())
+            .map_return(|(a, b)| [a, b].concat());
+        assert_eq!(
+            drive(gen),
+            (vec!["1".into(), "2".into(), "1".into()], "foobar".into())
+        );
+    }
+
+    #[test]
+    fn chain_empty() {
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let first = Coroutine::new(&mut a, |c: Control<'_, (), i32, ()>, ()| c.done(()));
+        let second = Coroutine::new(&mut b, |c, ()| -> Result<Finished<u8>, CoroutineError> {
+            let (c, ()) = c.r#yield(7)?;
+            c.done(8)
+        });
+        assert_eq!(drive(first.chain(second)), (vec![7], ((), 8)));
+    }
+
+    #[test]
+    fn pipe() {
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM * 16];
+        let mut c = [1u8; STACK_MINIMUM * 16];
+
+        // Running totals, then their squares. Both never return, and unwind
+        // once the pipe is dropped.
+        let sum = Coroutine::new(&mut b, |mut c: Control<'_, i32, i32, ()>, mut i| {
+            let mut total = 0;
+            loop {
+                total += i;
+                let (next, j) = c.r#yield(total)?;
+                c = next;
+                i = j;
+            }
+        });
+        let square = Coroutine::new(&mut c, |mut c: Control<'_, i32, i32, ()>, mut i| loop {
+            let (next, j) = c.r#yield(i * i)?;
+            c = next;
+            i = j;
+        });
+
+        let gen = super::pipe(super::pipe(count(&mut a, 3, "foo"), sum), square);
+        assert_eq!(
+            drive(gen),
+            (vec![1, 9, 36], Piped::Producer(Piped::Producer("foo")))
+        );
+    }
+
+    #[test]
+    fn pipe_consumer_completes() {
+        // The producer unwinds once the pipe is dropped.
+        let mut a = [1u8; STACK_MINIMUM * 16];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let take = Coroutine::new(&mut b, |c: Control<'_, i32, String, i32>, i| {
+            let (c, j) = c.r#yield(i.to_string())?;
+            c.done(i + j)
+        });
+
+        let gen = super::pipe(count(&mut a, 5, "foo"), take);
+        assert_eq!(drive(gen), (vec![String::from("1")], Piped::Consumer(3)));
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Applications which keep their stacks somewhere special (an arena, enclave
+//! memory, a region reserved at boot, ...) implement `StackAllocator`, and
+//! either install it for the whole process with `set_stack_allocator()`, or
+//! hand it to what allocates the stacks: `AllocatedStack::new_in()`, or
+//! `StackPool::with_allocator()` for a `ThreadPool`.
+
+#[cfg(feature = "std")]
+use crate::ProtectedStack;
+use crate::{Stack, STACK_ALIGNMENT};
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// What a stack is allocated with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackOptions {
+    /// The usable size of the stack, in bytes.
+    pub size: usize,
+
+    /// Whether the stack wants an inaccessible guard page below it, so that
+    /// overflowing it faults rather than corrupting the memory next to it.
+    /// Allocators which can't protect memory may ignore this.
+    pub guard: bool,
+}
+
+impl StackOptions {
+    /// Describes a stack of `size` bytes, with a guard page if `guard`.
+    pub fn new(size: usize, guard: bool) -> Self {
+        StackOptions { size, guard }
+    }
+}
+
+/// A source of coroutine stacks.
+///
+/// # Safety
+///
+/// `allocate()` must return memory which is valid for reads and writes of
+/// `options.size` bytes, initialized, aligned to `STACK_ALIGNMENT`, and not
+/// used by anything else until it is passed to `deallocate()`. Its bytes can
+/// be read through `AllocatedStack`: fresh memory must be zeroed, or filled
+/// in some other way, before it is handed out.
+pub unsafe trait StackAllocator: Sync {
+    /// Allocates a stack, or returns `None` if there is no memory for it.
+    ///
+    /// `options.size` is always a non-zero multiple of `STACK_ALIGNMENT`.
+    fn allocate(&self, options: StackOptions) -> Option<NonNull<u8>>;
+
+    /// Releases a stack.
+    ///
+    /// # Safety
+    ///
+    /// `bottom` must have been returned by `allocate()` on this allocator,
+    /// with the same `options`, and must not be used afterwards.
+    unsafe fn deallocate(&self, bottom: NonNull<u8>, options: StackOptions);
+
+    /// Tells the allocator that the contents of the lowest `len` bytes of a
+    /// stack which stays allocated are no longer needed, so that it may
+    /// release the memory behind them until the stack is used again. See
+    /// `Reclaim::Discard` and `Reclaim::Trim`.
+    ///
+    /// Does nothing by default.
+    ///
+    /// # Safety
+    ///
+    /// As for `deallocate()`, except that the stack may be used afterwards,
+    /// with unspecified contents in those `len` bytes, which are never more
+    /// than `options.size`. The contents must still be initialized, as with
+    /// `allocate()`.
+    unsafe fn discard(&self, _bottom: NonNull<u8>, _options: StackOptions, _len: usize) {}
+}
+
+/// Whether a stack is backed by huge pages. See
+/// `ProtectedStack::with_huge_pages()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    /// Use pages of the ordinary size.
+    No,
+
+    /// Ask for transparent huge pages, which the kernel uses when it can.
+    Transparent,
+
+    /// Take huge pages from the pool the administrator reserved, or
+    /// transparent huge pages if there are none left.
+    Explicit,
+}
+
+/// The stack allocator used unless `set_stack_allocator()` installs another.
+///
+/// With the `std` feature, stacks with a guard page are mapped like
+/// `ProtectedStack`s (with `MAP_STACK`, where the OS has it), as are all
+/// stacks if the allocator is set up to use huge pages or `MAP_GROWSDOWN`.
+/// The others come from the heap like `HeapStack`s.
+///
+/// ```
+/// use frenetic::{set_stack_allocator, DefaultStackAllocator, HugePages};
+///
+/// static HUGE: DefaultStackAllocator = DefaultStackAllocator::with_huge_pages(HugePages::Transparent);
+/// assert!(set_stack_allocator(&HUGE));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct DefaultStackAllocator {
+    huge_pages: HugePages,
+    grows_down: bool,
+}
+
+impl DefaultStackAllocator {
+    /// Creates an allocator which uses pages of the ordinary size.
+    pub const fn new() -> Self {
+        Self::with_huge_pages(HugePages::No)
+    }
+
+    /// Creates an allocator which backs the stacks it maps with huge pages.
+    /// If the OS has none, they get pages of the ordinary size. See
+    /// `ProtectedStack::with_huge_pages()`.
+    pub const fn with_huge_pages(huge_pages: HugePages) -> Self {
+        DefaultStackAllocator {
+            huge_pages,
+            grows_down: false,
+        }
+    }
+
+    /// Marks the stacks it maps as growing down (`MAP_GROWSDOWN`), on Linux.
+    ///
+    /// The kernel then keeps other mappings out of the gap below each stack
+    /// (`vm.stack_guard_gap`), as it does for the stack of the main thread,
+    /// so that a frame too large to hit the guard page still faults instead
+    /// of landing in another mapping.
+    pub const fn grows_down(self, grows_down: bool) -> Self {
+        DefaultStackAllocator { grows_down, ..self }
+    }
+
+    /// Returns whether the stacks it maps are backed by huge pages.
+    pub fn huge_pages(&self) -> HugePages {
+        self.huge_pages
+    }
+
+    /// Returns whether the stacks it maps are marked as growing down.
+    pub fn is_grows_down(&self) -> bool {
+        self.grows_down
+    }
+
+    /// Returns whether a stack is mapped, rather than taken from the heap.
+    #[cfg(feature = "std")]
+    fn mapped(&self, options: StackOptions) -> bool {
+        options.guard || self.grows_down || self.huge_pages != HugePages::No
+    }
+}
+
+impl Default for DefaultStackAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl StackAllocator for DefaultStackAllocator {
+    fn allocate(&self, options: StackOptions) -> Option<NonNull<u8>> {
+        #[cfg(feature = "std")]
+        {
+            if self.mapped(options) {
+                let stack =
+                    ProtectedStack::map(options.size, self.huge_pages, self.grows_down).ok()?;
+                return NonNull::new(stack.into_raw());
+            }
+        }
+
+        // Zeroed, as mapped memory is, since the bytes can be read through
+        // `AllocatedStack`.
+        let layout = Layout::from_size_align(options.size, STACK_ALIGNMENT).ok()?;
+        NonNull::new(unsafe { alloc_zeroed(layout) })
+    }
+
+    unsafe fn deallocate(&self, bottom: NonNull<u8>, options: StackOptions) {
+        #[cfg(feature = "std")]
+        {
+            if self.mapped(options) {
+                drop(ProtectedStack::from_raw(
+                    bottom.as_ptr(),
+                    options.size,
+                    self.huge_pages,
+                ));
+                return;
+            }
+        }
+
+        let layout = Layout::from_size_align_unchecked(options.size, STACK_ALIGNMENT);
+        dealloc(bottom.as_ptr(), layout)
+    }
+
+    #[cfg(feature = "std")]
+    unsafe fn discard(&self, bottom: NonNull<u8>, options: StackOptions, len: usize) {
+        if self.mapped(options) {
+            let mut stack =
+                ProtectedStack::from_raw(bottom.as_ptr(), options.size, self.huge_pages);
+            stack.discard(len);
+            let _ = stack.into_raw();
+        }
+    }
+}
+
+const UNSET: usize = 0;
+const SETTING: usize = 1;
+const SET: usize = 2;
+
+static STATE: AtomicUsize = AtomicUsize::new(UNSET);
+static DEFAULT: DefaultStackAllocator = DefaultStackAllocator::new();
+static mut GLOBAL: &dyn StackAllocator = &DEFAULT;
+
+/// Installs the stack allocator `Coroutine::with_stack_size()`,
+/// `CoroutineBuilder::spawn()`, `StackPool::new()` and `AllocatedStack::new()`
+/// use, in place of the `DefaultStackAllocator`.
+///
+/// This can only be done once. Returns `false`, and leaves the allocator as
+/// it is, if it was done before. Stacks are always released by the allocator
+/// which allocated them.
+pub fn set_stack_allocator(allocator: &'static dyn StackAllocator) -> bool {
+    if STATE
+        .compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return false;
+    }
+
+    unsafe { GLOBAL = allocator };
+    STATE.store(SET, Ordering::Release);
+    true
+}
+
+/// Returns the stack allocator installed by `set_stack_allocator()`, or the
+/// `DefaultStackAllocator`.
+pub fn stack_allocator() -> &'static dyn StackAllocator {
+    match STATE.load(Ordering::Acquire) {
+        SET => unsafe { GLOBAL },
+        _ => &DEFAULT,
+    }
+}
+
+/// A stack allocated by a `StackAllocator`, and released by it when dropped.
+pub struct AllocatedStack {
+    bottom: NonNull<u8>,
+    options: StackOptions,
+    allocator: &'static dyn StackAllocator,
+}
+
+// The memory is exclusively owned by the `AllocatedStack`, and allocators are
+// `Sync`.
+unsafe impl Send for AllocatedStack {}
+unsafe impl Sync for AllocatedStack {}
+
+impl AllocatedStack {
+    /// Allocates a stack with the allocator installed by
+    /// `set_stack_allocator()`. See `new_in()`.
+    pub fn new(options: StackOptions) -> Option<Self> {
+        Self::new_in(options, stack_allocator())
+    }
+
+    /// Allocates a stack with `allocator`, or returns `None` if it has no
+    /// memory for it.
+    ///
+    /// The size is rounded up to a multiple of `STACK_ALIGNMENT`.
+    pub fn new_in(options: StackOptions, allocator: &'static dyn StackAllocator) -> Option<Self> {
+        let size = options.size.max(1).checked_add(STACK_ALIGNMENT - 1)? & !(STACK_ALIGNMENT - 1);
+
+        let options = StackOptions { size, ..options };
+        let bottom = allocator.allocate(options)?;
+        Some(AllocatedStack {
+            bottom,
+            options,
+            allocator,
+        })
+    }
+
+    /// Returns what the stack was allocated with.
+    pub fn options(&self) -> StackOptions {
+        self.options
+    }
+
+    /// Lets the allocator release the memory behind the lowest `len` bytes
+    /// of the stack until they are used again. See
+    /// `StackAllocator::discard()`.
+    #[cfg(feature = "std")]
+    pub(crate) fn discard(&mut self, len: usize) {
+        let len = len.min(self.options.size);
+        unsafe { self.allocator.discard(self.bottom, self.options, len) }
+    }
+}
+
+impl Drop for AllocatedStack {
+    fn drop(&mut self) {
+        unsafe { self.allocator.deallocate(self.bottom, self.options) }
+    }
+}
+
+impl Deref for AllocatedStack {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.bottom.as_ptr(), self.options.size) }
+    }
+}
+
+impl DerefMut for AllocatedStack {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.bottom.as_ptr(), self.options.size) }
+    }
+}
+
+unsafe impl Stack for AllocatedStack {
+    fn bottom(&mut self) -> *mut u8 {
+        self.bottom.as_ptr()
+    }
+
+    fn len(&self) -> usize {
+        self.options.size
+    }
+}
+
+impl AsRef<[u8]> for AllocatedStack {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for AllocatedStack {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Coroutine, Generator, GeneratorState, STACK_MINIMUM};
+    use core::cell::UnsafeCell;
+    use core::pin::Pin;
+    use core::sync::atomic::AtomicBool;
+
+    /// Hands out a single region, reserved up front.
+    #[repr(C, align(16))] // STACK_ALIGNMENT
+    struct Arena {
+        memory: UnsafeCell<[u8; STACK_MINIMUM * 2]>,
+        used: AtomicBool,
+    }
+
+    unsafe impl Sync for Arena {}
+
+    unsafe impl StackAllocator for Arena {
+        fn allocate(&self, options: StackOptions) -> Option<NonNull<u8>> {
+            if options.size > STACK_MINIMUM * 2 || self.used.swap(true, Ordering::Acquire) {
+                return None;
+            }
+
+            NonNull::new(self.memory.get() as *mut u8)
+        }
+
+        unsafe fn deallocate(&self, bottom: NonNull<u8>, _options: StackOptions) {
+            assert_eq!(bottom.as_ptr(), self.memory.get() as *mut u8);
+            self.used.store(false, Ordering::Release);
+        }
+    }
+
+    #[test]
+    fn arena() {
+        static ARENA: Arena = Arena {
+            memory: UnsafeCell::new([0; STACK_MINIMUM * 2]),
+            used: AtomicBool::new(false),
+        };
+
+        let options = StackOptions::new(STACK_MINIMUM, false);
+        let stack = AllocatedStack::new_in(options, &ARENA).unwrap();
+        assert_eq!(stack.as_ptr(), ARENA.memory.get() as *const u8);
+        assert!(AllocatedStack::new_in(options, &ARENA).is_none());
+
+        let mut coro = Coroutine::new(stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        drop(coro);
+        assert!(AllocatedStack::new_in(options, &ARENA).is_some());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn default() {
+        for &guard in &[false, true] {
+            let options = StackOptions::new(STACK_MINIMUM + 1, guard);
+            let mut stack = AllocatedStack::new_in(options, &DEFAULT).unwrap();
+            assert_eq!(stack.options().size % STACK_ALIGNMENT, 0);
+            assert_eq!(stack.as_ptr() as usize % STACK_ALIGNMENT, 0);
+            assert!(Stack::len(&stack) > STACK_MINIMUM);
+            assert!(stack.iter().all(|&b| b == 0));
+            stack[STACK_MINIMUM] = 1;
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mmap().
+    fn huge_pages() {
+        static HUGE: DefaultStackAllocator =
+            DefaultStackAllocator::with_huge_pages(HugePages::Explicit);
+
+        for &guard in &[false, true] {
+            let options = StackOptions::new(STACK_MINIMUM, guard);
+            let mut stack = AllocatedStack::new_in(options, &HUGE).unwrap();
+            assert!(Stack::len(&stack) >= STACK_MINIMUM);
+            stack[STACK_MINIMUM - 1] = 1;
+            stack.discard(STACK_MINIMUM);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mmap().
+    fn grows_down() {
+        static DOWN: DefaultStackAllocator = DefaultStackAllocator::new().grows_down(true);
+
+        let options = StackOptions::new(STACK_MINIMUM, false);
+        let stack = AllocatedStack::new_in(options, &DOWN).unwrap();
+        let mut coro = Coroutine::new(stack, |c, ()| c.done(1));
+        assert_eq!(
+            Pin::new(&mut coro).resume(),
+            GeneratorState::<(), _>::Complete(1)
+        );
+    }
+}
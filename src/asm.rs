@@ -0,0 +1,470 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend written with `global_asm!`, so that building the crate needs no
+//! C compiler. It is a transcription of `src/jump/x86_64.S` and
+//! `src/jump/aarch64.S`, which describe the context layout in detail: the
+//! functions are the same, and so are the symbols, so the `extern` block in
+//! the crate root binds to whichever backend is built.
+//!
+//! The preprocessor conditionals of the assembly files become the macros
+//! below, which expand to the lines they wrap, or to nothing, depending on
+//! the target and the crate's features.
+
+use core::arch::global_asm;
+
+/// Expands to its lines on ELF targets.
+#[cfg(not(target_vendor = "apple"))]
+macro_rules! elf {
+    ($($line:expr),* $(,)?) => { concat!($($line, "\n"),*) };
+}
+
+#[cfg(target_vendor = "apple")]
+macro_rules! elf {
+    ($($line:expr),* $(,)?) => {
+        ""
+    };
+}
+
+/// The symbol of a C function.
+#[cfg(not(target_vendor = "apple"))]
+macro_rules! sym {
+    ($name:literal) => {
+        $name
+    };
+}
+
+#[cfg(target_vendor = "apple")]
+macro_rules! sym {
+    ($name:literal) => {
+        concat!("_", $name)
+    };
+}
+
+/// A label local to the object file. Mach-O only keeps labels starting with
+/// `L` out of the symbol table; anything else would split the functions up.
+#[cfg(not(target_vendor = "apple"))]
+macro_rules! local {
+    ($name:literal) => {
+        concat!(".L", $name)
+    };
+}
+
+#[cfg(target_vendor = "apple")]
+macro_rules! local {
+    ($name:literal) => {
+        concat!("L", $name)
+    };
+}
+
+/// Starts the global function `$name`, aligned to `$align` (a power of two).
+macro_rules! func {
+    ($name:literal, $align:literal) => {
+        concat!(
+            ".globl ",
+            sym!($name),
+            "\n",
+            elf!(concat!(".type ", $name, ", %function")),
+            ".p2align ",
+            $align,
+            "\n",
+            sym!($name),
+            ":",
+        )
+    };
+}
+
+/// Ends the function `$name`.
+macro_rules! end {
+    ($name:literal) => {
+        elf!(concat!(".size ", $name, ", .-", $name))
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64 {
+    use super::global_asm;
+
+    /// Expands to its lines with the `cet` feature.
+    #[cfg(feature = "cet")]
+    macro_rules! cet {
+        ($($line:expr),* $(,)?) => { concat!($($line, "\n"),*) };
+    }
+
+    #[cfg(not(feature = "cet"))]
+    macro_rules! cet {
+        ($($line:expr),* $(,)?) => {
+            ""
+        };
+    }
+
+    /// The size of the shadow stack pointer slot. See `src/jump/x86_64.S`.
+    #[cfg(feature = "cet")]
+    macro_rules! ssp_area {
+        () => {
+            "8"
+        };
+    }
+
+    #[cfg(not(feature = "cet"))]
+    macro_rules! ssp_area {
+        () => {
+            "0"
+        };
+    }
+
+    /// Switches to the shadow stack whose pointer is in `$reg`, if it isn't
+    /// null and isn't the current one already. Clobbers r11. See
+    /// `src/jump/x86_64.S`.
+    macro_rules! switch_ssp {
+        ($reg:literal) => {
+            cet!(
+                concat!("test ", $reg, ", ", $reg),
+                "jz 1f",
+                "rdsspq r11",
+                concat!("cmp r11, ", $reg),
+                "je 1f",
+                concat!("rstorssp [", $reg, " - 8]"),
+                "saveprevssp",
+                "1:",
+            )
+        };
+    }
+
+    /// Pushes the callee-saved registers, the floating-point control state
+    /// and the shadow stack pointer onto the current stack.
+    macro_rules! save {
+        () => {
+            concat!(
+                "push rbp\n",
+                ".cfi_adjust_cfa_offset 8\n",
+                ".cfi_rel_offset rbp, 0\n",
+                "push rbx\n",
+                ".cfi_adjust_cfa_offset 8\n",
+                ".cfi_rel_offset rbx, 0\n",
+                "push r12\n",
+                ".cfi_adjust_cfa_offset 8\n",
+                ".cfi_rel_offset r12, 0\n",
+                "push r13\n",
+                ".cfi_adjust_cfa_offset 8\n",
+                ".cfi_rel_offset r13, 0\n",
+                "push r14\n",
+                ".cfi_adjust_cfa_offset 8\n",
+                ".cfi_rel_offset r14, 0\n",
+                "push r15\n",
+                ".cfi_adjust_cfa_offset 8\n",
+                ".cfi_rel_offset r15, 0\n",
+                "sub rsp, 8\n",
+                ".cfi_adjust_cfa_offset 8\n",
+                "stmxcsr [rsp]\n",
+                "fnstcw [rsp + 4]\n",
+                cet!(
+                    "xor eax, eax",
+                    "rdsspq rax",
+                    "push rax",
+                    ".cfi_adjust_cfa_offset 8",
+                ),
+            )
+        };
+    }
+
+    // void jump_swap(void *from[1], void *into[1]);
+    // noreturn void jump_into(void *into[1]);
+    // void jump_init(void *addr, void *c, void *f,
+    //                void *(*func)(void *parent[1], void *c, void *f),
+    //                void *limit, void *shadow);
+    global_asm!(
+        func!("jump_swap", "4"),
+        ".cfi_startproc",
+        cet!("endbr64"),
+        save!(),
+        "mov [rdi], rsp",
+        "mov rsp, [rsi]",
+        concat!(local!("frenetic_restore"), ":"),
+        cet!("pop rax", ".cfi_adjust_cfa_offset -8"),
+        switch_ssp!("rax"),
+        "ldmxcsr [rsp]",
+        "fldcw [rsp + 4]",
+        "add rsp, 8",
+        ".cfi_adjust_cfa_offset -8",
+        "pop r15",
+        ".cfi_adjust_cfa_offset -8",
+        ".cfi_restore r15",
+        "pop r14",
+        ".cfi_adjust_cfa_offset -8",
+        ".cfi_restore r14",
+        "pop r13",
+        ".cfi_adjust_cfa_offset -8",
+        ".cfi_restore r13",
+        "pop r12",
+        ".cfi_adjust_cfa_offset -8",
+        ".cfi_restore r12",
+        "pop rbx",
+        ".cfi_adjust_cfa_offset -8",
+        ".cfi_restore rbx",
+        "pop rbp",
+        ".cfi_adjust_cfa_offset -8",
+        ".cfi_restore rbp",
+        "ret",
+        ".cfi_endproc",
+        end!("jump_swap"),
+        "",
+        func!("jump_into", "4"),
+        ".cfi_startproc",
+        cet!("endbr64"),
+        "mov rsp, [rdi]",
+        concat!(".cfi_def_cfa_offset 64 + ", ssp_area!()),
+        concat!("jmp ", local!("frenetic_restore")),
+        ".cfi_endproc",
+        end!("jump_into"),
+        "",
+        func!("jump_init", "4"),
+        ".cfi_startproc",
+        cet!("endbr64"),
+        save!(),
+        "mov rax, rsp",
+        "sub rsp, 40",
+        ".cfi_adjust_cfa_offset 40",
+        "mov [rsp], rax",
+        "mov rax, rdi",
+        "mov rdi, rsp",
+        "mov rsp, rax",
+        switch_ssp!("r9"),
+        ".cfi_remember_state",
+        ".cfi_undefined rip",
+        "lea rbp, [rsi - 16]",
+        "call rcx",
+        "mov rsp, [rax]",
+        ".cfi_restore_state",
+        ".cfi_adjust_cfa_offset -40",
+        concat!("jmp ", local!("frenetic_restore")),
+        ".cfi_endproc",
+        end!("jump_init"),
+    );
+
+    // Tell the linker the code supports IBT and shadow stacks.
+    #[cfg(all(feature = "cet", not(target_vendor = "apple")))]
+    global_asm!(
+        ".pushsection .note.gnu.property, \"a\"",
+        ".p2align 3",
+        ".long 4",
+        ".long 16",
+        ".long 5",
+        ".asciz \"GNU\"",
+        ".long 0xc0000002",
+        ".long 4",
+        ".long 3",
+        ".p2align 3",
+        ".popsection",
+    );
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+    use super::global_asm;
+
+    /// Expands to its lines with the `fpstate` feature.
+    #[cfg(feature = "fpstate")]
+    macro_rules! fpstate {
+        ($($line:expr),* $(,)?) => { concat!($($line, "\n"),*) };
+    }
+
+    #[cfg(not(feature = "fpstate"))]
+    macro_rules! fpstate {
+        ($($line:expr),* $(,)?) => {
+            ""
+        };
+    }
+
+    /// The size of the register save area. See `src/jump/aarch64.S`.
+    #[cfg(feature = "fpstate")]
+    macro_rules! frame {
+        () => {
+            "176"
+        };
+    }
+
+    #[cfg(not(feature = "fpstate"))]
+    macro_rules! frame {
+        () => {
+            "160"
+        };
+    }
+
+    /// Signs the return address and pushes the callee-saved registers onto
+    /// the current stack.
+    macro_rules! save {
+        () => {
+            concat!(
+                "hint #25\n", // paciasp
+                ".cfi_negate_ra_state\n",
+                "sub sp, sp, #",
+                frame!(),
+                "\n",
+                ".cfi_adjust_cfa_offset ",
+                frame!(),
+                "\n",
+                "stp x19, x20, [sp, #0]\n",
+                ".cfi_rel_offset x19, 0\n",
+                ".cfi_rel_offset x20, 8\n",
+                "stp x21, x22, [sp, #16]\n",
+                ".cfi_rel_offset x21, 16\n",
+                ".cfi_rel_offset x22, 24\n",
+                "stp x23, x24, [sp, #32]\n",
+                ".cfi_rel_offset x23, 32\n",
+                ".cfi_rel_offset x24, 40\n",
+                "stp x25, x26, [sp, #48]\n",
+                ".cfi_rel_offset x25, 48\n",
+                ".cfi_rel_offset x26, 56\n",
+                "stp x27, x28, [sp, #64]\n",
+                ".cfi_rel_offset x27, 64\n",
+                ".cfi_rel_offset x28, 72\n",
+                "stp x29, x30, [sp, #80]\n",
+                ".cfi_rel_offset x29, 80\n",
+                ".cfi_rel_offset x30, 88\n",
+                "stp d8, d9, [sp, #96]\n",
+                ".cfi_rel_offset d8, 96\n",
+                ".cfi_rel_offset d9, 104\n",
+                "stp d10, d11, [sp, #112]\n",
+                ".cfi_rel_offset d10, 112\n",
+                ".cfi_rel_offset d11, 120\n",
+                "stp d12, d13, [sp, #128]\n",
+                ".cfi_rel_offset d12, 128\n",
+                ".cfi_rel_offset d13, 136\n",
+                "stp d14, d15, [sp, #144]\n",
+                ".cfi_rel_offset d14, 144\n",
+                ".cfi_rel_offset d15, 152\n",
+                fpstate!("mrs x9, fpcr", "str x9, [sp, #160]"),
+            )
+        };
+    }
+
+    /// Pops the callee-saved registers pushed by `save!()` and authenticates
+    /// the return address.
+    macro_rules! restore {
+        () => {
+            concat!(
+                "ldp x19, x20, [sp, #0]\n",
+                "ldp x21, x22, [sp, #16]\n",
+                "ldp x23, x24, [sp, #32]\n",
+                "ldp x25, x26, [sp, #48]\n",
+                "ldp x27, x28, [sp, #64]\n",
+                "ldp x29, x30, [sp, #80]\n",
+                "ldp d8, d9, [sp, #96]\n",
+                "ldp d10, d11, [sp, #112]\n",
+                "ldp d12, d13, [sp, #128]\n",
+                "ldp d14, d15, [sp, #144]\n",
+                fpstate!("ldr x9, [sp, #160]", "msr fpcr, x9"),
+                "add sp, sp, #",
+                frame!(),
+                "\n",
+                ".cfi_adjust_cfa_offset -",
+                frame!(),
+                "\n",
+                ".cfi_restore x19\n",
+                ".cfi_restore x20\n",
+                ".cfi_restore x21\n",
+                ".cfi_restore x22\n",
+                ".cfi_restore x23\n",
+                ".cfi_restore x24\n",
+                ".cfi_restore x25\n",
+                ".cfi_restore x26\n",
+                ".cfi_restore x27\n",
+                ".cfi_restore x28\n",
+                ".cfi_restore x29\n",
+                ".cfi_restore x30\n",
+                ".cfi_restore d8\n",
+                ".cfi_restore d9\n",
+                ".cfi_restore d10\n",
+                ".cfi_restore d11\n",
+                ".cfi_restore d12\n",
+                ".cfi_restore d13\n",
+                ".cfi_restore d14\n",
+                ".cfi_restore d15\n",
+                "hint #29\n", // autiasp
+                ".cfi_negate_ra_state\n",
+            )
+        };
+    }
+
+    // void jump_swap(void *from[1], void *into[1]);
+    // noreturn void jump_into(void *into[1]);
+    // void jump_init(void *addr, void *c, void *f,
+    //                void *(*func)(void *parent[1], void *c, void *f),
+    //                void *limit, void *shadow);
+    global_asm!(
+        func!("jump_swap", "2"),
+        ".cfi_startproc",
+        "hint #34", // bti c
+        save!(),
+        "mov x2, sp",
+        "str x2, [x0]",
+        "ldr x2, [x1]",
+        "mov sp, x2",
+        concat!(local!("frenetic_restore"), ":"),
+        restore!(),
+        "ret",
+        ".cfi_endproc",
+        end!("jump_swap"),
+        "",
+        func!("jump_into", "2"),
+        ".cfi_startproc",
+        "hint #34",
+        "ldr x2, [x0]",
+        "mov sp, x2",
+        concat!(".cfi_def_cfa_offset ", frame!()),
+        concat!("b ", local!("frenetic_restore")),
+        ".cfi_endproc",
+        end!("jump_into"),
+        "",
+        func!("jump_init", "2"),
+        ".cfi_startproc",
+        "hint #34",
+        save!(),
+        "mov x4, sp",
+        "sub sp, sp, #48",
+        ".cfi_adjust_cfa_offset 48",
+        "str x4, [sp]",
+        "mov x4, x0",
+        "mov x0, sp",
+        "mov sp, x4",
+        ".cfi_remember_state",
+        ".cfi_undefined x30",
+        "sub x29, x1, #16",
+        "blr x3",
+        "ldr x2, [x0]",
+        "mov sp, x2",
+        ".cfi_restore_state",
+        ".cfi_adjust_cfa_offset -48",
+        concat!("b ", local!("frenetic_restore")),
+        ".cfi_endproc",
+        end!("jump_init"),
+    );
+
+    // Tell the linker the code supports BTI and PAC.
+    #[cfg(not(target_vendor = "apple"))]
+    global_asm!(
+        ".pushsection .note.gnu.property, \"a\"",
+        ".p2align 3",
+        ".word 4",
+        ".word 16",
+        ".word 5",
+        ".asciz \"GNU\"",
+        ".word 0xc0000000",
+        ".word 4",
+        ".word 3",
+        ".word 0",
+        ".popsection",
+    );
+}
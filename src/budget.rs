@@ -0,0 +1,190 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cooperative preemption: coroutines which run for long call
+//! `Control::maybe_yield()` now and then, which suspends them once they have
+//! used up the budget their scheduler gave them.
+
+use crate::{Control, Coroutine, CoroutineError, ResumeState};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// How much a coroutine may run before it lets others have a turn.
+///
+/// A scheduler (such as the `scheduler::Executor`, see its `set_budget()`)
+/// lends the budget to every coroutine it resumes, and refills it first.
+/// Every call to `Control::maybe_yield()` spends one unit of it, and once it
+/// is used up, suspends the coroutine.
+///
+/// Preemption can also be requested from outside, for instance by a timer
+/// thread or a signal handler, with `preempt()`: the next call to
+/// `maybe_yield()` then suspends the coroutine whatever is left.
+#[derive(Debug)]
+pub struct Budget {
+    refill: u32,
+    left: AtomicU32,
+    pending: AtomicBool,
+}
+
+impl Budget {
+    /// Creates a budget of `units` calls to `maybe_yield()` per resume.
+    pub fn new(units: u32) -> Self {
+        Budget {
+            refill: units,
+            left: AtomicU32::new(units),
+            pending: AtomicBool::new(false),
+        }
+    }
+
+    /// Asks the running coroutine to yield at its next `maybe_yield()`.
+    ///
+    /// This only stores to an atomic flag, so it may be called from a signal
+    /// handler.
+    pub fn preempt(&self) {
+        self.pending.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the coroutine lent this budget should yield: it has
+    /// used it up, or preemption was requested.
+    pub fn is_exhausted(&self) -> bool {
+        self.left.load(Ordering::Relaxed) == 0 || self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Refills the budget, for the next resume.
+    pub fn refill(&self) {
+        self.left.store(self.refill, Ordering::Relaxed);
+    }
+
+    /// Spends a unit. Returns `true` if the coroutine should yield.
+    fn spend(&self) -> bool {
+        if self.pending.load(Ordering::Relaxed) {
+            self.pending.store(false, Ordering::Relaxed);
+            return true;
+        }
+
+        match self.left.load(Ordering::Relaxed) {
+            0 => true,
+            left => {
+                self.left.store(left - 1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
+
+impl<'a, A, Y, R, S> Coroutine<'a, A, Y, R, S> {
+    /// Lends `budget` to the coroutine until `lend_budget(null())`.
+    #[cfg(feature = "std")]
+    pub(crate) fn lend_budget(&mut self, budget: *const Budget) {
+        if let Some(ctx) = self.ctx {
+            unsafe { (&mut (*ctx.as_ptr()).budget as *mut *const Budget).write_volatile(budget) };
+        }
+    }
+}
+
+impl<'a, Y, R> Control<'a, (), Y, R> {
+    /// Spends a unit of the budget the scheduler gave this coroutine, and
+    /// lets other coroutines run if it is used up or preemption was
+    /// requested. See `Budget`.
+    ///
+    /// The coroutine is suspended as if it waited for an event which has
+    /// already occurred: its waker is woken right away, so that a scheduler
+    /// puts it back into its run queue. Without a budget or a waker, this
+    /// does nothing.
+    #[cfg_attr(feature = "registry", track_caller)]
+    pub fn maybe_yield(self) -> Result<(Self, ()), CoroutineError> {
+        let budget = unsafe { (&(*self.0).budget as *const *const Budget).read_volatile() };
+        match unsafe { budget.as_ref() } {
+            Some(budget) if budget.spend() => {}
+            _ => return Ok((self, ())),
+        }
+
+        match self.waker() {
+            Some(waker) => waker.wake_by_ref(),
+            None => return Ok((self, ())),
+        }
+
+        self.suspend(ResumeState::Pending)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::{GeneratorState, STACK_MINIMUM};
+    use std::sync::Arc;
+
+    #[test]
+    fn maybe_yield() {
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let budget = Arc::new(Budget::new(3));
+        let mut executor = Executor::<&str, (), _>::new();
+        executor.set_budget(Some(budget.clone()));
+
+        let _ = executor.spawn(Coroutine::new(&mut a, |mut c, ()| {
+            for _ in 0..8 {
+                c = c.maybe_yield()?.0;
+            }
+            c.done(())
+        }));
+        let _ = executor.spawn(Coroutine::new(&mut b, |mut c, ()| {
+            for _ in 0..3 {
+                c = c.r#yield("b")?.0;
+            }
+            c.done(())
+        }));
+
+        let mut log = Vec::new();
+        executor.run(|id, state| {
+            if let GeneratorState::Yielded(_) = state {
+                log.push(id);
+            }
+        });
+
+        // `a` is suspended after every three calls, and `b` gets a turn.
+        assert_eq!(log, [1, 1, 1]);
+        assert_eq!(executor.metrics().resumes, 3 + 4);
+    }
+
+    #[test]
+    fn preempt() {
+        let mut a = [1u8; STACK_MINIMUM];
+
+        let budget = Arc::new(Budget::new(u32::MAX));
+        let mut executor = Executor::<(), u32, _>::new();
+        executor.set_budget(Some(budget.clone()));
+
+        let _ = executor.spawn(Coroutine::new(&mut a, |mut c, ()| {
+            for i in 0.. {
+                if i == 5 {
+                    budget.preempt();
+                }
+                c = c.maybe_yield()?.0;
+                if i == 5 {
+                    return c.done(i);
+                }
+            }
+            unreachable!()
+        }));
+
+        // The first resume is cut short by the preemption request.
+        assert!(executor.step(|_, _| panic!("nothing to report")));
+        let mut done = None;
+        executor.run(|_, state| done = Some(state));
+        assert_eq!(done, Some(GeneratorState::Complete(5)));
+        assert!(!budget.is_exhausted());
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "alloc")]
+use crate::hooks::Hooks;
+#[cfg(feature = "alloc")]
+use crate::{AllocatedStack, StackOptions};
+use crate::{Control, Coroutine, CoroutineError, Finished, PanicPolicy, Stack, STACK_MINIMUM};
+#[cfg(feature = "alloc")]
+use alloc::{string::String, sync::Arc};
+
+/// Whether the floating-point control state is kept per coroutine. See the
+/// `fpstate` feature.
+const FP_STATE: bool = cfg!(any(
+    feature = "fpstate",
+    feature = "threads",
+    miri,
+    target_arch = "x86_64"
+));
+
+/// Configures a coroutine before spawning it.
+///
+/// `Coroutine::new()` takes a stack and a closure, and nothing else. Every
+/// other option goes here, so that new ones don't change its signature.
+///
+/// ```
+/// # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+/// use frenetic::{Coroutine, Generator, GeneratorState, STACK_MINIMUM};
+/// use core::pin::Pin;
+///
+/// # #[cfg(feature = "alloc")] {
+/// let mut stack = [0u8; STACK_MINIMUM * 8];
+/// let mut coro = Coroutine::builder()
+///     .name("parser")
+///     .spawn_on(&mut stack, |c, ()| c.done(1));
+///
+/// assert_eq!(coro.name(), Some("parser"));
+/// match Pin::new(&mut coro).resume() {
+///     GeneratorState::<(), _>::Complete(1) => {}
+///     _ => panic!("unexpected return from resume"),
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct CoroutineBuilder {
+    stack_size: usize,
+    #[cfg(feature = "alloc")]
+    name: Option<String>,
+    fp_state: bool,
+    panic_policy: PanicPolicy,
+    #[cfg(feature = "alloc")]
+    hooks: Hooks,
+}
+
+impl Default for CoroutineBuilder {
+    fn default() -> Self {
+        CoroutineBuilder {
+            stack_size: CoroutineBuilder::STACK_SIZE,
+            #[cfg(feature = "alloc")]
+            name: None,
+            fp_state: false,
+            panic_policy: PanicPolicy::Propagate,
+            #[cfg(feature = "alloc")]
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+impl CoroutineBuilder {
+    /// The size of the stack `spawn()` allocates, unless told otherwise.
+    pub const STACK_SIZE: usize = STACK_MINIMUM * 16;
+
+    /// Creates a builder with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the size of the stack `spawn()` allocates. See
+    /// `AllocatedStack::new_in()`.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Names the coroutine. See `Coroutine::name()`. Requires the `alloc`
+    /// feature.
+    #[cfg(feature = "alloc")]
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Requires the coroutine to keep floating-point control state (the
+    /// rounding mode and exception masks) of its own.
+    ///
+    /// Whether the context switch saves this state is decided when the
+    /// crate is built, by the `fpstate` feature (x86_64 and the thread
+    /// backend always do). Spawning panics if it is required but not saved.
+    pub fn fp_state(mut self, enabled: bool) -> Self {
+        self.fp_state = enabled;
+        self
+    }
+
+    /// Sets what happens to a panic which the coroutine's closure doesn't
+    /// catch: by default, it unwinds the coroutine's stack and then carries
+    /// on unwinding in whoever resumed it. With `PanicPolicy::Abort`, the
+    /// process aborts instead, for code which must not see a panic come out
+    /// of a resume.
+    ///
+    /// Without the `std` feature, such a panic always aborts.
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Calls `hook` whenever the coroutine is about to be resumed, right
+    /// before switching into it. A canceled coroutine which had started is
+    /// resumed one last time, to unwind it, and this is called then too.
+    ///
+    /// Like `on_suspend()` and `on_complete()`, the hook runs on the thread
+    /// and on the stack which resumes the coroutine, not on the coroutine's
+    /// own. Every coroutine spawned by this builder shares it.
+    ///
+    /// Like `on_suspend()` and `on_complete()`, requires the `alloc` feature.
+    #[cfg(feature = "alloc")]
+    pub fn on_resume(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.resume = Some(Arc::new(hook));
+        self
+    }
+
+    /// Calls `hook` whenever the coroutine has yielded a value or is waiting
+    /// for an event, right after it has switched back.
+    #[cfg(feature = "alloc")]
+    pub fn on_suspend(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.suspend = Some(Arc::new(hook));
+        self
+    }
+
+    /// Calls `hook` once the coroutine has returned, panicked, or been
+    /// unwound by a cancellation, right after it has switched back for the
+    /// last time. A coroutine which is dropped before it has started never
+    /// runs, and doesn't call it.
+    #[cfg(feature = "alloc")]
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.complete = Some(Arc::new(hook));
+        self
+    }
+
+    /// Spawns the coroutine on a stack from the stack allocator (see
+    /// `set_stack_allocator()`). Requires the `alloc` feature.
+    ///
+    /// # Panics
+    ///
+    /// If the stack size is smaller than `STACK_MINIMUM`, the allocator has no
+    /// memory for the stack, or `fp_state()` is required but not supported.
+    #[cfg(feature = "alloc")]
+    pub fn spawn<A, Y, R, F>(self, func: F) -> Coroutine<'static, A, Y, R, AllocatedStack>
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'static,
+    {
+        let stack = AllocatedStack::new(StackOptions::new(self.stack_size, false))
+            .expect("failed to allocate a stack");
+        self.spawn_on(stack, func)
+    }
+
+    /// Spawns the coroutine on the given stack, ignoring `stack_size()`.
+    ///
+    /// # Panics
+    ///
+    /// If the stack is smaller than `STACK_MINIMUM`, or `fp_state()` is
+    /// required but not supported.
+    pub fn spawn_on<'a, A, Y, R, S, F>(self, stack: S, func: F) -> Coroutine<'a, A, Y, R, S>
+    where
+        S: Stack + 'a,
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'a,
+    {
+        if self.fp_state && !FP_STATE {
+            panic!("saving the floating-point state requires the fpstate feature");
+        }
+
+        let mut coro = Coroutine::new(stack, func);
+        coro.set_panic_policy(self.panic_policy);
+        #[cfg(feature = "alloc")]
+        {
+            coro.set_name(self.name);
+            coro.set_hooks(self.hooks);
+        }
+        coro
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "alloc")]
+    use crate::{Generator, GeneratorState};
+    #[cfg(feature = "alloc")]
+    use core::pin::Pin;
+    #[cfg(feature = "std")]
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    #[cfg(feature = "std")]
+    use std::process::{Command, Stdio};
+    #[cfg(feature = "alloc")]
+    use std::sync::Mutex;
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn spawn_on() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::builder()
+            .name("foo")
+            .spawn_on(&mut stack, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done("foo")
+            });
+
+        assert_eq!(coro.name(), Some("foo"));
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn hooks() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (resume, suspend, complete) = (log.clone(), log.clone(), log.clone());
+
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::builder()
+            .on_resume(move || resume.lock().unwrap().push("resume"))
+            .on_suspend(move || suspend.lock().unwrap().push("suspend"))
+            .on_complete(move || complete.lock().unwrap().push("complete"))
+            .spawn_on(&mut stack, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done(2)
+            });
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+        assert_eq!(*log.lock().unwrap(), ["resume", "suspend"]);
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(2));
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["resume", "suspend", "resume", "complete"]
+        );
+
+        // Canceling a coroutine which has completed doesn't resume it.
+        drop(coro);
+        assert_eq!(log.lock().unwrap().len(), 4);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn spawn() {
+        let coro = CoroutineBuilder::new()
+            .stack_size(STACK_MINIMUM * 2)
+            .spawn(|c: Control<'_, (), (), ()>, ()| c.done(()));
+
+        assert_eq!(coro.name(), None);
+        assert!(Stack::len(&coro.into_stack()) >= STACK_MINIMUM * 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn panic_policy_propagate() {
+        // Unwinding needs considerably more stack than the minimum.
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::builder()
+            .panic_policy(PanicPolicy::Propagate)
+            .spawn_on(&mut stack, |c: Control<'_, (), (), ()>, ()| {
+                if true {
+                    panic!("foo");
+                }
+                c.done(())
+            });
+
+        let result = catch_unwind(AssertUnwindSafe(|| Pin::new(&mut coro).resume()));
+        assert_eq!(result.unwrap_err().downcast_ref(), Some(&"foo"));
+        assert!(coro.is_finished());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn panic_policy_abort() {
+        const CHILD: &str = "FRENETIC_PANIC_POLICY_ABORT";
+
+        // Aborting takes the whole process down, so the coroutine runs in a
+        // child process: this same test, told so by its environment.
+        if std::env::var_os(CHILD).is_some() {
+            let mut stack = [1u8; STACK_MINIMUM * 16];
+            let mut coro = Coroutine::builder()
+                .panic_policy(PanicPolicy::Abort)
+                .spawn_on(&mut stack, |c: Control<'_, (), (), ()>, ()| {
+                    if true {
+                        panic!("foo");
+                    }
+                    c.done(())
+                });
+
+            let _ = catch_unwind(AssertUnwindSafe(|| Pin::new(&mut coro).resume()));
+            std::process::exit(0);
+        }
+
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "builder::tests::panic_policy_abort"])
+            .env(CHILD, "1")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+
+        assert!(!status.success());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            assert_eq!(status.signal(), Some(libc::SIGABRT));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(
+        any(feature = "fpstate", feature = "threads", miri, target_arch = "x86_64"),
+        ignore
+    )]
+    #[should_panic(expected = "requires the fpstate feature")]
+    fn fp_state() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let _ = Coroutine::builder()
+            .fp_state(true)
+            .spawn_on(&mut stack, |c: Control<'_, (), (), ()>, ()| c.done(()));
+    }
+}
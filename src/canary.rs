@@ -0,0 +1,73 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A coroutine which runs off the lower end of a borrowed stack silently
+//! corrupts whatever memory lies below it. In debug builds, a canary word at
+//! the lower end of every stack is checked whenever control comes back from
+//! the coroutine, so that an overflow is at least reported as one.
+//!
+//! In release builds, `Canary` is an empty type and all of this compiles
+//! away.
+
+#[cfg(debug_assertions)]
+mod imp {
+    #[cfg(feature = "zeroize")]
+    use core::mem::size_of;
+
+    // 0xc3c3...c3, whatever the size of a word.
+    const CANARY: usize = !0 / 0xff * 0xc3;
+
+    /// The canary at the lower end of a coroutine's stack.
+    pub(crate) struct Canary(*mut usize);
+
+    impl Canary {
+        /// The number of bytes the canary takes up at the lower end.
+        #[cfg(feature = "zeroize")]
+        pub(crate) const SIZE: usize = size_of::<usize>();
+
+        /// Places a canary at `bottom`, the lower end of a stack.
+        pub(crate) unsafe fn new(bottom: *mut u8) -> Self {
+            let canary = bottom as *mut usize;
+            canary.write_unaligned(CANARY);
+            Canary(canary)
+        }
+
+        /// Returns `false` if the canary has been overwritten.
+        pub(crate) fn is_intact(&self) -> bool {
+            unsafe { self.0.read_unaligned() == CANARY }
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod imp {
+    pub(crate) struct Canary;
+
+    impl Canary {
+        #[cfg(feature = "zeroize")]
+        pub(crate) const SIZE: usize = 0;
+
+        #[inline(always)]
+        pub(crate) unsafe fn new(_bottom: *mut u8) -> Self {
+            Canary
+        }
+
+        #[inline(always)]
+        pub(crate) fn is_intact(&self) -> bool {
+            true
+        }
+    }
+}
+
+pub(crate) use self::imp::Canary;
@@ -0,0 +1,132 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! With Intel CET shadow stacks, the CPU pushes every return address onto a
+//! second, protected stack too, and `ret` faults unless the two agree. So
+//! every coroutine needs a shadow stack of its own, which the x86_64 backend
+//! switches to along with the stack.
+//!
+//! Linux enables shadow stacks per process, when the C library asks for them
+//! at startup, so whether a coroutine needs one is decided at run time.
+//!
+//! Without the `cet` feature (or off x86_64 Linux, or with the `threads`
+//! backend, where every coroutine runs on an ordinary thread) `ShadowStack`
+//! is an empty type and all of this compiles away.
+
+#[cfg(all(
+    feature = "cet",
+    target_arch = "x86_64",
+    target_os = "linux",
+    not(any(feature = "threads", miri))
+))]
+mod imp {
+    use core::ptr::null_mut;
+    use std::io::Error;
+
+    // See asm/prctl.h, asm/mman.h and asm/unistd_64.h.
+    const ARCH_SHSTK_STATUS: libc::c_long = 0x5005;
+    const ARCH_SHSTK_SHSTK: u64 = 1 << 0;
+    const SHADOW_STACK_SET_TOKEN: libc::c_ulong = 1 << 0;
+    const SYS_MAP_SHADOW_STACK: libc::c_long = 453;
+
+    /// The shadow stack of a coroutine, if shadow stacks are enabled.
+    pub(crate) struct ShadowStack(Option<(*mut u8, usize)>);
+
+    impl ShadowStack {
+        /// Allocates a shadow stack for a coroutine with a stack of `len`
+        /// bytes, if shadow stacks are enabled for the current thread.
+        ///
+        /// # Panics
+        ///
+        /// If the shadow stack can't be allocated.
+        pub(crate) fn new(len: usize) -> Self {
+            let mut status = 0u64;
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_arch_prctl,
+                    ARCH_SHSTK_STATUS,
+                    &mut status as *mut u64,
+                )
+            };
+
+            // Older kernels don't know about shadow stacks at all.
+            if ret != 0 || status & ARCH_SHSTK_SHSTK == 0 {
+                return ShadowStack(None);
+            }
+
+            // Every call pushes at least as much onto the stack as onto the
+            // shadow stack, so the same size is always enough.
+            let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+            let size = (len + page - 1) & !(page - 1);
+
+            // The kernel puts a restore token on top of the new shadow stack.
+            let base =
+                unsafe { libc::syscall(SYS_MAP_SHADOW_STACK, 0, size, SHADOW_STACK_SET_TOKEN) };
+            if base == -1 {
+                panic!(
+                    "failed to allocate a shadow stack: {}",
+                    Error::last_os_error()
+                );
+            }
+
+            ShadowStack(Some((base as *mut u8, size)))
+        }
+
+        /// Returns the top of the shadow stack, just above its restore token,
+        /// or null without one.
+        pub(crate) fn top(&self) -> *mut u8 {
+            match self.0 {
+                Some((base, size)) => unsafe { base.add(size) },
+                None => null_mut(),
+            }
+        }
+
+        /// Frees the shadow stack, if it hasn't been freed already. Nothing
+        /// may run on it any more.
+        pub(crate) unsafe fn free(&mut self) {
+            if let Some((base, size)) = self.0.take() {
+                let _ = libc::munmap(base as _, size);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(
+    feature = "cet",
+    target_arch = "x86_64",
+    target_os = "linux",
+    not(any(feature = "threads", miri))
+)))]
+mod imp {
+    use core::ptr::null_mut;
+
+    pub(crate) struct ShadowStack;
+
+    impl ShadowStack {
+        #[inline(always)]
+        pub(crate) fn new(_len: usize) -> Self {
+            ShadowStack
+        }
+
+        #[inline(always)]
+        pub(crate) fn top(&self) -> *mut u8 {
+            null_mut()
+        }
+
+        #[inline(always)]
+        pub(crate) unsafe fn free(&mut self) {}
+    }
+}
+
+pub(crate) use self::imp::ShadowStack;
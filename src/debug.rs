@@ -0,0 +1,222 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Debuggers only see the stack a thread is running on. To show where a
+//! suspended coroutine is, they need to know where its context was saved, and
+//! how. This is described by the `frenetic_debug` symbol, a `DebugInfo` which
+//! the scripts in `debug/` read:
+//!
+//! * the jump buffer a suspended coroutine is saved into is at offset
+//!   `child` in its Context, and its first word is the saved stack pointer;
+//! * the frame record (frame pointer, then return address) of the suspended
+//!   context is at offset `frame` from that stack pointer, and the context
+//!   continues with the stack pointer at offset `resumed` from it.
+//!
+//! The layout only describes the x86_64 (but not Windows) and aarch64
+//! backends; elsewhere `frame` and `resumed` are zero. It is only extended,
+//! and `version` is bumped whenever it changes in any other way.
+//!
+//! With the `registry` feature, `coroutines` points to the first of a list
+//! of `DebugNode`s, one per live coroutine, linked and unlinked by the
+//! registry while it is locked.
+
+use crate::frame;
+use crate::JumpBuffer;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicPtr;
+
+/// The layout of a suspended context, and the live coroutines.
+#[repr(C)]
+pub(crate) struct DebugInfo {
+    version: u32,
+    word: u32,
+    child: u32,
+    frame: u32,
+    resumed: u32,
+    pub(crate) coroutines: AtomicPtr<DebugNode>,
+}
+
+#[used]
+#[export_name = "frenetic_debug"]
+pub(crate) static DEBUG: DebugInfo = DebugInfo {
+    version: 1,
+    word: size_of::<usize>() as u32,
+    // The parent's jump buffer comes first, then the child's. See `Context`.
+    child: size_of::<JumpBuffer>() as u32,
+    frame: if frame::LINKED {
+        frame::SAVED as u32
+    } else {
+        0
+    },
+    resumed: if frame::LINKED {
+        frame::RESUMED as u32
+    } else {
+        0
+    },
+    coroutines: AtomicPtr::new(null_mut()),
+};
+
+/// The state of a coroutine, as the scripts see it.
+#[cfg_attr(not(feature = "registry"), allow(dead_code))]
+#[repr(u32)]
+#[derive(Clone, Copy)]
+pub(crate) enum DebugState {
+    Created = 0,
+    Suspended = 1,
+    Finished = 2,
+    Running = 3,
+}
+
+/// A live coroutine, in the list `DebugInfo::coroutines` points to.
+#[cfg_attr(not(feature = "registry"), allow(dead_code))]
+#[repr(C)]
+pub(crate) struct DebugNode {
+    pub(crate) next: *mut DebugNode,
+    pub(crate) prev: *mut DebugNode,
+    pub(crate) id: u64,
+    pub(crate) ctx: *mut u8,
+    pub(crate) state: DebugState,
+    pub(crate) name: *const u8,
+    pub(crate) name_len: usize,
+}
+
+/// Adds `node` to the list of live coroutines. The registry must be locked.
+#[cfg(feature = "registry")]
+pub(crate) unsafe fn link(node: *mut DebugNode) {
+    use core::sync::atomic::Ordering;
+
+    let head = DEBUG.coroutines.load(Ordering::Relaxed);
+    (*node).next = head;
+    (*node).prev = null_mut();
+    if !head.is_null() {
+        (*head).prev = node;
+    }
+
+    DEBUG.coroutines.store(node, Ordering::Release);
+}
+
+/// Removes `node` from the list of live coroutines. The registry must be
+/// locked.
+#[cfg(feature = "registry")]
+pub(crate) unsafe fn unlink(node: *mut DebugNode) {
+    use core::sync::atomic::Ordering;
+
+    let (prev, next) = ((*node).prev, (*node).next);
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+
+    if prev.is_null() {
+        DEBUG.coroutines.store(next, Ordering::Release);
+    } else {
+        (*prev).next = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{align_of, size_of};
+
+    fn offset<T, F>(base: &T, field: &F) -> usize {
+        field as *const F as usize - base as *const T as usize
+    }
+
+    #[test]
+    fn layout() {
+        // The offsets the scripts in `debug/` read, on 64-bit targets.
+        if size_of::<usize>() != 8 {
+            return;
+        }
+
+        assert_eq!(DEBUG.word, 8);
+        assert_eq!(offset(&DEBUG, &DEBUG.coroutines), 24);
+        assert_eq!(DEBUG.frame != 0, frame::LINKED);
+        assert!(DEBUG.resumed >= DEBUG.frame);
+        assert_eq!(align_of::<DebugInfo>(), 8);
+
+        let node = DebugNode {
+            next: null_mut(),
+            prev: null_mut(),
+            id: 0,
+            ctx: null_mut(),
+            state: DebugState::Created,
+            name: core::ptr::null(),
+            name_len: 0,
+        };
+        assert_eq!(offset(&node, &node.id), 16);
+        assert_eq!(offset(&node, &node.ctx), 24);
+        assert_eq!(offset(&node, &node.state), 32);
+        assert_eq!(offset(&node, &node.name), 40);
+        assert_eq!(offset(&node, &node.name_len), 48);
+    }
+
+    #[cfg(feature = "registry")]
+    #[test]
+    fn nodes() {
+        use crate::{Coroutine, Generator, STACK_MINIMUM};
+        use core::pin::Pin;
+        use core::sync::atomic::Ordering;
+
+        // Finds the node of the coroutine named `name`, as a debugger would.
+        fn find(name: &str) -> Option<(*mut u8, u32)> {
+            crate::registry::locked(|| unsafe {
+                let mut node = DEBUG.coroutines.load(Ordering::Acquire);
+                while !node.is_null() {
+                    let n = &*node;
+                    if !n.name.is_null()
+                        && core::slice::from_raw_parts(n.name, n.name_len) == name.as_bytes()
+                    {
+                        return Some((n.ctx, n.state as u32));
+                    }
+                    node = n.next;
+                }
+                None
+            })
+        }
+
+        // Dropping it suspended unwinds its stack, which takes more room.
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut coro =
+            Coroutine::builder()
+                .name("debug::nodes")
+                .spawn_on(&mut stack[..], |c, ()| {
+                    let (c, ()) = c.r#yield(())?;
+                    c.done(())
+                });
+
+        let (ctx, state) = find("debug::nodes").unwrap();
+        assert!(!ctx.is_null());
+        assert_eq!(state, DebugState::Created as u32);
+
+        let _ = Pin::new(&mut coro).resume();
+        assert_eq!(
+            find("debug::nodes"),
+            Some((ctx, DebugState::Suspended as u32))
+        );
+
+        if frame::LINKED {
+            // The saved context continues in the closure, on the stack.
+            unsafe {
+                let sp = *(ctx.add(DEBUG.child as usize) as *const usize);
+                let pc = *((sp + DEBUG.frame as usize + 8) as *const usize);
+                assert!(pc != 0);
+            }
+        }
+
+        drop(coro);
+        assert_eq!(find("debug::nodes"), None);
+    }
+}
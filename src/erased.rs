@@ -0,0 +1,282 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coroutines of different kinds, in one collection.
+//!
+//! A `Coroutine` doesn't name the type of its closure, but it does name the
+//! type of its stack, and the types it is resumed with, yields and returns.
+//! `DynCoroutine` boxes the stack, and `AnyCoroutine` boxes the whole
+//! coroutine, with every value passed in and out of it boxed as `dyn Any`.
+
+use crate::{
+    CancelOutcome, Coroutine, CoroutineError, CoroutineState, Generator, GeneratorState, Stack,
+};
+use alloc::boxed::Box;
+use core::any::Any;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::ptr;
+
+/// A coroutine on a boxed stack of any kind. See `Coroutine::into_dyn()`.
+///
+/// It is a `Coroutine` like any other. Coroutines on different kinds of
+/// stacks, such as a `ProtectedStack` and a `&mut [u8]`, become the same type.
+pub type DynCoroutine<'a, Y, R, A = ()> = Coroutine<'a, A, Y, R, Box<dyn Stack + 'a>>;
+
+impl<'a, A, Y, R, S: Stack + 'a> Coroutine<'a, A, Y, R, S> {
+    /// Boxes the coroutine's stack, so that it can be kept along with
+    /// coroutines on other kinds of stacks. The coroutine is not resumed, and
+    /// its stack doesn't move. Requires the `alloc` feature.
+    pub fn into_dyn(self) -> DynCoroutine<'a, Y, R, A> {
+        self.map_stack(|stack| Box::new(Boxed(stack)) as Box<dyn Stack + 'a>)
+    }
+}
+
+/// The stack of a `DynCoroutine`. Like `Coroutine`, it keeps the stack in a
+/// `MaybeUninit`, so that moving it doesn't reborrow a `&mut [u8]` stack. It
+/// is always initialized.
+struct Boxed<S>(MaybeUninit<S>);
+
+unsafe impl<S: Stack> Stack for Boxed<S> {
+    fn bottom(&mut self) -> *mut u8 {
+        unsafe { (*self.0.as_mut_ptr()).bottom() }
+    }
+
+    fn len(&self) -> usize {
+        unsafe { (*self.0.as_ptr()).len() }
+    }
+}
+
+impl<S> Drop for Boxed<S> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.0.as_mut_ptr()) }
+    }
+}
+
+/// A coroutine which is resumed with, yields and returns boxed values of any
+/// type. See `Coroutine::into_any()`.
+pub struct AnyCoroutine<'a>(Box<dyn Erased + 'a>);
+
+/// What an `AnyCoroutine` yields or returns.
+type AnyState = GeneratorState<Box<dyn Any>, Box<dyn Any>>;
+
+/// The methods of a `Coroutine` which `AnyCoroutine` needs, with its types
+/// erased.
+trait Erased {
+    fn resume_with(&mut self, arg: Box<dyn Any>) -> AnyState;
+    fn try_resume_with(&mut self, arg: Box<dyn Any>) -> Result<AnyState, CoroutineError>;
+    fn is_finished(&self) -> bool;
+    fn name(&self) -> Option<&str>;
+    fn state(&self) -> CoroutineState;
+    fn cancel(self: Box<Self>) -> CancelOutcome;
+}
+
+/// Unboxes the argument of an `AnyCoroutine`.
+fn unbox<A: 'static>(arg: Box<dyn Any>) -> A {
+    match arg.downcast::<A>() {
+        Ok(arg) => *arg,
+        Err(_) => panic!("coroutine resumed with an argument of the wrong type"),
+    }
+}
+
+/// Boxes what an `AnyCoroutine` yields or returns.
+fn boxed<Y: 'static, R: 'static>(state: GeneratorState<Y, R>) -> AnyState {
+    match state {
+        GeneratorState::Yielded(y) => GeneratorState::Yielded(Box::new(y)),
+        GeneratorState::Complete(r) => GeneratorState::Complete(Box::new(r)),
+    }
+}
+
+impl<'a, A: 'static, Y: 'static, R: 'static, S> Erased for Coroutine<'a, A, Y, R, S> {
+    fn resume_with(&mut self, arg: Box<dyn Any>) -> AnyState {
+        boxed(Pin::new(self).resume_with(unbox(arg)))
+    }
+
+    fn try_resume_with(&mut self, arg: Box<dyn Any>) -> Result<AnyState, CoroutineError> {
+        Pin::new(self).try_resume_with(unbox(arg)).map(boxed)
+    }
+
+    fn is_finished(&self) -> bool {
+        Coroutine::is_finished(self)
+    }
+
+    fn name(&self) -> Option<&str> {
+        Coroutine::name(self)
+    }
+
+    fn state(&self) -> CoroutineState {
+        Coroutine::state(self)
+    }
+
+    fn cancel(self: Box<Self>) -> CancelOutcome {
+        Coroutine::cancel(*self)
+    }
+}
+
+impl<'a, A: 'static, Y: 'static, R: 'static, S: 'a> Coroutine<'a, A, Y, R, S> {
+    /// Boxes the coroutine, so that it can be kept along with coroutines of
+    /// other types. Requires the `alloc` feature.
+    pub fn into_any(self) -> AnyCoroutine<'a> {
+        AnyCoroutine(Box::new(self))
+    }
+}
+
+impl<'a> AnyCoroutine<'a> {
+    /// Resumes the coroutine with `arg`, like `Coroutine::resume_with()`,
+    /// and boxes what it yields or returns.
+    ///
+    /// # Panics
+    ///
+    /// If `arg` isn't of the type the coroutine takes, or as
+    /// `Coroutine::resume_with()` does.
+    pub fn resume_with(&mut self, arg: Box<dyn Any>) -> AnyState {
+        self.0.resume_with(arg)
+    }
+
+    /// Resumes the coroutine with `arg`, like `Coroutine::try_resume_with()`.
+    ///
+    /// # Panics
+    ///
+    /// If `arg` isn't of the type the coroutine takes.
+    pub fn try_resume_with(&mut self, arg: Box<dyn Any>) -> Result<AnyState, CoroutineError> {
+        self.0.try_resume_with(arg)
+    }
+
+    /// See `Coroutine::is_finished()`.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    /// See `Coroutine::name()`.
+    pub fn name(&self) -> Option<&str> {
+        self.0.name()
+    }
+
+    /// See `Coroutine::state()`.
+    pub fn state(&self) -> CoroutineState {
+        self.0.state()
+    }
+
+    /// See `Coroutine::cancel()`.
+    pub fn cancel(self) -> CancelOutcome {
+        self.0.cancel()
+    }
+}
+
+/// Resumes a coroutine which takes `()`.
+impl<'a> Generator for AnyCoroutine<'a> {
+    type Yield = Box<dyn Any>;
+    type Return = Box<dyn Any>;
+
+    fn resume(self: Pin<&mut Self>) -> AnyState {
+        self.get_mut().resume_with(Box::new(()))
+    }
+}
+
+impl<'a> fmt::Debug for AnyCoroutine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnyCoroutine")
+            .field("name", &self.name())
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Control, STACK_MINIMUM};
+
+    #[test]
+    fn into_dyn() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let borrowed = Coroutine::new(&mut stack[..], |c: Control<'_, (), i32, &str>, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+        let owned =
+            Coroutine::with_stack_size(STACK_MINIMUM, |c: Control<'_, (), i32, &str>, ()| {
+                let (c, ()) = c.r#yield(2)?;
+                c.done("bar")
+            });
+
+        let mut coros: Vec<DynCoroutine<'_, i32, &str>> =
+            vec![borrowed.into_dyn(), owned.into_dyn()];
+        for coro in coros.iter_mut() {
+            assert!(matches!(
+                Pin::new(coro).resume(),
+                GeneratorState::Yielded(_)
+            ));
+        }
+
+        let returned: Vec<_> = coros
+            .iter_mut()
+            .map(|coro| Pin::new(coro).resume())
+            .collect();
+        assert_eq!(
+            returned,
+            [
+                GeneratorState::Complete("foo"),
+                GeneratorState::Complete("bar")
+            ]
+        );
+    }
+
+    #[test]
+    fn into_any() {
+        // Unwinding needs considerably more stack than the minimum.
+        let mut a = [1u8; STACK_MINIMUM * 16];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let ints = Coroutine::new(&mut a, |c: Control<'_, i32, i32, i32>, x| {
+            let (c, y) = c.r#yield(x + 1)?;
+            c.done(y)
+        });
+        let strings = Coroutine::new(&mut b, |c: Control<'_, (), String, usize>, ()| {
+            let (c, ()) = c.r#yield(String::from("foo"))?;
+            c.done(3)
+        });
+
+        let mut ints = ints.into_any();
+        let mut strings = strings.into_any();
+        assert_eq!(
+            format!("{:?}", ints),
+            "AnyCoroutine { name: None, state: Created }"
+        );
+
+        match ints.resume_with(Box::new(1)) {
+            GeneratorState::Yielded(y) => assert_eq!(y.downcast_ref::<i32>(), Some(&2)),
+            _ => panic!("unexpected return from resume"),
+        }
+        match Pin::new(&mut strings).resume() {
+            GeneratorState::Yielded(y) => assert_eq!(y.downcast_ref::<String>().unwrap(), "foo"),
+            _ => panic!("unexpected return from resume"),
+        }
+        match Pin::new(&mut strings).resume() {
+            GeneratorState::Complete(r) => assert_eq!(r.downcast_ref::<usize>(), Some(&3)),
+            _ => panic!("unexpected return from resume"),
+        }
+        assert!(strings.is_finished());
+        assert_eq!(ints.cancel(), CancelOutcome::Acknowledged);
+    }
+
+    #[test]
+    #[should_panic(expected = "argument of the wrong type")]
+    fn into_any_wrong_type() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let coro = Coroutine::new(&mut stack, |c: Control<'_, i32, (), ()>, _| c.done(()));
+        let _ = coro.into_any().resume_with(Box::new("foo"));
+    }
+}
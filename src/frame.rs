@@ -0,0 +1,140 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Profilers like `perf record --call-graph fp` walk the frame pointer chain:
+//! every frame starts with a record of the caller's frame pointer and the
+//! return address. A coroutine's chain would end in its outermost frame,
+//! `callback()`, and a sample taken inside a coroutine wouldn't show what
+//! resumed it.
+//!
+//! So the Context of every coroutine is preceded by a frame record of its
+//! own, which `jump_init()` makes the caller of `callback()`. It starts out
+//! empty, ending the chain. On the x86_64 and aarch64 backends, the context
+//! which switches into the coroutine is suspended with its frame pointer and
+//! return address side by side: a frame record too. Whenever the coroutine is
+//! resumed, it copies that record into its own, so that the chain leads from
+//! the coroutine's frames, through the switch, into the frames of whoever
+//! resumed it.
+//!
+//! The record is copied from the context the coroutine was suspended into,
+//! its parent. After a transfer, the chain may lead to the parent even while
+//! the coroutine which transferred resumes it. Either way, it only ever leads
+//! into a stack which is suspended for as long as the coroutine runs.
+//!
+//! Unwinders using the call frame information stop at `callback()` as
+//! before: the record is for frame pointer walkers only.
+
+use crate::JumpBuffer;
+use core::ffi::c_void;
+use core::ptr::null;
+
+/// A frame record: a frame pointer points to one of these.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct FrameRecord {
+    next: *const c_void,
+    ret: *const c_void,
+}
+
+impl FrameRecord {
+    /// A record which ends the chain.
+    pub(crate) const END: FrameRecord = FrameRecord {
+        next: null(),
+        ret: null(),
+    };
+}
+
+/// Whether the backend suspends contexts with a frame record. See `link()`.
+pub(crate) const LINKED: bool = cfg!(all(
+    any(
+        target_arch = "aarch64",
+        all(target_arch = "x86_64", not(target_os = "windows"))
+    ),
+    not(any(feature = "threads", miri, frenetic_sjlj))
+));
+
+/// The offset of the frame record in a suspended context, from the stack
+/// pointer saved in its jump buffer. See `src/jump/x86_64.S`: the frame
+/// pointer is pushed right below the return address, then five registers,
+/// the floating-point control state and, with the `cet` feature, the shadow
+/// stack pointer.
+#[cfg(all(target_arch = "x86_64", feature = "cet"))]
+pub(crate) const SAVED: usize = 56;
+#[cfg(all(target_arch = "x86_64", not(feature = "cet")))]
+pub(crate) const SAVED: usize = 48;
+
+/// See `src/jump/aarch64.S`: x29 and x30 are stored as a pair, above x19-x28.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const SAVED: usize = 80;
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) const SAVED: usize = 0;
+
+/// The offset of the stack pointer a suspended context continues with once
+/// it is resumed, from the one saved in its jump buffer: right above the
+/// return address on x86_64, and above the register save area on aarch64.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const RESUMED: usize = SAVED + 16;
+#[cfg(all(target_arch = "aarch64", feature = "fpstate"))]
+pub(crate) const RESUMED: usize = 176;
+#[cfg(all(target_arch = "aarch64", not(feature = "fpstate")))]
+pub(crate) const RESUMED: usize = 160;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) const RESUMED: usize = 0;
+
+/// Returns the frame record of the coroutine whose Context is at `ctx`.
+pub(crate) fn record(ctx: *mut u8) -> *mut FrameRecord {
+    (ctx as *mut FrameRecord).wrapping_sub(1)
+}
+
+/// Links the frame record of the coroutine whose Context is at `ctx` to the
+/// context suspended into `from`, which has just switched into it.
+#[inline(always)]
+pub(crate) unsafe fn link(ctx: *mut u8, from: *const JumpBuffer) {
+    if LINKED {
+        let sp = (*from)[0] as *const u8;
+        let saved = (sp.add(SAVED) as *const FrameRecord).read_volatile();
+        record(ctx).write_volatile(saved);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Control, Coroutine, Generator, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    #[test]
+    fn linked() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), _, ()>, ()| {
+            let r = unsafe { record(c.0 as *mut u8).read() };
+            let (c, ()) = c.r#yield(r.ret)?;
+            let r = unsafe { record(c.0 as *mut u8).read() };
+            let (c, ()) = c.r#yield(r.ret)?;
+            c.done(())
+        });
+
+        // The record holds the return address into whoever resumed the
+        // coroutine, on the backends which link it.
+        for _ in 0..2 {
+            match Pin::new(&mut coro).resume() {
+                GeneratorState::Yielded(ret) => assert_eq!(!ret.is_null(), LINKED),
+                _ => panic!("unexpected return from resume"),
+            }
+        }
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(()));
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Control, Coroutine, CoroutineError, ResumeState};
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::null;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// A coroutine driven as a `Future`.
+///
+/// Every time the coroutine yields or waits for an event, polling returns
+/// `Poll::Pending`. Once it completes, polling returns its return value. See
+/// `Coroutine::into_future()`.
+pub struct CoroutineFuture<'a, R, S = &'a mut [u8]>(Coroutine<'a, (), (), R, S>);
+
+impl<'a, R, S> Coroutine<'a, (), (), R, S> {
+    /// Turns this coroutine into a `Future` which resolves to its return
+    /// value.
+    ///
+    /// While the future is polled, the waker of the polling task is available
+    /// to the coroutine through `Control::waker()`. A coroutine that yields
+    /// must make sure the waker is woken once it can make progress, just
+    /// like a `Future` returning `Poll::Pending` would.
+    pub fn into_future(self) -> CoroutineFuture<'a, R, S> {
+        CoroutineFuture(self)
+    }
+}
+
+impl<'a, R, S> Future for CoroutineFuture<'a, R, S> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        match Pin::new(&mut self.get_mut().0).poll_resume((), cx) {
+            ResumeState::Yielded(()) | ResumeState::Pending => Poll::Pending,
+            ResumeState::Complete(r) => Poll::Ready(r),
+        }
+    }
+}
+
+impl<'a, Y, R> Control<'a, (), Y, R> {
+    /// Runs `future` to completion within this coroutine, and returns its
+    /// output.
+    ///
+    /// The future is polled on the coroutine's stack, with the waker of the
+    /// task driving the coroutine. Whenever it isn't ready, the coroutine
+    /// waits for it like `yield_pending()` does, so that a coroutine driven by
+    /// `into_future()` reports `Poll::Pending` to the task polling it.
+    pub fn block_on<F: Future>(mut self, future: F) -> Result<(Self, F::Output), CoroutineError> {
+        let mut future = future;
+
+        // The future stays in this frame, on the coroutine's stack, until it
+        // is dropped, even if the coroutine is canceled in between.
+        let mut future = unsafe { Pin::new_unchecked(&mut future) };
+
+        loop {
+            // Before the first suspension, we may have been resumed by
+            // `resume_with()`, with no task around. The future may be ready
+            // right away, otherwise our parent panics.
+            let noop = noop_waker();
+            let mut cx = Context::from_waker(self.waker().unwrap_or(&noop));
+
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return Ok((self, output));
+            }
+
+            self = self.suspend(ResumeState::Pending)?.0;
+        }
+    }
+}
+
+/// Returns a waker which does nothing when woken.
+pub(crate) fn noop_waker() -> Waker {
+    unsafe fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(null(), &VTABLE)
+    }
+
+    unsafe fn noop(_: *const ()) {}
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    unsafe { Waker::from_raw(clone(null())) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::STACK_MINIMUM;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    fn waker() -> Waker {
+        unsafe fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(null(), &VTABLE)
+        }
+
+        unsafe fn wake(_: *const ()) {
+            let _ = WAKES.fetch_add(1, Ordering::SeqCst);
+        }
+
+        unsafe fn drop(_: *const ()) {}
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+        unsafe { Waker::from_raw(clone(null())) }
+    }
+
+    #[test]
+    fn future() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut future = Coroutine::new(&mut stack, |c, ()| {
+            c.waker().unwrap().wake_by_ref();
+            let (c, ()) = c.r#yield(())?;
+            c.waker().unwrap().wake_by_ref();
+            let (c, ()) = c.r#yield(())?;
+            c.done(7)
+        })
+        .into_future();
+
+        let waker = waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut polls = 0;
+        let value = loop {
+            polls += 1;
+            if let Poll::Ready(value) = Pin::new(&mut future).poll(&mut cx) {
+                break value;
+            }
+        };
+
+        assert_eq!(value, 7);
+        assert_eq!(polls, 3);
+        assert_eq!(WAKES.load(Ordering::SeqCst), 2);
+    }
+
+    /// A future which is pending the given number of times, waking its task
+    /// each time.
+    struct Countdown(usize);
+
+    impl Future for Countdown {
+        type Output = &'static str;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<&'static str> {
+            if self.0 == 0 {
+                return Poll::Ready("done");
+            }
+
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn block_on() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut future = Coroutine::new(&mut stack, |c, ()| {
+            let (c, output) = c.block_on(Countdown(2))?;
+            c.done(output)
+        })
+        .into_future();
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready("done"));
+    }
+
+    #[test]
+    fn block_on_yielding() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            let (c, output) = c.block_on(Countdown(1))?;
+            let (c, ()) = c.r#yield(2)?;
+            c.done(output)
+        });
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut coro = Pin::new(&mut coro);
+
+        assert_eq!(
+            coro.as_mut().poll_resume((), &mut cx),
+            ResumeState::Yielded(1)
+        );
+        assert_eq!(coro.as_mut().poll_resume((), &mut cx), ResumeState::Pending);
+        assert_eq!(
+            coro.as_mut().poll_resume((), &mut cx),
+            ResumeState::Yielded(2)
+        );
+        assert_eq!(
+            coro.as_mut().poll_resume((), &mut cx),
+            ResumeState::Complete("done")
+        );
+    }
+}
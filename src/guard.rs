@@ -0,0 +1,344 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A coroutine which overflows a stack with a guard page below it (such as a
+//! `ProtectedStack`) faults on the guard page, and the SIGSEGV kills the
+//! process. With the `guard` feature, a SIGSEGV handler running on an
+//! alternate signal stack checks whether the fault hit the page just below
+//! the stack of the coroutine running on the current thread. If so, it
+//! abandons the coroutine and jumps back into its parent, which panics with
+//! "coroutine stack overflow". Any other fault is passed on to the handler
+//! that was installed before.
+//!
+//! The coroutine's frames are abandoned, not unwound, so whatever they own is
+//! leaked. And since the fault may have hit it anywhere, even while it held a
+//! lock, the coroutine is never resumed or canceled afterwards.
+//!
+//! Jumping out of a signal handler is only sound where the kernel tells
+//! whether a thread runs on its alternate signal stack by looking at the
+//! stack pointer, which is what Linux does. Elsewhere (and with the `threads`
+//! backend, where every coroutine runs on an ordinary thread) `Guard` is an
+//! empty type and all of this compiles away. There is no Windows version:
+//! Windows runs exception handlers on the stack which faulted, and that one
+//! has no room left.
+
+#[cfg(all(
+    feature = "guard",
+    any(target_os = "linux", target_os = "android"),
+    not(any(feature = "threads", miri))
+))]
+mod imp {
+    use crate::{jump_swap, JumpBuffer, JUMP_BUFFER};
+    use core::cell::Cell;
+    use core::ffi::c_void;
+    use core::mem::{transmute, zeroed};
+    use core::ptr::null_mut;
+    use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+    use libc::{c_int, siginfo_t};
+    use std::io::Error;
+    use std::sync::Once;
+
+    // Plenty for the handler, and for the one before it, which it may call.
+    const ALTSTACK_SIZE: usize = 0x10000;
+
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    static PREVIOUS: AtomicPtr<libc::sigaction> = AtomicPtr::new(null_mut());
+
+    /// The coroutine running on the current thread.
+    #[derive(Copy, Clone)]
+    struct Record {
+        bottom: usize,
+        parent: *mut JumpBuffer,
+        overflowed: *mut bool,
+    }
+
+    thread_local! {
+        static RUNNING: Cell<Option<Record>> = const { Cell::new(None) };
+        static OVERFLOWED: Cell<bool> = const { Cell::new(false) };
+        static ALTSTACK: AltStack = AltStack::new();
+    }
+
+    /// An alternate signal stack, if the thread had none already.
+    ///
+    /// The standard library sets one up for the threads it starts, but other
+    /// threads may not have one.
+    struct AltStack(*mut c_void);
+
+    impl AltStack {
+        fn new() -> Self {
+            unsafe {
+                let mut old: libc::stack_t = zeroed();
+                let _ = libc::sigaltstack(null_mut(), &mut old);
+                if old.ss_flags & libc::SS_DISABLE == 0 {
+                    return AltStack(null_mut());
+                }
+
+                let ptr = libc::mmap(
+                    null_mut(),
+                    ALTSTACK_SIZE,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                if ptr == libc::MAP_FAILED {
+                    panic!(
+                        "failed to allocate a signal stack: {}",
+                        Error::last_os_error()
+                    );
+                }
+
+                let new = libc::stack_t {
+                    ss_sp: ptr,
+                    ss_flags: 0,
+                    ss_size: ALTSTACK_SIZE,
+                };
+                let _ = libc::sigaltstack(&new, null_mut());
+                AltStack(ptr)
+            }
+        }
+    }
+
+    impl Drop for AltStack {
+        fn drop(&mut self) {
+            if !self.0.is_null() {
+                unsafe {
+                    let disable = libc::stack_t {
+                        ss_sp: null_mut(),
+                        ss_flags: libc::SS_DISABLE,
+                        ss_size: ALTSTACK_SIZE,
+                    };
+                    let _ = libc::sigaltstack(&disable, null_mut());
+                    let _ = libc::munmap(self.0, ALTSTACK_SIZE);
+                }
+            }
+        }
+    }
+
+    /// Installs the SIGSEGV handler, the first time it is called.
+    fn install() {
+        static ONCE: Once = Once::new();
+
+        ONCE.call_once(|| unsafe {
+            PAGE_SIZE.store(
+                libc::sysconf(libc::_SC_PAGESIZE) as usize,
+                Ordering::Relaxed,
+            );
+
+            // Store the old action first, the handler may run right away.
+            let mut previous: libc::sigaction = zeroed();
+            let _ = libc::sigaction(libc::SIGSEGV, null_mut(), &mut previous);
+            PREVIOUS.store(Box::into_raw(Box::new(previous)), Ordering::Release);
+
+            // SA_NODEFER, since the handler never returns from an overflow
+            // and nothing would unblock the signal again.
+            let mut action: libc::sigaction = zeroed();
+            let handler: unsafe extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = handler;
+            action.sa_sigaction = handler as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_NODEFER;
+            let _ = libc::sigemptyset(&mut action.sa_mask);
+
+            if libc::sigaction(libc::SIGSEGV, &action, null_mut()) != 0 {
+                panic!(
+                    "failed to install the SIGSEGV handler: {}",
+                    Error::last_os_error()
+                );
+            }
+        });
+    }
+
+    unsafe extern "C" fn handler(signum: c_int, info: *mut siginfo_t, uctx: *mut c_void) {
+        let addr = (*info).si_addr() as usize;
+        let page = PAGE_SIZE.load(Ordering::Relaxed);
+
+        if let Ok(Some(r)) = RUNNING.try_with(Cell::get) {
+            if addr < r.bottom && addr >= r.bottom.saturating_sub(page) {
+                // Tell the parent what happened, and jump back into it as if
+                // the coroutine had yielded. See `Coroutine::enter()`.
+                r.overflowed.write_volatile(true);
+                let _ = OVERFLOWED.try_with(|o| o.set(true));
+
+                let mut abandoned: JumpBuffer = [null_mut(); JUMP_BUFFER];
+                jump_swap(&mut abandoned, r.parent);
+            }
+        }
+
+        // Not an overflow of ours. Pass it on, or let it kill the process.
+        let previous = PREVIOUS.load(Ordering::Acquire);
+        match (*previous).sa_sigaction {
+            libc::SIG_DFL | libc::SIG_IGN => {
+                let _ = libc::signal(signum, libc::SIG_DFL);
+            }
+
+            f if (*previous).sa_flags & libc::SA_SIGINFO != 0 => {
+                let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = transmute(f);
+                f(signum, info, uctx);
+            }
+
+            f => {
+                let f: extern "C" fn(c_int) = transmute(f);
+                f(signum);
+            }
+        }
+    }
+
+    /// The lower end of a coroutine's stack, below which the guard page is.
+    pub(crate) struct Guard(usize);
+
+    impl Guard {
+        pub(crate) fn new(bottom: *mut u8) -> Self {
+            install();
+            Guard(bottom as usize)
+        }
+
+        /// Registers the coroutine as running on the current thread, until
+        /// the returned value is dropped. Should it overflow its stack, the
+        /// handler sets `*overflowed` and jumps into `parent`.
+        pub(crate) unsafe fn enter(
+            &self,
+            parent: *mut JumpBuffer,
+            overflowed: *mut bool,
+        ) -> Running {
+            // The handler needs a signal stack on whichever thread this is.
+            let _ = ALTSTACK.try_with(|_| ());
+
+            let record = Record {
+                bottom: self.0,
+                parent,
+                overflowed,
+            };
+
+            Running(RUNNING.with(|r| r.replace(Some(record))))
+        }
+    }
+
+    /// Registers the coroutine that was running before again when dropped.
+    pub(crate) struct Running(Option<Record>);
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            RUNNING.with(|r| r.set(self.0));
+        }
+    }
+
+    /// Returns whether a coroutine overflowed its stack and jumped back into
+    /// the current thread since the last call.
+    pub(crate) fn overflowed() -> bool {
+        OVERFLOWED.with(|o| o.replace(false))
+    }
+}
+
+#[cfg(not(all(
+    feature = "guard",
+    any(target_os = "linux", target_os = "android"),
+    not(any(feature = "threads", miri))
+)))]
+mod imp {
+    use crate::JumpBuffer;
+
+    pub(crate) struct Guard;
+
+    impl Guard {
+        #[inline(always)]
+        pub(crate) fn new(_bottom: *mut u8) -> Self {
+            Guard
+        }
+
+        #[inline(always)]
+        pub(crate) unsafe fn enter(
+            &self,
+            _parent: *mut JumpBuffer,
+            _overflowed: *mut bool,
+        ) -> Running {
+            Running
+        }
+    }
+
+    pub(crate) struct Running;
+
+    #[inline(always)]
+    pub(crate) fn overflowed() -> bool {
+        false
+    }
+}
+
+pub(crate) use self::imp::{overflowed, Guard};
+
+#[cfg(all(
+    test,
+    feature = "guard",
+    any(target_os = "linux", target_os = "android"),
+    not(any(feature = "threads", miri))
+))]
+mod tests {
+    use crate::{CancelOutcome, Control, Coroutine, Generator, ProtectedStack, STACK_MINIMUM};
+    use core::pin::Pin;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    fn recurse(depth: usize) -> usize {
+        let frame = [depth; 64];
+
+        if depth == usize::MAX {
+            return 0;
+        }
+
+        // Read the frame after the call, so that it can't become a loop.
+        let n = recurse(depth + 1);
+        n + unsafe { (&frame[depth % 64] as *const usize).read_volatile() }
+    }
+
+    #[test]
+    fn overflow() {
+        // Twice, to check that the handler is still armed afterwards.
+        for _ in 0..2 {
+            let stack = ProtectedStack::new(STACK_MINIMUM * 4).unwrap();
+            let mut coro = Coroutine::new(stack, |c, ()| {
+                let (c, ()) = c.r#yield(0)?;
+                c.done(recurse(0))
+            });
+
+            let _ = Pin::new(&mut coro).resume();
+
+            let err = catch_unwind(AssertUnwindSafe(|| Pin::new(&mut coro).resume())).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<String>().unwrap(),
+                "coroutine stack overflow"
+            );
+            assert!(coro.is_finished());
+        }
+    }
+
+    #[test]
+    fn transfer() {
+        let stack = ProtectedStack::new(STACK_MINIMUM * 4).unwrap();
+        let mut other = Coroutine::new(stack, |c: Control<'_, (), i32, usize>, ()| {
+            c.done(recurse(0))
+        });
+
+        let mut stack = [0u8; STACK_MINIMUM * 16];
+        let mut coro = Coroutine::new(&mut stack, move |c, ()| {
+            let (c, _) = c.transfer(&mut other, ())?;
+            c.done(1)
+        });
+
+        let err = catch_unwind(AssertUnwindSafe(|| Pin::new(&mut coro).resume())).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<String>().unwrap(),
+            "coroutine stack overflow"
+        );
+
+        // The coroutine which transferred can still be canceled.
+        assert_eq!(coro.cancel(), CancelOutcome::Acknowledged);
+    }
+}
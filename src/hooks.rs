@@ -0,0 +1,111 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The lifecycle hooks of a coroutine. See `CoroutineBuilder::on_resume()`.
+//!
+//! They all run on the side of the parent: right before it switches into the
+//! coroutine, or right after the coroutine has switched back. Without the
+//! `alloc` feature, there are no hooks, and `Lifecycle` is an empty type.
+
+#[cfg(feature = "alloc")]
+mod imp {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use core::fmt;
+
+    /// A hook, shared by every coroutine spawned with the same builder.
+    pub(crate) type Hook = Arc<dyn Fn() + Send + Sync>;
+
+    /// The hooks a builder gives to the coroutines it spawns.
+    #[derive(Clone, Default)]
+    pub(crate) struct Hooks {
+        pub(crate) resume: Option<Hook>,
+        pub(crate) suspend: Option<Hook>,
+        pub(crate) complete: Option<Hook>,
+    }
+
+    impl fmt::Debug for Hooks {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Hooks")
+                .field("resume", &self.resume.is_some())
+                .field("suspend", &self.suspend.is_some())
+                .field("complete", &self.complete.is_some())
+                .finish()
+        }
+    }
+
+    /// The hooks of a coroutine, boxed so that a coroutine without any only
+    /// pays for a pointer.
+    pub(crate) struct Lifecycle(Option<Box<Hooks>>);
+
+    impl Lifecycle {
+        pub(crate) fn none() -> Self {
+            Lifecycle(None)
+        }
+
+        pub(crate) fn new(hooks: Hooks) -> Self {
+            if hooks.resume.is_none() && hooks.suspend.is_none() && hooks.complete.is_none() {
+                return Lifecycle(None);
+            }
+
+            Lifecycle(Some(Box::new(hooks)))
+        }
+
+        fn run(&self, hook: fn(&Hooks) -> &Option<Hook>) {
+            if let Some(hook) = self.0.as_ref().and_then(|h| hook(h).as_ref()) {
+                hook();
+            }
+        }
+
+        /// Runs the hook for a coroutine about to be resumed.
+        pub(crate) fn resume(&self) {
+            self.run(|h| &h.resume)
+        }
+
+        /// Runs the hook for a coroutine which has yielded or is pending.
+        pub(crate) fn suspend(&self) {
+            self.run(|h| &h.suspend)
+        }
+
+        /// Runs the hook for a coroutine which has completed.
+        pub(crate) fn complete(&self) {
+            self.run(|h| &h.complete)
+        }
+    }
+}
+
+#[cfg(not(feature = "alloc"))]
+mod imp {
+    pub(crate) struct Lifecycle;
+
+    impl Lifecycle {
+        #[inline(always)]
+        pub(crate) fn none() -> Self {
+            Lifecycle
+        }
+
+        #[inline(always)]
+        pub(crate) fn resume(&self) {}
+
+        #[inline(always)]
+        pub(crate) fn suspend(&self) {}
+
+        #[inline(always)]
+        pub(crate) fn complete(&self) {}
+    }
+}
+
+#[cfg(feature = "alloc")]
+pub(crate) use self::imp::Hooks;
+pub(crate) use self::imp::Lifecycle;
@@ -61,8 +61,11 @@ done:                                        ; setjmp(%from) returned !0
 ;   1. Call setjmp().
 ;   2. Set the stack pointer to %addr.
 ;   3. Call %func(%c, %f).
+;   4. Call longjmp() on the buffer %func returns.
+; The lower end of the stack (%limit) is only needed by some platform backends,
+; and the top of the shadow stack (%shadow) only by the x86_64 one.
 define dso_local void
-@jump_init(i8* %addr, i8* %c, i8* %f, void ([5 x i8*]*, i8*, i8*)* %func)
+@jump_init(i8* %addr, i8* %c, i8* %f, [5 x i8*]* ([5 x i8*]*, i8*, i8*)* %func, i8* %limit, i8* %shadow)
 nounwind
 {
   %buff = alloca [5 x i8*], align 4          ; Allocate setjmp() buffer
@@ -74,7 +77,9 @@ nounwind
 
 next:                                         ; setjmp(%buff) returned 0
   call void @llvm.stackrestore(i8* %addr)     ; Move onto new stack %addr
-  call void %func([5 x i8*]* %buff, i8* %c, i8* %f) ; Call %func(%buff, %c, %f)
+  %into = call [5 x i8*]* %func([5 x i8*]* %buff, i8* %c, i8* %f) ; Call %func(%buff, %c, %f)
+  %ibuf = bitcast [5 x i8*]* %into to i8*
+  call void @llvm.eh.sjlj.longjmp(i8* %ibuf)  ; longjmp(%into)
   unreachable
 
 done:                                         ; setjmp(%buff) returned !0
@@ -0,0 +1,138 @@
+; WebAssembly code can't switch call stacks by itself, so on wasm32 the host
+; does it for us: a coroutine runs on a host fiber (a JS Promise Integration
+; suspender, a stack-switching continuation, a Wasmtime fiber, ...). What we
+; do ourselves is move the shadow stack, the part of the stack in linear
+; memory that __stack_pointer points into.
+;
+; The host provides these functions in the "frenetic" import module:
+;
+;   current() -> fiber  Returns the running fiber, including the one the
+;                       module was entered on.
+;   spawn(arg) -> fiber Creates a suspended fiber which, once resumed, calls
+;                       the exported frenetic_fiber_main(arg).
+;   switch(fiber)       Suspends the running fiber and resumes %fiber. Returns
+;                       once something resumes the running fiber again.
+;
+; When frenetic_fiber_main() returns a fiber, its own fiber has ended and the
+; host resumes the returned one.
+;
+; A jump buffer holds the fiber its context runs on in its first slot. Every
+; fiber shares __stack_pointer, so each side of a switch puts back its own
+; shadow stack pointer once it is resumed.
+
+; The shadow stack pointer. LLVM keeps its own copy of it in a local while a
+; function runs, so we can't use @llvm.stacksave() and @llvm.stackrestore().
+@__stack_pointer = external addrspace(1) global i8*
+
+declare i8* @frenetic_current() nounwind "wasm-import-module"="frenetic" "wasm-import-name"="current"
+declare i8* @frenetic_spawn(i8*) nounwind "wasm-import-module"="frenetic" "wasm-import-name"="spawn"
+declare void @frenetic_switch(i8*) nounwind "wasm-import-module"="frenetic" "wasm-import-name"="switch"
+
+; The arguments of jump_init(), as passed to a new fiber.
+%init = type {
+  i8*,                                            ; %addr
+  i8*,                                            ; %c
+  i8*,                                            ; %f
+  [1 x i8*]* ([1 x i8*]*, i8*, i8*)*,             ; %func
+  [1 x i8*]*                                      ; The parent's buffer
+}
+
+; Suspends the current context into %from and resumes %fiber.
+; This function is internal only.
+define private void
+@jump_save([1 x i8*]* nonnull %from, i8* %fiber)
+alwaysinline nounwind
+{
+  %stack = load i8*, i8* addrspace(1)* @__stack_pointer ; Save the stack
+
+  ; Store the current fiber.
+  %self = call i8* @frenetic_current()
+  %slot = getelementptr inbounds [1 x i8*], [1 x i8*]* %from, i32 0, i32 0
+  store i8* %self, i8** %slot
+
+  call void @frenetic_switch(i8* %fiber)         ; Switch to %fiber
+  store i8* %stack, i8* addrspace(1)* @__stack_pointer ; Resumed: restore it
+  ret void
+}
+
+; Resumes %into, abandoning the current context. The host can't tell, so the
+; fiber we are running on stays suspended forever.
+define dso_local void
+@jump_into([1 x i8*]* nonnull %into)
+noreturn nounwind
+{
+  %slot = getelementptr inbounds [1 x i8*], [1 x i8*]* %into, i32 0, i32 0
+  %fiber = load i8*, i8** %slot
+  call void @frenetic_switch(i8* %fiber)
+  unreachable
+}
+
+; Suspends the current context into %from and resumes %into.
+define dso_local void
+@jump_swap([1 x i8*]* nonnull %from, [1 x i8*]* nonnull %into)
+nounwind
+{
+  %slot = getelementptr inbounds [1 x i8*], [1 x i8*]* %into, i32 0, i32 0
+  %fiber = load i8*, i8** %slot
+  call void @jump_save([1 x i8*]* %from, i8* %fiber)
+  ret void
+}
+
+; This function sets up the coroutine. It does the following steps:
+;   1. Ask the host for a new fiber.
+;   2. Switch to it, which calls frenetic_fiber_main() below.
+; The lower end of the stack (%limit) is only needed by some platform backends,
+; and the top of the shadow stack (%shadow) only by the x86_64 one.
+define dso_local void
+@jump_init(i8* %addr, i8* %c, i8* %f, [1 x i8*]* ([1 x i8*]*, i8*, i8*)* %func, i8* %limit, i8* %shadow)
+nounwind
+{
+  %buff = alloca [1 x i8*], align 4              ; Allocate the jump buffer
+  %args = alloca %init, align 4
+
+  ; Store the arguments for the new fiber.
+  %aoff = getelementptr inbounds %init, %init* %args, i32 0, i32 0
+  store i8* %addr, i8** %aoff
+  %coff = getelementptr inbounds %init, %init* %args, i32 0, i32 1
+  store i8* %c, i8** %coff
+  %foff = getelementptr inbounds %init, %init* %args, i32 0, i32 2
+  store i8* %f, i8** %foff
+  %xoff = getelementptr inbounds %init, %init* %args, i32 0, i32 3
+  store [1 x i8*]* ([1 x i8*]*, i8*, i8*)* %func, [1 x i8*]* ([1 x i8*]*, i8*, i8*)** %xoff
+  %boff = getelementptr inbounds %init, %init* %args, i32 0, i32 4
+  store [1 x i8*]* %buff, [1 x i8*]** %boff
+
+  %arg = bitcast %init* %args to i8*
+  %fiber = call i8* @frenetic_spawn(i8* %arg)
+  call void @jump_save([1 x i8*]* %buff, i8* %fiber)
+  ret void
+}
+
+; The entry point of a new fiber. It does the following steps:
+;   1. Set the shadow stack pointer to %addr.
+;   2. Call %func(%buff, %c, %f).
+;   3. Return the fiber of the buffer %func returns, to be resumed.
+; %arg is only valid until %func first suspends.
+define dso_local i8*
+@frenetic_fiber_main(i8* %arg)
+nounwind "wasm-export-name"="frenetic_fiber_main"
+{
+  %args = bitcast i8* %arg to %init*
+  %aoff = getelementptr inbounds %init, %init* %args, i32 0, i32 0
+  %addr = load i8*, i8** %aoff
+  %coff = getelementptr inbounds %init, %init* %args, i32 0, i32 1
+  %c = load i8*, i8** %coff
+  %foff = getelementptr inbounds %init, %init* %args, i32 0, i32 2
+  %f = load i8*, i8** %foff
+  %xoff = getelementptr inbounds %init, %init* %args, i32 0, i32 3
+  %func = load [1 x i8*]* ([1 x i8*]*, i8*, i8*)*, [1 x i8*]* ([1 x i8*]*, i8*, i8*)** %xoff
+  %boff = getelementptr inbounds %init, %init* %args, i32 0, i32 4
+  %buff = load [1 x i8*]*, [1 x i8*]** %boff
+
+  store i8* %addr, i8* addrspace(1)* @__stack_pointer ; Move onto %addr
+  %into = call [1 x i8*]* %func([1 x i8*]* %buff, i8* %c, i8* %f) ; Call %func(%buff, %c, %f)
+
+  %slot = getelementptr inbounds [1 x i8*], [1 x i8*]* %into, i32 0, i32 0
+  %fiber = load i8*, i8** %slot
+  ret i8* %fiber
+}
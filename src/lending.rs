@@ -0,0 +1,162 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Yielding borrowed data.
+//!
+//! A coroutine can't yield a reference to something on its own stack: the
+//! parent could keep it past the next resume, when the coroutine is free to
+//! change or drop what it points to. `Control::lend()` yields such a
+//! reference as a `Lend`, which the parent can't look into by itself.
+//! `LendingGenerator::resume()` turns it back into a reference, which
+//! borrows the coroutine, so that it can't outlive the suspension it was
+//! lent for.
+//!
+//! ```
+//! # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+//! use frenetic::{Coroutine, GeneratorState, LendingGenerator, STACK_MINIMUM};
+//!
+//! let mut stack = [0u8; STACK_MINIMUM * 8];
+//! let mut coro = Coroutine::new(&mut stack, |mut c, ()| {
+//!     let mut buf = [0u8; 16];
+//!     for i in 0..2 {
+//!         buf[0] = i;
+//!         c = c.lend(&mut buf[..])?.0;
+//!     }
+//!     c.done(())
+//! });
+//!
+//! while let GeneratorState::Yielded(buf) = coro.resume() {
+//!     assert_eq!(buf.len(), 16);
+//! }
+//! ```
+//!
+//! Keeping a lent reference across a resume doesn't compile:
+//!
+//! ```compile_fail
+//! # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+//! use frenetic::{Coroutine, GeneratorState, LendingGenerator, STACK_MINIMUM};
+//!
+//! let mut stack = [0u8; STACK_MINIMUM * 8];
+//! let mut coro = Coroutine::new(&mut stack, |c, ()| {
+//!     let mut x = 1;
+//!     let (c, ()) = c.lend(&mut x)?;
+//!     c.done(())
+//! });
+//!
+//! if let GeneratorState::Yielded(x) = coro.resume() {
+//!     let _ = coro.resume();
+//!     *x += 1;
+//! }
+//! ```
+
+use crate::{Control, Coroutine, CoroutineError, Generator, GeneratorState};
+use core::fmt;
+use core::pin::Pin;
+use core::ptr::NonNull;
+
+/// A reference a coroutine has lent to its parent. See `Control::lend()`.
+///
+/// Only `LendingGenerator::resume()` can turn it back into a reference. It
+/// is of no use once the coroutine has been resumed again.
+pub struct Lend<T: ?Sized>(NonNull<T>);
+
+impl<T: ?Sized> fmt::Debug for Lend<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Lend")
+    }
+}
+
+impl<'a, A, T: ?Sized, R> Control<'a, A, Lend<T>, R> {
+    /// Lends `value` to the parent, like `r#yield()`. The parent gets it back
+    /// from `LendingGenerator::resume()` as a reference it can use until it
+    /// resumes the coroutine again, which is when this returns.
+    #[cfg_attr(feature = "registry", track_caller)]
+    pub fn lend(self, value: &mut T) -> Result<(Self, A), CoroutineError> {
+        self.r#yield(Lend(NonNull::from(value)))
+    }
+}
+
+/// A generator which yields references borrowing from itself.
+///
+/// Each value it yields borrows the generator, so that it has to be dropped
+/// before the generator can be resumed again. Requires Rust 1.65.
+pub trait LendingGenerator {
+    /// The type of the values it yields, borrowing it for `'b`.
+    type Yield<'b>
+    where
+        Self: 'b;
+
+    /// The type of the value it returns.
+    type Return;
+
+    /// Resumes the generator. See `Generator::resume()`.
+    fn resume(&mut self) -> GeneratorState<Self::Yield<'_>, Self::Return>;
+}
+
+impl<'a, T: ?Sized, R, S> LendingGenerator for Coroutine<'a, (), Lend<T>, R, S> {
+    type Yield<'b>
+        = &'b mut T
+    where
+        Self: 'b;
+    type Return = R;
+
+    fn resume(&mut self) -> GeneratorState<&mut T, R> {
+        match Generator::resume(Pin::new(self)) {
+            // The coroutine is suspended in `Control::lend()` until the next
+            // resume, which the borrow of `self` holds off.
+            GeneratorState::Yielded(lend) => {
+                GeneratorState::Yielded(unsafe { &mut *lend.0.as_ptr() })
+            }
+            GeneratorState::Complete(r) => GeneratorState::Complete(r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::STACK_MINIMUM;
+
+    #[test]
+    fn lend() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), Lend<[u8]>, u8>, ()| {
+            let mut buf = [1u8; 8];
+            let (c, ()) = c.lend(&mut buf[..])?;
+
+            // The parent has written to it in the meantime.
+            let (c, ()) = c.lend(&mut buf[2..4])?;
+            c.done(buf.iter().sum())
+        });
+
+        match coro.resume() {
+            GeneratorState::Yielded(buf) => {
+                assert_eq!(buf, [1; 8]);
+                buf[7] = 3;
+            }
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match coro.resume() {
+            GeneratorState::Yielded(buf) => buf.copy_from_slice(&[0, 0]),
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match coro.resume() {
+            GeneratorState::Complete(8) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+}
@@ -13,8 +13,53 @@
 // limitations under the License.
 
 //! Frenetic is an implementation of stackful coroutines. It is written in Rust
-//! and LLVM. Notably, this approach does not require any system calls or hand-
-//! crafted assembly at all.
+//! and LLVM. Notably, this approach does not require any system calls. The
+//! context switch is a portable LLVM IR implementation, except where a small
+//! assembly backend replaces it: where LLVM cannot lower the intrinsics we rely
+//! on (aarch64, riscv64, s390x), where the intrinsics don't follow the platform
+//! ABI (ARMv7 and later, ppc64le, Windows), or to describe the frames to
+//! unwinders (x86, x86_64).
+//!
+//! On targets with no backend at all, enable the `threads` feature. It runs
+//! each coroutine on a parked OS thread instead. This is much slower, but
+//! supports the same API everywhere the standard library does. Code inside a
+//! coroutine then sees that thread's thread-local variables.
+//!
+//! To build without a C compiler, enable the `asm` feature. It replaces the
+//! x86_64 and aarch64 backends with the same code written with `global_asm!`
+//! (Rust 1.59 or later), and is used there anyway when the C compiler can't
+//! be run.
+//!
+//! The crate is `no_std`. The `std` feature, on by default, adds catching
+//! panics inside coroutines, unwinding canceled ones, and everything else
+//! which needs the standard library; the `alloc` feature, heap-allocated
+//! stacks, names and hooks.
+//!
+//! On Cortex-M microcontrollers, coroutines run on stacks in `static`s
+//! handed out by `StaticStack`, without a heap; see
+//! `examples/static_stacks.rs`.
+//!
+//! On wasm32, coroutines run on fibers provided by the host, which supplies
+//! them through the `frenetic` import module (see `src/jump/wasm32.ll`).
+//!
+//! On Linux, the `guard` feature turns a fault on the guard page below a
+//! coroutine's stack (see `ProtectedStack`) into a "coroutine stack overflow"
+//! panic in its parent, rather than a crash.
+//!
+//! The `zeroize` feature wipes the part of a coroutine's stack it has used
+//! once the coroutine is dropped or canceled, or its stack is taken back.
+//!
+//! The `tracing` feature gives every coroutine a `tracing` span, which is
+//! entered while the coroutine runs.
+//!
+//! The `sgx` feature restricts the context switch to what runs inside an
+//! Intel SGX enclave, on x86_64, and the `snp` feature to instructions which
+//! never make an AMD SEV-SNP guest exit to its hypervisor.
+//!
+//! Miri cannot run the native backends, so under `cargo miri` the thread
+//! backend is always used. This lets Miri check the crate, and the code using
+//! it, through the same API. Miri can't change memory protections, so use
+//! borrowed buffers or `HeapStack` rather than `ProtectedStack` there.
 //!
 //! # Example usage
 //! ```
@@ -26,8 +71,8 @@
 //! let mut stack = [0u8; STACK_MINIMUM * 8];
 //!
 //! // Then, you can initialize with `Coroutine::new`.
-//! let mut coro = Coroutine::new(&mut stack, |c| {
-//!     let c = c.r#yield(1)?; // Yield an integer value.
+//! let mut coro = Coroutine::new(&mut stack, |c, ()| {
+//!     let (c, ()) = c.r#yield(1)?; // Yield an integer value.
 //!     c.done("foo") // Return a string value.
 //! });
 //!
@@ -42,7 +87,20 @@
 //! }
 //! ```
 
-#![cfg_attr(has_generator_trait, feature(generator_trait))]
+#![cfg_attr(
+    any(has_generator_trait, has_generator_resume_arg),
+    feature(generator_trait)
+)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![cfg_attr(has_coroutine_trait, feature(coroutine_trait))]
+// For the generator literals in tests, whose syntax is feature gated even
+// where they are configured out.
+#![cfg_attr(has_coroutine_trait, feature(coroutines))]
+#![cfg_attr(all(test, has_coroutine_trait), feature(stmt_expr_attributes))]
+#![cfg_attr(
+    has_debugger_visualizer,
+    debugger_visualizer(gdb_script_file = "../debug/frenetic_gdb.py")
+)]
 #![deny(
     warnings,
     absolute_paths_not_starting_with_crate,
@@ -63,48 +121,289 @@
     rust_2018_compatibility
 )]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod actor;
+mod adapter;
+#[cfg(feature = "alloc")]
+mod allocator;
+#[cfg(frenetic_asm)]
+mod asm;
+mod budget;
+mod builder;
+mod canary;
+mod cet;
+mod debug;
+#[cfg(feature = "alloc")]
+mod erased;
+mod frame;
+#[cfg(feature = "futures")]
+mod future;
+mod guard;
+mod hooks;
+#[cfg(has_gats)]
+mod lending;
+#[cfg(all(
+    feature = "net",
+    unix,
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    )
+))]
+pub mod net;
+#[cfg(any(has_generator_resume_arg, has_coroutine_trait))]
+mod nightly;
+#[cfg(feature = "std")]
+mod pool;
+mod registry;
+#[cfg(feature = "std")]
+pub mod scheduler;
+#[cfg(feature = "alloc")]
+mod scope;
+#[cfg(feature = "alloc")]
+mod segment;
+mod send;
+#[cfg(feature = "sgx")]
+mod sgx;
+#[cfg(feature = "snp")]
+mod snp;
+mod stack;
+mod stats;
+#[cfg(feature = "futures")]
+mod stream;
+#[cfg(feature = "std")]
+pub mod sync;
+#[cfg(any(feature = "threads", miri))]
+mod thread;
+#[cfg(feature = "std")]
+pub mod time;
+mod trace;
+mod tsan;
+mod unwind;
+mod valgrind;
+mod wake;
+mod zeroize;
+
+pub use adapter::{pipe, Chain, GeneratorExt, MapReturn, MapYield, Pipe, Piped};
+#[cfg(feature = "alloc")]
+pub use allocator::{
+    set_stack_allocator, stack_allocator, AllocatedStack, DefaultStackAllocator, HugePages,
+    StackAllocator, StackOptions,
+};
+pub use budget::Budget;
+pub use builder::CoroutineBuilder;
+#[cfg(feature = "alloc")]
+pub use erased::{AnyCoroutine, DynCoroutine};
+#[cfg(feature = "macros")]
+pub use frenetic_macros::coroutine;
+#[cfg(feature = "futures")]
+pub use future::CoroutineFuture;
+#[cfg(has_gats)]
+pub use lending::{Lend, LendingGenerator};
+#[cfg(feature = "std")]
+pub use pool::{PooledStack, Reclaim, StackPool};
+#[cfg(feature = "registry")]
+pub use registry::{coroutines, dump, CoroutineInfo};
+#[cfg(feature = "alloc")]
+pub use scope::{scope, Scope};
+#[cfg(feature = "alloc")]
+pub use segment::{Segment, Segments};
+pub use send::SendCoroutine;
+#[cfg(feature = "std")]
+pub use send::SyncCoroutine;
+#[cfg(feature = "alloc")]
+pub use stack::HeapStack;
+#[cfg(feature = "std")]
+pub use stack::ProtectedStack;
+#[cfg(has_const_generics)]
+pub use stack::{AlignedStack, StaticStack};
+pub use stack::{RawStack, Stack};
+#[cfg(feature = "counters")]
+pub use stats::{switches, CoroutineStats};
+#[cfg(feature = "futures")]
+pub use stream::CoroutineStream;
+pub use unwind::PanicPolicy;
+#[cfg(feature = "std")]
+pub use wake::WakerSlot;
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+use canary::Canary;
+use cet::ShadowStack;
+#[cfg(feature = "alloc")]
+use core::any::Any;
 use core::ffi::c_void;
-use core::mem::MaybeUninit;
+use core::fmt;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of, ManuallyDrop, MaybeUninit};
 #[cfg(has_generator_trait)]
 pub use core::ops::{Generator, GeneratorState};
 use core::pin::Pin;
-use core::ptr::null_mut;
-
+use core::ptr::{self, null_mut, NonNull};
+use core::task::Waker;
+use frame::FrameRecord;
+use guard::Guard;
+#[cfg(feature = "alloc")]
+use hooks::Hooks;
+use hooks::Lifecycle;
+use registry::{Registered, Site};
+use stats::{count_switch, Stats};
+#[cfg(feature = "std")]
+use std::thread::ThreadId;
+use trace::Trace;
+use tsan::Fiber;
+use unwind::Payload;
+use valgrind::StackId;
+use zeroize::Zeroize;
+
+/// The alignment of the stack pointer at a call: 16 bytes, the strictest any
+/// supported ABI asks for (32-bit ARM only needs 8). Stacks are aligned to
+/// it, and so is the stack pointer a coroutine starts with.
 pub const STACK_ALIGNMENT: usize = 16;
-pub const STACK_MINIMUM: usize = 4096;
 
+/// The size of the smallest stack a coroutine can be spawned on. It depends
+/// on the target: 4096 bytes, or 2048 on 32-bit targets, whose frames are
+/// smaller and whose memory is often scarce. A closure with a lot to move
+/// onto the stack may need more; see `Coroutine::try_new()`.
+#[cfg(not(target_pointer_width = "32"))]
+pub const STACK_MINIMUM: usize = 4096;
+#[cfg(target_pointer_width = "32")]
+pub const STACK_MINIMUM: usize = 2048;
+
+/// The size of a jump buffer, in pointers.
+///
+/// A context is suspended into a jump buffer by `jump_swap()` and resumed
+/// from one. The assembly backends save the callee-saved registers of the
+/// target ABI, and nothing else, on the suspended context's own stack, and
+/// only need the stack pointer in the buffer. On wasm32, the buffer holds the
+/// host's fiber, and with the thread backend, the waiting thread. The
+/// portable LLVM IR backend needs five: the frame address, the resume
+/// address, the stack pointer and two words `llvm.eh.sjlj.setjmp` keeps for
+/// the target.
+#[cfg(frenetic_sjlj)]
+const JUMP_BUFFER: usize = 5;
+#[cfg(not(frenetic_sjlj))]
+const JUMP_BUFFER: usize = 1;
+
+/// A jump buffer. See `JUMP_BUFFER`.
+type JumpBuffer = [*mut c_void; JUMP_BUFFER];
+
+#[cfg(not(any(feature = "threads", miri)))]
 extern "C" {
-    fn jump_into(into: *mut [*mut c_void; 5]) -> !;
-    fn jump_swap(from: *mut [*mut c_void; 5], into: *mut [*mut c_void; 5]);
+    fn jump_swap(from: *mut JumpBuffer, into: *mut JumpBuffer);
     fn jump_init(
         stack: *mut u8,
         ctx: *mut c_void,
         fnc: *mut c_void,
         func: unsafe extern "C" fn(
-            parent: *mut [*mut c_void; 5],
+            parent: *mut JumpBuffer,
             ctx: *mut c_void,
             fnc: *mut c_void,
-        ) -> !,
+        ) -> *mut JumpBuffer,
+        limit: *mut u8,
+        shadow: *mut u8,
     );
 }
 
+#[cfg(any(feature = "threads", miri))]
+use thread::{jump_init, jump_swap};
+
+/// Where a coroutine leaves what it did for whoever resumed it: the state it
+/// suspended in, or else the error its closure returned. It starts out
+/// empty, and is filled in before the coroutine switches back.
+type Outcome<T> = Option<Result<T, CoroutineError>>;
+
 #[repr(C, align(16))]
-struct Context<Y, R> {
-    parent: [*mut c_void; 5],
-    child: [*mut c_void; 5],
-    arg: MaybeUninit<*mut GeneratorState<Y, R>>,
+struct Context<A, Y, R> {
+    parent: JumpBuffer,
+    child: JumpBuffer,
+    arg: *mut Outcome<ResumeState<Y, R>>,
+    input: *mut Option<A>,
+    panic: Option<Payload>,
+    #[cfg(feature = "alloc")]
+    thrown: Option<Box<dyn Any + Send>>,
+    back: *mut JumpBuffer,
+    back_out: *mut Outcome<R>,
+    done: bool,
+    ignored: bool,
+    overflowed: bool,
+    abort: bool,
+    name: Option<NonNull<str>>,
+    parent_fiber: Fiber,
+    waker: *const Waker,
+    budget: *const Budget,
+    site: Site,
+    #[cfg(feature = "alloc")]
+    bottom: *const u8,
+    #[cfg(feature = "alloc")]
+    segment: *const segment::Link,
 }
 
-impl<Y, R> Default for Context<Y, R> {
+impl<A, Y, R> Default for Context<A, Y, R> {
     fn default() -> Self {
         Context {
-            parent: [null_mut(); 5],
-            child: [null_mut(); 5],
-            arg: MaybeUninit::uninit(),
+            parent: [null_mut(); JUMP_BUFFER],
+            child: [null_mut(); JUMP_BUFFER],
+            arg: null_mut(),
+            input: null_mut(),
+            panic: None,
+            #[cfg(feature = "alloc")]
+            thrown: None,
+            back: null_mut(),
+            back_out: null_mut(),
+            done: false,
+            ignored: false,
+            overflowed: false,
+            abort: false,
+            name: None,
+            parent_fiber: Fiber::null(),
+            waker: ptr::null(),
+            budget: ptr::null(),
+            site: Site::UNKNOWN,
+            #[cfg(feature = "alloc")]
+            bottom: ptr::null(),
+            #[cfg(feature = "alloc")]
+            segment: ptr::null(),
         }
     }
 }
 
+/// How to start a coroutine on its first resume. See `Coroutine::new()`.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// The stack pointer to start with, right below the closure.
+    addr: *mut u8,
+
+    /// The closure's slot on the coroutine's stack, an `Option<F>` that holds
+    /// it until the callback takes it.
+    fnc: *mut c_void,
+
+    /// `callback()`, which runs the closure.
+    func: unsafe extern "C" fn(
+        parent: *mut JumpBuffer,
+        ctx: *mut c_void,
+        fnc: *mut c_void,
+    ) -> *mut JumpBuffer,
+
+    /// Drops the closure, if the coroutine is canceled before it starts.
+    drop: unsafe fn(fnc: *mut c_void),
+
+    /// The lower end of the stack.
+    limit: *mut u8,
+}
+
 #[cfg(not(has_generator_trait))]
 pub trait Generator {
     /// The type of value this generator yields.
@@ -153,6 +452,7 @@ pub trait Generator {
 }
 
 #[cfg(not(has_generator_trait))]
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Debug, Hash)]
 pub enum GeneratorState<Y, R> {
     /// The generator suspended with a value.
     ///
@@ -170,233 +470,2483 @@ pub enum GeneratorState<Y, R> {
     Complete(R),
 }
 
+/// The value a coroutine returns with, wrapped so that only a completed
+/// coroutine can produce it. Usually made by `Control::done()`; `new()` and
+/// `From` make one outside of the closure, for example in a helper function
+/// which finishes the coroutine on its behalf.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Debug, Hash)]
 pub struct Finished<R>(R);
 
-pub struct Canceled(());
+impl<R> Finished<R> {
+    /// Wraps the return value of a coroutine.
+    pub fn new(value: R) -> Self {
+        Finished(value)
+    }
+
+    /// Returns the wrapped return value.
+    pub fn into_inner(self) -> R {
+        self.0
+    }
+}
+
+impl<R> From<R> for Finished<R> {
+    fn from(value: R) -> Self {
+        Finished(value)
+    }
+}
+
+/// What a coroutine did when it was resumed, telling a value it yielded apart
+/// from a wait for some event. See `Coroutine::poll_resume()`.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Debug, Hash)]
+pub enum ResumeState<Y, R> {
+    /// The coroutine yielded a value.
+    Yielded(Y),
+
+    /// The coroutine is waiting for an event, and has arranged for the waker
+    /// it was resumed with to be woken once it occurs. See
+    /// `Control::yield_pending()`.
+    Pending,
+
+    /// The coroutine completed with a return value.
+    Complete(R),
+}
+
+/// The errors of coroutine operations.
+#[derive(Debug)]
+pub enum CoroutineError {
+    /// The coroutine has been canceled, because its `Coroutine` has been
+    /// dropped or canceled. Returned by `Control::r#yield()` and
+    /// `Control::transfer()` where unwinding isn't possible: the coroutine
+    /// must clean up and exit, typically by passing it on with `?`.
+    Canceled,
+
+    /// The stack is smaller than `STACK_MINIMUM`, or too small for the
+    /// closure. See `Coroutine::try_new()`.
+    StackTooSmall {
+        /// How large the stack needs to be, in bytes.
+        required: usize,
+
+        /// How large it is.
+        provided: usize,
+    },
+
+    /// The coroutine has already completed, and can't be resumed.
+    AlreadyFinished,
+
+    /// The coroutine is pinned to another thread. See
+    /// `SendCoroutine::pin_to_current_thread()`.
+    WrongThread,
+
+    /// The coroutine panicked, with this payload. It has unwound its stack
+    /// and can't be resumed. See `Coroutine::try_resume_with()`.
+    ///
+    /// Requires the `std` feature, without which panics aren't caught.
+    #[cfg(feature = "std")]
+    Panicked(Box<dyn Any + Send>),
+
+    /// The parent has thrown this error into the coroutine, with
+    /// `Coroutine::resume_with_error()`. Returned by the `Control::r#yield()`
+    /// call the coroutine is paused in, for it to handle there; passed on
+    /// with `?`, it comes back out of `Coroutine::try_resume_with_error()`.
+    ///
+    /// Requires the `alloc` feature.
+    #[cfg(feature = "alloc")]
+    Thrown(Box<dyn Any + Send>),
+}
+
+impl CoroutineError {
+    /// Cancels the running coroutine after its parent has been dropped.
+    ///
+    /// Unwinds the coroutine's stack from here, so that every live local on
+    /// it is dropped. Where unwinding isn't possible (with `panic = "abort"`,
+    /// without the `std` feature, or while the parent is already panicking)
+    /// the `Canceled` error is returned instead, and the coroutine must clean
+    /// up and exit by itself.
+    fn unwind() -> Self {
+        if unwind::UNWIND && !unwind::panicking() {
+            unwind::cancel();
+        }
+
+        CoroutineError::Canceled
+    }
+
+    /// The error of a coroutine which panicked.
+    #[cfg(feature = "std")]
+    fn panicked(payload: Payload) -> Self {
+        CoroutineError::Panicked(payload)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn panicked(payload: Payload) -> Self {
+        match payload {}
+    }
+}
+
+impl fmt::Display for CoroutineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoroutineError::Canceled => write!(f, "coroutine has been canceled"),
+            CoroutineError::StackTooSmall { required, provided } => write!(
+                f,
+                "stack of {} bytes is smaller than the {} it needs",
+                provided, required
+            ),
+            CoroutineError::AlreadyFinished => write!(f, "coroutine has already completed"),
+            CoroutineError::WrongThread => write!(f, "coroutine is pinned to another thread"),
+            #[cfg(feature = "std")]
+            CoroutineError::Panicked(payload) => {
+                if let Some(msg) = payload.downcast_ref::<&str>() {
+                    write!(f, "coroutine panicked: {}", msg)
+                } else if let Some(msg) = payload.downcast_ref::<String>() {
+                    write!(f, "coroutine panicked: {}", msg)
+                } else {
+                    write!(f, "coroutine panicked")
+                }
+            }
+            #[cfg(feature = "alloc")]
+            CoroutineError::Thrown(err) => {
+                if let Some(msg) = err.downcast_ref::<&str>() {
+                    write!(f, "error thrown into coroutine: {}", msg)
+                } else if let Some(msg) = err.downcast_ref::<String>() {
+                    write!(f, "error thrown into coroutine: {}", msg)
+                } else {
+                    write!(f, "error thrown into coroutine")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CoroutineError {}
+
+/// The state of a `Coroutine`. See `Coroutine::state()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CoroutineState {
+    /// The coroutine has not been resumed yet.
+    Created,
+
+    /// The coroutine has been resumed and is paused in a yield.
+    Suspended,
+
+    /// The coroutine has completed, or panicked. It can't be resumed.
+    Finished,
+}
+
+/// The name of a coroutine. See `Coroutine::name()`. Without the `alloc`
+/// feature, coroutines can't be named.
+#[cfg(feature = "alloc")]
+type Name = String;
+
+#[cfg(not(feature = "alloc"))]
+enum Name {}
+
+#[cfg(not(feature = "alloc"))]
+impl core::ops::Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match *self {}
+    }
+}
+
+/// The body of a coroutine spawned by `Coroutine::from_fn_mut()`, which
+/// `Coroutine::reset()` runs again.
+#[cfg(feature = "alloc")]
+type Body<'a, A, Y, R> =
+    dyn FnMut(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError> + 'a;
+
+/// Names a coroutine in a panic message, if it has a name.
+struct Named<'a>(Option<&'a str>);
+
+impl fmt::Display for Named<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(name) => write!(f, " (coroutine {:?})", name),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How a coroutine responded to being canceled. See `Coroutine::cancel()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CancelOutcome {
+    /// The coroutine had already completed. There was nothing to cancel.
+    Completed,
+
+    /// The coroutine exited because of the cancellation: either its stack
+    /// was unwound, or it returned `CoroutineError::Canceled`. A coroutine
+    /// which had not been resumed yet is dropped without running at all.
+    Acknowledged,
 
-pub struct Coroutine<'a, Y, R>(Option<&'a mut Context<Y, R>>);
+    /// The coroutine stopped the cancellation and returned a value anyway.
+    /// The value has been dropped.
+    Ignored,
+}
+
+pub struct Coroutine<'a, A, Y, R, S = &'a mut [u8]> {
+    // The coroutine accesses its `Context` too, so we must not hold a
+    // reference to it, or to the stack it lives in, while it runs. The stack
+    // is kept in a `MaybeUninit` so that a `&mut [u8]` stack isn't reborrowed
+    // whenever the `Coroutine` is moved, which would invalidate every pointer
+    // into it. It is always initialized.
+    ctx: Option<NonNull<Context<A, Y, R>>>,
+    entry: Option<Entry>,
+    fiber: Fiber,
+    stack_id: StackId,
+    shadow: ShadowStack,
+    canary: Canary,
+    guard: Guard,
+    zeroize: Zeroize,
+    trace: Trace,
+    hooks: Lifecycle,
+    stats: Stats,
+    registered: Registered,
+    #[cfg(feature = "std")]
+    thread: Option<ThreadId>,
+    name: Option<Name>,
+    panic_policy: PanicPolicy,
+    // Owned, like a `Box`, but a `Box` would be retagged whenever the
+    // `Coroutine` is moved, and with it every pointer the coroutine holds.
+    #[cfg(feature = "alloc")]
+    body: Option<NonNull<Body<'a, A, Y, R>>>,
+    stack: MaybeUninit<S>,
+    life: PhantomData<&'a mut Context<A, Y, R>>,
+}
+
+// The coroutine's state lives in its stack, which does not move when the
+// `Coroutine` (and the `Stack` it owns) is moved. See `Stack`.
+impl<'a, A, Y, R, S> Unpin for Coroutine<'a, A, Y, R, S> {}
 
-unsafe extern "C" fn callback<Y, R, F>(
-    p: *mut [*mut c_void; 5],
+unsafe extern "C" fn callback<A, Y, R, F>(
+    p: *mut JumpBuffer,
     c: *mut c_void,
     f: *mut c_void,
-) -> !
+) -> *mut JumpBuffer
 where
-    F: FnOnce(Control<'_, Y, R>) -> Result<Finished<R>, Canceled>,
+    F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
 {
-    // Allocate a Context and a closure.
-    let mut ctx = Context::<Y, R>::default();
-    let mut fnc = MaybeUninit::<F>::uninit();
+    // Cast the incoming pointers to their correct types, and take the
+    // closure out of its slot at the top of the stack. `Coroutine::new()`
+    // moved it there whole before the first jump, so it is always there to
+    // take, once: the slot is empty from here on, and dropping it is a no-op.
+    let ctx = c as *mut Context<A, Y, R>;
+    let fnc = (*(f as *mut Option<F>)).take().unwrap();
+
+    // Nothing in here may unwind into whatever called us. A panic in the
+    // closure is caught below, but not without the `std` feature, and then
+    // it aborts here instead. See `unwind`.
+    let bomb = unwind::Bomb;
+
+    // We have been started by the first resume, which is suspended into `p`.
+    // A jump buffer may be copied, so move it to where it expects to be
+    // resumed from: the buffer `Control::transfer()` lends us as `back`, or
+    // our parent. See `Coroutine::enter()`.
+    let back = (&(*ctx).back as *const *mut JumpBuffer).read_volatile();
+    if back.is_null() {
+        (*ctx).parent = *p;
+    } else {
+        *back = *p;
+    }
+    frame::link(c as *mut u8, p);
+
+    // Run the closure on this stack. A panic must not unwind past this
+    // function: there are no frames above it to unwind into, and the unwind
+    // would cross the `extern "C"` boundary. Catch it here and hand the
+    // payload to the parent instead. See `Coroutine::resume_with()`.
+    let result = unwind::catch(|| {
+        // An error thrown in before we ever ran completes us without running
+        // the closure at all.
+        match take_input(ctx) {
+            Ok(input) => fnc(Control(ctx, PhantomData), input),
+            Err(err) => Err(err),
+        }
+    });
 
-    // Cast the incoming pointers to their correct types.
-    // See `Coroutine::new()`.
-    let c = c as *mut Coroutine<'_, Y, R>;
-    let f = f as *mut *mut F;
+    let jump = finish(ctx, result);
+    bomb.defuse();
+    jump
+}
 
-    // Pass references to the stack-allocated Context and closure back into
-    // Coroutine::new() through the incoming pointers.
-    (*c).0 = Some(&mut ctx);
-    *f = fnc.as_mut_ptr();
+/// Moves the resume argument out of the input variable in
+/// `Coroutine::resume_with()` or `Control::transfer()`, or returns the error
+/// `Coroutine::resume_with_error()` has thrown in its place.
+unsafe fn take_input<A, Y, R>(ctx: *mut Context<A, Y, R>) -> Result<A, CoroutineError> {
+    #[cfg(feature = "alloc")]
+    {
+        if let Some(err) = (*ctx).thrown.take() {
+            return Err(CoroutineError::Thrown(err));
+        }
+    }
 
-    // Yield control to the parent. The first call to `Generator::resume()`
-    // will resume at this location. The `Coroutine::new()` function is
-    // responsible to move the closure into this stack while we are yielded.
-    jump_swap(ctx.child.as_mut_ptr() as _, p as _);
+    let ptr_input = (&(*ctx).input as *const *mut Option<A>).read_volatile();
+    Ok((*ptr_input).take().unwrap())
+}
 
-    let fnc = fnc.assume_init();
+/// Hands what the closure of a coroutine returned to whoever is waiting for
+/// it, and returns the context to jump to, for `callback()`. It is a function
+/// of its own so that nothing it needs takes up the stack while the closure
+/// runs.
+#[inline(never)]
+unsafe fn finish<A, Y, R>(
+    ctx: *mut Context<A, Y, R>,
+    result: Result<Result<Finished<R>, CoroutineError>, Payload>,
+) -> *mut JumpBuffer {
+    // If we were started by `Control::transfer()`, we return to the
+    // coroutine that started us rather than to the parent.
+    let back = (&(*ctx).back as *const *mut JumpBuffer).read_volatile();
+    let back_out = (&(*ctx).back_out as *const *mut Outcome<R>).read_volatile();
+
+    match result {
+        // If the closure returns, then move the return value into the
+        // argument variable in `Coroutine::resume_with()`, or the one in
+        // `Control::transfer()`.
+        Ok(Ok(r)) if !back_out.is_null() => back_out.write(Some(Ok(r.0))),
+        Ok(Ok(r)) => {
+            let arg = (&(*ctx).arg as *const *mut Outcome<ResumeState<Y, R>>).read_volatile();
+            if !arg.is_null() {
+                arg.write(Some(Ok(ResumeState::Complete(r.0))));
+            } else {
+                // We were canceled, but returned a value anyway. Nobody is
+                // left to receive it.
+                (*ctx).ignored = true;
+            }
+        }
 
-    // Call the closure. If the closure returns, then move the return value
-    // into the argument variable in `Generator::resume()`.
-    if let Ok(r) = fnc(Control(&mut ctx)) {
-        let arg = ctx.arg.assume_init();
-        if !arg.is_null() {
-            *arg = GeneratorState::Complete(r.0);
+        // The closure gave up with an error, which goes to whoever resumed
+        // us, unless we were canceled and it only passed that on.
+        Ok(Err(e)) if !back_out.is_null() => back_out.write(Some(Err(e))),
+        Ok(Err(e)) => {
+            let arg = (&(*ctx).arg as *const *mut Outcome<ResumeState<Y, R>>).read_volatile();
+            if !arg.is_null() {
+                arg.write(Some(Err(e)));
+            }
         }
+
+        // The coroutine was canceled and its stack has been unwound. This is
+        // not a panic to hand to the parent.
+        Err(ref payload) if unwind::is_cancel(payload) => {}
+
+        Err(payload) if (&(*ctx).abort as *const bool).read_volatile() => unwind::abort(payload),
+        Err(payload) => (*ctx).panic = Some(payload),
     }
 
-    // We cannot be resumed, so jump away forever.
-    jump_into(ctx.parent.as_mut_ptr() as _);
+    (&mut (*ctx).done as *mut bool).write_volatile(true);
+    count_switch();
+
+    // We cannot be resumed. Have the backend jump away forever. The coroutine
+    // we jump to switches ThreadSanitizer over to its own fiber; see `tsan`.
+    if !back.is_null() {
+        return back;
+    }
+
+    &mut (*ctx).parent
+}
+
+/// Drops the closure of a coroutine which is canceled before it starts.
+unsafe fn drop_closure<F>(fnc: *mut c_void) {
+    ptr::drop_in_place(fnc as *mut Option<F>)
 }
 
-impl<'a, Y, R> Coroutine<'a, Y, R> {
+impl<'a, A, Y, R, S: Stack> Coroutine<'a, A, Y, R, S> {
+    /// Spawns a new coroutine, like `new()`, but returns
+    /// `CoroutineError::StackTooSmall` instead of panicking if the stack is
+    /// smaller than `STACK_MINIMUM`, or too small for the closure.
+    pub fn try_new<F>(stack: S, func: F) -> Result<Self, CoroutineError>
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'a,
+        S: 'a,
+    {
+        let required = Self::required::<F>();
+        if stack.len() < required {
+            return Err(CoroutineError::StackTooSmall {
+                required,
+                provided: stack.len(),
+            });
+        }
+
+        // Calculate the aligned top of the stack. From here on, the stack is
+        // only accessed through `bottom`; see `Coroutine`.
+        let len = stack.len();
+        let mut stack = MaybeUninit::new(stack);
+        let bottom = unsafe { (*stack.as_mut_ptr()).bottom() };
+
+        // This must come first, before anything is written to the stack.
+        let zeroize = unsafe { Zeroize::new(bottom, len) };
+
+        let (ctx, entry) = unsafe { Self::prepare(bottom, len, func) };
+
+        let fiber = Fiber::new();
+        let stack_id = unsafe { StackId::register(bottom, bottom.add(len)) };
+        let shadow = ShadowStack::new(len);
+        let canary = unsafe { Canary::new(bottom) };
+        let guard = Guard::new(bottom);
+
+        Ok(Coroutine {
+            ctx: NonNull::new(ctx),
+            entry: Some(entry),
+            fiber,
+            stack_id,
+            shadow,
+            canary,
+            guard,
+            zeroize,
+            trace: Trace::new(),
+            hooks: Lifecycle::none(),
+            stats: Stats::new(),
+            registered: Registered::new(ctx as *mut u8),
+            #[cfg(feature = "std")]
+            thread: None,
+            name: None,
+            panic_policy: PanicPolicy::Propagate,
+            #[cfg(feature = "alloc")]
+            body: None,
+            stack,
+            life: PhantomData,
+        })
+    }
+
     /// Spawns a new coroutine.
     ///
-    /// This sets up the stack, and executes the closure within that stack.
+    /// This sets up the stack and moves the closure onto it. The closure
+    /// starts running on the stack on the first resume.
     ///
     /// # Arguments
     ///
-    /// * `stack` - A stack for this coroutine to use.
-    /// This must be larger than `STACK_MINIMUM`, currently 4096, or Frenetic
-    /// will panic.
-    /// NOTE: It is up to the caller to properly allocate this stack. We
-    /// recommend the stack include a guard page.
+    /// * `stack` - A stack for this coroutine to use. This can be a mutable
+    ///   reference to a byte buffer or any other `Stack` implementation.
+    ///   This must be at least `STACK_MINIMUM` bytes, or Frenetic will panic
+    ///   (see `try_new()`).
+    ///   NOTE: It is up to the caller to properly allocate this stack. We
+    ///   recommend the stack include a guard page (see `ProtectedStack`).
+    ///   Debug builds also keep a canary at the lower end of the stack, and
+    ///   panic with "coroutine stack overflow" once it has been overwritten.
+    ///
+    /// * `func` - The closure to be executed as part of the coroutine. It
+    ///   receives the argument passed to the first resume. It is moved onto
+    ///   the top of the stack, and must leave at least half of
+    ///   `STACK_MINIMUM` below to run on, or Frenetic will panic. The first
+    ///   resume moves it once more, onto the stack below, to call it.
+    pub fn new<F>(stack: S, func: F) -> Self
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'a,
+        S: 'a,
+    {
+        match Self::try_new(stack, func) {
+            Ok(coro) => coro,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Spawns a new coroutine, like `new()`, whose closure can be run more
+    /// than once: `reset()` runs it again from the start. Each run borrows
+    /// it, so whatever it captures stays with it from one run to the next.
+    ///
+    /// The closure is moved onto the heap rather than onto the stack.
+    /// Requires the `alloc` feature.
+    #[cfg(feature = "alloc")]
+    pub fn from_fn_mut<F>(stack: S, func: F) -> Self
+    where
+        F: FnMut(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'a,
+        S: 'a,
+    {
+        let body: Box<Body<'a, A, Y, R>> = Box::new(func);
+        let body = unsafe { NonNull::new_unchecked(Box::into_raw(body)) };
+
+        match Self::try_new(stack, move |c, a| unsafe { (*body.as_ptr())(c, a) }) {
+            Ok(mut coro) => {
+                coro.body = Some(body);
+                coro
+            }
+            Err(err) => {
+                drop(unsafe { Box::from_raw(body.as_ptr()) });
+                panic!("{}", err)
+            }
+        }
+    }
+
+    /// The size of the smallest stack a coroutine running a closure of type
+    /// `F` can be spawned on: `STACK_MINIMUM`, or however much more it takes
+    /// for everything `prepare()` puts at the top of the stack, aligned any
+    /// which way, to leave half of `STACK_MINIMUM` below to run on.
+    fn required<F>() -> usize {
+        let top = size_of::<Context<A, Y, R>>()
+            + align_of::<Context<A, Y, R>>()
+            + size_of::<FrameRecord>()
+            + size_of::<Option<F>>()
+            + align_of::<Option<F>>()
+            + STACK_ALIGNMENT * 2;
+
+        STACK_MINIMUM.max(top + STACK_MINIMUM / 2)
+    }
+
+    /// Lays out the stack of `len` bytes at `bottom` for a coroutine running
+    /// `func`, and returns its Context and how to start it. See `new()`.
+    /// The stack must be large enough; see `required()`.
+    unsafe fn prepare<F>(bottom: *mut u8, len: usize, func: F) -> (*mut Context<A, Y, R>, Entry)
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'a,
+    {
+        let top = bottom.add(len);
+        let top = top.sub(top as usize % STACK_ALIGNMENT);
+
+        // Place the Context at the very top of the stack. It lives there,
+        // rather than in a frame of the callback function above, so that it
+        // stays valid for as long as we own the stack, no matter how the
+        // backend disposes of the coroutine's frames once it has completed.
+        let ctx = {
+            let ctx = top.sub(size_of::<Context<A, Y, R>>());
+            let ctx = ctx.sub(ctx as usize % align_of::<Context<A, Y, R>>());
+            let ctx = ctx as *mut Context<A, Y, R>;
+            ctx.write(Context {
+                #[cfg(feature = "alloc")]
+                bottom,
+                ..Context::default()
+            });
+            ctx
+        };
+
+        // Below the Context, a frame record for the outermost frame of the
+        // coroutine. See `frame`.
+        let record = frame::record(ctx as *mut u8);
+        record.write(FrameRecord::END);
+
+        // Move the closure into a slot on the stack, right below the frame
+        // record. The first resume calls the callback function on the stack
+        // below it, which takes it from there. Nothing runs on the stack
+        // until then, and if nothing ever does, the slot is dropped instead.
+        let fnc = {
+            let fnc = (record as *mut u8).sub(size_of::<Option<F>>());
+            let fnc = fnc.sub(fnc as usize % align_of::<Option<F>>());
+            let fnc = fnc as *mut Option<F>;
+            fnc.write(Some(func));
+            fnc
+        };
+
+        // The stack pointer to start with. `jump_init()` calls the callback
+        // with it as the ABI wants it at a call: aligned, with nothing live
+        // below. Whatever the call itself needs goes below it, and so does
+        // the red zone of every frame (128 bytes on x86_64 and 64-bit Apple
+        // targets, 288 on powerpc64), which leaf functions use without
+        // moving the stack pointer. So nothing above can be overwritten.
+        let addr = fnc as *mut u8;
+        let addr = addr.sub(addr as usize % STACK_ALIGNMENT);
+
+        let entry = Entry {
+            addr,
+            fnc: fnc as _,
+            func: callback::<A, Y, R, F>,
+            drop: drop_closure::<F>,
+            limit: bottom,
+        };
+
+        (ctx, entry)
+    }
+}
+
+impl<'a, A, Y, R, S: Stack + 'a> Coroutine<'a, A, Y, R, S> {
+    /// Spawns a new coroutine on the stack of this one.
     ///
-    /// * `func` - The closure to be executed as part of the coroutine.
-    pub fn new<F>(stack: &'a mut [u8], func: F) -> Self
+    /// If this coroutine has not completed yet, it is canceled first. This is
+    /// a shorthand for `Coroutine::new(self.into_stack(), func)`.
+    pub fn restart<B, Z, T, F>(self, func: F) -> Coroutine<'a, B, Z, T, S>
     where
-        F: FnOnce(Control<'_, Y, R>) -> Result<Finished<R>, Canceled>,
+        F: FnOnce(Control<'_, B, Z, T>, B) -> Result<Finished<T>, CoroutineError>,
+        F: 'a,
     {
-        // These variables are going to receive output from the callback
-        // function above. Specifically, the callback function is going to
-        // allocate space for a Context and our closure on the new stack. Then,
-        // it is going to store references to those instances inside these
-        // variables.
-        let mut cor = Coroutine(None);
-        let mut fnc = MaybeUninit::<&mut F>::uninit();
+        Coroutine::new(self.into_stack(), func)
+    }
 
-        assert!(stack.len() >= STACK_MINIMUM);
+    /// Starts `func` on the stack of this coroutine, which has completed,
+    /// without giving the stack back first.
+    ///
+    /// Unlike `restart()`, this keeps the types, and everything set up for
+    /// the coroutine besides its closure: its name, hooks, the thread it is
+    /// pinned to and its registrations. Only the statistics start over. This
+    /// suits a long-lived worker running one job after another.
+    ///
+    /// # Panics
+    ///
+    /// If the coroutine has not completed yet, or the stack is too small for
+    /// the closure (see `try_new()`).
+    pub fn restart_in_place<F>(&mut self, func: F)
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'a,
+    {
+        assert!(
+            self.is_finished(),
+            "coroutine{} has not completed yet",
+            Named(self.name())
+        );
 
         unsafe {
-            // Calculate the aligned top of the stack.
-            let top = stack.as_mut_ptr().add(stack.len());
-            let top = top.sub(top.align_offset(STACK_ALIGNMENT));
-
-            // Call into the callback on the specified stack.
-            jump_init(
-                top,
-                &mut cor as *mut _ as _,
-                fnc.as_mut_ptr() as *mut _ as _,
-                callback::<Y, R, F>,
-            );
-            let fnc = fnc.assume_init();
-            // Move the closure onto the coroutine's stack.
-            *fnc = func;
+            // Nothing refers to the stack any more, so borrow it afresh.
+            let stack = &mut *self.stack.as_mut_ptr();
+            let len = stack.len();
+            let required = Self::required::<F>();
+            if len < required {
+                let provided = len;
+                panic!("{}", CoroutineError::StackTooSmall { required, provided });
+            }
+            let bottom = stack.bottom();
+
+            // As in `new()`, this comes first.
+            self.zeroize.reset(bottom, len);
+
+            let (ctx, entry) = Self::prepare(bottom, len, func);
+            self.ctx = NonNull::new(ctx);
+            self.entry = Some(entry);
+            self.set_panic_policy(self.panic_policy);
+
+            // The old shadow stack may have been left without a restore token.
+            self.shadow.free();
+            self.shadow = ShadowStack::new(len);
+            self.canary = Canary::new(bottom);
+
+            // A coroutine which has been canceled has given these up too.
+            self.fiber.destroy();
+            self.fiber = Fiber::new();
+            self.stack_id.deregister();
+            self.stack_id = StackId::register(bottom, bottom.add(len));
         }
 
-        cor
+        self.stats = Stats::new();
+        self.registered
+            .suspended(CoroutineState::Created, Site::UNKNOWN);
+    }
+
+    /// Runs the body of a coroutine spawned by `from_fn_mut()` again, from
+    /// the start, on the same stack. If the coroutine has not completed yet,
+    /// it is canceled first, just as if it were dropped.
+    ///
+    /// The body keeps whatever it has stored in its captures from one run to
+    /// the next. Like `restart_in_place()`, this keeps everything else set up
+    /// for the coroutine too. Requires the `alloc` feature.
+    ///
+    /// # Panics
+    ///
+    /// If the coroutine was not spawned by `from_fn_mut()`, or panics while it
+    /// is being canceled.
+    #[cfg(feature = "alloc")]
+    pub fn reset(&mut self) {
+        let body = match self.body {
+            Some(body) => body,
+            None => panic!("coroutine{} has no body to run again", Named(self.name())),
+        };
+
+        let _ = self.stop();
+        self.restart_in_place(move |c, a| unsafe { (*body.as_ptr())(c, a) });
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<A, Y, R> Coroutine<'static, A, Y, R, AllocatedStack> {
+    /// Spawns a new coroutine on a stack from the stack allocator (see
+    /// `set_stack_allocator()`), which by default allocates it on the heap.
+    ///
+    /// The coroutine owns its stack, so it does not borrow anything and can
+    /// be moved around or stored freely.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the stack. See `AllocatedStack::new_in()`.
+    /// * `func` - The closure to run within the coroutine.
+    ///
+    /// # Panics
+    ///
+    /// If the allocator has no memory for the stack.
+    pub fn with_stack_size<F>(size: usize, func: F) -> Self
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'static,
+    {
+        let stack = AllocatedStack::new(StackOptions::new(size, false))
+            .expect("failed to allocate a stack");
+        Self::new(stack, func)
+    }
+}
+
+impl<'a, A, Y, R> Coroutine<'a, A, Y, R, RawStack> {
+    /// Spawns a new coroutine on memory described by a pointer and a length.
+    ///
+    /// This is a shorthand for `Coroutine::new(RawStack::new(bottom, len),
+    /// func)`, for stacks allocated outside of Rust.
+    ///
+    /// # Safety
+    ///
+    /// See `RawStack::new()`. The memory must stay valid until the coroutine
+    /// has been dropped, or its stack taken back with `into_stack()`.
+    pub unsafe fn new_raw<F>(bottom: *mut u8, len: usize, func: F) -> Self
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'a,
+    {
+        Self::new(RawStack::new(bottom, len), func)
+    }
+}
+
+/// A coroutine's handle on itself, passed to its closure.
+///
+/// Every `r#yield()` consumes the `Control` and hands it back, and the
+/// closure completes by consuming it for good with `done()`, or with
+/// `finish()` once it has yielded for the last time. Either way the compiler
+/// rejects any yield after it. `yielder()` narrows it down to a `Yielder`,
+/// which can't complete the coroutine but through `finish()`.
+///
+/// That the coroutine completes exactly once, after its last yield, is only
+/// enforced for code written against `Yielder` and `Finisher`. A closure
+/// holding the `Control` itself can still complete without it, with
+/// `Finished::new()`, and helpers which take a `Control` may both yield and
+/// complete.
+pub struct Control<'a, A, Y, R>(*mut Context<A, Y, R>, PhantomData<&'a mut Context<A, Y, R>>);
+
+/// The handle of a coroutine which may yield any number of times, made by
+/// `Control::yielder()`. It has no `done()`: the coroutine can only complete
+/// once it has been given up for a `Finisher` with `finish()`.
+///
+/// ```
+/// # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+/// use frenetic::{Coroutine, CoroutineError, Finished, Generator};
+/// use frenetic::{GeneratorState, Yielder, STACK_MINIMUM};
+/// use core::pin::Pin;
+///
+/// type Result = core::result::Result<Finished<i32>, CoroutineError>;
+///
+/// fn count(mut y: Yielder<'_, (), i32, i32>, n: i32) -> Result {
+///     for i in 0..n {
+///         y = y.r#yield(i)?.0;
+///     }
+///     y.finish().done(n)
+/// }
+///
+/// let mut stack = [0u8; STACK_MINIMUM * 8];
+/// let mut coro = Coroutine::new(&mut stack, |c, ()| count(c.yielder(), 2));
+///
+/// assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(0));
+/// assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+/// assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(2));
+/// ```
+///
+/// Completing the coroutine straight from it doesn't compile:
+///
+/// ```compile_fail
+/// # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+/// use frenetic::{Coroutine, STACK_MINIMUM};
+///
+/// let mut stack = [0u8; STACK_MINIMUM * 8];
+/// let _coro = Coroutine::new(&mut stack, |c, ()| {
+///     let (y, ()) = c.yielder().r#yield(1)?;
+///     y.done(())
+/// });
+/// ```
+pub struct Yielder<'a, A, Y, R>(Control<'a, A, Y, R>);
+
+impl<'a, A, Y, R> Yielder<'a, A, Y, R> {
+    /// Returns the name of this coroutine, if it was given one. See
+    /// `Control::name()`.
+    pub fn name(&self) -> Option<&str> {
+        self.0.name()
+    }
+
+    /// Pauses execution of this coroutine, and passes `arg` to the parent.
+    /// See `Control::r#yield()`.
+    #[cfg_attr(feature = "registry", track_caller)]
+    pub fn r#yield(self, arg: Y) -> Result<(Self, A), CoroutineError> {
+        let (c, a) = self.0.r#yield(arg)?;
+        Ok((Yielder(c), a))
+    }
+
+    /// Gives up yielding, for a `Finisher` which can only complete this
+    /// coroutine. See `Finisher`.
+    pub fn finish(self) -> Finisher<'a, A, Y, R> {
+        self.0.finish()
+    }
+}
+
+/// The handle of a coroutine which has yielded for the last time, made by
+/// `Control::finish()` or `Yielder::finish()`. All it can do is complete the
+/// coroutine, once, with `done()`.
+///
+/// Hand it to a helper which finishes the coroutine on its behalf, but must
+/// not yield:
+///
+/// ```
+/// # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+/// use frenetic::{Coroutine, CoroutineError, Finished, Finisher, Generator};
+/// use frenetic::{GeneratorState, STACK_MINIMUM};
+/// use core::pin::Pin;
+///
+/// type Result = core::result::Result<Finished<i32>, CoroutineError>;
+///
+/// fn sum(f: Finisher<'_, (), i32, i32>, a: i32, b: i32) -> Result {
+///     f.done(a + b)
+/// }
+///
+/// let mut stack = [0u8; STACK_MINIMUM * 8];
+/// let mut coro = Coroutine::new(&mut stack, |c, ()| {
+///     let (c, ()) = c.r#yield(1)?;
+///     sum(c.finish(), 1, 2)
+/// });
+///
+/// assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+/// assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(3));
+/// ```
+///
+/// Yielding once the coroutine has been finished doesn't compile:
+///
+/// ```compile_fail
+/// # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+/// use frenetic::{Coroutine, STACK_MINIMUM};
+///
+/// let mut stack = [0u8; STACK_MINIMUM * 8];
+/// let _coro = Coroutine::new(&mut stack, |c, ()| {
+///     let f = c.finish();
+///     let (_, ()) = c.r#yield(1)?;
+///     f.done(())
+/// });
+/// ```
+#[must_use = "a coroutine completes with what `Finisher::done()` returns"]
+pub struct Finisher<'a, A, Y, R>(*mut Context<A, Y, R>, PhantomData<&'a mut Context<A, Y, R>>);
+
+impl<'a, A, Y, R> Finisher<'a, A, Y, R> {
+    /// Returns the name of this coroutine, if it was given one. See
+    /// `Control::name()`.
+    pub fn name(&self) -> Option<&str> {
+        unsafe { (*self.0).name.map(|name| &*name.as_ptr()) }
+    }
+
+    /// Finishes execution of this coroutine, with `arg` as its return value.
+    pub fn done<E>(self, arg: R) -> Result<Finished<R>, E> {
+        Ok(Finished(arg))
     }
 }
 
-pub struct Control<'a, Y, R>(&'a mut Context<Y, R>);
+impl<'a, A, Y, R> Control<'a, A, Y, R> {
+    /// Returns the name of this coroutine, if it was given one. See
+    /// `Coroutine::name()`.
+    pub fn name(&self) -> Option<&str> {
+        unsafe { (*self.0).name.map(|name| &*name.as_ptr()) }
+    }
 
-impl<'a, Y, R> Control<'a, Y, R> {
     /// Pauses execution of this coroutine, saves function position, and passes
     /// control back to parent.
-    /// Returns a `Canceled` error if the parent has been dropped.
+    /// Returns the argument the parent resumes us with.
+    ///
+    /// If the parent is dropped instead, the coroutine's stack is unwound
+    /// from here, dropping its live locals, as if by a panic which the
+    /// coroutine doesn't report to anyone. Where unwinding isn't possible,
+    /// `CoroutineError::Canceled` is returned instead. If the parent resumes
+    /// us with `Coroutine::resume_with_error()`, its error is returned as
+    /// `CoroutineError::Thrown`.
     ///
     /// # Arguments
     ///
     /// * `arg` - Passed on to the argument variable for the generator, if it
-    /// exists.
-    pub fn r#yield(self, arg: Y) -> Result<Self, Canceled> {
-        unsafe {
-            let ptr_arg = self.0.arg.assume_init();
+    ///   exists.
+    #[cfg_attr(feature = "registry", track_caller)]
+    pub fn r#yield(self, arg: Y) -> Result<(Self, A), CoroutineError> {
+        self.suspend(ResumeState::Yielded(arg))
+    }
+
+    /// Passes `state` to the parent and suspends. See `r#yield()`.
+    #[cfg_attr(feature = "registry", track_caller)]
+    fn suspend(self, state: ResumeState<Y, R>) -> Result<(Self, A), CoroutineError> {
+        let site = registry::site();
+        let input = unsafe {
+            let ptr_arg = (*self.0).arg;
 
             // The parent `Coroutine` object has been dropped. Resume the child
             // coroutine with the Canceled error. It must clean up and exit.
             if ptr_arg.is_null() {
-                return Err(Canceled(()));
+                return Err(CoroutineError::Canceled);
             }
 
-            // Move the argument value into the argument variable in
-            // `Generator::resume()`.
-            *ptr_arg = GeneratorState::Yielded(arg);
+            // Move the state into the argument variable in
+            // `Coroutine::resume_with()`. It is empty, so there is nothing
+            // to drop; see `callback()` too.
+            ptr_arg.write(Some(Ok(state)));
+            (&mut (*self.0).site as *mut Site).write_volatile(site);
 
             // Save our current position and yield control to the parent.
+            (&(*self.0).parent_fiber as *const Fiber)
+                .read_volatile()
+                .switch();
+            count_switch();
             jump_swap(
-                self.0.child.as_mut_ptr() as _,
-                self.0.parent.as_mut_ptr() as _,
+                (*self.0).child.as_mut_ptr() as _,
+                (*self.0).parent.as_mut_ptr() as _,
             );
 
+            frame::link(self.0 as *mut u8, (*self.0).parent.as_ptr() as _);
+
             // Let the compiler re-read *self.0.arg
-            let ptr_arg = self.0.arg.as_mut_ptr().read_volatile();
+            let ptr_arg =
+                (&(*self.0).arg as *const *mut Outcome<ResumeState<Y, R>>).read_volatile();
 
-            // The parent `Coroutine` object has been dropped. Resume the child
-            // coroutine with the Canceled error. It must clean up and exit.
+            // The parent `Coroutine` object has been dropped. Unwind the child
+            // coroutine, or failing that, return the Canceled error.
             if ptr_arg.is_null() {
-                return Err(Canceled(()));
+                return Err(CoroutineError::unwind());
             }
-        }
 
-        Ok(self)
-    }
+            // Move the resume argument out of the input variable in
+            // `Coroutine::resume_with()`.
+            take_input(self.0)
+        };
 
-    /// Finishes execution of this coroutine.
-    pub fn done<E>(self, arg: R) -> Result<Finished<R>, E> {
-        Ok(Finished(arg))
+        input.map(|input| (self, input))
     }
-}
 
-impl<'a, Y, R> Generator for Coroutine<'a, Y, R> {
-    type Yield = Y;
-    type Return = R;
+    /// Pauses execution of this coroutine and switches directly to `other`,
+    /// without passing through the parent.
+    ///
+    /// `other` takes our place: when it yields, the value is passed to our
+    /// parent as if we had yielded it, and the parent's next resume continues
+    /// this coroutine. If `other` completes instead, control comes straight
+    /// back here.
+    ///
+    /// Returns `GeneratorState::Yielded` with the argument the parent resumes
+    /// us with, or `GeneratorState::Complete` with the return value of
+    /// `other`. If the parent has been dropped, this unwinds just like
+    /// `r#yield()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The coroutine to switch to. It must yield and return the
+    ///   same types as this coroutine.
+    /// * `arg` - Passed to `other` as its resume argument.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `other` has already completed, or is pinned to another
+    /// thread. If `other` panics, the panic is propagated to this coroutine.
+    pub fn transfer<B, S>(
+        self,
+        other: &mut Coroutine<'_, B, Y, R, S>,
+        arg: B,
+    ) -> Result<(Self, GeneratorState<A, R>), CoroutineError> {
+        let ptr_arg = unsafe { (*self.0).arg };
+
+        // The parent `Coroutine` object has been dropped. Resume the child
+        // coroutine with the Canceled error. It must clean up and exit.
+        if ptr_arg.is_null() {
+            return Err(CoroutineError::Canceled);
+        }
 
-    /// Resumes a paused coroutine.
-    /// Re-initialize stack and continue execution where it was left off.
-    fn resume(mut self: Pin<&mut Self>) -> GeneratorState<Y, R> {
-        // Allocate an argument variable on the stack. See `Control::r#yield()` and
-        // `callback()` for where this is initialized.
-        let mut arg = MaybeUninit::<GeneratorState<Y, R>>::uninit();
+        // Allocate an input variable for the other coroutine and a variable
+        // to receive its return value, should it complete.
+        let mut input = Some(arg);
+        let mut out = None;
 
-        match self.0 {
-            None => panic!("Called Generator::resume() after completion!"),
-            Some(ref mut p) => unsafe {
-                // Pass the pointer so that the child can move the argument out.
-                p.arg.as_mut_ptr().write_volatile(arg.as_mut_ptr());
+        if other.ctx.is_some() && !other.on_its_thread() {
+            panic!(
+                "Called Control::transfer() on the wrong thread!{}",
+                Named(other.name())
+            );
+        }
 
-                // Jump back into the child.
-                jump_swap(p.parent.as_mut_ptr() as _, p.child.as_mut_ptr() as _);
+        let done = match other.ctx {
+            None => panic!(
+                "Called Control::transfer() after completion!{}",
+                Named(other.name())
+            ),
+            Some(o) => unsafe {
+                let o = o.as_ptr();
+                let child = &mut (*self.0).child as *mut JumpBuffer;
+
+                // Lend our parent to the other coroutine. It yields directly
+                // into the argument variable in `Coroutine::resume_with()`.
+                (*o).parent = (*self.0).parent;
+                (&mut (*o).arg as *mut *mut Outcome<ResumeState<Y, R>>).write_volatile(ptr_arg);
+                (&mut (*o).input as *mut *mut Option<B>).write_volatile(&mut input);
+                (*o).back = child;
+                (*o).back_out = &mut out;
+                (*o).parent_fiber = (&(*self.0).parent_fiber as *const Fiber).read_volatile();
+
+                // Save our current position and switch to the other coroutine.
+                let fiber = Fiber::current();
+                other.fiber.switch();
+                let overflowed = other.enter(o, child);
+
+                // We have been resumed either by the other coroutine when it
+                // completed, or by our parent.
+                frame::link(self.0 as *mut u8, (*self.0).parent.as_ptr() as _);
+                (*o).back = null_mut();
+                (*o).back_out = null_mut();
+                (&mut (*o).arg as *mut *mut Outcome<ResumeState<Y, R>>).write_volatile(null_mut());
+                (&mut (*o).input as *mut *mut Option<B>).write_volatile(null_mut());
+
+                // The other coroutine, or one it transferred to, has
+                // overflowed its stack. Panic, unless we are being canceled
+                // and must unwind anyway.
+                if overflowed {
+                    if (&(*self.0).arg as *const *mut Outcome<ResumeState<Y, R>>)
+                        .read_volatile()
+                        .is_null()
+                    {
+                        return Err(CoroutineError::unwind());
+                    }
+
+                    panic!("coroutine stack overflow{}", Named(other.name()));
+                }
 
-                // Clear the pointer as the value is about to become invalid.
-                p.arg.as_mut_ptr().write_volatile(null_mut());
+                if (&(*o).done as *const bool).read_volatile() {
+                    fiber.switch();
+                    Some((*o).panic.take())
+                } else {
+                    None
+                }
             },
-        }
+        };
 
-        let state = unsafe { arg.assume_init() };
+        // The other coroutine has completed. Make it so that it can never be
+        // resumed again and hand back its return value or panic.
+        if let Some(panic) = done {
+            other.ctx = None;
 
-        // If the child coroutine has completed, we are done. Make it so that
-        // we can never resume the coroutine by clearing the reference.
-        if let GeneratorState::Complete(_) = state {
-            self.0 = None;
+            if let Some(payload) = panic {
+                unwind::resume(payload);
+            }
+
+            return match out {
+                Some(Ok(r)) => Ok((self, GeneratorState::Complete(r))),
+                Some(Err(err)) => Err(err),
+                None => Err(CoroutineError::Canceled),
+            };
         }
 
-        state
-    }
-}
+        let input = unsafe {
+            // Let the compiler re-read *self.0.arg
+            let ptr_arg =
+                (&(*self.0).arg as *const *mut Outcome<ResumeState<Y, R>>).read_volatile();
 
-impl<'a, Y, R> Drop for Coroutine<'a, Y, R> {
-    fn drop(&mut self) {
-        // If we are still able to resume the coroutine, do so.
-        if let Some(x) = self.0.take() {
-            unsafe {
-                // set the argument pointer to null, `Control::r#yield()` will return `Canceled`.
-                x.arg.as_mut_ptr().write_volatile(null_mut());
-                jump_swap(x.parent.as_mut_ptr() as _, x.child.as_mut_ptr() as _);
+            // The parent `Coroutine` object has been dropped. Unwind the child
+            // coroutine, or failing that, return the Canceled error.
+            if ptr_arg.is_null() {
+                return Err(CoroutineError::unwind());
             }
-        }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            // Move the resume argument out of the input variable in
+            // `Coroutine::resume_with()`.
+            take_input(self.0)
+        };
 
-    #[test]
-    fn stack() {
-        let mut stack = [1u8; STACK_MINIMUM];
+        input.map(|input| (self, GeneratorState::Yielded(input)))
+    }
 
-        let mut coro = Coroutine::new(&mut stack, |c| {
-            let c = c.r#yield(1)?;
-            c.done("foo")
-        });
+    /// Finishes execution of this coroutine.
+    pub fn done<E>(self, arg: R) -> Result<Finished<R>, E> {
+        self.finish().done(arg)
+    }
 
-        match Pin::new(&mut coro).resume() {
-            GeneratorState::Yielded(1) => {}
-            _ => panic!("unexpected return from resume"),
-        }
+    /// Gives up yielding, for a `Finisher` which can only complete this
+    /// coroutine. See `Finisher`.
+    pub fn finish(self) -> Finisher<'a, A, Y, R> {
+        Finisher(self.0, PhantomData)
+    }
 
-        match Pin::new(&mut coro).resume() {
-            GeneratorState::Complete("foo") => {}
-            _ => panic!("unexpected return from resume"),
+    /// Gives up completing this coroutine but through `finish()`, for a
+    /// `Yielder`. See `Yielder`.
+    pub fn yielder(self) -> Yielder<'a, A, Y, R> {
+        Yielder(self)
+    }
+
+    /// Returns the waker of the task driving this coroutine, if it is being
+    /// resumed by `Coroutine::poll_resume()` (as `into_future()` and the
+    /// `scheduler::Executor` do).
+    ///
+    /// A coroutine which waits for an event should arrange for this waker to
+    /// be woken once the event occurs. See `Control::yield_pending()`.
+    pub fn waker(&self) -> Option<&Waker> {
+        unsafe {
+            (&(*self.0).waker as *const *const Waker)
+                .read_volatile()
+                .as_ref()
         }
     }
+}
 
-    #[test]
+impl<'a, Y, R> Control<'a, (), Y, R> {
+    /// Runs `inner` to completion within this coroutine, delegating to it.
+    ///
+    /// Every value `inner` yields is yielded on to our parent, and every
+    /// resume from our parent resumes `inner`. Returns the return value of
+    /// `inner`. If our parent is dropped, `inner` is canceled along with us.
+    ///
+    /// If `inner` waits for an event, so do we, and `inner` shares the waker
+    /// we are resumed with. See `Control::yield_pending()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `inner` has already completed. If `inner` panics, the panic
+    /// is propagated to this coroutine.
+    pub fn yield_from<T, S>(
+        mut self,
+        mut inner: Coroutine<'_, (), Y, T, S>,
+    ) -> Result<(Self, T), CoroutineError> {
+        loop {
+            // Lend our waker and budget, if any, to `inner` for the duration
+            // of the resume.
+            unsafe {
+                if let Some(ctx) = inner.ctx {
+                    let waker = (&(*self.0).waker as *const *const Waker).read_volatile();
+                    (&mut (*ctx.as_ptr()).waker as *mut *const Waker).write_volatile(waker);
+                    let budget = (&(*self.0).budget as *const *const Budget).read_volatile();
+                    (&mut (*ctx.as_ptr()).budget as *mut *const Budget).write_volatile(budget);
+                }
+            }
+
+            let state = match Pin::new(&mut inner).resume_state(()) {
+                Ok(state) => state,
+                Err(err) => inner.fail(err),
+            };
+
+            self = match state {
+                ResumeState::Yielded(y) => self.r#yield(y)?.0,
+                ResumeState::Pending => self.suspend(ResumeState::Pending)?.0,
+                ResumeState::Complete(t) => return Ok((self, t)),
+            };
+        }
+    }
+
+    /// Yields every item of `iter` in turn, and returns once it runs out.
+    ///
+    /// Each item is yielded as by `r#yield()`, so if our parent is dropped
+    /// in between, the rest of the items are never taken from `iter`.
+    pub fn yield_all<I>(mut self, iter: I) -> Result<Self, CoroutineError>
+    where
+        I: IntoIterator<Item = Y>,
+    {
+        for y in iter {
+            self = self.r#yield(y)?.0;
+        }
+
+        Ok(self)
+    }
+}
+
+impl Coroutine<'static, (), (), ()> {
+    /// Returns a builder, to configure a coroutine before spawning it.
+    pub fn builder() -> CoroutineBuilder {
+        CoroutineBuilder::new()
+    }
+}
+
+impl<'a, A, Y, R, S> Coroutine<'a, A, Y, R, S> {
+    /// Returns the name given to the coroutine with
+    /// `CoroutineBuilder::name()`, if any.
+    ///
+    /// Panics raised by Frenetic on behalf of a named coroutine, such as a
+    /// stack overflow, include its name. The coroutine itself can read it
+    /// with `Control::name()`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+
+    /// Names the coroutine. Its Context points into the `String` kept here,
+    /// whose buffer stays put when the `Coroutine` is moved.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_name(&mut self, name: Option<Name>) {
+        if let Some(ctx) = self.ctx {
+            unsafe { (*ctx.as_ptr()).name = name.as_ref().map(|n| NonNull::from(&n[..])) };
+        }
+
+        self.trace.set_name(name.as_deref());
+        self.registered.set_name(name.as_deref());
+        self.name = name;
+    }
+
+    /// Drops the body of a coroutine spawned by `from_fn_mut()`. It must not
+    /// be running, nor be run again.
+    #[cfg(feature = "alloc")]
+    unsafe fn drop_body(&mut self) {
+        if let Some(body) = self.body.take() {
+            drop(Box::from_raw(body.as_ptr()));
+        }
+    }
+
+    /// Sets what happens to a panic in the coroutine. See
+    /// `CoroutineBuilder::panic_policy()`.
+    pub(crate) fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        if let Some(ctx) = self.ctx {
+            unsafe { (*ctx.as_ptr()).abort = policy == PanicPolicy::Abort };
+        }
+
+        self.panic_policy = policy;
+    }
+
+    /// Sets the lifecycle hooks. See `CoroutineBuilder::on_resume()`.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_hooks(&mut self, hooks: Hooks) {
+        self.hooks = Lifecycle::new(hooks);
+    }
+
+    /// Returns how many times the coroutine has been resumed, and how long it
+    /// has run. Requires the `counters` feature.
+    #[cfg(feature = "counters")]
+    pub fn stats(&self) -> CoroutineStats {
+        self.stats.get()
+    }
+
+    /// Returns the state of the coroutine, without resuming it.
+    pub fn state(&self) -> CoroutineState {
+        match self.ctx {
+            None => CoroutineState::Finished,
+            Some(_) if self.entry.is_some() => CoroutineState::Created,
+            Some(_) => CoroutineState::Suspended,
+        }
+    }
+
+    /// Returns `true` if the coroutine has completed and can't be resumed.
+    pub fn is_finished(&self) -> bool {
+        self.ctx.is_none()
+    }
+
+    /// Returns `false` if the coroutine is pinned to a thread other than the
+    /// current one. See `SendCoroutine::pin_to_current_thread()`.
+    #[cfg(feature = "std")]
+    fn on_its_thread(&self) -> bool {
+        match self.thread {
+            Some(id) => id == std::thread::current().id(),
+            None => true,
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn on_its_thread(&self) -> bool {
+        true
+    }
+
+    /// Suspends the current context into `from` and switches to the
+    /// coroutine, starting it if it hasn't run yet.
+    ///
+    /// A coroutine is started with `jump_init()`, which suspends the current
+    /// context into a buffer of its own instead. `callback()` moves it into
+    /// `from`, which must be the coroutine's parent, or its `back` buffer.
+    ///
+    /// Returns `true` if the coroutine, or one it transferred to, has
+    /// overflowed its stack. See `guard`.
+    unsafe fn enter(&mut self, ctx: *mut Context<A, Y, R>, from: *mut JumpBuffer) -> bool {
+        count_switch();
+
+        {
+            // `parent` is the first field of the Context. Don't borrow it,
+            // which would invalidate `from` if that points to it.
+            let parent = ctx as *mut JumpBuffer;
+            let _running = self.guard.enter(parent, &mut (*ctx).overflowed);
+
+            match self.entry.take() {
+                Some(e) => jump_init(e.addr, ctx as _, e.fnc, e.func, e.limit, self.shadow.top()),
+                None => jump_swap(from, (*ctx).child.as_mut_ptr() as _),
+            }
+        }
+
+        let overflowed = guard::overflowed();
+
+        // The coroutine has run off the end of its stack, and may have
+        // corrupted whatever lies below, or has faulted on the guard page
+        // below it. Never run it again, not even to unwind it.
+        if (&(*ctx).overflowed as *const bool).read_volatile() || !self.canary.is_intact() {
+            self.ctx = None;
+            return true;
+        }
+
+        // Otherwise a coroutine it transferred to has faulted on its guard
+        // page, and the handler jumped back here. This one can still be
+        // canceled.
+        overflowed
+    }
+
+    /// Resumes a paused coroutine, passing it an argument.
+    /// Re-initialize stack and continue execution where it was left off.
+    ///
+    /// On the first resume, `arg` is passed to the coroutine's closure.
+    /// Afterwards, it is returned from the `Control::r#yield()` call the
+    /// coroutine is paused in.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the coroutine has already completed, is pinned to another
+    /// thread, or waits for an event (see `Control::yield_pending()`). If the
+    /// coroutine itself panics, the panic is propagated to the caller and the
+    /// coroutine is considered completed.
+    pub fn resume_with(mut self: Pin<&mut Self>, arg: A) -> GeneratorState<Y, R> {
+        match self.as_mut().try_resume_with(arg) {
+            Ok(state) => state,
+            Err(err) => self.fail(err),
+        }
+    }
+
+    /// Panics on behalf of `resume_with()`, because of `err`.
+    fn fail(&self, err: CoroutineError) -> ! {
+        match err {
+            #[cfg(feature = "std")]
+            CoroutineError::Panicked(payload) => unwind::resume(payload),
+            CoroutineError::WrongThread => {
+                panic!(
+                    "Called Generator::resume() on the wrong thread!{}",
+                    Named(self.name())
+                )
+            }
+            CoroutineError::AlreadyFinished => {
+                panic!(
+                    "Called Generator::resume() after completion!{}",
+                    Named(self.name())
+                )
+            }
+            err => panic!("coroutine failed: {}{}", err, Named(self.name())),
+        }
+    }
+
+    /// Resumes a paused coroutine, passing it an argument, unless it has
+    /// already completed or is pinned to another thread.
+    ///
+    /// This is like `resume_with()`, except that it returns an error instead
+    /// of panicking if the coroutine can't be resumed. If the coroutine
+    /// panics, the panic is returned as `CoroutineError::Panicked` rather
+    /// than propagated. If its closure returns an error instead of a value,
+    /// such as one passed on from `Control::transfer()`, it has completed
+    /// and the error is returned. A stack overflow, or a coroutine waiting
+    /// for an event, is still a panic.
+    pub fn try_resume_with(
+        mut self: Pin<&mut Self>,
+        arg: A,
+    ) -> Result<GeneratorState<Y, R>, CoroutineError> {
+        let state = self.as_mut().resume_state(arg)?;
+        Ok(self.generator_state(state))
+    }
+
+    /// Resumes a paused coroutine with an error instead of an argument, like
+    /// Python's `generator.throw()`.
+    ///
+    /// The `Control::r#yield()` call the coroutine is paused in returns
+    /// `CoroutineError::Thrown` with `err`, for the coroutine to handle where
+    /// it is: to recover, or to clean up and pass the error on with `?`. A
+    /// coroutine which hasn't started yet completes with the error right
+    /// away, without running its closure.
+    ///
+    /// # Panics
+    ///
+    /// Like `resume_with()`. If the coroutine passes the error on, or
+    /// completes with any other error, that is a panic too; see
+    /// `try_resume_with_error()`.
+    #[cfg(feature = "alloc")]
+    pub fn resume_with_error<E: Any + Send>(
+        mut self: Pin<&mut Self>,
+        err: E,
+    ) -> GeneratorState<Y, R> {
+        match self.as_mut().try_resume_with_error(err) {
+            Ok(state) => state,
+            Err(err) => self.fail(err),
+        }
+    }
+
+    /// Resumes a paused coroutine with an error instead of an argument, unless
+    /// it has already completed or is pinned to another thread.
+    ///
+    /// This is like `resume_with_error()`, except that errors are returned as
+    /// they are by `try_resume_with()`. Should the coroutine pass `err` on,
+    /// it comes back as `CoroutineError::Thrown`, for the caller to downcast.
+    #[cfg(feature = "alloc")]
+    pub fn try_resume_with_error<E: Any + Send>(
+        mut self: Pin<&mut Self>,
+        err: E,
+    ) -> Result<GeneratorState<Y, R>, CoroutineError> {
+        let ctx = self.ctx.ok_or(CoroutineError::AlreadyFinished)?;
+        if !self.on_its_thread() {
+            return Err(CoroutineError::WrongThread);
+        }
+
+        // Leave the error where `Control::r#yield()` looks for it before it
+        // takes the resume argument, and resume without one.
+        unsafe { (*ctx.as_ptr()).thrown = Some(Box::new(err)) };
+        let state = self.as_mut().resume_input(None)?;
+        Ok(self.generator_state(state))
+    }
+
+    /// Converts the state of a resumed coroutine for `try_resume_with()`.
+    fn generator_state(&self, state: ResumeState<Y, R>) -> GeneratorState<Y, R> {
+        match state {
+            ResumeState::Yielded(y) => GeneratorState::Yielded(y),
+            ResumeState::Complete(r) => GeneratorState::Complete(r),
+            ResumeState::Pending => panic!(
+                "Called Generator::resume() on a coroutine waiting for an event!{}",
+                Named(self.name())
+            ),
+        }
+    }
+
+    /// Resumes the coroutine, like `try_resume_with()`, and reports whether
+    /// it yielded a value or waits for an event.
+    pub(crate) fn resume_state(
+        self: Pin<&mut Self>,
+        arg: A,
+    ) -> Result<ResumeState<Y, R>, CoroutineError> {
+        self.resume_input(Some(arg))
+    }
+
+    /// Resumes the coroutine with `input`, which is only `None` if an error
+    /// has been thrown in instead. See `resume_state()`.
+    fn resume_input(
+        mut self: Pin<&mut Self>,
+        mut input: Option<A>,
+    ) -> Result<ResumeState<Y, R>, CoroutineError> {
+        // Allocate an argument variable on the stack. See `Control::r#yield()` and
+        // `callback()` for where this is filled in.
+        let mut arg_out = None;
+
+        // `input` is the input variable. See `Control::r#yield()` and
+        // `callback()` for where it is consumed.
+
+        if self.ctx.is_some() && !self.on_its_thread() {
+            return Err(CoroutineError::WrongThread);
+        }
+
+        let fiber = self.fiber;
+        let site;
+
+        let panic = match self.ctx {
+            None => return Err(CoroutineError::AlreadyFinished),
+            Some(p) => unsafe {
+                let p = p.as_ptr();
+
+                // Pass the pointers so that the child can move the argument
+                // out and the input in.
+                (&mut (*p).arg as *mut *mut Outcome<ResumeState<Y, R>>)
+                    .write_volatile(&mut arg_out);
+                (&mut (*p).input as *mut *mut Option<A>).write_volatile(&mut input);
+
+                // Jump back into the child.
+                self.hooks.resume();
+                self.registered.resumed();
+                let started = self.stats.start();
+                let overflowed = {
+                    let _entered = self.trace.resume();
+                    (*p).parent_fiber = Fiber::current();
+                    fiber.switch();
+                    self.enter(p, (*p).parent.as_mut_ptr() as _)
+                };
+                self.stats.stop(started);
+                if overflowed {
+                    panic!("coroutine stack overflow{}", Named(self.name()));
+                }
+
+                // If the child has completed, it has left ThreadSanitizer on
+                // its own fiber.
+                if (&(*p).done as *const bool).read_volatile() {
+                    (*p).parent_fiber.switch();
+                }
+
+                // Clear the pointers as the values are about to become invalid.
+                (&mut (*p).arg as *mut *mut Outcome<ResumeState<Y, R>>).write_volatile(null_mut());
+                (&mut (*p).input as *mut *mut Option<A>).write_volatile(null_mut());
+
+                site = (&(*p).site as *const Site).read_volatile();
+                (*p).panic.take()
+            },
+        };
+
+        // The child coroutine panicked. It has unwound its stack and can never
+        // be resumed, so clear the reference and hand over the payload.
+        if let Some(payload) = panic {
+            self.ctx = None;
+            self.trace.completed(true);
+            self.registered.suspended(CoroutineState::Finished, site);
+            self.hooks.complete();
+            return Err(CoroutineError::panicked(payload));
+        }
+
+        // The child's closure returned an error rather than a value. It has
+        // completed all the same.
+        let state = match arg_out {
+            Some(Ok(state)) => state,
+            Some(Err(err)) => {
+                self.ctx = None;
+                self.trace.completed(false);
+                self.registered.suspended(CoroutineState::Finished, site);
+                self.hooks.complete();
+                return Err(err);
+            }
+            None => unreachable!("coroutine switched back without a result"),
+        };
+
+        // If the child coroutine has completed, we are done. Make it so that
+        // we can never resume the coroutine by clearing the reference.
+        match state {
+            ResumeState::Yielded(_) => {
+                self.trace.yielded(false);
+                self.hooks.suspend();
+            }
+            ResumeState::Pending => {
+                self.trace.yielded(true);
+                self.hooks.suspend();
+            }
+            ResumeState::Complete(_) => {
+                self.ctx = None;
+                self.trace.completed(false);
+                self.hooks.complete();
+            }
+        }
+
+        self.registered.suspended(self.state(), site);
+
+        Ok(state)
+    }
+}
+
+impl<'a, Y, R, S> Coroutine<'a, (), Y, R, S> {
+    /// Resumes a paused coroutine, unless it has already completed.
+    ///
+    /// This is like `Generator::resume()`, except that it returns an error
+    /// instead of panicking if the coroutine can't be resumed.
+    pub fn try_resume(self: Pin<&mut Self>) -> Result<GeneratorState<Y, R>, CoroutineError> {
+        self.try_resume_with(())
+    }
+
+    /// Spawns a new coroutine, like `new()`, which runs the generator `generator`,
+    /// yielding what it yields and returning what it returns.
+    ///
+    /// This turns any `Generator` into a coroutine: an adapter, or on nightly
+    /// toolchains where `Generator` is `core::ops::Generator`, a generator
+    /// literal. The generator is moved onto the coroutine's stack and pinned
+    /// there. See `from_nightly()` for the nightly traits which take a resume
+    /// argument.
+    pub fn from_generator<G>(stack: S, generator: G) -> Self
+    where
+        G: Generator<Yield = Y, Return = R> + 'a,
+        S: Stack + 'a,
+    {
+        Self::new(stack, move |mut c, ()| {
+            let mut generator = generator;
+
+            // It stays right here until it is dropped, when the closure
+            // returns or unwinds.
+            let mut generator = unsafe { Pin::new_unchecked(&mut generator) };
+            loop {
+                match generator.as_mut().resume() {
+                    GeneratorState::Yielded(y) => c = c.r#yield(y)?.0,
+                    GeneratorState::Complete(r) => return c.done(r),
+                }
+            }
+        })
+    }
+}
+
+impl<'a, Y, R, S> Generator for Coroutine<'a, (), Y, R, S> {
+    type Yield = Y;
+    type Return = R;
+
+    /// Resumes a paused coroutine.
+    /// Re-initialize stack and continue execution where it was left off.
+    fn resume(self: Pin<&mut Self>) -> GeneratorState<Y, R> {
+        self.resume_with(())
+    }
+}
+
+impl<'a, Y, S> Coroutine<'a, (), Y, (), S> {
+    /// Returns an iterator over the values yielded by this coroutine.
+    ///
+    /// The iterator resumes the coroutine on each call to `next()` and ends
+    /// once the coroutine completes.
+    pub fn iter(&mut self) -> Iter<'_, 'a, Y, S> {
+        Iter(self)
+    }
+}
+
+/// An iterator over the values yielded by a borrowed coroutine.
+///
+/// See `Coroutine::iter()`.
+pub struct Iter<'b, 'a, Y, S = &'a mut [u8]>(&'b mut Coroutine<'a, (), Y, (), S>);
+
+impl<'b, 'a, Y, S> Iterator for Iter<'b, 'a, Y, S> {
+    type Item = Y;
+
+    fn next(&mut self) -> Option<Y> {
+        // Don't resume a completed coroutine; that would panic.
+        match self.0.ctx {
+            None => None,
+            Some(_) => match Pin::new(&mut *self.0).resume() {
+                GeneratorState::Yielded(y) => Some(y),
+                GeneratorState::Complete(()) => None,
+            },
+        }
+    }
+}
+
+impl<'b, 'a, Y, S> FusedIterator for Iter<'b, 'a, Y, S> {}
+
+/// An iterator over the values yielded by an owned coroutine.
+///
+/// See the `IntoIterator` implementation of `Coroutine`.
+pub struct IntoIter<'a, Y, S = &'a mut [u8]>(Coroutine<'a, (), Y, (), S>);
+
+impl<'a, Y, S> Iterator for IntoIter<'a, Y, S> {
+    type Item = Y;
+
+    fn next(&mut self) -> Option<Y> {
+        self.0.iter().next()
+    }
+}
+
+impl<'a, Y, S> FusedIterator for IntoIter<'a, Y, S> {}
+
+impl<'a, Y, S> IntoIterator for Coroutine<'a, (), Y, (), S> {
+    type Item = Y;
+    type IntoIter = IntoIter<'a, Y, S>;
+
+    fn into_iter(self) -> IntoIter<'a, Y, S> {
+        IntoIter(self)
+    }
+}
+
+impl<'b, 'a, Y, S> IntoIterator for &'b mut Coroutine<'a, (), Y, (), S> {
+    type Item = Y;
+    type IntoIter = Iter<'b, 'a, Y, S>;
+
+    fn into_iter(self) -> Iter<'b, 'a, Y, S> {
+        self.iter()
+    }
+}
+
+impl<'a, A, Y, R, S> Coroutine<'a, A, Y, R, S> {
+    /// Cancels the coroutine and waits for it to exit.
+    ///
+    /// The coroutine is resumed one last time, and its pending yield unwinds
+    /// its stack (or returns `CoroutineError::Canceled`, see
+    /// `Control::r#yield()`). Returns how the coroutine responded. Dropping a
+    /// coroutine cancels it the same way, but discards the outcome.
+    ///
+    /// # Panics
+    ///
+    /// If the coroutine panics while it is being canceled, the panic is
+    /// propagated to the caller.
+    pub fn cancel(mut self) -> CancelOutcome {
+        self.stop()
+    }
+
+    /// Cancels the coroutine if it has not completed yet.
+    fn stop(&mut self) -> CancelOutcome {
+        // If we are still able to resume the coroutine, do so.
+        let x = match self.ctx.take() {
+            Some(x) => x.as_ptr(),
+            None => {
+                unsafe {
+                    self.fiber.destroy();
+                    self.stack_id.deregister();
+                    self.shadow.free();
+                    self.zeroize.wipe();
+                }
+                return CancelOutcome::Completed;
+            }
+        };
+
+        unsafe {
+            match self.entry.take() {
+                // The coroutine hasn't started: there is nothing on its stack
+                // but the closure. Drop it right here.
+                Some(e) => {
+                    let result = unwind::catch(|| (e.drop)(e.fnc));
+                    (*x).panic = result.err();
+                }
+
+                None => {
+                    // set the argument pointer to null, `Control::r#yield()`
+                    // will unwind the child's stack (or return `Canceled`).
+                    (&mut (*x).arg as *mut *mut Outcome<ResumeState<Y, R>>)
+                        .write_volatile(null_mut());
+                    self.hooks.resume();
+                    self.registered.resumed();
+                    let started = self.stats.start();
+                    (*x).parent_fiber = Fiber::current();
+                    self.fiber.switch();
+                    count_switch();
+                    {
+                        let parent = x as *mut JumpBuffer;
+                        let _entered = self.trace.enter();
+                        let _running = self.guard.enter(parent, &mut (*x).overflowed);
+                        jump_swap(parent, (*x).child.as_mut_ptr() as _);
+                    }
+                    self.stats.stop(started);
+                    let _ = guard::overflowed();
+
+                    // The child has completed. Switch back to our fiber; see
+                    // `Coroutine::try_resume_with()`.
+                    (*x).parent_fiber.switch();
+                    self.hooks.complete();
+                }
+            }
+
+            self.fiber.destroy();
+            self.stack_id.deregister();
+            self.shadow.free();
+            self.registered
+                .suspended(CoroutineState::Finished, Site::UNKNOWN);
+
+            // Take what we need from the Context, and from the canary, before
+            // the stack they live on is wiped.
+            let panic = (*x).panic.take();
+            let overflowed = (&(*x).overflowed as *const bool).read_volatile();
+            let overflowed = overflowed || !self.canary.is_intact();
+            let ignored = (&(*x).ignored as *const bool).read_volatile();
+            self.zeroize.wipe();
+
+            // Propagate a panic raised while the child was unwinding,
+            // unless we are already unwinding ourselves.
+            if let Some(payload) = panic {
+                if !unwind::panicking() {
+                    unwind::resume(payload);
+                }
+            }
+
+            if overflowed && !unwind::panicking() {
+                panic!("coroutine stack overflow{}", Named(self.name()));
+            }
+
+            let outcome = if ignored {
+                CancelOutcome::Ignored
+            } else {
+                CancelOutcome::Acknowledged
+            };
+
+            self.trace.canceled(outcome);
+            outcome
+        }
+    }
+
+    /// Returns the coroutine's stack, so that it can be reused.
+    ///
+    /// If the coroutine has not completed yet, it is canceled first, just as
+    /// if it were dropped.
+    pub fn into_stack(mut self) -> S {
+        let _ = self.stop();
+
+        // Move the stack out without running our `Drop` implementation. It
+        // has nothing left to do, but the fields which own something besides
+        // the stack must still be dropped.
+        let mut this = ManuallyDrop::new(self);
+        unsafe {
+            ptr::drop_in_place(&mut this.trace);
+            ptr::drop_in_place(&mut this.hooks);
+            ptr::drop_in_place(&mut this.registered);
+            ptr::drop_in_place(&mut this.name);
+            #[cfg(feature = "alloc")]
+            this.drop_body();
+            ptr::read(this.stack.as_ptr())
+        }
+    }
+}
+
+impl<'a, A, Y, R, S> Coroutine<'a, A, Y, R, S> {
+    /// Turns the coroutine's stack into another kind of `Stack`, describing
+    /// the same memory, which doesn't move. See `Coroutine::into_dyn()`.
+    ///
+    /// The stack is handed to `f` still in its `MaybeUninit`, for the reason
+    /// given in `Coroutine`.
+    #[cfg(feature = "alloc")]
+    fn map_stack<T>(self, f: impl FnOnce(MaybeUninit<S>) -> T) -> Coroutine<'a, A, Y, R, T> {
+        // Move every field out without running our `Drop` implementation.
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            Coroutine {
+                ctx: this.ctx,
+                entry: this.entry,
+                fiber: ptr::read(&this.fiber),
+                stack_id: ptr::read(&this.stack_id),
+                shadow: ptr::read(&this.shadow),
+                canary: ptr::read(&this.canary),
+                guard: ptr::read(&this.guard),
+                zeroize: ptr::read(&this.zeroize),
+                trace: ptr::read(&this.trace),
+                hooks: ptr::read(&this.hooks),
+                stats: ptr::read(&this.stats),
+                registered: ptr::read(&this.registered),
+                #[cfg(feature = "std")]
+                thread: this.thread,
+                name: ptr::read(&this.name),
+                panic_policy: this.panic_policy,
+                body: this.body,
+                stack: MaybeUninit::new(f(ptr::read(&this.stack))),
+                life: PhantomData,
+            }
+        }
+    }
+}
+
+impl<'a, A, Y, R, S> fmt::Debug for Coroutine<'a, A, Y, R, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Coroutine")
+            .field("name", &self.name())
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl<'a, A, Y, R, S> Drop for Coroutine<'a, A, Y, R, S> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+        unsafe {
+            #[cfg(feature = "alloc")]
+            self.drop_body();
+            ptr::drop_in_place(self.stack.as_mut_ptr())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    // Sets a flag when dropped.
+    struct Guard<'a>(&'a mut bool);
+
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            *self.0 = true;
+        }
+    }
+
+    #[test]
+    fn stack() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[test]
     fn heap() {
         let mut stack = Box::new([1u8; STACK_MINIMUM]);
 
-        let mut coro = Coroutine::new(&mut *stack, |c| {
-            let c = c.r#yield(1)?;
+        let mut coro = Coroutine::new(&mut *stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let mut cancelled = false;
+
+        {
+            let mut stack = [1u8; STACK_MINIMUM * 4];
+
+            let mut coro = Coroutine::new(&mut stack, |c, ()| {
+                let _guard = Guard(&mut cancelled);
+                let (c, ()) = c.r#yield(1)?;
+                c.done("foo")
+            });
+
+            match Pin::new(&mut coro).resume() {
+                GeneratorState::Yielded(1) => {}
+                _ => panic!("unexpected return from resume"),
+            }
+
+            // Coroutine is cancelled when it goes out of scope.
+        }
+
+        assert!(cancelled);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn cancel_unwinds() {
+        let mut dropped = false;
+
+        {
+            let mut stack = [1u8; STACK_MINIMUM * 4];
+
+            let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, ()>, ()| {
+                let _guard = Guard(&mut dropped);
+
+                // Cancellation unwinds the stack, so this never returns.
+                let _ = c.r#yield(1);
+                panic!("resumed after cancellation");
+            });
+
+            match Pin::new(&mut coro).resume() {
+                GeneratorState::Yielded(1) => {}
+                _ => panic!("unexpected return from resume"),
+            }
+        }
+
+        assert!(dropped);
+    }
+
+    #[test]
+    fn cancel_outcome() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+
+        let coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+        assert_eq!(coro.cancel(), CancelOutcome::Acknowledged);
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+        let _ = Pin::new(&mut coro).resume();
+        assert_eq!(coro.cancel(), CancelOutcome::Acknowledged);
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, &str>, ()| {
+            let _ = catch_unwind(AssertUnwindSafe(|| c.r#yield(1)));
+            Err(CoroutineError::Canceled)
+        });
+        let _ = Pin::new(&mut coro).resume();
+        assert_eq!(coro.cancel(), CancelOutcome::Acknowledged);
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, &str>, ()| {
+            let _ = catch_unwind(AssertUnwindSafe(|| c.r#yield(1)));
+            Ok(Finished::new("foo"))
+        });
+        let _ = Pin::new(&mut coro).resume();
+        assert_eq!(coro.cancel(), CancelOutcome::Ignored);
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+        let _ = Pin::new(&mut coro).resume();
+        let _ = Pin::new(&mut coro).resume();
+        assert_eq!(coro.cancel(), CancelOutcome::Completed);
+    }
+
+    #[test]
+    fn coro_early_drop_yield_done() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let _coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+    }
+
+    #[test]
+    fn coro_early_drop_done_only() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let _coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, &str>, ()| {
+            c.done("foo")
+        });
+    }
+
+    #[test]
+    fn coro_early_drop_result_ok() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let _coro = Coroutine::new(&mut stack, |_c: Control<'_, (), i32, &str>, ()| {
+            Ok(Finished::new("foo"))
+        });
+    }
+
+    #[test]
+    fn finished() {
+        fn body(c: Control<'_, (), i32, i32>) -> Result<Finished<i32>, CoroutineError> {
+            let (c, ()) = c.r#yield(1)?;
+            let (_, ()) = c.r#yield(2)?;
+            Ok(3.into())
+        }
+
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::new(&mut stack, |c, ()| body(c));
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(2));
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(3));
+
+        assert_eq!(Finished::new(4).into_inner(), 4);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn finisher() {
+        fn body(f: Finisher<'_, (), i32, String>) -> Result<Finished<String>, CoroutineError> {
+            let name = String::from(f.name().unwrap());
+            f.done(name)
+        }
+
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::builder()
+            .name("foo")
+            .spawn_on(&mut stack, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                body(c.finish())
+            });
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+        assert_eq!(
+            Pin::new(&mut coro).resume(),
+            GeneratorState::Complete(String::from("foo"))
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn yielder() {
+        fn body(mut y: Yielder<'_, i32, i32, String>) -> Result<Finished<String>, CoroutineError> {
+            let mut sum = 0;
+            while sum < 10 {
+                let (next, n) = y.r#yield(sum)?;
+                y = next;
+                sum += n;
+            }
+
+            let name = String::from(y.name().unwrap());
+            y.finish().done(format!("{} {}", name, sum))
+        }
+
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::builder()
+            .name("foo")
+            .spawn_on(&mut stack, |c, _| body(c.yielder()));
+
+        assert_eq!(
+            Pin::new(&mut coro).resume_with(0),
+            GeneratorState::Yielded(0)
+        );
+        assert_eq!(
+            Pin::new(&mut coro).resume_with(4),
+            GeneratorState::Yielded(4)
+        );
+        assert_eq!(
+            Pin::new(&mut coro).resume_with(8),
+            GeneratorState::Complete(String::from("foo 12"))
+        );
+    }
+
+    #[test]
+    fn coro_early_drop_result_err() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let _coro = Coroutine::new(&mut stack, |_c: Control<'_, (), i32, &str>, ()| {
+            Err(CoroutineError::Canceled)
+        });
+    }
+
+    #[test]
+    fn resume_with() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, a: u32| {
+            let (c, b) = c.r#yield(a + 1)?;
+            let (c, d) = c.r#yield(b * 2)?;
+            c.done(d)
+        });
+
+        match Pin::new(&mut coro).resume_with(1) {
+            GeneratorState::Yielded(2) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume_with(5) {
+            GeneratorState::Yielded(10) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume_with(7) {
+            GeneratorState::Complete(7) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[test]
+    fn resume_with_cancel() {
+        let mut cancelled = false;
+
+        {
+            let mut stack = [1u8; STACK_MINIMUM * 4];
+
+            let mut coro = Coroutine::new(&mut stack, |c, a: &str| {
+                let _guard = Guard(&mut cancelled);
+                let (c, b) = c.r#yield(a.len())?;
+                c.done(b)
+            });
+
+            match Pin::new(&mut coro).resume_with("foo") {
+                GeneratorState::Yielded(3) => {}
+                _ => panic!("unexpected return from resume"),
+            }
+        }
+
+        assert!(cancelled);
+    }
+
+    #[test]
+    fn yield_all() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let c = c.yield_all(1..4)?;
+            let c = c.yield_all(None)?;
+            let c = c.yield_all(vec![4])?;
+            c.done(())
+        });
+
+        assert_eq!(coro.iter().collect::<Vec<_>>(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn yield_all_cancel() {
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+        let mut taken = 0;
+
+        {
+            let taken = &mut taken;
+            let mut coro = Coroutine::new(&mut stack, move |c, ()| {
+                let c = c.yield_all((0..10).inspect(|_| *taken += 1))?;
+                c.done(())
+            });
+
+            assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(0));
+            assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+        }
+
+        assert_eq!(taken, 2);
+    }
+
+    #[test]
+    fn yield_from() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut inner = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+
+            let (c, r) = c.yield_from(Coroutine::new(&mut inner, |c, ()| {
+                let (c, ()) = c.r#yield(2)?;
+                let (c, ()) = c.r#yield(3)?;
+                c.done("foo")
+            }))?;
+
+            let (c, ()) = c.r#yield(4)?;
+            c.done(r)
+        });
+
+        let mut states = Vec::new();
+        loop {
+            let state = Pin::new(&mut coro).resume();
+            states.push(state);
+            if let GeneratorState::Complete(_) = state {
+                break;
+            }
+        }
+
+        assert_eq!(
+            states,
+            [
+                GeneratorState::Yielded(1),
+                GeneratorState::Yielded(2),
+                GeneratorState::Yielded(3),
+                GeneratorState::Yielded(4),
+                GeneratorState::Complete("foo"),
+            ]
+        );
+    }
+
+    #[test]
+    fn iter() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            let (c, ()) = c.r#yield(2)?;
+            let (c, ()) = c.r#yield(3)?;
+            c.done(())
+        });
+
+        let mut sum = 0;
+        for i in &mut coro {
+            sum += i;
+        }
+        assert_eq!(sum, 6);
+        assert!(coro.iter().next().is_none());
+    }
+
+    #[test]
+    fn into_iter() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield("foo")?;
+            let (c, ()) = c.r#yield("bar")?;
+            c.done(())
+        });
+
+        let items: Vec<_> = coro.into_iter().map(str::len).collect();
+        assert_eq!(items, [3, 3]);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn with_stack_size() {
+        fn spawn() -> Coroutine<'static, (), i32, &'static str, AllocatedStack> {
+            Coroutine::with_stack_size(STACK_MINIMUM, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done("foo")
+            })
+        }
+
+        let mut coro = spawn();
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[test]
+    fn state() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        assert_eq!(coro.state(), CoroutineState::Created);
+        assert!(!coro.is_finished());
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        assert_eq!(coro.state(), CoroutineState::Suspended);
+        assert!(!coro.is_finished());
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        assert_eq!(coro.state(), CoroutineState::Finished);
+        assert!(coro.is_finished());
+    }
+
+    #[test]
+    fn try_resume() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).try_resume() {
+            Ok(GeneratorState::Yielded(1)) => {}
+            _ => panic!("unexpected return from try_resume"),
+        }
+
+        match Pin::new(&mut coro).try_resume() {
+            Ok(GeneratorState::Complete("foo")) => {}
+            _ => panic!("unexpected return from try_resume"),
+        }
+
+        match Pin::new(&mut coro).try_resume() {
+            Err(CoroutineError::AlreadyFinished) => {}
+            _ => panic!("unexpected return from try_resume"),
+        }
+    }
+
+    #[test]
+    fn from_generator() {
+        let mut a = [1u8; STACK_MINIMUM];
+        // Resuming the inner coroutine takes more stack.
+        let mut b = [1u8; STACK_MINIMUM * 4];
+
+        let inner = Coroutine::new(&mut a, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+        let mut coro = Coroutine::from_generator(&mut b, inner.map_yield(|y| y * 10));
+
+        let mut coro = Pin::new(&mut coro);
+        assert_eq!(coro.as_mut().resume(), GeneratorState::Yielded(10));
+        assert_eq!(coro.as_mut().resume(), GeneratorState::Complete("foo"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn try_resume_panicked() {
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, ()>, ()| {
+            if true {
+                panic!("foo");
+            }
+            c.done(())
+        });
+
+        match Pin::new(&mut coro).try_resume() {
+            Err(err @ CoroutineError::Panicked(_)) => {
+                assert_eq!(err.to_string(), "coroutine panicked: foo")
+            }
+            _ => panic!("unexpected return from try_resume"),
+        }
+
+        assert!(coro.is_finished());
+    }
+
+    #[test]
+    fn try_resume_error() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        // A return type which can't be left uninitialized.
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), bool, Box<u8>>, ()| {
+            let (_, ()) = c.r#yield(true)?;
+            Err(CoroutineError::WrongThread)
+        });
+
+        let mut coro = Pin::new(&mut coro);
+        assert_eq!(
+            coro.as_mut().try_resume().unwrap(),
+            GeneratorState::Yielded(true)
+        );
+        match coro.as_mut().try_resume() {
+            Err(CoroutineError::WrongThread) => {}
+            _ => panic!("unexpected return from try_resume"),
+        }
+
+        assert!(coro.is_finished());
+    }
+
+    #[test]
+    fn transfer_error() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut other = [1u8; STACK_MINIMUM];
+
+        let mut other = Coroutine::new(&mut other, |_: Control<'_, (), (), Box<u8>>, ()| {
+            Err(CoroutineError::WrongThread)
+        });
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), (), Box<u8>>, ()| {
+            match c.transfer(&mut other, ()) {
+                Err(CoroutineError::WrongThread) => Ok(Finished::new(Box::new(1))),
+                _ => panic!("unexpected return from transfer"),
+            }
+        });
+
+        assert_eq!(
+            Pin::new(&mut coro).resume(),
+            GeneratorState::Complete(Box::new(1))
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn resume_with_error() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, String>, ()| {
+            match c.r#yield(1) {
+                Err(CoroutineError::Thrown(err)) => {
+                    let err = err.downcast::<&str>().unwrap();
+                    Ok(Finished::new(format!("handled {}", err)))
+                }
+                _ => panic!("unexpected return from yield"),
+            }
+        });
+
+        let mut coro = Pin::new(&mut coro);
+        assert_eq!(coro.as_mut().resume(), GeneratorState::Yielded(1));
+        assert_eq!(
+            coro.as_mut().resume_with_error("connection closed"),
+            GeneratorState::Complete(String::from("handled connection closed"))
+        );
+        assert!(coro.is_finished());
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn try_resume_with_error() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, ()>, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done(())
+        });
+
+        let mut coro = Pin::new(&mut coro);
+        assert_eq!(coro.as_mut().resume(), GeneratorState::Yielded(1));
+        match coro.as_mut().try_resume_with_error(42u32) {
+            Err(CoroutineError::Thrown(err)) => assert_eq!(err.downcast_ref(), Some(&42u32)),
+            _ => panic!("unexpected return from try_resume_with_error"),
+        }
+
+        assert!(coro.is_finished());
+        match coro.as_mut().try_resume_with_error(42u32) {
+            Err(CoroutineError::AlreadyFinished) => {}
+            _ => panic!("unexpected return from try_resume_with_error"),
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    #[should_panic(expected = "coroutine failed: error thrown into coroutine: foo")]
+    fn resume_with_error_unstarted() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |_: Control<'_, (), (), ()>, ()| {
+            panic!("closure ran");
+        });
+
+        let _ = Pin::new(&mut coro).resume_with_error("foo");
+    }
+
+    #[test]
+    fn try_new() {
+        let mut stack = [1u8; STACK_MINIMUM - 1];
+
+        let coro = Coroutine::try_new(&mut stack, |c: Control<'_, (), (), ()>, ()| c.done(()));
+        match coro {
+            Err(CoroutineError::StackTooSmall { required, provided }) => {
+                assert_eq!(required, STACK_MINIMUM);
+                assert_eq!(provided, STACK_MINIMUM - 1);
+            }
+            _ => panic!("unexpected return from try_new"),
+        }
+    }
+
+    #[test]
+    fn into_stack() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+
+        let mut coro = Coroutine::new(&mut stack[..], |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
             c.done("foo")
         });
 
@@ -405,73 +2955,592 @@ mod tests {
             _ => panic!("unexpected return from resume"),
         }
 
+        // Cancels the suspended coroutine and hands the stack back.
+        let stack = coro.into_stack();
+        assert_eq!(stack.len(), STACK_MINIMUM * 4);
+    }
+
+    #[test]
+    #[cfg(not(feature = "zeroize"))] // Which zeroes the whole stack.
+    fn lazy_start() {
+        let mut stack = [0xa5u8; STACK_MINIMUM * 2];
+
+        // Nothing runs on the stack before the first resume: only the
+        // Context, the frame record and the closure at its top are written.
+        let coro = Coroutine::new(&mut stack[..], |c: Control<'_, (), i32, ()>, ()| c.done(()));
+        assert_eq!(coro.state(), CoroutineState::Created);
+
+        // Apart from the canary at the lower end, in debug builds.
+        let stack = coro.into_stack();
+        assert!(stack[size_of::<usize>()..STACK_MINIMUM]
+            .iter()
+            .all(|&b| b == 0xa5));
+    }
+
+    #[test]
+    fn restart() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
         match Pin::new(&mut coro).resume() {
-            GeneratorState::Complete("foo") => {}
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        let mut coro = coro.restart(|c, ()| {
+            let (c, ()) = c.r#yield(2.0)?;
+            c.done(true)
+        });
+
+        assert_eq!(coro.state(), CoroutineState::Created);
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(y) => assert_eq!(y, 2.0),
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(true) => {}
             _ => panic!("unexpected return from resume"),
         }
     }
 
+    #[cfg(feature = "alloc")]
     #[test]
-    fn cancel() {
-        let mut cancelled = false;
-
-        {
-            let mut stack = [1u8; STACK_MINIMUM];
+    fn restart_in_place() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+
+        let mut coro =
+            Coroutine::builder()
+                .name("worker")
+                .spawn_on(&mut stack[..], |c, job: usize| {
+                    let (c, _) = c.r#yield(job)?;
+                    c.done(job * 2)
+                });
+
+        for job in 1..4 {
+            assert_eq!(coro.state(), CoroutineState::Created);
+            assert_eq!(
+                Pin::new(&mut coro).resume_with(job),
+                GeneratorState::Yielded(job)
+            );
+            assert_eq!(
+                Pin::new(&mut coro).resume_with(0),
+                GeneratorState::Complete(job * 2)
+            );
+            assert!(coro.is_finished());
 
-            let mut coro = Coroutine::new(&mut stack, |c| match c.r#yield(1) {
-                Ok(c) => c.done("foo"),
-                Err(v) => {
-                    cancelled = true;
-                    Err(v)
-                }
+            coro.restart_in_place(|c, job| {
+                let (c, _) = c.r#yield(job)?;
+                c.done(job * 2)
             });
+            assert_eq!(coro.name(), Some("worker"));
+        }
+    }
 
-            match Pin::new(&mut coro).resume() {
-                GeneratorState::Yielded(1) => {}
-                _ => panic!("unexpected return from resume"),
+    #[test]
+    #[should_panic(expected = "has not completed yet")]
+    fn restart_in_place_running() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), (), ()>, ()| c.done(()));
+        coro.restart_in_place(|c, ()| c.done(()));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn from_fn_mut() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut runs = 0;
+
+        // Every run yields one more number than the one before.
+        let mut coro = Coroutine::from_fn_mut(&mut stack[..], move |mut c, ()| {
+            runs += 1;
+            for i in 0..runs {
+                c = c.r#yield(i)?.0;
             }
+            c.done(())
+        });
 
-            // Coroutine is cancelled when it goes out of scope.
+        assert_eq!(coro.iter().collect::<Vec<_>>(), [0]);
+        coro.reset();
+        assert_eq!(coro.iter().collect::<Vec<_>>(), [0, 1]);
+        coro.reset();
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(0));
+
+        // Resetting a coroutine which is still running cancels it first.
+        coro.reset();
+        assert_eq!(coro.iter().collect::<Vec<_>>(), [0, 1, 2, 3]);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn from_fn_mut_drop() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut dropped = false;
+        let guard = Guard(&mut dropped);
+
+        let coro = Coroutine::from_fn_mut(&mut stack[..], move |c: Control<'_, (), (), ()>, ()| {
+            let _guard = &guard;
+            c.done(())
+        });
+
+        let _ = coro.into_stack();
+        assert!(dropped);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    #[should_panic(expected = "has no body to run again")]
+    fn reset_without_body() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), (), ()>, ()| c.done(()));
+        coro.reset();
+    }
+
+    #[test]
+    fn transfer() {
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let mut other = Coroutine::new(&mut b, |c: Control<'_, i32, i32, i32>, x| {
+            let (c, y) = c.r#yield(x + 1)?;
+            c.done(x + y)
+        });
+
+        let mut coro = Coroutine::new(&mut a, |c: Control<'_, (), i32, i32>, ()| {
+            // The other coroutine yields straight to our parent.
+            let (c, state) = c.transfer(&mut other, 1)?;
+            assert_eq!(state, GeneratorState::Yielded(()));
+
+            // The other coroutine completes and returns here.
+            let (c, state) = c.transfer(&mut other, 10)?;
+            assert_eq!(state, GeneratorState::Complete(11));
+
+            c.done(100)
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(2) => {}
+            _ => panic!("unexpected return from resume"),
         }
 
-        assert!(cancelled);
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(100) => {}
+            _ => panic!("unexpected return from resume"),
+        }
     }
 
     #[test]
-    fn coro_early_drop_yield_done() {
+    fn owned_values() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(String::from("foo"))?;
+            c.done(String::from("bar"))
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(s) => assert_eq!(s, "foo"),
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(s) => assert_eq!(s, "bar"),
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "counters")]
+    #[cfg_attr(any(feature = "threads", miri), ignore)] // Coroutines run on threads of their own.
+    fn switches() {
         let mut stack = [1u8; STACK_MINIMUM];
+        let before = crate::switches();
 
-        let _coro = Coroutine::new(&mut stack, |c| {
-            let c = c.r#yield(1)?;
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
             c.done("foo")
         });
+        assert_eq!(crate::switches(), before);
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+        assert_eq!(crate::switches(), before + 2);
+
+        // Canceling it switches in to unwind its stack, and back out.
+        drop(coro);
+        assert_eq!(crate::switches(), before + 4);
     }
 
     #[test]
-    fn coro_early_drop_done_only() {
+    #[cfg(debug_assertions)]
+    #[cfg_attr(miri, ignore)] // The coroutine writes through a stale pointer.
+    #[should_panic(expected = "coroutine stack overflow")]
+    fn stack_overflow() {
         let mut stack = [1u8; STACK_MINIMUM];
+        let bottom = stack.as_mut_ptr() as usize;
+
+        let mut coro = Coroutine::new(&mut stack, move |c: Control<'_, (), i32, ()>, ()| {
+            // Stand in for a frame running off the end of the stack.
+            unsafe { (bottom as *mut u8).write_volatile(0) };
+            c.done(())
+        });
 
-        let _coro = Coroutine::new(&mut stack, |c: Control<'_, i32, &str>| c.done("foo"));
+        let _ = Pin::new(&mut coro).resume();
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Job {
+        Print(String),
+        Sum(Box<[u32]>),
+        Stop,
     }
 
     #[test]
-    fn coro_early_drop_result_ok() {
+    fn captured_values() {
         let mut stack = [1u8; STACK_MINIMUM];
 
-        let _coro = Coroutine::new(&mut stack, |_c: Control<'_, i32, &str>| Ok(Finished("foo")));
+        let name = String::from("foo");
+        let data = Box::new([1u32, 2, 3]);
+        let jobs = [Job::Print(String::from("bar")), Job::Sum(data), Job::Stop];
+
+        let mut coro = Coroutine::new(&mut stack, move |c, ()| {
+            let (mut c, ()) = c.r#yield(name)?;
+            for job in jobs.iter() {
+                let out = match job {
+                    Job::Print(s) => s.clone(),
+                    Job::Sum(v) => v.iter().sum::<u32>().to_string(),
+                    Job::Stop => break,
+                };
+                c = c.r#yield(out)?.0;
+            }
+            c.done(jobs)
+        });
+
+        for expected in ["foo", "bar", "6"].iter() {
+            match Pin::new(&mut coro).resume() {
+                GeneratorState::Yielded(s) => assert_eq!(s, *expected),
+                _ => panic!("unexpected return from resume"),
+            }
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(jobs) => assert_eq!(jobs[2], Job::Stop),
+            _ => panic!("unexpected return from resume"),
+        }
     }
 
     #[test]
-    fn coro_early_drop_result_err() {
+    fn captured_values_dropped() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut dropped = false;
+
+        // Never started, so the closure is dropped where it was moved to.
+        let guard = Guard(&mut dropped);
+        let job = Job::Print(String::from("foo"));
+        let coro = Coroutine::new(&mut stack, move |c: Control<'_, (), (), ()>, ()| {
+            let _guard = guard;
+            let _job = job;
+            c.done(())
+        });
+        drop(coro);
+
+        assert!(dropped);
+    }
+
+    #[test]
+    fn large_closure() {
+        let mut stack = [1u8; STACK_MINIMUM * 2];
+        let data = [7u8; STACK_MINIMUM * 2];
+
+        let coro = Coroutine::try_new(&mut stack, move |c: Control<'_, (), (), u8>, ()| {
+            c.done(data[0])
+        });
+        match coro {
+            Err(CoroutineError::StackTooSmall { required, provided }) => {
+                assert!(required > STACK_MINIMUM * 2);
+                assert_eq!(provided, STACK_MINIMUM * 2);
+            }
+            _ => panic!("unexpected return from try_new"),
+        }
+
+        // Starting it moves the closure again, off its slot onto the stack
+        // below, and debug builds copy it around a few more times.
+        let mut stack = [1u8; STACK_MINIMUM * 64];
+        let mut coro = Coroutine::try_new(&mut stack, move |c: Control<'_, (), (), u8>, ()| {
+            c.done(data[data.len() - 1])
+        })
+        .unwrap();
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(7) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn named() {
         let mut stack = [1u8; STACK_MINIMUM];
 
-        let _coro = Coroutine::new(&mut stack, |_c: Control<'_, i32, &str>| Err(Canceled(())));
+        let mut coro = Coroutine::builder().name("parser").spawn_on(
+            &mut stack,
+            |c: Control<'_, (), (), String>, ()| {
+                let name = c.name().map(String::from);
+                c.done(name.unwrap())
+            },
+        );
+
+        assert_eq!(
+            format!("{:?}", coro),
+            r#"Coroutine { name: Some("parser"), state: Created }"#
+        );
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(name) => assert_eq!(name, "parser"),
+            _ => panic!("unexpected return from resume"),
+        }
+
+        let err = catch_unwind(AssertUnwindSafe(|| Pin::new(&mut coro).resume())).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<String>().unwrap(),
+            r#"Called Generator::resume() after completion! (coroutine "parser")"#
+        );
     }
 
     #[test]
-    #[should_panic(expected = "stack.len() >= STACK_MINIMUM")]
+    fn context_size() {
+        let word = size_of::<usize>();
+
+        // Only the LLVM IR backend needs more than a pointer to suspend a
+        // context into.
+        assert_eq!(size_of::<JumpBuffer>(), JUMP_BUFFER * word);
+        #[cfg(not(frenetic_sjlj))]
+        assert_eq!(JUMP_BUFFER, 1);
+
+        // Besides the two jump buffers, a Context holds at most fifteen
+        // words: eight pointers (a panic payload and a thrown error take two
+        // each), the flags, the name (two more), the waker, the budget, and
+        // the yield site and the ThreadSanitizer fiber, when enabled.
+        let max = (2 * size_of::<JumpBuffer>() + 15 * word + 15) & !15;
+        assert!(size_of::<Context<(), (), ()>>() <= max);
+    }
+
+    #[test]
+    #[should_panic(expected = "bytes is smaller than the")]
     fn small_stack() {
         let mut stack = [1u8; STACK_MINIMUM - 1];
-        let _coro = Coroutine::new(&mut stack, |_c: Control<'_, i32, &str>| Err(Canceled(())));
+        let _coro = Coroutine::new(&mut stack, |_c: Control<'_, (), i32, &str>, ()| {
+            Err(CoroutineError::Canceled)
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "foo")]
+    fn panic() {
+        // Unwinding needs considerably more stack than the minimum.
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), i32, ()>, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            if true {
+                panic!("foo");
+            }
+            c.done(())
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        let _ = Pin::new(&mut coro).resume();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn panic_completes() {
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |_c: Control<'_, (), (), ()>, ()| {
+            if true {
+                panic!("foo");
+            }
+            Err(CoroutineError::Canceled)
+        });
+
+        let payload = catch_unwind(AssertUnwindSafe(|| {
+            let _ = Pin::new(&mut coro).resume();
+        }))
+        .unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"foo"));
+
+        // The coroutine has finished; resuming it again is an error.
+        let again = catch_unwind(AssertUnwindSafe(|| {
+            let _ = Pin::new(&mut coro).resume();
+        }));
+        assert!(again.is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "foo")]
+    fn panic_on_drop() {
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), (), ()>, ()| {
+            // Stop the unwinding cancellation and panic instead.
+            let _ = catch_unwind(AssertUnwindSafe(|| c.r#yield(())));
+            panic!("foo");
+        });
+
+        let _ = Pin::new(&mut coro).resume();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't access MXCSR.
+    #[allow(deprecated)]
+    fn fpstate() {
+        use core::arch::x86_64::{_mm_getcsr, _mm_setcsr, _MM_ROUND_MASK, _MM_ROUND_TOWARD_ZERO};
+
+        let mut stack = [1u8; STACK_MINIMUM];
+        let parent = unsafe { _mm_getcsr() } & _MM_ROUND_MASK;
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            unsafe { _mm_setcsr(_mm_getcsr() & !_MM_ROUND_MASK | _MM_ROUND_TOWARD_ZERO) };
+            let (c, ()) = c.r#yield(())?;
+            assert_eq!(
+                unsafe { _mm_getcsr() } & _MM_ROUND_MASK,
+                _MM_ROUND_TOWARD_ZERO
+            );
+            c.done(())
+        });
+
+        // The rounding mode set inside the coroutine stays there.
+        let _ = Pin::new(&mut coro).resume();
+        assert_eq!(unsafe { _mm_getcsr() } & _MM_ROUND_MASK, parent);
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(()) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+        assert_eq!(unsafe { _mm_getcsr() } & _MM_ROUND_MASK, parent);
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn macro_coroutine() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(
+            &mut stack,
+            coroutine! {
+                let mut sum = 0;
+                for i in 1..4 {
+                    sum += i;
+                    yield sum;
+                }
+                if sum > 100 {
+                    return "big";
+                }
+                "small"
+            },
+        );
+
+        let mut yielded = Vec::new();
+        let ret = loop {
+            match Pin::new(&mut coro).resume() {
+                GeneratorState::Yielded(y) => yielded.push(y),
+                GeneratorState::Complete(r) => break r,
+            }
+        };
+
+        assert_eq!(yielded, [1, 3, 6]);
+        assert_eq!(ret, "small");
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn macro_resume_with() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(
+            &mut stack,
+            coroutine! {
+                let a: i32 = yield ();
+                let b: i32 = yield ();
+                a * b
+            },
+        );
+
+        let _ = Pin::new(&mut coro).resume_with(0);
+        let _ = Pin::new(&mut coro).resume_with(6);
+        match Pin::new(&mut coro).resume_with(7) {
+            GeneratorState::Complete(42) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    // A leaf function which fills the red zone below its stack pointer with
+    // `value`, as compilers let leaf functions do, and returns the stack
+    // pointer it was called with.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux", not(miri)))]
+    core::arch::global_asm!(
+        ".globl frenetic_test_leaf",
+        "frenetic_test_leaf:",
+        "mov rcx, -128",
+        "2:",
+        "mov [rsp + rcx], rdi",
+        "add rcx, 8",
+        "jnz 2b",
+        "mov rax, rsp",
+        "ret",
+    );
+
+    #[cfg(all(
+        feature = "alloc",
+        target_arch = "x86_64",
+        target_os = "linux",
+        not(miri)
+    ))]
+    #[test]
+    fn red_zone() {
+        extern "C" {
+            fn frenetic_test_leaf(value: u64) -> usize;
+        }
+
+        // Leaves the coroutine on the stack segment it runs on, so that both
+        // `Coroutine::new()` and `Control::maybe_grow()` are covered.
+        type Ctl<'a> = Control<'a, u64, usize, u64>;
+        fn leaf(c: Ctl<'_>, value: u64) -> Result<(Ctl<'_>, u64), CoroutineError> {
+            let sp = unsafe { frenetic_test_leaf(value) };
+            c.r#yield(sp % STACK_ALIGNMENT)
+        }
+
+        let mut stack = [1u8; STACK_MINIMUM * 2];
+        let len = stack.len();
+
+        // Neither end of the stack need be aligned.
+        for skip in 0..STACK_ALIGNMENT {
+            let mut coro = Coroutine::new(&mut stack[skip..len - skip], |c, value| {
+                let (c, value) = leaf(c, value)?;
+                let (c, value) = c.maybe_grow(usize::MAX, STACK_MINIMUM, |c| leaf(c, value))?;
+                c.done(value)
+            });
+
+            // Right after the call, the stack pointer is 8 bytes off, and the
+            // red zone didn't reach anything the coroutine keeps.
+            let mut coro = Pin::new(&mut coro);
+            assert_eq!(coro.as_mut().resume_with(!0), GeneratorState::Yielded(8));
+            assert_eq!(coro.as_mut().resume_with(!1), GeneratorState::Yielded(8));
+            assert_eq!(coro.as_mut().resume_with(2), GeneratorState::Complete(2));
+        }
     }
 }
@@ -0,0 +1,495 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Waiting for file descriptors to become ready.
+//!
+//! A coroutine which waits for a file descriptor registers its waker with the
+//! reactor, and waits like `Control::yield_pending()` does. The reactor is a
+//! thread, started on first use, which polls the file descriptors (with epoll
+//! on Linux and kqueue on the BSDs and macOS) and wakes the coroutines whose
+//! file descriptors are ready, so that the thread driving them never blocks.
+//!
+//! File descriptors are registered in one-shot mode, and only while some
+//! coroutine waits for them, so they can be closed at any time.
+
+use crate::sync::WaitResult;
+use crate::{Control, ResumeState};
+use core::ptr::null_mut;
+use core::task::Waker;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::Event;
+    use core::ptr::null_mut;
+    use std::io::{Error, Result};
+    use std::os::unix::io::RawFd;
+
+    pub(super) fn new() -> Result<RawFd> {
+        match unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) } {
+            -1 => Err(Error::last_os_error()),
+            ep => Ok(ep),
+        }
+    }
+
+    pub(super) fn arm(ep: RawFd, fd: RawFd, read: bool, write: bool) -> Result<()> {
+        let mut events = libc::EPOLLONESHOT as u32;
+        if read {
+            events |= (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+        }
+        if write {
+            events |= libc::EPOLLOUT as u32;
+        }
+
+        let mut event = libc::epoll_event {
+            events,
+            u64: fd as u64,
+        };
+
+        unsafe {
+            if libc::epoll_ctl(ep, libc::EPOLL_CTL_MOD, fd, &mut event) == 0 {
+                return Ok(());
+            }
+
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ENOENT) {
+                return Err(err);
+            }
+
+            match libc::epoll_ctl(ep, libc::EPOLL_CTL_ADD, fd, &mut event) {
+                0 => Ok(()),
+                _ => Err(Error::last_os_error()),
+            }
+        }
+    }
+
+    pub(super) fn disarm(ep: RawFd, fd: RawFd) {
+        let _ = unsafe { libc::epoll_ctl(ep, libc::EPOLL_CTL_DEL, fd, null_mut()) };
+    }
+
+    pub(super) fn wait(ep: RawFd, events: &mut Vec<Event>) -> Result<()> {
+        let mut buf = [libc::epoll_event { events: 0, u64: 0 }; 64];
+        let n = unsafe { libc::epoll_wait(ep, buf.as_mut_ptr(), buf.len() as _, -1) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let hup = (libc::EPOLLERR | libc::EPOLLHUP) as u32;
+        for e in &buf[..n as usize] {
+            let flags = e.events;
+            events.push(Event {
+                fd: e.u64 as RawFd,
+                read: flags & (libc::EPOLLIN | libc::EPOLLRDHUP) as u32 != 0 || flags & hup != 0,
+                write: flags & libc::EPOLLOUT as u32 != 0 || flags & hup != 0,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod sys {
+    use super::Event;
+    use core::mem::zeroed;
+    use core::ptr::{null, null_mut};
+    use std::io::{Error, Result};
+    use std::os::unix::io::RawFd;
+
+    pub(super) fn new() -> Result<RawFd> {
+        match unsafe { libc::kqueue() } {
+            -1 => Err(Error::last_os_error()),
+            kq => Ok(kq),
+        }
+    }
+
+    pub(super) fn arm(kq: RawFd, fd: RawFd, read: bool, write: bool) -> Result<()> {
+        let mut changes: [libc::kevent; 2] = unsafe { zeroed() };
+        let mut n = 0;
+
+        for &(wanted, filter) in &[(read, libc::EVFILT_READ), (write, libc::EVFILT_WRITE)] {
+            if wanted {
+                changes[n].ident = fd as _;
+                changes[n].filter = filter;
+                changes[n].flags = libc::EV_ADD | libc::EV_ONESHOT;
+                n += 1;
+            }
+        }
+
+        match unsafe { libc::kevent(kq, changes.as_ptr(), n as _, null_mut(), 0, null()) } {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn disarm(kq: RawFd, fd: RawFd) {
+        // One at a time, as deleting a filter which isn't there fails.
+        for &filter in &[libc::EVFILT_READ, libc::EVFILT_WRITE] {
+            let mut change: libc::kevent = unsafe { zeroed() };
+            change.ident = fd as _;
+            change.filter = filter;
+            change.flags = libc::EV_DELETE;
+            let _ = unsafe { libc::kevent(kq, &change, 1, null_mut(), 0, null()) };
+        }
+    }
+
+    pub(super) fn wait(kq: RawFd, events: &mut Vec<Event>) -> Result<()> {
+        let mut buf: [libc::kevent; 64] = unsafe { zeroed() };
+        let n = unsafe { libc::kevent(kq, null(), 0, buf.as_mut_ptr(), buf.len() as _, null()) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        for e in &buf[..n as usize] {
+            events.push(Event {
+                fd: e.ident as RawFd,
+                read: e.filter == libc::EVFILT_READ,
+                write: e.filter == libc::EVFILT_WRITE,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A file descriptor which is ready.
+struct Event {
+    fd: RawFd,
+    read: bool,
+    write: bool,
+}
+
+/// A waiting coroutine: the flag the reactor sets when it wakes it.
+type Waiter = (Arc<AtomicBool>, Waker);
+
+/// The coroutines waiting for a file descriptor.
+#[derive(Default)]
+struct Interest {
+    read: Vec<Waiter>,
+    write: Vec<Waiter>,
+}
+
+struct Reactor {
+    poller: RawFd,
+    fds: Mutex<HashMap<RawFd, Interest>>,
+}
+
+impl Reactor {
+    /// Returns the reactor, starting it the first time.
+    fn get() -> Result<&'static Reactor> {
+        static ONCE: Once = Once::new();
+        static REACTOR: AtomicPtr<Reactor> = AtomicPtr::new(null_mut());
+        static ERROR: AtomicI32 = AtomicI32::new(0);
+
+        ONCE.call_once(|| match sys::new() {
+            Ok(poller) => {
+                let reactor: &'static Reactor = Box::leak(Box::new(Reactor {
+                    poller,
+                    fds: Mutex::default(),
+                }));
+
+                let _ = thread::Builder::new()
+                    .name("frenetic-reactor".into())
+                    .spawn(move || reactor.run())
+                    .expect("failed to start the reactor thread");
+
+                REACTOR.store(reactor as *const Reactor as *mut Reactor, Ordering::Release);
+            }
+
+            Err(err) => ERROR.store(err.raw_os_error().unwrap_or(0), Ordering::Relaxed),
+        });
+
+        match unsafe { REACTOR.load(Ordering::Acquire).as_ref() } {
+            Some(reactor) => Ok(reactor),
+            None => Err(Error::from_raw_os_error(ERROR.load(Ordering::Relaxed))),
+        }
+    }
+
+    fn register(&self, fd: RawFd, write: bool, waiter: Waiter) -> Result<()> {
+        let mut fds = self.fds.lock().unwrap();
+        let interest = fds.entry(fd).or_default();
+        let list = if write {
+            &mut interest.write
+        } else {
+            &mut interest.read
+        };
+        list.push(waiter);
+
+        let (read, write) = (!interest.read.is_empty(), !interest.write.is_empty());
+        if let Err(err) = sys::arm(self.poller, fd, read, write) {
+            let _ = fds.remove(&fd);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Forgets a coroutine which has stopped waiting without having been
+    /// woken, and disarms `fd` if nobody else waits for it.
+    fn unregister(&self, fd: RawFd, ready: &Arc<AtomicBool>) {
+        let mut fds = self.fds.lock().unwrap_or_else(|e| e.into_inner());
+        let interest = match fds.get_mut(&fd) {
+            Some(interest) => interest,
+            None => return,
+        };
+
+        interest.read.retain(|(r, _)| !Arc::ptr_eq(r, ready));
+        interest.write.retain(|(r, _)| !Arc::ptr_eq(r, ready));
+        if interest.read.is_empty() && interest.write.is_empty() {
+            let _ = fds.remove(&fd);
+            sys::disarm(self.poller, fd);
+        }
+    }
+
+    /// Wakes the coroutines waiting for the file descriptors which are ready.
+    fn run(&self) {
+        let mut events = Vec::new();
+        loop {
+            events.clear();
+            match sys::wait(self.poller, &mut events) {
+                Ok(()) => {}
+                Err(ref err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(err) => panic!("failed to poll file descriptors: {}", err),
+            }
+
+            let mut woken = Vec::new();
+            let mut fds = self.fds.lock().unwrap();
+            for event in &events {
+                let interest = match fds.get_mut(&event.fd) {
+                    Some(interest) => interest,
+                    None => continue,
+                };
+
+                if event.read {
+                    woken.append(&mut interest.read);
+                }
+                if event.write {
+                    woken.append(&mut interest.write);
+                }
+
+                // Arm it again for the coroutines which still wait.
+                let (read, write) = (!interest.read.is_empty(), !interest.write.is_empty());
+                if !read && !write {
+                    let _ = fds.remove(&event.fd);
+                } else if sys::arm(self.poller, event.fd, read, write).is_err() {
+                    // It has been closed: wake them, to find out.
+                    let interest = fds.remove(&event.fd).unwrap_or_default();
+                    woken.extend(interest.read.into_iter().chain(interest.write));
+                }
+            }
+            drop(fds);
+
+            for (ready, waker) in woken {
+                ready.store(true, Ordering::Release);
+                waker.wake();
+            }
+        }
+    }
+}
+
+fn wait<'a, Y, R>(
+    mut c: Control<'a, (), Y, R>,
+    fd: RawFd,
+    write: bool,
+) -> WaitResult<'a, Y, R, Result<()>> {
+    /// Takes the coroutine out of the reactor if it stops waiting before it
+    /// has been woken, even if it is canceled.
+    struct Leave<'l> {
+        reactor: &'l Reactor,
+        fd: RawFd,
+        ready: &'l Arc<AtomicBool>,
+    }
+
+    impl Drop for Leave<'_> {
+        fn drop(&mut self) {
+            if !self.ready.load(Ordering::Acquire) {
+                self.reactor.unregister(self.fd, self.ready);
+            }
+        }
+    }
+
+    // Without a task, nothing would ever resume the coroutine.
+    let waker = match c.waker() {
+        Some(waker) => waker.clone(),
+        None => {
+            let err = Error::new(ErrorKind::Other, "no task drives the coroutine");
+            return Ok((c, Err(err)));
+        }
+    };
+
+    let reactor = match Reactor::get() {
+        Ok(reactor) => reactor,
+        Err(err) => return Ok((c, Err(err))),
+    };
+
+    let ready = Arc::new(AtomicBool::new(false));
+    if let Err(err) = reactor.register(fd, write, (ready.clone(), waker)) {
+        return Ok((c, Err(err)));
+    }
+
+    let _leave = Leave {
+        reactor,
+        fd,
+        ready: &ready,
+    };
+    while !ready.load(Ordering::Acquire) {
+        c = c.suspend(ResumeState::Pending)?.0;
+    }
+
+    Ok((c, Ok(())))
+}
+
+/// Suspends the coroutine until `fd` is readable, without blocking the
+/// thread. Hands back the `Control`, along with an error if `fd` can't be
+/// polled.
+///
+/// Like the primitives in `sync`, this needs the coroutine to be driven by a
+/// task, such as the `scheduler::Executor`; without one, it fails right
+/// away, as nothing would wake the coroutine. Readiness is only a hint: the
+/// coroutine should read in non-blocking mode, and wait again when it gets
+/// `ErrorKind::WouldBlock`.
+pub fn wait_readable<'a, Y, R>(
+    c: Control<'a, (), Y, R>,
+    fd: RawFd,
+) -> WaitResult<'a, Y, R, Result<()>> {
+    wait(c, fd, false)
+}
+
+/// Suspends the coroutine until `fd` is writable. See `wait_readable()`.
+pub fn wait_writable<'a, Y, R>(
+    c: Control<'a, (), Y, R>,
+    fd: RawFd,
+) -> WaitResult<'a, Y, R, Result<()>> {
+    wait(c, fd, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::{Coroutine, Generator, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+    use core::task::Context;
+    use std::task::Wake;
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri reports the reactor thread as leaked.
+    fn pipe() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [rx, tx] = fds;
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut c = [1u8; STACK_MINIMUM * 4];
+
+        let mut executor = Executor::<(), u8, _>::new();
+        let _ = executor.spawn(Coroutine::new(&mut a[..], move |c, ()| {
+            let (c, ready) = wait_readable(c, rx)?;
+            ready.unwrap();
+
+            let mut byte = 0u8;
+            assert_eq!(unsafe { libc::read(rx, &mut byte as *mut u8 as _, 1) }, 1);
+            c.done(byte)
+        }));
+
+        let mut log = Vec::new();
+        executor.run(|_, state| log.push(state));
+        assert!(log.is_empty());
+
+        let _ = executor.spawn(Coroutine::new(&mut b[..], move |c, ()| {
+            let (c, ready) = wait_writable(c, tx)?;
+            ready.unwrap();
+
+            assert_eq!(unsafe { libc::write(tx, b"x".as_ptr() as _, 1) }, 1);
+            c.done(0)
+        }));
+
+        while !executor.is_empty() {
+            executor.run(|_, state| log.push(state));
+            executor.wait();
+        }
+
+        assert_eq!(
+            log,
+            [GeneratorState::Complete(0), GeneratorState::Complete(b'x')]
+        );
+
+        // Errors are reported.
+        let _ = executor.spawn(Coroutine::new(&mut c[..], |c, ()| {
+            let (c, ready) = wait_readable(c, -1)?;
+            c.done(ready.is_err() as u8)
+        }));
+        log.clear();
+        executor.run(|_, state| log.push(state));
+        assert_eq!(log, [GeneratorState::Complete(1)]);
+
+        unsafe {
+            let _ = libc::close(rx);
+            let _ = libc::close(tx);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri reports the reactor thread as leaked.
+    fn cancel_waiting() {
+        struct Noop;
+
+        impl Wake for Noop {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [rx, tx] = fds;
+
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+        let mut coro = Coroutine::new(&mut stack[..], move |c: Control<'_, (), (), ()>, ()| {
+            let (c, ready) = wait_readable(c, rx)?;
+            ready.unwrap();
+            c.done(())
+        });
+
+        let waker = Waker::from(Arc::new(Noop));
+        let state = Pin::new(&mut coro).poll_resume((), &mut Context::from_waker(&waker));
+        assert_eq!(state, ResumeState::Pending);
+
+        // Canceled, it leaves the reactor.
+        let reactor = Reactor::get().unwrap();
+        assert!(reactor.fds.lock().unwrap().contains_key(&rx));
+        drop(coro);
+        assert!(!reactor.fds.lock().unwrap().contains_key(&rx));
+
+        unsafe {
+            let _ = libc::close(rx);
+            let _ = libc::close(tx);
+        }
+    }
+
+    #[test]
+    fn no_task() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut coro = Coroutine::new(&mut stack[..], |c: Control<'_, (), (), bool>, ()| {
+            let (c, ready) = wait_readable(c, 0)?;
+            c.done(ready.is_err())
+        });
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(true));
+    }
+}
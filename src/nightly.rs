@@ -0,0 +1,153 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Newer nightly toolchains give the generator trait a resume argument, and
+//! later rename it to `Coroutine`. Our own `Generator` trait keeps its
+//! argument-less `resume()`; coroutines implement the nightly trait as well,
+//! with `resume(arg)` doing what `Coroutine::resume_with()` does. The other
+//! way around, `Coroutine::from_nightly()` runs a nightly generator, such as
+//! a generator literal, in a coroutine, so code can move from one to the
+//! other a bit at a time.
+
+#[cfg(has_coroutine_trait)]
+use core::ops::{Coroutine as Trait, CoroutineState as State};
+#[cfg(has_generator_resume_arg)]
+use core::ops::{Generator as Trait, GeneratorState as State};
+
+use crate::{Coroutine, GeneratorState, SendCoroutine, Stack};
+use core::pin::Pin;
+
+fn convert<Y, R>(state: GeneratorState<Y, R>) -> State<Y, R> {
+    match state {
+        GeneratorState::Yielded(y) => State::Yielded(y),
+        GeneratorState::Complete(r) => State::Complete(r),
+    }
+}
+
+impl<'a, A, Y, R, S> Trait<A> for Coroutine<'a, A, Y, R, S> {
+    type Yield = Y;
+    type Return = R;
+
+    fn resume(self: Pin<&mut Self>, arg: A) -> State<Y, R> {
+        convert(self.resume_with(arg))
+    }
+}
+
+impl<'a, A, Y, R, S: Stack> Coroutine<'a, A, Y, R, S> {
+    /// Spawns a new coroutine, like `new()`, which runs the nightly generator
+    /// `generator`, passing it the arguments the coroutine is resumed with, and
+    /// yielding what it yields and returning what it returns.
+    ///
+    /// The generator is moved onto the coroutine's stack and pinned there.
+    pub fn from_nightly<G>(stack: S, generator: G) -> Self
+    where
+        G: Trait<A, Yield = Y, Return = R> + 'a,
+        S: 'a,
+    {
+        Self::new(stack, move |mut c, mut arg| {
+            let mut generator = generator;
+
+            // It stays right here until it is dropped, when the closure
+            // returns or unwinds.
+            let mut generator = unsafe { Pin::new_unchecked(&mut generator) };
+            loop {
+                match generator.as_mut().resume(arg) {
+                    State::Yielded(y) => {
+                        let (next, a) = c.r#yield(y)?;
+                        c = next;
+                        arg = a;
+                    }
+                    State::Complete(r) => return c.done(r),
+                }
+            }
+        })
+    }
+}
+
+impl<'a, A, Y, R, S> Trait<A> for SendCoroutine<'a, A, Y, R, S> {
+    type Yield = Y;
+    type Return = R;
+
+    fn resume(self: Pin<&mut Self>, arg: A) -> State<Y, R> {
+        convert(self.resume_with(arg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::STACK_MINIMUM;
+
+    fn drive<G: Trait<i32, Yield = i32, Return = i32>>(mut g: Pin<&mut G>) -> Vec<i32> {
+        let mut out = Vec::new();
+        let mut arg = 1;
+
+        loop {
+            match g.as_mut().resume(arg) {
+                State::Yielded(y) => out.push(y),
+                State::Complete(r) => {
+                    out.push(r);
+                    return out;
+                }
+            }
+
+            arg += 1;
+        }
+    }
+
+    #[test]
+    fn resume_arg() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, a| {
+            let (c, b) = c.r#yield(a * 10)?;
+            let (c, d) = c.r#yield(b * 10)?;
+            c.done(d * 10)
+        });
+
+        assert_eq!(drive(Pin::new(&mut coro)), [10, 20, 30]);
+    }
+
+    #[test]
+    fn from_nightly() {
+        let mut a = [1u8; STACK_MINIMUM];
+        // Resuming the inner coroutine takes more stack.
+        let mut b = [1u8; STACK_MINIMUM * 4];
+
+        // Any nightly generator will do, even a coroutine.
+        let inner = Coroutine::new(&mut a, |c, a| {
+            let (c, b) = c.r#yield(a * 10)?;
+            c.done(b * 10)
+        });
+        let mut coro = Coroutine::from_nightly(&mut b, inner);
+
+        assert_eq!(drive(Pin::new(&mut coro)), [10, 20]);
+    }
+
+    #[test]
+    #[cfg(has_coroutine_trait)]
+    fn from_literal() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let generator = #[coroutine]
+        |a: i32| {
+            let b = yield a * 10;
+            let d = yield b * 10;
+            d * 10
+        };
+        let mut coro = Coroutine::from_nightly(&mut stack, generator);
+
+        assert_eq!(drive(Pin::new(&mut coro)), [10, 20, 30]);
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{stack_allocator, AllocatedStack, Stack, StackAllocator, StackOptions};
+use core::ops::{Deref, DerefMut};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+
+/// What a `StackPool` does with a stack when it is returned.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reclaim {
+    /// Keep the stack as it is. This is the cheapest option, but whatever the
+    /// previous coroutine left on the stack is visible to the next one.
+    Keep,
+
+    /// Overwrite the stack with zeros.
+    Zero,
+
+    /// Let the OS release the stack's physical pages (e.g. with
+    /// `madvise(MADV_DONTNEED)`). The pages are faulted in again on the next
+    /// use. The contents of the stack are unspecified afterwards. Stacks from
+    /// other allocators are passed to `StackAllocator::discard()`.
+    Discard,
+
+    /// Keep the top of the stack, this many bytes of it, and discard the pages
+    /// below like `Discard` does.
+    ///
+    /// Most coroutines only ever use the top of their stack, which the next
+    /// one will fault in again right away, while a few go deep. This keeps
+    /// the pages every coroutine uses, and lets the OS release the ones that
+    /// only the deep ones touched, so that a large pool of stacks doesn't
+    /// keep the memory of its deepest coroutines forever.
+    Trim(usize),
+}
+
+struct Shared {
+    size: usize,
+    capacity: usize,
+    reclaim: Reclaim,
+    allocator: &'static dyn StackAllocator,
+    stacks: Mutex<Vec<AllocatedStack>>,
+}
+
+/// A pool of reusable stacks.
+///
+/// Allocating a fresh stack, and faulting in its pages, for every coroutine
+/// can be expensive. A `StackPool` hands out `PooledStack`s which return
+/// their memory to the pool when dropped, so it can be used again.
+///
+/// Cloning a `StackPool` gives another handle to the same pool.
+#[derive(Clone)]
+pub struct StackPool(Arc<Shared>);
+
+impl StackPool {
+    /// Creates an empty pool of stacks with guard pages, from the stack
+    /// allocator (see `set_stack_allocator()`). By default, these are
+    /// mapped like `ProtectedStack`s.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the stacks in the pool. See
+    ///   `ProtectedStack::new()`.
+    /// * `capacity` - The maximum number of idle stacks kept in the pool.
+    ///   Stacks returned to a full pool are freed.
+    /// * `reclaim` - What to do with a stack when it is returned.
+    pub fn new(size: usize, capacity: usize, reclaim: Reclaim) -> Self {
+        Self::with_allocator(size, capacity, reclaim, stack_allocator())
+    }
+
+    /// Creates an empty pool, like `new()`, of stacks from `allocator`.
+    pub fn with_allocator(
+        size: usize,
+        capacity: usize,
+        reclaim: Reclaim,
+        allocator: &'static dyn StackAllocator,
+    ) -> Self {
+        StackPool(Arc::new(Shared {
+            size,
+            capacity,
+            reclaim,
+            allocator,
+            stacks: Mutex::new(Vec::with_capacity(capacity)),
+        }))
+    }
+
+    /// Takes a stack from the pool, allocating a new one if the pool is
+    /// empty.
+    pub fn get(&self) -> Result<PooledStack> {
+        let stack = self.0.stacks.lock().unwrap().pop();
+
+        let stack = match stack {
+            Some(stack) => stack,
+            None => {
+                let options = StackOptions::new(self.0.size, true);
+                AllocatedStack::new_in(options, self.0.allocator)
+                    .ok_or_else(|| Error::from(ErrorKind::OutOfMemory))?
+            }
+        };
+
+        Ok(PooledStack {
+            stack: Some(stack),
+            pool: self.0.clone(),
+        })
+    }
+
+    /// Returns the number of idle stacks in the pool.
+    pub fn len(&self) -> usize {
+        self.0.stacks.lock().unwrap().len()
+    }
+
+    /// Returns `true` if there are no idle stacks in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A stack borrowed from a `StackPool`.
+///
+/// The stack goes back to the pool when the `PooledStack` is dropped.
+pub struct PooledStack {
+    stack: Option<AllocatedStack>,
+    pool: Arc<Shared>,
+}
+
+impl Drop for PooledStack {
+    fn drop(&mut self) {
+        let mut stack = match self.stack.take() {
+            Some(stack) => stack,
+            None => return,
+        };
+
+        let mut stacks = self.pool.stacks.lock().unwrap();
+        if stacks.len() >= self.pool.capacity {
+            return;
+        }
+
+        match self.pool.reclaim {
+            Reclaim::Keep => {}
+            Reclaim::Zero => {
+                for b in stack.iter_mut() {
+                    *b = 0;
+                }
+            }
+            Reclaim::Discard => stack.discard(usize::MAX),
+            Reclaim::Trim(keep) => {
+                let len = Stack::len(&stack).saturating_sub(keep);
+                stack.discard(len);
+            }
+        }
+
+        stacks.push(stack);
+    }
+}
+
+impl Deref for PooledStack {
+    type Target = AllocatedStack;
+
+    fn deref(&self) -> &AllocatedStack {
+        self.stack.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledStack {
+    fn deref_mut(&mut self) -> &mut AllocatedStack {
+        self.stack.as_mut().unwrap()
+    }
+}
+
+unsafe impl Stack for PooledStack {
+    fn bottom(&mut self) -> *mut u8 {
+        Stack::bottom(&mut **self)
+    }
+
+    fn len(&self) -> usize {
+        Stack::len(&**self)
+    }
+}
+
+impl AsRef<[u8]> for PooledStack {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for PooledStack {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Coroutine, Generator, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn reuse() {
+        let pool = StackPool::new(STACK_MINIMUM, 1, Reclaim::Zero);
+
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        let addr = a.as_ptr();
+        assert!(pool.is_empty());
+
+        drop(a);
+        drop(b);
+        assert_eq!(pool.len(), 1);
+
+        let mut stack = pool.get().unwrap();
+        assert_eq!(stack.as_ptr(), addr);
+        assert!(pool.is_empty());
+
+        stack[0] = 1;
+        let mut coro = Coroutine::new(stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        drop(coro);
+        assert!(pool.get().unwrap().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn trim() {
+        let pool = StackPool::new(STACK_MINIMUM * 4, 1, Reclaim::Trim(STACK_MINIMUM));
+
+        let mut stack = pool.get().unwrap();
+        let len = stack.len();
+        for b in stack.iter_mut() {
+            *b = 1;
+        }
+
+        drop(stack);
+        let stack = pool.get().unwrap();
+        assert!(stack[..len - STACK_MINIMUM].iter().all(|b| *b == 0));
+        assert!(stack[len - STACK_MINIMUM..].iter().all(|b| *b == 1));
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! With the `registry` feature, every coroutine is entered into a global
+//! registry when it is spawned, and removed when it is dropped. The registry
+//! keeps the name of each one, its state, and where it last suspended itself,
+//! which `coroutines()` and `dump()` report. This is meant for debugging, for
+//! finding out what a hung program's coroutines are waiting on.
+//!
+//! The site is recorded by `Control::r#yield()`, `yield_pending()` and
+//! `maybe_yield()`, which take it from their caller. Primitives built on top
+//! of them, like `sync::Mutex::lock()`, report a site of their own.
+//!
+//! The registry also keeps a list of the coroutines for debuggers, with the
+//! address of each one's Context. See `debug`.
+//!
+//! Without it, `Registered` is an empty type and all of this compiles away.
+
+#[cfg(feature = "registry")]
+mod imp {
+    use crate::debug::{self, DebugNode, DebugState};
+    use crate::CoroutineState;
+    use core::fmt::Write;
+    use core::panic::Location;
+    use core::ptr::{null, null_mut};
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Where a coroutine last suspended itself.
+    #[derive(Clone, Copy)]
+    pub(crate) struct Site(Option<&'static Location<'static>>);
+
+    impl Site {
+        pub(crate) const UNKNOWN: Site = Site(None);
+    }
+
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    static LIVE: Mutex<BTreeMap<u64, Arc<Mutex<CoroutineInfo>>>> = Mutex::new(BTreeMap::new());
+
+    /// What the registry knows about a live coroutine. See `coroutines()`.
+    ///
+    /// Requires the `registry` feature.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct CoroutineInfo {
+        /// A number identifying the coroutine, unique within the process.
+        pub id: u64,
+
+        /// The coroutine's name. See `Coroutine::name()`.
+        pub name: Option<String>,
+
+        /// The coroutine's state. See `Coroutine::state()`.
+        pub state: CoroutineState,
+
+        /// Whether the coroutine is running, or a coroutine it resumed is.
+        pub running: bool,
+
+        /// Where the coroutine last yielded, if it has.
+        pub site: Option<&'static Location<'static>>,
+    }
+
+    /// Returns every coroutine which hasn't been dropped yet, in the order
+    /// they were spawned.
+    ///
+    /// Requires the `registry` feature.
+    pub fn coroutines() -> Vec<CoroutineInfo> {
+        let live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+        live.values()
+            .map(|info| info.lock().unwrap_or_else(|e| e.into_inner()).clone())
+            .collect()
+    }
+
+    /// Runs `f` with the registry, and so the list of `debug`, locked.
+    #[cfg(test)]
+    pub(crate) fn locked<T>(f: impl FnOnce() -> T) -> T {
+        let _live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+        f()
+    }
+
+    /// Formats a table of every coroutine which hasn't been dropped yet: its
+    /// id, name, state and where it last yielded. See `coroutines()`.
+    ///
+    /// Requires the `registry` feature.
+    pub fn dump() -> String {
+        let mut out = String::from("ID    NAME                 STATE      SITE\n");
+
+        for info in coroutines() {
+            let state = match info.state {
+                _ if info.running => "Running",
+                CoroutineState::Created => "Created",
+                CoroutineState::Suspended => "Suspended",
+                CoroutineState::Finished => "Finished",
+            };
+
+            let _ = write!(
+                out,
+                "{:<5} {:<20} {:<10} ",
+                info.id,
+                info.name.as_deref().unwrap_or("-"),
+                state
+            );
+
+            let _ = match info.site {
+                Some(site) => writeln!(out, "{}", site),
+                None => writeln!(out, "-"),
+            };
+        }
+
+        out
+    }
+
+    /// The entry of a coroutine in the registry, which it leaves when this
+    /// is dropped, and its node in the list of the debuggers.
+    pub(crate) struct Registered(Arc<Mutex<CoroutineInfo>>, *mut DebugNode);
+
+    // The node is only touched with the registry locked, or through the
+    // `Coroutine` which owns it.
+    unsafe impl Send for Registered {}
+    unsafe impl Sync for Registered {}
+
+    impl Registered {
+        /// Registers the coroutine whose Context is at `ctx`.
+        pub(crate) fn new(ctx: *mut u8) -> Self {
+            let id = NEXT.fetch_add(1, Ordering::Relaxed);
+            let info = Arc::new(Mutex::new(CoroutineInfo {
+                id,
+                name: None,
+                state: CoroutineState::Created,
+                running: false,
+                site: None,
+            }));
+
+            let node = Box::into_raw(Box::new(DebugNode {
+                next: null_mut(),
+                prev: null_mut(),
+                id,
+                ctx,
+                state: DebugState::Created,
+                name: null(),
+                name_len: 0,
+            }));
+
+            let mut live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = live.insert(id, info.clone());
+            unsafe { debug::link(node) };
+            Registered(info, node)
+        }
+
+        fn update(&self, f: impl FnOnce(&mut CoroutineInfo)) {
+            let mut info = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut info);
+
+            // The name stays put until it is replaced, along with the node's.
+            let node = self.1;
+            unsafe {
+                (*node).state = match info.state {
+                    _ if info.running => DebugState::Running,
+                    CoroutineState::Created => DebugState::Created,
+                    CoroutineState::Suspended => DebugState::Suspended,
+                    CoroutineState::Finished => DebugState::Finished,
+                };
+                (*node).name = info.name.as_ref().map_or(null(), |n| n.as_ptr());
+                (*node).name_len = info.name.as_ref().map_or(0, |n| n.len());
+            }
+        }
+
+        pub(crate) fn set_name(&self, name: Option<&str>) {
+            self.update(|info| info.name = name.map(String::from));
+        }
+
+        /// Records that the coroutine is being resumed.
+        pub(crate) fn resumed(&self) {
+            self.update(|info| info.running = true);
+        }
+
+        /// Records that the coroutine has switched back, and where it was
+        /// suspended, if it is.
+        pub(crate) fn suspended(&self, state: CoroutineState, site: Site) {
+            self.update(|info| {
+                info.running = false;
+                info.state = state;
+                if site.0.is_some() {
+                    info.site = site.0;
+                }
+            });
+        }
+    }
+
+    impl Drop for Registered {
+        fn drop(&mut self) {
+            let id = self.0.lock().unwrap_or_else(|e| e.into_inner()).id;
+            let mut live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = live.remove(&id);
+            unsafe {
+                debug::unlink(self.1);
+                drop(Box::from_raw(self.1));
+            }
+        }
+    }
+
+    /// Returns the site of the caller.
+    #[track_caller]
+    #[inline(always)]
+    pub(crate) fn site() -> Site {
+        Site(Some(Location::caller()))
+    }
+}
+
+#[cfg(not(feature = "registry"))]
+mod imp {
+    use crate::CoroutineState;
+
+    #[derive(Clone, Copy)]
+    pub(crate) struct Site;
+
+    impl Site {
+        pub(crate) const UNKNOWN: Site = Site;
+    }
+
+    pub(crate) struct Registered;
+
+    impl Registered {
+        #[inline(always)]
+        pub(crate) fn new(_ctx: *mut u8) -> Self {
+            Registered
+        }
+
+        #[cfg(feature = "alloc")]
+        #[inline(always)]
+        pub(crate) fn set_name(&self, _name: Option<&str>) {}
+
+        #[inline(always)]
+        pub(crate) fn resumed(&self) {}
+
+        #[inline(always)]
+        pub(crate) fn suspended(&self, _state: CoroutineState, _site: Site) {}
+    }
+
+    #[inline(always)]
+    pub(crate) fn site() -> Site {
+        Site
+    }
+}
+
+#[cfg(feature = "registry")]
+pub use self::imp::{coroutines, dump, CoroutineInfo};
+pub(crate) use self::imp::{site, Registered, Site};
+
+#[cfg(all(test, feature = "registry"))]
+pub(crate) use self::imp::locked;
+
+#[cfg(all(test, feature = "registry"))]
+mod tests {
+    use super::*;
+    use crate::{Coroutine, CoroutineState, Generator, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    fn find(id: u64) -> Option<CoroutineInfo> {
+        coroutines().into_iter().find(|info| info.id == id)
+    }
+
+    #[test]
+    fn registry() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut coro = Coroutine::builder()
+            .name("registered")
+            .spawn_on(&mut stack[..], |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done(2)
+            });
+
+        let info = coroutines()
+            .into_iter()
+            .find(|info| info.name.as_deref() == Some("registered"))
+            .unwrap();
+        assert_eq!(info.state, CoroutineState::Created);
+        assert_eq!(info.site, None);
+
+        let _ = Pin::new(&mut coro).resume();
+        let info = find(info.id).unwrap();
+        assert_eq!(info.state, CoroutineState::Suspended);
+        assert!(!info.running);
+        assert_eq!(info.site.unwrap().file(), file!());
+
+        let line = dump()
+            .lines()
+            .find(|line| line.starts_with(&format!("{:<5} ", info.id)))
+            .unwrap()
+            .to_string();
+        assert!(line.contains("registered"), "{}", line);
+        assert!(line.contains("Suspended"), "{}", line);
+        assert!(line.contains(file!()), "{}", line);
+
+        drop(coro);
+        assert_eq!(find(info.id), None);
+    }
+}
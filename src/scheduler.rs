@@ -0,0 +1,1086 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cooperative scheduling of multiple coroutines.
+
+use crate::sync::WaitResult;
+use crate::{Budget, Control, Coroutine, CoroutineError, GeneratorState, ResumeState};
+use core::cmp::Reverse;
+use core::mem::take;
+use core::pin::Pin;
+use core::ptr::null;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+mod threaded;
+
+pub use self::threaded::ThreadPool;
+
+/// The identifiers of the coroutines whose wakers have been woken.
+#[derive(Default)]
+struct Woken {
+    ids: Mutex<Vec<usize>>,
+    cond: Condvar,
+}
+
+/// The value a coroutine returned, or why it didn't, once it has finished.
+struct Joined<R> {
+    finished: bool,
+    result: Option<Result<R, CoroutineError>>,
+    /// The coroutine joining it.
+    joiner: Option<Waker>,
+}
+
+/// Where a coroutine's `JoinHandle` finds what it returned.
+struct Slot<R> {
+    id: usize,
+    /// The priority of the coroutine on an `Executor`.
+    priority: AtomicU8,
+    joined: Mutex<Joined<R>>,
+    cond: Condvar,
+}
+
+impl<R> Slot<R> {
+    fn new(id: usize) -> Self {
+        Slot {
+            id,
+            priority: AtomicU8::new(0),
+            joined: Mutex::new(Joined {
+                finished: false,
+                result: None,
+                joiner: None,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Hands what the coroutine returned to its handle, unless it has
+    /// finished already.
+    fn set(&self, result: Result<R, CoroutineError>) {
+        let mut joined = self.joined.lock().unwrap();
+        if !joined.finished {
+            joined.finished = true;
+            joined.result = Some(result);
+            let joiner = joined.joiner.take();
+            drop(joined);
+
+            self.cond.notify_all();
+            if let Some(waker) = joiner {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A coroutine, along with the waker it is resumed with.
+struct Task<'a, Y, R, S> {
+    id: usize,
+    coroutine: Coroutine<'a, (), Y, R, S>,
+    waker: Waker,
+    slot: Arc<Slot<R>>,
+    /// The step at which it entered the run queue.
+    since: u64,
+    metrics: TaskMetrics,
+}
+
+impl<Y, R, S> Task<'_, Y, R, S> {
+    /// Returns `true` if its `JoinHandle` is still around.
+    fn joined(&self) -> bool {
+        Arc::strong_count(&self.slot) > 1
+    }
+}
+
+impl<Y, R, S> Drop for Task<'_, Y, R, S> {
+    fn drop(&mut self) {
+        // Unless it has finished already, it is dropped along with the
+        // executor, which cancels it.
+        self.slot.set(Err(CoroutineError::Canceled));
+    }
+}
+
+/// A snapshot of the state of an `Executor`. See `Executor::metrics()`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// The number of coroutines in the run queue.
+    pub ready: usize,
+
+    /// The number of coroutines waiting for an event.
+    pub waiting: usize,
+
+    /// The number of times coroutines were resumed so far, summed over all
+    /// of them, including those which have completed since. Every resume
+    /// switches into a coroutine and back out of it; switches a coroutine
+    /// makes on its own, such as into a nested one, aren't counted.
+    pub resumes: u64,
+
+    /// The time spent running coroutines so far.
+    pub run_time: Duration,
+}
+
+impl Metrics {
+    /// Returns the number of coroutines which have not completed yet.
+    pub fn live(&self) -> usize {
+        self.ready + self.waiting
+    }
+}
+
+/// How much a coroutine has run so far. See `Executor::task_metrics()`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TaskMetrics {
+    /// The number of times it has been resumed.
+    pub resumes: u64,
+
+    /// The time it has spent running.
+    pub run_time: Duration,
+}
+
+/// Runs a set of coroutines in round-robin order.
+///
+/// Each coroutine runs until it yields or completes, then the next one gets
+/// its turn. Completed coroutines are removed from the executor.
+///
+/// Coroutines with a higher priority (see `set_priority()`) get their turn
+/// first. So that the others don't starve, a coroutine gains one level of
+/// priority for every step it spends in the run queue, until it runs: one of
+/// priority 0 runs before one of priority 10 which joined the run queue 10
+/// steps or more after it. Among coroutines of the same priority, the order is
+/// round-robin.
+///
+/// A coroutine may also wait for an event (see `Control::yield_pending()`).
+/// It then sits out until its waker is woken, which may happen on any thread.
+pub struct Executor<'a, Y, R, S = &'a mut [u8]> {
+    queue: VecDeque<Task<'a, Y, R, S>>,
+    waiting: HashMap<usize, Task<'a, Y, R, S>>,
+    woken: Arc<Woken>,
+    next: usize,
+    steps: u64,
+    run_time: Duration,
+    budget: Option<Arc<Budget>>,
+}
+
+impl<'a, Y, R, S> Default for Executor<'a, Y, R, S> {
+    fn default() -> Self {
+        Executor {
+            queue: VecDeque::new(),
+            waiting: HashMap::new(),
+            woken: Arc::default(),
+            next: 0,
+            steps: 0,
+            run_time: Duration::default(),
+            budget: None,
+        }
+    }
+}
+
+impl<'a, Y, R, S> Executor<'a, Y, R, S> {
+    /// Creates an empty executor.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a coroutine to the end of the run queue.
+    ///
+    /// Returns a `JoinHandle` to wait for the value the coroutine returns.
+    /// Its `id()` is passed to the callback of `step()` and `run()` along with
+    /// the values the coroutine yields. Dropping the handle detaches the
+    /// coroutine: the value it returns is passed to the callback too, and
+    /// should it panic, the panic is propagated to the caller of `step()`.
+    pub fn spawn(&mut self, coroutine: Coroutine<'a, (), Y, R, S>) -> JoinHandle<R> {
+        let id = self.next;
+        self.next += 1;
+
+        let waker = waker(id, self.woken.clone());
+        let slot = Arc::new(Slot::new(id));
+        self.enqueue(Task {
+            id,
+            coroutine,
+            waker,
+            slot: slot.clone(),
+            since: 0,
+            metrics: TaskMetrics::default(),
+        });
+        JoinHandle(slot)
+    }
+
+    fn enqueue(&mut self, mut task: Task<'a, Y, R, S>) {
+        task.since = self.steps;
+        self.queue.push_back(task);
+    }
+
+    /// Returns the number of coroutines which have not completed yet,
+    /// including those waiting for an event.
+    pub fn len(&self) -> usize {
+        self.queue.len() + self.waiting.len()
+    }
+
+    /// Returns `true` if all coroutines have completed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sets the budget to lend to every coroutine this executor resumes, so
+    /// that `Control::maybe_yield()` suspends those which run too long.
+    pub fn set_budget(&mut self, budget: Option<Arc<Budget>>) {
+        self.budget = budget;
+    }
+
+    /// Returns how many coroutines are ready or waiting, and how much they
+    /// have run in total, including those which have completed since.
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            ready: self.queue.len(),
+            waiting: self.waiting.len(),
+            resumes: self.steps,
+            run_time: self.run_time,
+        }
+    }
+
+    /// Returns how much the coroutine `id` has run so far, or `None` if there
+    /// is no such coroutine, because it has completed.
+    pub fn task_metrics(&self, id: usize) -> Option<TaskMetrics> {
+        self.queue
+            .iter()
+            .find(|t| t.id == id)
+            .or_else(|| self.waiting.get(&id))
+            .map(|t| t.metrics)
+    }
+
+    /// Resumes the coroutine whose turn it is.
+    ///
+    /// The value it yields is passed to `f`, and so is the value it returns,
+    /// unless its `JoinHandle` is still around to take it. A coroutine which
+    /// waits for an event instead leaves the run queue until it is woken.
+    /// Returns `false` if there was no coroutine to resume, because all of
+    /// them have completed or are waiting.
+    pub fn step<F>(&mut self, mut f: F) -> bool
+    where
+        F: FnMut(usize, GeneratorState<Y, R>),
+    {
+        // Move the coroutines which have been woken back into the run queue.
+        let woken = take(&mut *self.woken.ids.lock().unwrap());
+        for id in woken {
+            if let Some(task) = self.waiting.remove(&id) {
+                self.enqueue(task);
+            }
+        }
+
+        // The first of those with the highest priority, counting the steps
+        // they have waited for.
+        let steps = self.steps;
+        let next = self
+            .queue
+            .iter()
+            .enumerate()
+            .max_by_key(|&(i, t)| {
+                let priority = t.slot.priority.load(Ordering::Relaxed);
+                (u64::from(priority) + steps - t.since, Reverse(i))
+            })
+            .map(|(i, _)| i);
+
+        let mut task = match next.and_then(|i| self.queue.remove(i)) {
+            None => return false,
+            Some(task) => task,
+        };
+        self.steps += 1;
+
+        let mut cx = Context::from_waker(&task.waker);
+        let id = task.id;
+
+        if let Some(budget) = &self.budget {
+            budget.refill();
+            task.coroutine.lend_budget(&**budget);
+        }
+
+        let start = Instant::now();
+        let coroutine = Pin::new(&mut task.coroutine);
+        let state = catch_unwind(AssertUnwindSafe(|| coroutine.poll_resume((), &mut cx)));
+        task.coroutine.lend_budget(null());
+        let elapsed = start.elapsed();
+        task.metrics.resumes += 1;
+        task.metrics.run_time += elapsed;
+        self.run_time += elapsed;
+
+        let state = match state {
+            Ok(state) => state,
+            Err(payload) if task.joined() => {
+                task.slot.set(Err(CoroutineError::Panicked(payload)));
+                return true;
+            }
+            Err(payload) => resume_unwind(payload),
+        };
+
+        match state {
+            ResumeState::Yielded(y) => {
+                self.enqueue(task);
+                f(id, GeneratorState::Yielded(y));
+            }
+
+            // It may have been woken already, before it even suspended.
+            ResumeState::Pending => {
+                let mut woken = self.woken.ids.lock().unwrap();
+                if woken.contains(&id) {
+                    woken.retain(|&w| w != id);
+                    drop(woken);
+                    self.enqueue(task);
+                } else {
+                    let _ = self.waiting.insert(id, task);
+                }
+            }
+
+            ResumeState::Complete(r) if task.joined() => task.slot.set(Ok(r)),
+            ResumeState::Complete(r) => f(id, GeneratorState::Complete(r)),
+        }
+
+        true
+    }
+
+    /// Resumes the coroutines in turn until all of them have completed, or
+    /// all that are left are waiting for events.
+    ///
+    /// Every value yielded or returned is passed to `f`.
+    pub fn run<F>(&mut self, mut f: F)
+    where
+        F: FnMut(usize, GeneratorState<Y, R>),
+    {
+        while self.step(&mut f) {}
+    }
+
+    /// Blocks the current thread until one of the coroutines waiting for an
+    /// event is woken.
+    ///
+    /// Returns right away if one has been woken already, or if none is
+    /// waiting.
+    pub fn wait(&self) {
+        let mut ids = self.woken.ids.lock().unwrap();
+        while ids.is_empty() && !self.waiting.is_empty() {
+            ids = self.woken.cond.wait(ids).unwrap();
+        }
+    }
+}
+
+/// A handle to a coroutine spawned on an `Executor` or a `ThreadPool`, to
+/// wait for the value it returns.
+///
+/// If the coroutine panicked, `CoroutineError::Panicked` is returned instead,
+/// with the payload. If the executor or the pool was dropped before the
+/// coroutine completed, it is `CoroutineError::Canceled`.
+pub struct JoinHandle<R>(Arc<Slot<R>>);
+
+impl<R> JoinHandle<R> {
+    /// Suspends the calling coroutine until the coroutine of this handle has
+    /// finished. Hands back the `Control`, along with what the coroutine
+    /// returned.
+    ///
+    /// Like the primitives in `sync`, this needs the calling coroutine to be
+    /// driven by a task, such as the `Executor` or the `ThreadPool` itself.
+    pub fn join<'a, Y, T>(
+        self,
+        mut c: Control<'a, (), Y, T>,
+    ) -> WaitResult<'a, Y, T, Result<R, CoroutineError>> {
+        /// Forgets the joiner's waker once it stops waiting, even if it is
+        /// canceled.
+        struct Leave<'s, R>(&'s Slot<R>);
+
+        impl<R> Drop for Leave<'_, R> {
+            fn drop(&mut self) {
+                let mut joined = self.0.joined.lock().unwrap_or_else(|e| e.into_inner());
+                joined.joiner = None;
+            }
+        }
+
+        let _leave = Leave(&self.0);
+        loop {
+            {
+                let mut joined = self.0.joined.lock().unwrap();
+                if let Some(result) = joined.result.take() {
+                    return Ok((c, result));
+                }
+
+                // Only this handle joins, so there is a single waker to keep,
+                // which stays the same from one resume to the next unless the
+                // coroutine moves to another task.
+                if let Some(waker) = c.waker() {
+                    if !joined.joiner.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                        joined.joiner = Some(waker.clone());
+                    }
+                }
+            }
+
+            c = c.suspend(ResumeState::Pending)?.0;
+        }
+    }
+
+    /// Blocks the current thread until the coroutine has finished, and
+    /// returns what it returned.
+    ///
+    /// Unless the coroutine has already finished, an `Executor` has to be
+    /// run on another thread for this to return.
+    pub fn wait(self) -> Result<R, CoroutineError> {
+        let mut joined = self.0.joined.lock().unwrap();
+        loop {
+            match joined.result.take() {
+                Some(r) => return r,
+                None => joined = self.0.cond.wait(joined).unwrap(),
+            }
+        }
+    }
+
+    /// Returns `true` if the coroutine has completed, or was canceled.
+    pub fn is_finished(&self) -> bool {
+        self.0.joined.lock().unwrap().finished
+    }
+
+    /// Sets the priority of the coroutine on an `Executor`. Coroutines start
+    /// with priority 0, the lowest. The `ThreadPool` doesn't prioritize
+    /// coroutines.
+    ///
+    /// Returns `false` if the coroutine has finished already.
+    pub fn set_priority(&self, priority: u8) -> bool {
+        self.0.priority.store(priority, Ordering::Relaxed);
+        !self.is_finished()
+    }
+
+    /// Returns the identifier of the coroutine, unique within the `Executor`
+    /// or `ThreadPool` it was spawned on.
+    pub fn id(&self) -> usize {
+        self.0.id
+    }
+}
+
+/// Coroutines which must all finish before the `task_scope()` they were
+/// spawned in returns.
+pub struct TaskScope<'a, E, S = &'a mut [u8]>(Executor<'a, (), Result<(), E>, S>);
+
+impl<'a, E, S> TaskScope<'a, E, S> {
+    /// Adds a child coroutine to the scope.
+    ///
+    /// It does not run until the closure passed to `task_scope()` has
+    /// returned. The values it yields are ignored.
+    pub fn spawn(&mut self, coroutine: Coroutine<'a, (), (), Result<(), E>, S>) {
+        let _ = self.0.spawn(coroutine);
+    }
+
+    /// Returns the number of children which have not completed yet.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns `true` if there are no children left.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Runs child coroutines in a scope which they can't outlive.
+///
+/// The closure spawns the children. Once it returns, they are run in turn, as
+/// by an `Executor`, until all of them have completed; while all of those left
+/// wait for events, the thread blocks. The first child to return an error
+/// ends the scope: the others are canceled, so their stacks are unwound, and
+/// the error is returned. Likewise, if a child panics, the others are canceled
+/// and the panic goes on in the caller. If the closure itself panics, the
+/// children are dropped without having run.
+pub fn task_scope<'a, E, S, F, T>(f: F) -> Result<T, E>
+where
+    E: 'a,
+    F: FnOnce(&mut TaskScope<'a, E, S>) -> T,
+{
+    // Dropping the executor cancels whichever children are left, even if
+    // `f` or one of them panics.
+    let mut scope = TaskScope(Executor::new());
+    let value = f(&mut scope);
+
+    let mut error = None;
+    while !scope.is_empty() {
+        while error.is_none()
+            && scope.0.step(|_, state| {
+                if let GeneratorState::Complete(Err(e)) = state {
+                    error = Some(e);
+                }
+            })
+        {}
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        scope.0.wait();
+    }
+
+    Ok(value)
+}
+
+/// Resumes whichever of several coroutines is ready.
+///
+/// A coroutine is ready unless it waits for an event (see
+/// `Control::yield_pending()`) and its waker hasn't been woken since. Each
+/// coroutine is known by an index of its own, which must stay the same from
+/// one call to the next. The `select!` macro does this for you.
+#[derive(Default)]
+pub struct Select {
+    woken: Arc<Woken>,
+    wakers: Vec<Waker>,
+    waiting: Vec<bool>,
+}
+
+impl Select {
+    /// Creates a `Select` for which every coroutine is ready.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resumes the coroutine known by `index` if it is ready.
+    ///
+    /// Returns the value it yielded or returned, or `None` if it wasn't ready,
+    /// has finished already, or waits for an event now.
+    pub fn poll<Y, R, S>(
+        &mut self,
+        index: usize,
+        coroutine: Pin<&mut Coroutine<'_, (), Y, R, S>>,
+    ) -> Option<GeneratorState<Y, R>> {
+        while self.wakers.len() <= index {
+            let id = self.wakers.len();
+            self.wakers.push(waker(id, self.woken.clone()));
+            self.waiting.push(false);
+        }
+
+        for id in take(&mut *self.woken.ids.lock().unwrap()) {
+            self.waiting[id] = false;
+        }
+
+        if self.waiting[index] || coroutine.is_finished() {
+            return None;
+        }
+
+        let mut cx = Context::from_waker(&self.wakers[index]);
+        match coroutine.poll_resume((), &mut cx) {
+            ResumeState::Yielded(y) => Some(GeneratorState::Yielded(y)),
+            ResumeState::Complete(r) => Some(GeneratorState::Complete(r)),
+
+            // It may have been woken already, before it even suspended.
+            ResumeState::Pending => {
+                let mut woken = self.woken.ids.lock().unwrap();
+                if woken.contains(&index) {
+                    woken.retain(|&w| w != index);
+                } else {
+                    self.waiting[index] = true;
+                }
+
+                None
+            }
+        }
+    }
+
+    /// Blocks the current thread until one of the coroutines waiting for an
+    /// event is woken.
+    ///
+    /// Returns `false` right away if none is waiting.
+    pub fn wait(&self) -> bool {
+        if !self.waiting.contains(&true) {
+            return false;
+        }
+
+        let mut ids = self.woken.ids.lock().unwrap();
+        while ids.is_empty() {
+            ids = self.woken.cond.wait(ids).unwrap();
+        }
+
+        true
+    }
+}
+
+/// Resumes the first of several coroutines which is ready, and evaluates the
+/// arm that goes with it.
+///
+/// Takes a `scheduler::Select`, followed by arms of the form
+/// `pattern = coroutine => expression`. The coroutines take `()`, but may
+/// yield and return different types. The first one which is ready is resumed,
+/// and its `GeneratorState` is bound to the pattern, which must be
+/// irrefutable. While all of them wait for events, the thread blocks. Panics
+/// if all of them have finished.
+///
+/// ```
+/// use frenetic::scheduler::Select;
+/// use frenetic::{select, Coroutine, GeneratorState, WakerSlot, STACK_MINIMUM};
+///
+/// let slot = WakerSlot::new();
+/// let mut a = [1u8; STACK_MINIMUM * 4];
+/// let mut b = [1u8; STACK_MINIMUM * 4];
+///
+/// let mut events = Coroutine::<(), (), _, _>::new(&mut a, |c, ()| {
+///     let (c, ()) = c.yield_pending(&slot)?;
+///     c.done("event")
+/// });
+/// let mut ticks = Coroutine::new(&mut b, |c, ()| {
+///     let (c, ()) = c.r#yield(1)?;
+///     slot.wake();
+///     c.done(2)
+/// });
+///
+/// let mut sel = Select::new();
+/// let mut log = Vec::new();
+/// while !events.is_finished() {
+///     select! { sel,
+///         state = events => log.push(format!("{:?}", state)),
+///         state = ticks => log.push(format!("{:?}", state)),
+///     }
+/// }
+///
+/// assert_eq!(log, ["Yielded(1)", "Complete(2)", "Complete(\"event\")"]);
+/// ```
+#[macro_export]
+macro_rules! select {
+    ($select:expr, $($pat:pat = $coroutine:expr => $body:expr),+ $(,)?) => {{
+        let select: &mut $crate::scheduler::Select = &mut $select;
+        let state = loop {
+            let mut index = 0usize;
+            $crate::select!(@poll select, index, [], $($coroutine),+);
+            if !select.wait() {
+                panic!("select! on coroutines which have all finished");
+            }
+        };
+        $crate::select!(@match state; $($pat => $body),+)
+    }};
+
+    // The state of the coroutine at position `n` is wrapped in `n` `Err`s and
+    // an `Ok`, except for the last one, which needs no `Ok`.
+    (@poll $select:ident, $index:ident, [$($wrap:tt)*], $coroutine:expr) => {
+        if let Some(state) = $select.poll($index, ::core::pin::Pin::new(&mut $coroutine)) {
+            break $crate::select!(@wrap [$($wrap)*] state);
+        }
+    };
+    (@poll $select:ident, $index:ident, [$($wrap:tt)*], $coroutine:expr, $($rest:expr),+) => {
+        if let Some(state) = $select.poll($index, ::core::pin::Pin::new(&mut $coroutine)) {
+            break $crate::select!(@wrap [$($wrap)* ok] state);
+        }
+        $index += 1;
+        $crate::select!(@poll $select, $index, [$($wrap)* err], $($rest),+);
+    };
+
+    (@wrap [] $state:ident) => { $state };
+    (@wrap [ok $($rest:tt)*] $state:ident) => {
+        ::core::result::Result::Ok($crate::select!(@wrap [$($rest)*] $state))
+    };
+    (@wrap [err $($rest:tt)*] $state:ident) => {
+        ::core::result::Result::Err($crate::select!(@wrap [$($rest)*] $state))
+    };
+
+    (@match $state:expr; $pat:pat => $body:expr) => {
+        match $state {
+            $pat => $body,
+        }
+    };
+    (@match $state:expr; $pat:pat => $body:expr, $($rest:tt)+) => {
+        match $state {
+            ::core::result::Result::Ok($pat) => $body,
+            ::core::result::Result::Err(state) => $crate::select!(@match state; $($rest)+),
+        }
+    };
+}
+
+/// The waker of a coroutine: records its identifier in `woken`.
+struct Notify {
+    id: usize,
+    woken: Arc<Woken>,
+}
+
+fn waker(id: usize, woken: Arc<Woken>) -> Waker {
+    unsafe fn clone(ptr: *const ()) -> RawWaker {
+        let notify = Arc::from_raw(ptr as *const Notify);
+        let clone = notify.clone();
+        let _ = Arc::into_raw(notify);
+        RawWaker::new(Arc::into_raw(clone) as *const (), &VTABLE)
+    }
+
+    unsafe fn wake(ptr: *const ()) {
+        wake_by_ref(ptr);
+        drop(ptr);
+    }
+
+    unsafe fn wake_by_ref(ptr: *const ()) {
+        let notify = &*(ptr as *const Notify);
+        notify.woken.ids.lock().unwrap().push(notify.id);
+        notify.woken.cond.notify_one();
+    }
+
+    unsafe fn drop(ptr: *const ()) {
+        let _ = Arc::from_raw(ptr as *const Notify);
+    }
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+
+    let notify = Arc::new(Notify { id, woken });
+    unsafe { Waker::from_raw(RawWaker::new(Arc::into_raw(notify) as *const (), &VTABLE)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{WakerSlot, STACK_MINIMUM};
+    use core::sync::atomic::AtomicUsize;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::task::Wake;
+    use std::thread;
+
+    #[test]
+    fn round_robin() {
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let mut executor = Executor::new();
+
+        // Both are detached, so that their return values are passed on too.
+        let x = executor
+            .spawn(Coroutine::new(&mut a, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                let (c, ()) = c.r#yield(2)?;
+                c.done(3)
+            }))
+            .id();
+
+        let y = executor
+            .spawn(Coroutine::new(&mut b, |c, ()| {
+                let (c, ()) = c.r#yield(10)?;
+                c.done(20)
+            }))
+            .id();
+
+        assert_eq!(executor.len(), 2);
+
+        let mut events = Vec::new();
+        executor.run(|id, state| events.push((id, state)));
+
+        assert!(executor.is_empty());
+        assert_eq!(
+            events,
+            [
+                (x, GeneratorState::Yielded(1)),
+                (y, GeneratorState::Yielded(10)),
+                (x, GeneratorState::Yielded(2)),
+                (y, GeneratorState::Complete(20)),
+                (x, GeneratorState::Complete(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn waiting() {
+        let slot = WakerSlot::new();
+        let mut a = [1u8; STACK_MINIMUM];
+
+        let mut executor = Executor::<(), i32, _>::new();
+        let id = executor
+            .spawn(Coroutine::new(&mut a, |c, ()| {
+                let (c, ()) = c.yield_pending(&slot)?;
+                c.done(1)
+            }))
+            .id();
+
+        let mut events = Vec::new();
+        executor.run(|_, state| events.push(state));
+        assert!(events.is_empty());
+        assert_eq!(executor.len(), 1);
+
+        let metrics = executor.metrics();
+        assert_eq!((metrics.ready, metrics.waiting, metrics.live()), (0, 1, 1));
+        assert_eq!(metrics.resumes, 1);
+        assert_eq!(executor.task_metrics(id).unwrap().resumes, 1);
+
+        slot.wake();
+        executor.run(|_, state| events.push(state));
+        assert_eq!(events, [GeneratorState::Complete(1)]);
+        assert!(executor.is_empty());
+        assert_eq!(executor.task_metrics(id), None);
+        assert_eq!(executor.metrics().resumes, 2);
+    }
+
+    #[test]
+    fn join() {
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut c = [1u8; STACK_MINIMUM * 4];
+
+        let mut executor = Executor::<(), i32, _>::new();
+        let child = executor.spawn(Coroutine::new(&mut a, |c, ()| {
+            let (c, ()) = c.r#yield(())?;
+            c.done(6)
+        }));
+
+        // The parent waits for the child, and gets what it returned.
+        let parent = executor.spawn(Coroutine::new(&mut b, move |c, ()| {
+            let (c, r) = child.join(c)?;
+            c.done(r.unwrap() * 7)
+        }));
+
+        let panics = executor.spawn(Coroutine::new(&mut c, |_, ()| panic!("failed")));
+
+        let mut events = Vec::new();
+        executor.run(|_, state| events.push(state));
+        assert!(executor.is_empty());
+        assert_eq!(events, [GeneratorState::Yielded(())]);
+
+        assert_eq!(parent.wait().unwrap(), 42);
+        match panics.wait() {
+            Err(CoroutineError::Panicked(p)) => assert_eq!(p.downcast_ref(), Some(&"failed")),
+            _ => panic!("unexpected join result"),
+        }
+    }
+
+    #[test]
+    fn join_woken_once() {
+        #[derive(Default)]
+        struct Counter(AtomicUsize);
+
+        impl Wake for Counter {
+            fn wake(self: Arc<Self>) {
+                let _ = self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+
+        let mut executor = Executor::<(), i32, _>::new();
+        let child = executor.spawn(Coroutine::new(&mut a, |c, ()| c.done(6)));
+        let mut parent = Coroutine::new(&mut b, move |c: Control<'_, (), (), i32>, ()| {
+            let (c, r) = child.join(c)?;
+            c.done(r.unwrap())
+        });
+
+        let counter = Arc::new(Counter::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // Resumed again before the child has finished, the parent doesn't
+        // register another copy of its waker.
+        for _ in 0..3 {
+            let state = Pin::new(&mut parent).poll_resume((), &mut cx);
+            assert_eq!(state, ResumeState::Pending);
+        }
+
+        executor.run(|_, _| {});
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        let state = Pin::new(&mut parent).poll_resume((), &mut cx);
+        assert_eq!(state, ResumeState::Complete(6));
+    }
+
+    #[test]
+    fn join_canceled() {
+        let mut a = [1u8; STACK_MINIMUM * 16];
+
+        let mut executor = Executor::<(), (), _>::new();
+        let handle = executor.spawn(Coroutine::new(&mut a, |mut c, ()| loop {
+            c = c.r#yield(())?.0;
+        }));
+
+        assert!(executor.step(|_, _| {}));
+        assert!(!handle.is_finished());
+        drop(executor);
+
+        assert!(handle.is_finished());
+        match handle.wait() {
+            Err(CoroutineError::Canceled) => {}
+            _ => panic!("unexpected join result"),
+        }
+    }
+
+    struct Log<'a>(&'a Mutex<Vec<&'static str>>, &'static str);
+
+    impl Drop for Log<'_> {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push(self.1);
+        }
+    }
+
+    #[test]
+    fn scope_joins() {
+        let slot = WakerSlot::new();
+        let log = Mutex::new(Vec::new());
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let result: Result<_, ()> = thread::scope(|t| {
+            task_scope(|s| {
+                s.spawn(Coroutine::new(&mut a, |c, ()| {
+                    let (c, ()) = c.yield_pending(&slot)?;
+                    log.lock().unwrap().push("a");
+                    c.done(Ok(()))
+                }));
+
+                s.spawn(Coroutine::new(&mut b, |c, ()| {
+                    let (c, ()) = c.r#yield(())?;
+                    log.lock().unwrap().push("b");
+
+                    // Only wake `a` from another thread once it waits.
+                    let _ = t.spawn(|| slot.wake());
+                    c.done(Ok(()))
+                }));
+
+                assert_eq!(s.len(), 2);
+                7
+            })
+        });
+
+        assert_eq!(result, Ok(7));
+        assert_eq!(*log.lock().unwrap(), ["b", "a"]);
+    }
+
+    #[test]
+    fn scope_error() {
+        let log = Mutex::new(Vec::new());
+        // `a` is unwound, which takes more than `STACK_MINIMUM` in debug builds.
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let result = task_scope(|s| {
+            s.spawn(Coroutine::new(&mut a[..], |c, ()| {
+                let _log = Log(&log, "a canceled");
+                let (c, ()) = c.r#yield(())?;
+                let (c, ()) = c.r#yield(())?;
+                c.done(Ok(()))
+            }));
+
+            s.spawn(Coroutine::new(&mut b[..], |c, ()| c.done(Err("b failed"))));
+        });
+
+        assert_eq!(result, Err("b failed"));
+        assert_eq!(*log.lock().unwrap(), ["a canceled"]);
+    }
+
+    #[test]
+    fn scope_panic() {
+        let log = Mutex::new(Vec::new());
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+
+        let err = catch_unwind(AssertUnwindSafe(|| {
+            task_scope::<(), _, _, _>(|s| {
+                s.spawn(Coroutine::new(&mut a[..], |c, ()| {
+                    let _log = Log(&log, "a canceled");
+                    let (c, ()) = c.r#yield(())?;
+                    let (c, ()) = c.r#yield(())?;
+                    c.done(Ok(()))
+                }));
+
+                s.spawn(Coroutine::new(&mut b[..], |_, ()| panic!("b panicked")));
+            })
+        }))
+        .unwrap_err();
+
+        assert_eq!(*err.downcast_ref::<&str>().unwrap(), "b panicked");
+        assert_eq!(*log.lock().unwrap(), ["a canceled"]);
+    }
+
+    #[test]
+    fn select() {
+        let slot = WakerSlot::new();
+        let mut a = [1u8; STACK_MINIMUM];
+        let mut b = [1u8; STACK_MINIMUM];
+
+        let mut x = Coroutine::<(), (), _, _>::new(&mut a, |c, ()| {
+            let (c, ()) = c.yield_pending(&slot)?;
+            c.done('x')
+        });
+
+        let mut y = Coroutine::new(&mut b, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            let (c, ()) = c.r#yield(2)?;
+            c.done(3)
+        });
+
+        let mut sel = Select::new();
+        let mut log = Vec::new();
+
+        // `x` waits, so `y` keeps being picked.
+        for _ in 0..2 {
+            crate::select! { sel,
+                _ = x => unreachable!(),
+                state = y => log.push(state),
+            }
+        }
+        assert_eq!(
+            log,
+            [GeneratorState::Yielded(1), GeneratorState::Yielded(2)]
+        );
+
+        slot.wake();
+        let first = crate::select! { sel,
+            state = x => state == GeneratorState::Complete('x'),
+            _ = y => false,
+        };
+        assert!(first);
+
+        // Once `x` has finished, only `y` is left.
+        let last = crate::select! { sel,
+            _ = x => None,
+            state = y => Some(state),
+        };
+        assert_eq!(last, Some(GeneratorState::Complete(3)));
+
+        let err = catch_unwind(AssertUnwindSafe(|| {
+            crate::select! { sel,
+                _ = x => (),
+                _ = y => (),
+            }
+        }))
+        .unwrap_err();
+        assert!(err.downcast_ref::<&str>().unwrap().contains("all finished"));
+    }
+
+    #[test]
+    fn priority() {
+        let mut stacks = [[1u8; STACK_MINIMUM]; 3];
+        let [a, b, c] = &mut stacks;
+
+        let mut executor = Executor::<&str, (), _>::new();
+        let spin = |name| {
+            move |mut c: Control<'_, (), &'static str, ()>, ()| {
+                for _ in 0..20 {
+                    c = c.r#yield(name)?.0;
+                }
+                c.done(())
+            }
+        };
+
+        let low = executor.spawn(Coroutine::new(a, spin("low")));
+        let high = [
+            executor.spawn(Coroutine::new(b, spin("high"))),
+            executor.spawn(Coroutine::new(c, spin("high"))),
+        ];
+        assert!(high.iter().all(|h| h.set_priority(4)));
+
+        let mut order = Vec::new();
+        for _ in 0..30 {
+            let _ = executor.step(|_, state| {
+                if let GeneratorState::Yielded(name) = state {
+                    order.push(name);
+                }
+            });
+        }
+
+        // The first one of high priority goes first; the low one gets one
+        // turn in every few.
+        assert_eq!(order[0], "high");
+        let lows = order.iter().filter(|&&n| n == "low").count();
+        assert!(lows > 0 && lows < 10, "{:?}", order);
+
+        executor.run(|_, _| {});
+        assert!(!low.set_priority(1));
+    }
+}
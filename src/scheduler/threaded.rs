@@ -0,0 +1,513 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An executor running coroutines on a pool of threads.
+//!
+//! Every worker thread has a run queue of its own. A coroutine which yields
+//! goes back to the end of the queue of the worker which resumed it, while
+//! newly spawned and woken coroutines go to a queue all workers share. A
+//! worker with nothing to do takes from the shared queue, then steals from
+//! the back of the other workers' queues, and sleeps if there is nothing to
+//! steal either.
+
+use super::{JoinHandle, Slot};
+use crate::{
+    Control, CoroutineError, Finished, PooledStack, ResumeState, SendCoroutine, StackPool,
+};
+use core::mem::take;
+use core::pin::Pin;
+use core::task::{Context, Waker};
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::Wake;
+use std::thread;
+
+type Queue = Mutex<VecDeque<Arc<Task>>>;
+
+/// A coroutine spawned on the pool, with its return value type erased.
+trait Job: Send {
+    fn poll(&mut self, cx: &mut Context<'_>) -> ResumeState<(), ()>;
+}
+
+struct Body<R: 'static> {
+    coroutine: SendCoroutine<'static, (), (), R, PooledStack>,
+    /// What the coroutine returned, handed to the slot when the job is
+    /// dropped, after the pool has forgotten it.
+    result: Option<core::result::Result<R, CoroutineError>>,
+    slot: Arc<Slot<R>>,
+}
+
+impl<R: Send + 'static> Job for Body<R> {
+    fn poll(&mut self, cx: &mut Context<'_>) -> ResumeState<(), ()> {
+        let coroutine = Pin::new(&mut self.coroutine);
+        match catch_unwind(AssertUnwindSafe(|| coroutine.poll_resume((), cx))) {
+            Ok(ResumeState::Yielded(())) => ResumeState::Yielded(()),
+            Ok(ResumeState::Pending) => ResumeState::Pending,
+            Ok(ResumeState::Complete(r)) => {
+                self.result = Some(Ok(r));
+                ResumeState::Complete(())
+            }
+            Err(payload) => {
+                self.result = Some(Err(CoroutineError::Panicked(payload)));
+                ResumeState::Complete(())
+            }
+        }
+    }
+}
+
+impl<R: 'static> Drop for Body<R> {
+    fn drop(&mut self) {
+        // The pool drops the job once it has forgotten it: when it has
+        // completed, or when the pool is shut down before it could, in which
+        // case it is canceled.
+        let result = self.result.take();
+        self.slot
+            .set(result.unwrap_or(Err(CoroutineError::Canceled)));
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    /// Waiting for its waker to be woken.
+    Idle,
+    /// In a run queue.
+    Queued,
+    /// Being resumed by a worker.
+    Running,
+    /// Being resumed by a worker, and woken meanwhile.
+    Notified,
+    /// Completed.
+    Done,
+}
+
+struct Task {
+    id: usize,
+    job: Mutex<Option<Box<dyn Job>>>,
+    state: Mutex<State>,
+    shared: Arc<Shared>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Idle => {
+                *state = State::Queued;
+                drop(state);
+                self.shared.push(self.clone());
+            }
+            State::Running => *state = State::Notified,
+            _ => {}
+        }
+    }
+}
+
+struct Shared {
+    /// Spawned and woken coroutines.
+    injector: Queue,
+    /// The run queue of every worker.
+    queues: Vec<Queue>,
+    /// Every coroutine which has not completed, to cancel on shutdown.
+    tasks: Mutex<HashMap<usize, Weak<Task>>>,
+    /// The number of sleeping workers.
+    sleeping: Mutex<usize>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+    next: AtomicUsize,
+}
+
+impl Shared {
+    /// Adds a coroutine to the shared queue, and wakes a worker for it.
+    fn push(&self, task: Arc<Task>) {
+        {
+            let mut injector = self.injector.lock().unwrap();
+            if self.shutdown.load(Ordering::Acquire) {
+                return;
+            }
+
+            injector.push_back(task);
+        }
+
+        self.notify();
+    }
+
+    fn notify(&self) {
+        if *self.sleeping.lock().unwrap() > 0 {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Finds a coroutine for the worker `index` to resume.
+    fn find(&self, index: usize) -> Option<Arc<Task>> {
+        if let Some(task) = self.queues[index].lock().unwrap().pop_front() {
+            return Some(task);
+        }
+
+        if let Some(task) = self.injector.lock().unwrap().pop_front() {
+            return Some(task);
+        }
+
+        let n = self.queues.len();
+        (1..n).find_map(|i| self.queues[(index + i) % n].lock().unwrap().pop_back())
+    }
+
+    fn work(&self, index: usize) {
+        loop {
+            let task = match self.find(index) {
+                Some(task) => task,
+                None => {
+                    let mut sleeping = self.sleeping.lock().unwrap();
+                    if self.shutdown.load(Ordering::Acquire) {
+                        return;
+                    }
+
+                    // Check again with the lock held, so that no wakeup is
+                    // lost in between.
+                    match self.find(index) {
+                        Some(task) => task,
+                        None => {
+                            *sleeping += 1;
+                            let mut sleeping = self.wake.wait(sleeping).unwrap();
+                            *sleeping -= 1;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            if self.shutdown.load(Ordering::Acquire) {
+                return;
+            }
+
+            self.run(index, task);
+        }
+    }
+
+    fn run(&self, index: usize, task: Arc<Task>) {
+        *task.state.lock().unwrap() = State::Running;
+
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+        let state = match &mut *task.job.lock().unwrap() {
+            Some(job) => job.poll(&mut cx),
+            None => return,
+        };
+
+        let mut guard = task.state.lock().unwrap();
+        match (state, *guard) {
+            (ResumeState::Complete(()), _) => {
+                *guard = State::Done;
+                drop(guard);
+                let _ = self.tasks.lock().unwrap().remove(&task.id);
+                drop(task.job.lock().unwrap().take());
+            }
+
+            (ResumeState::Pending, State::Running) => *guard = State::Idle,
+
+            // It yielded, or was woken before it even suspended.
+            _ => {
+                *guard = State::Queued;
+                drop(guard);
+                self.queues[index].lock().unwrap().push_back(task);
+                self.notify();
+            }
+        }
+    }
+}
+
+/// Runs coroutines on a pool of worker threads.
+///
+/// Every coroutine runs on a stack of its own, taken from a `StackPool`, and
+/// may be resumed by any of the workers. Coroutines yield `()` to let others
+/// run, and may wait for events (see `Control::yield_pending()`).
+///
+/// Dropping the pool stops the workers, once they are done resuming the
+/// coroutines they are running, and cancels every coroutine which hasn't
+/// completed.
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    stacks: StackPool,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Starts a pool of `threads` worker threads, running coroutines on
+    /// stacks from `stacks`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn new(threads: usize, stacks: StackPool) -> Result<Self> {
+        assert!(threads > 0, "a thread pool needs at least one thread");
+
+        let shared = Arc::new(Shared {
+            injector: Queue::default(),
+            queues: (0..threads).map(|_| Queue::default()).collect(),
+            tasks: Mutex::default(),
+            sleeping: Mutex::new(0),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            next: AtomicUsize::new(0),
+        });
+
+        let mut pool = ThreadPool {
+            shared,
+            stacks,
+            workers: Vec::with_capacity(threads),
+        };
+
+        for index in 0..threads {
+            let shared = pool.shared.clone();
+            let worker = thread::Builder::new()
+                .name(format!("frenetic-worker-{}", index))
+                .spawn(move || shared.work(index))?;
+            pool.workers.push(worker);
+        }
+
+        Ok(pool)
+    }
+
+    /// Spawns a coroutine on the pool.
+    ///
+    /// Returns a `JoinHandle` to wait for the value the coroutine returns, or an
+    /// error if no stack could be allocated for it.
+    ///
+    /// # Safety
+    ///
+    /// The coroutine may move between the worker threads whenever it yields
+    /// or waits, and the `Send` bounds only check what the closure captures.
+    /// As for `SendCoroutine::new()`, the closure must not hold a value which
+    /// isn't `Send` (such as an `Rc`, a `MutexGuard` or a reference into a
+    /// thread-local variable) across a yield or a wait, nor rely on the
+    /// address of a thread-local variable staying the same across one.
+    pub unsafe fn spawn<R, F>(&self, func: F) -> Result<JoinHandle<R>>
+    where
+        F: FnOnce(Control<'_, (), (), R>, ()) -> core::result::Result<Finished<R>, CoroutineError>,
+        F: Send + 'static,
+        R: Send + 'static,
+    {
+        let stack = self.stacks.get()?;
+        let id = self.shared.next.fetch_add(1, Ordering::Relaxed);
+        let slot = Arc::new(Slot::new(id));
+
+        let body = Body {
+            coroutine: SendCoroutine::new(stack, func),
+            result: None,
+            slot: slot.clone(),
+        };
+
+        let task = Arc::new(Task {
+            id,
+            job: Mutex::new(Some(Box::new(body))),
+            state: Mutex::new(State::Queued),
+            shared: self.shared.clone(),
+        });
+
+        let _ = self
+            .shared
+            .tasks
+            .lock()
+            .unwrap()
+            .insert(task.id, Arc::downgrade(&task));
+        self.shared.push(task);
+
+        Ok(JoinHandle(slot))
+    }
+
+    /// Returns the number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Returns the number of coroutines which have not completed yet.
+    pub fn len(&self) -> usize {
+        self.shared.tasks.lock().unwrap().len()
+    }
+
+    /// Returns `true` if all coroutines have completed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        {
+            let _injector = self.shared.injector.lock().unwrap();
+            self.shared.shutdown.store(true, Ordering::Release);
+        }
+
+        {
+            let _sleeping = self.shared.sleeping.lock().unwrap();
+            self.shared.wake.notify_all();
+        }
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+
+        // Cancel what is left, outside of any lock: the coroutines run again
+        // while they unwind.
+        let tasks = take(&mut *self.shared.tasks.lock().unwrap());
+        for task in tasks.values().filter_map(Weak::upgrade) {
+            let job = task.job.lock().unwrap().take();
+            drop(job);
+        }
+
+        drop(take(&mut *self.shared.injector.lock().unwrap()));
+        for queue in &self.shared.queues {
+            drop(take(&mut *queue.lock().unwrap()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Reclaim, WakerSlot, STACK_MINIMUM};
+
+    fn pool(threads: usize) -> ThreadPool {
+        ThreadPool::new(
+            threads,
+            StackPool::new(STACK_MINIMUM * 16, 8, Reclaim::Keep),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn spawn() {
+        let pool = pool(4);
+        assert_eq!(pool.threads(), 4);
+
+        let handles: Vec<_> = (0..32)
+            .map(|i| unsafe {
+                pool.spawn(move |mut c, ()| {
+                    for _ in 0..i % 5 {
+                        c = c.r#yield(())?.0;
+                    }
+                    c.done(i * 2)
+                })
+            })
+            .collect::<Result<_>>()
+            .unwrap();
+
+        let total: usize = handles.into_iter().map(|h| h.wait().unwrap()).sum();
+        assert_eq!(total, (0..32).map(|i| i * 2).sum());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn wait() {
+        let pool = pool(2);
+        let slot = Arc::new(WakerSlot::new());
+        let event = Arc::new(AtomicBool::new(false));
+
+        let waiter = {
+            let (slot, event) = (slot.clone(), event.clone());
+            unsafe {
+                pool.spawn(move |mut c, ()| {
+                    while !event.load(Ordering::Acquire) {
+                        c = c.yield_pending(&slot)?.0;
+                    }
+                    c.done("woken")
+                })
+            }
+        }
+        .unwrap();
+
+        let waker = unsafe {
+            pool.spawn(move |c, ()| {
+                event.store(true, Ordering::Release);
+                slot.wake();
+                c.done(())
+            })
+        }
+        .unwrap();
+
+        waker.wait().unwrap();
+        assert_eq!(waiter.wait().unwrap(), "woken");
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn panic_and_cancel() {
+        let pool = pool(1);
+        let slot = Arc::new(WakerSlot::new());
+
+        let panics =
+            unsafe { pool.spawn(|_: Control<'_, (), (), ()>, ()| panic!("oops")) }.unwrap();
+        let waits = {
+            let slot = slot.clone();
+            unsafe { pool.spawn(move |c, ()| c.yield_pending(&slot)?.0.done(())) }.unwrap()
+        };
+
+        match panics.wait() {
+            Err(CoroutineError::Panicked(p)) => {
+                assert_eq!(*p.downcast_ref::<&str>().unwrap(), "oops")
+            }
+            _ => panic!("expected a panic"),
+        }
+
+        while pool.len() > 1 {
+            thread::yield_now();
+        }
+
+        drop(pool);
+        assert!(waits.is_finished());
+        match waits.wait() {
+            Err(CoroutineError::Canceled) => {}
+            _ => panic!("expected a cancellation"),
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn join() {
+        // With a single worker, joining must not block it.
+        let pool = pool(1);
+
+        let target = unsafe {
+            pool.spawn(|mut c, ()| {
+                for _ in 0..3 {
+                    c = c.r#yield(())?.0;
+                }
+                c.done(5)
+            })
+        }
+        .unwrap();
+        let panics =
+            unsafe { pool.spawn(|_: Control<'_, (), (), u8>, ()| panic!("oops")) }.unwrap();
+
+        let joiner = unsafe {
+            pool.spawn(move |c, ()| {
+                let (c, value) = target.join(c)?;
+                let (c, panicked) = panics.join(c)?;
+                let panicked = matches!(panicked, Err(CoroutineError::Panicked(_)));
+                c.done((value.unwrap() + 1, panicked))
+            })
+        }
+        .unwrap();
+
+        assert_eq!(joiner.wait().unwrap(), (6, true));
+    }
+}
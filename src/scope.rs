@@ -0,0 +1,152 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Control, Coroutine, CoroutineError, Finished, Stack};
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::RefCell;
+use core::marker::PhantomData;
+
+// Erases the type of a coroutine owned by a `Scope`. Dropping the box drops
+// (and thereby cancels) the coroutine.
+trait Owned {}
+impl<T> Owned for T {}
+
+// The coroutines spawned in a scope. They are boxed, but kept as raw pointers:
+// moving a `Box` asserts unique access to its contents, which would
+// invalidate the references handed out by `Scope::spawn()`.
+struct Coroutines<'env>(RefCell<Vec<*mut (dyn Owned + 'env)>>);
+
+impl<'env> Drop for Coroutines<'env> {
+    fn drop(&mut self) {
+        // Later coroutines may refer to earlier ones, so cancel them first.
+        let coroutines = self.0.get_mut();
+        while let Some(coroutine) = coroutines.pop() {
+            drop(unsafe { Box::from_raw(coroutine) });
+        }
+    }
+}
+
+/// A scope for spawning coroutines which borrow from their environment.
+///
+/// See `scope()`.
+pub struct Scope<'scope, 'env> {
+    coroutines: &'scope Coroutines<'env>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Creates a scope for spawning coroutines.
+///
+/// The closure is passed a `Scope` which can spawn coroutines that borrow
+/// non-`'static` data from the enclosing function. When the closure returns
+/// (or panics), every coroutine spawned in the scope which has not completed
+/// yet is canceled, in the reverse order of spawning. So all of them have
+/// finished running before `scope()` returns.
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    // This outlives the `Scope`, and is dropped even if `f` panics.
+    let coroutines = Coroutines(RefCell::new(Vec::new()));
+
+    let scope = Scope {
+        coroutines: &coroutines,
+        scope: PhantomData,
+        env: PhantomData,
+    };
+
+    f(&scope)
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Spawns a new coroutine within this scope.
+    ///
+    /// This works like `Coroutine::new()`, except that the scope owns the
+    /// coroutine. The returned reference can be used to drive it for the rest
+    /// of the scope.
+    // Every call hands out a reference to a different coroutine.
+    #[allow(clippy::mut_from_ref)]
+    pub fn spawn<A, Y, R, S, F>(
+        &'scope self,
+        stack: S,
+        func: F,
+    ) -> &'scope mut Coroutine<'env, A, Y, R, S>
+    where
+        S: Stack + 'env,
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: 'env,
+        A: 'env,
+        Y: 'env,
+        R: 'env,
+    {
+        let ptr = Box::into_raw(Box::new(Coroutine::new(stack, func)));
+        self.coroutines.0.borrow_mut().push(ptr);
+
+        // The coroutine lives on the heap, so it doesn't move when the vector
+        // grows. The scope doesn't touch it again until it is dropped, after
+        // `'scope` has ended.
+        unsafe { &mut *ptr }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Generator, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    #[test]
+    fn borrow() {
+        let mut a = [1u8; STACK_MINIMUM];
+        // `b` is unwound, which takes more than `STACK_MINIMUM` in debug builds.
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut log = Vec::new();
+        let mut total = 0;
+
+        scope(|s| {
+            let x = s.spawn(&mut a, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done(2)
+            });
+
+            let y = s.spawn(&mut b, |c, ()| {
+                struct Canceled<'a>(&'a mut Vec<&'static str>);
+
+                impl Drop for Canceled<'_> {
+                    fn drop(&mut self) {
+                        self.0.push("canceled");
+                    }
+                }
+
+                log.push("started");
+                let _log = Canceled(&mut log);
+                let (c, ()) = c.r#yield(10)?;
+                c.done(20)
+            });
+
+            while let GeneratorState::Yielded(v) = Pin::new(&mut *x).resume() {
+                total += v;
+            }
+
+            if let GeneratorState::Yielded(v) = Pin::new(&mut *y).resume() {
+                total += v;
+            }
+
+            // `y` is still suspended here; the scope cancels it.
+        });
+
+        assert_eq!(total, 11);
+        assert_eq!(log, ["started", "canceled"]);
+    }
+}
@@ -0,0 +1,468 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A coroutine's stack has a fixed size, which has to allow for its deepest
+//! call. With `Control::maybe_grow()`, a coroutine may start out on a small
+//! stack and only grow it where it is about to recurse: if less than a red
+//! zone is left, the closure runs on a new segment, allocated from the stack
+//! allocator and freed once the closure returns.
+//!
+//! A segment is entered with `jump_init()`, like a new coroutine, but by the
+//! coroutine itself, which the closure keeps on running as: it may yield,
+//! transfer and be canceled from within the segment as anywhere else. The top
+//! of the segment holds a `Link` to the segment below, and the coroutine's
+//! Context the newest one, so the chain can be walked from there. Right below
+//! the link is a frame record, which `trampoline()` links to the frame that
+//! called `maybe_grow()`, so that the frame pointer chain leads through every
+//! segment (see `frame`). Unwinders using the call frame information stop at
+//! `trampoline()`, which catches a panic and resumes it on the stack below.
+//!
+//! The guard page handler and the canary only know about the coroutine's own
+//! stack. A segment is allocated with a guard page where the allocator
+//! supports one, so overflowing it faults, but kills the process.
+//!
+//! With the `threads` backend, every coroutine runs on a thread with a stack
+//! of its own, which can't be grown, and `maybe_grow()` just calls the
+//! closure.
+
+use crate::{Control, Coroutine, CoroutineError};
+use core::fmt;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::ptr::null;
+
+/// The top of a segment. See the module documentation.
+#[repr(C, align(16))]
+pub(crate) struct Link {
+    /// The segment below, or null for the coroutine's own stack.
+    prev: *const Link,
+
+    /// The lower end of this segment.
+    bottom: *const u8,
+}
+
+/// One segment of a coroutine's stack, a contiguous range of memory its
+/// frames live in. See `Control::segments()`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    bottom: *const u8,
+    top: *const u8,
+}
+
+impl Segment {
+    /// Returns the lowest address of the segment.
+    pub fn bottom(&self) -> *const u8 {
+        self.bottom
+    }
+
+    /// Returns the address right above the segment's highest frame.
+    pub fn top(&self) -> *const u8 {
+        self.top
+    }
+
+    /// Returns the size of the segment in bytes.
+    pub fn size(&self) -> usize {
+        self.top as usize - self.bottom as usize
+    }
+
+    /// Returns whether `addr` lies within the segment.
+    pub fn contains(&self, addr: *const u8) -> bool {
+        self.bottom <= addr && addr < self.top
+    }
+}
+
+impl fmt::Debug for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Segment({:p}..{:p})", self.bottom, self.top)
+    }
+}
+
+/// An iterator over the segments of a coroutine's stack, from the newest to
+/// the coroutine's own stack. See `Control::segments()`.
+pub struct Segments<'a> {
+    link: *const Link,
+    bottom: *const u8,
+    top: *const u8,
+    life: PhantomData<&'a ()>,
+}
+
+impl Segments<'_> {
+    /// An iterator over the chain from `link` down to the stack between
+    /// `bottom` and `top`. A null `top` ends it right away.
+    fn new(link: *const Link, bottom: *const u8, top: *const u8) -> Self {
+        Segments {
+            link,
+            bottom,
+            top,
+            life: PhantomData,
+        }
+    }
+}
+
+impl Iterator for Segments<'_> {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Segment> {
+        if self.top.is_null() {
+            return None;
+        }
+
+        if self.link.is_null() {
+            let segment = Segment {
+                bottom: self.bottom,
+                top: self.top,
+            };
+            self.top = null();
+            return Some(segment);
+        }
+
+        let link = unsafe { &*self.link };
+        self.link = link.prev;
+        Some(Segment {
+            bottom: link.bottom,
+            top: link as *const Link as *const u8,
+        })
+    }
+}
+
+impl FusedIterator for Segments<'_> {}
+
+impl<'a, A, Y, R> Control<'a, A, Y, R> {
+    /// Returns the segments of this coroutine's stack, from the one it runs
+    /// on to its own stack. Without `maybe_grow()`, that is the only one.
+    ///
+    /// Each segment starts right above the frame which grew the stack, in
+    /// the one after it, so this is what a stack walker needs to tell the
+    /// coroutine's frames from any other memory.
+    pub fn segments(&self) -> Segments<'_> {
+        unsafe {
+            let ctx = self.0;
+            Segments::new((*ctx).segment, (*ctx).bottom, ctx as *const u8)
+        }
+    }
+
+    /// Returns how many bytes are left on the stack segment the coroutine
+    /// runs on, below the caller's frame.
+    ///
+    /// With the `threads` backend, where coroutines run on threads of their
+    /// own, the size of their stacks isn't known, and this returns
+    /// `usize::MAX`.
+    pub fn remaining_stack(&self) -> usize {
+        imp::remaining(self.limit())
+    }
+
+    /// Calls `f`, on a new stack segment of `size` bytes if less than
+    /// `red_zone` bytes are left on the current one.
+    ///
+    /// This lets a coroutine start out on a small stack and grow it on
+    /// demand: call it at the top of a recursive function, with a red zone
+    /// bigger than what the function needs before the next call. The closure
+    /// receives the `Control`, and may yield, transfer or be canceled from
+    /// within the segment. Once it returns, the segment is freed again, and
+    /// if it panics, the panic continues on the stack below.
+    ///
+    /// The segment is allocated from the stack allocator (see
+    /// `set_stack_allocator()`), with a guard page below it where the
+    /// allocator supports one, and is at least `STACK_MINIMUM` bytes.
+    ///
+    /// # Panics
+    ///
+    /// If the segment can't be allocated.
+    pub fn maybe_grow<T, F>(
+        self,
+        red_zone: usize,
+        size: usize,
+        f: F,
+    ) -> Result<(Self, T), CoroutineError>
+    where
+        F: FnOnce(Self) -> Result<(Self, T), CoroutineError>,
+    {
+        if self.remaining_stack() >= red_zone {
+            return f(self);
+        }
+
+        imp::grow(self, size, f)
+    }
+
+    /// Returns the lower end of the segment the coroutine runs on.
+    fn limit(&self) -> *const u8 {
+        unsafe {
+            let ctx = self.0;
+            match (*ctx).segment.as_ref() {
+                Some(link) => link.bottom,
+                None => (*ctx).bottom,
+            }
+        }
+    }
+}
+
+impl<'a, A, Y, R, S> Coroutine<'a, A, Y, R, S> {
+    /// Returns the segments of this coroutine's stack, from the newest to its
+    /// own stack, or none once it has completed. See `Control::segments()`.
+    pub fn segments(&self) -> Segments<'_> {
+        match self.ctx {
+            Some(ctx) => unsafe {
+                let ctx = ctx.as_ptr();
+                Segments::new((*ctx).segment, (*ctx).bottom, ctx as *const u8)
+            },
+            None => Segments::new(null(), null(), null()),
+        }
+    }
+}
+
+#[cfg(not(any(feature = "threads", miri)))]
+mod imp {
+    use super::Link;
+    use crate::frame::{self, FrameRecord};
+    use crate::unwind::{self, Payload};
+    use crate::valgrind::StackId;
+    use crate::{
+        jump_init, AllocatedStack, Control, CoroutineError, JumpBuffer, Stack, StackOptions,
+        STACK_ALIGNMENT, STACK_MINIMUM,
+    };
+    use core::ffi::c_void;
+    use core::mem::size_of;
+    use core::ptr::null_mut;
+
+    /// The closure to run on a segment, and then what it returned.
+    struct Call<F, T> {
+        func: Option<F>,
+        result: Option<Result<T, Payload>>,
+    }
+
+    pub(super) fn remaining(limit: *const u8) -> usize {
+        // Close enough to the stack pointer.
+        let marker = 0u8;
+        (&marker as *const u8 as usize).saturating_sub(limit as usize)
+    }
+
+    pub(super) fn grow<'a, A, Y, R, T, F>(
+        c: Control<'a, A, Y, R>,
+        size: usize,
+        f: F,
+    ) -> Result<(Control<'a, A, Y, R>, T), CoroutineError>
+    where
+        F: FnOnce(Control<'a, A, Y, R>) -> Result<(Control<'a, A, Y, R>, T), CoroutineError>,
+    {
+        let options = StackOptions::new(size.max(STACK_MINIMUM), true);
+        let mut stack = AllocatedStack::new(options).expect("failed to allocate a stack segment");
+        let ctx = c.0;
+
+        unsafe {
+            let bottom = stack.bottom();
+            let top = bottom.add(stack.len());
+            let top = top.sub(top as usize % STACK_ALIGNMENT);
+
+            // The link goes at the very top, with the frame record right
+            // below it, where `jump_init()` expects it. Both are aligned, so
+            // the stack pointer starts right below them, as in
+            // `Coroutine::new()`.
+            let link = top.sub(size_of::<Link>()) as *mut Link;
+            link.write(Link {
+                prev: (*ctx).segment,
+                bottom,
+            });
+            let record = frame::record(link as *mut u8);
+            record.write(FrameRecord::END);
+
+            let mut stack_id = StackId::register(bottom, top);
+            (*ctx).segment = link;
+
+            let result = run(record as *mut u8, link, bottom, move || f(c));
+
+            (*ctx).segment = (*link).prev;
+            stack_id.deregister();
+            drop(stack);
+
+            match result {
+                Ok(result) => result,
+                Err(payload) => unwind::resume(payload),
+            }
+        }
+    }
+
+    /// Calls `func` on the stack which starts at `addr` and ends at `limit`,
+    /// with the `Link` at `link` on top.
+    unsafe fn run<F, T>(
+        addr: *mut u8,
+        link: *mut Link,
+        limit: *mut u8,
+        func: F,
+    ) -> Result<T, Payload>
+    where
+        F: FnOnce() -> T,
+    {
+        let mut call = Call {
+            func: Some(func),
+            result: None,
+        };
+
+        jump_init(
+            addr,
+            link as *mut c_void,
+            &mut call as *mut Call<F, T> as *mut c_void,
+            trampoline::<F, T>,
+            limit,
+            null_mut(),
+        );
+
+        call.result.unwrap()
+    }
+
+    /// The outermost frame on a segment. Runs the closure of the `Call` at
+    /// `f` and returns to the `maybe_grow()` suspended into `p`, which stays
+    /// where it is until then.
+    unsafe extern "C" fn trampoline<F, T>(
+        p: *mut JumpBuffer,
+        c: *mut c_void,
+        f: *mut c_void,
+    ) -> *mut JumpBuffer
+    where
+        F: FnOnce() -> T,
+    {
+        frame::link(c as *mut u8, p);
+
+        // As in `callback()`, a panic must not unwind past this function.
+        let bomb = unwind::Bomb;
+        let call = &mut *(f as *mut Call<F, T>);
+        let func = call.func.take().unwrap();
+        call.result = Some(unwind::catch(func));
+        bomb.defuse();
+        p
+    }
+}
+
+#[cfg(any(feature = "threads", miri))]
+mod imp {
+    use crate::{Control, CoroutineError};
+
+    pub(super) fn remaining(_limit: *const u8) -> usize {
+        usize::MAX
+    }
+
+    pub(super) fn grow<'a, A, Y, R, T, F>(
+        c: Control<'a, A, Y, R>,
+        _size: usize,
+        f: F,
+    ) -> Result<(Control<'a, A, Y, R>, T), CoroutineError>
+    where
+        F: FnOnce(Control<'a, A, Y, R>) -> Result<(Control<'a, A, Y, R>, T), CoroutineError>,
+    {
+        f(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Control, Coroutine, CoroutineError, Generator, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    type Ctl<'a> = Control<'a, (), usize, usize>;
+
+    // Takes at least half a kilobyte per call: far more than fits on
+    // STACK_MINIMUM.
+    fn recurse(c: Ctl<'_>, depth: usize) -> Result<(Ctl<'_>, usize), CoroutineError> {
+        c.maybe_grow(4096, STACK_MINIMUM * 4, |c| {
+            let buffer = [depth as u8; 512];
+            if depth == 0 {
+                let segments = c.segments().count();
+                let (c, ()) = c.r#yield(segments)?;
+                return Ok((c, 0));
+            }
+
+            // Read the buffer after the call, so that it stays on the stack.
+            let (c, sum) = recurse(c, depth - 1)?;
+            let byte = unsafe { (&buffer[depth % 512] as *const u8).read_volatile() };
+            Ok((c, sum + byte as usize))
+        })
+    }
+
+    #[test]
+    fn grow() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::new(&mut stack, |c: Ctl<'_>, ()| {
+            assert_eq!(c.segments().count(), 1);
+            let (c, sum) = recurse(c, 64)?;
+            assert_eq!(c.segments().count(), 1);
+            c.done(sum)
+        });
+
+        let segments = match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(segments) => segments,
+            _ => panic!("unexpected return from resume"),
+        };
+
+        // Suspended deep inside the chain, which is visible from outside.
+        assert_eq!(coro.segments().count(), segments);
+        if cfg!(not(any(feature = "threads", miri))) {
+            assert!(segments > 4);
+        }
+
+        let sum = (1..=64).sum();
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(sum));
+        assert_eq!(coro.segments().count(), 0);
+    }
+
+    #[test]
+    fn chain() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::new(&mut stack, |c: Ctl<'_>, ()| {
+            let (c, ()) = c.maybe_grow(usize::MAX, STACK_MINIMUM, |c| {
+                let local = 0u8;
+                let segments: Vec<_> = c.segments().collect();
+                if cfg!(not(any(feature = "threads", miri))) {
+                    assert!(segments[0].contains(&local));
+                    assert_eq!(segments.len(), 2);
+                    assert!(segments[0].size() <= STACK_MINIMUM);
+                    assert!(!segments[1].contains(&local));
+                }
+                Ok((c, ()))
+            })?;
+            c.done(0)
+        });
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(0));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn panic() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut coro = Coroutine::new(&mut stack, |c: Ctl<'_>, ()| {
+            let (c, ()) = c.maybe_grow(usize::MAX, STACK_MINIMUM * 8, |_| panic!("foo"))?;
+            c.done(0)
+        });
+
+        let err = Pin::new(&mut coro).try_resume().unwrap_err();
+        match err {
+            CoroutineError::Panicked(payload) => assert_eq!(payload.downcast_ref(), Some(&"foo")),
+            _ => panic!("unexpected error"),
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut coro = Coroutine::new(&mut stack, |c: Ctl<'_>, ()| {
+            let (c, ()) = c.maybe_grow(usize::MAX, STACK_MINIMUM * 4, |c| {
+                let (c, ()) = c.r#yield(1)?;
+                Ok((c, ()))
+            })?;
+            c.done(0)
+        });
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+        drop(coro);
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    CancelOutcome, Control, Coroutine, CoroutineError, CoroutineState, Finished, Generator,
+    GeneratorState, ResumeState, Stack,
+};
+use core::pin::Pin;
+use core::task::Context;
+#[cfg(feature = "std")]
+use std::sync::{Mutex, MutexGuard};
+
+/// A coroutine which may be moved to, and resumed on, another thread.
+///
+/// A plain `Coroutine` is neither `Send` nor `Sync`: the type system can't
+/// see what the code running on its stack keeps there across a yield. This
+/// wrapper is `Send` whenever its closure, stack, resume argument, yield and
+/// return types are. It only hands out the parts of the `Coroutine` API which
+/// can't be used to swap a different coroutine in.
+///
+/// None of the backends carry the thread pointer along with a coroutine, so
+/// after moving to another thread, the coroutine sees that thread's
+/// thread-local variables. Switching it too would not help: the C library
+/// keeps its own per-thread state (`errno`, allocator caches, the thread's
+/// identity) behind the same pointer, and two threads would end up sharing
+/// it. A coroutine whose code must only ever see one thread's thread-local
+/// variables can be pinned to it with `pin_to_current_thread()` instead.
+pub struct SendCoroutine<'a, A, Y, R, S = &'a mut [u8]>(Coroutine<'a, A, Y, R, S>);
+
+unsafe impl<'a, A: Send, Y: Send, R: Send, S: Send> Send for SendCoroutine<'a, A, Y, R, S> {}
+
+impl<'a, A, Y, R, S: Stack + 'a> SendCoroutine<'a, A, Y, R, S> {
+    /// Spawns a new coroutine which may be sent to other threads.
+    ///
+    /// See `Coroutine::new()`.
+    ///
+    /// # Safety
+    ///
+    /// `Send` bounds can only check what the closure captures, not the
+    /// locals it creates while it runs. The closure must not hold a value
+    /// which isn't `Send` (such as an `Rc`, a `MutexGuard` or a reference
+    /// into a thread-local variable) across a yield. It must not rely on
+    /// the address of a thread-local variable staying the same across a
+    /// yield either.
+    pub unsafe fn new<F>(stack: S, func: F) -> Self
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: Send + 'a,
+    {
+        SendCoroutine(Coroutine::new(stack, func))
+    }
+}
+
+impl<'a, A, Y, R, S> SendCoroutine<'a, A, Y, R, S> {
+    /// See `Coroutine::state()`.
+    pub fn state(&self) -> CoroutineState {
+        self.0.state()
+    }
+
+    /// See `Coroutine::is_finished()`.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    /// See `Coroutine::resume_with()`.
+    pub fn resume_with(self: Pin<&mut Self>, arg: A) -> GeneratorState<Y, R> {
+        Pin::new(&mut self.get_mut().0).resume_with(arg)
+    }
+
+    /// See `Coroutine::try_resume_with()`.
+    pub fn try_resume_with(
+        self: Pin<&mut Self>,
+        arg: A,
+    ) -> Result<GeneratorState<Y, R>, CoroutineError> {
+        Pin::new(&mut self.get_mut().0).try_resume_with(arg)
+    }
+
+    /// See `Coroutine::poll_resume()`.
+    pub fn poll_resume(self: Pin<&mut Self>, arg: A, cx: &mut Context<'_>) -> ResumeState<Y, R> {
+        Pin::new(&mut self.get_mut().0).poll_resume(arg, cx)
+    }
+
+    /// Pins the coroutine to the current thread.
+    ///
+    /// It may still be moved to other threads, but only resumed on this one:
+    /// anywhere else, `try_resume_with()` returns `CoroutineError::WrongThread`
+    /// and `resume_with()` panics. Dropping or canceling it elsewhere still
+    /// unwinds its stack on the thread doing so. Requires the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn pin_to_current_thread(&mut self) {
+        self.0.thread = Some(std::thread::current().id());
+    }
+
+    /// See `Coroutine::cancel()`.
+    pub fn cancel(self) -> CancelOutcome {
+        self.0.cancel()
+    }
+
+    /// Unwraps the coroutine. It can no longer be sent to other threads.
+    pub fn into_inner(self) -> Coroutine<'a, A, Y, R, S> {
+        self.0
+    }
+}
+
+impl<'a, Y, R, S> Generator for SendCoroutine<'a, (), Y, R, S> {
+    type Yield = Y;
+    type Return = R;
+
+    fn resume(self: Pin<&mut Self>) -> GeneratorState<Y, R> {
+        self.resume_with(())
+    }
+}
+
+/// A coroutine which any thread may resume, through a shared reference.
+///
+/// It wraps a `SendCoroutine` in a mutex, so it is `Send` and `Sync`
+/// whenever that is `Send`, and can be shared between the threads of a pool,
+/// say in an `Arc`, for whichever is free to resume it. Resumes are
+/// serialized: one which comes while another thread is resuming the
+/// coroutine blocks until that has returned. The coroutine must not resume
+/// itself, which would deadlock.
+///
+/// The caveats of `SendCoroutine` about thread-local variables apply: each
+/// resume runs on the thread which calls it, seeing that thread's. A
+/// coroutine pinned with `pin_to_current_thread()` can still be shared, but
+/// only that thread can resume it. Requires the `std` feature.
+#[cfg(feature = "std")]
+pub struct SyncCoroutine<'a, A, Y, R, S = &'a mut [u8]>(Mutex<SendCoroutine<'a, A, Y, R, S>>);
+
+#[cfg(feature = "std")]
+impl<'a, A, Y, R, S: Stack + 'a> SyncCoroutine<'a, A, Y, R, S> {
+    /// Spawns a new coroutine which may be resumed from any thread.
+    ///
+    /// See `Coroutine::new()`.
+    ///
+    /// # Safety
+    ///
+    /// Like `SendCoroutine::new()`.
+    pub unsafe fn new<F>(stack: S, func: F) -> Self
+    where
+        F: FnOnce(Control<'_, A, Y, R>, A) -> Result<Finished<R>, CoroutineError>,
+        F: Send + 'a,
+    {
+        SyncCoroutine::from(SendCoroutine::new(stack, func))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A, Y, R, S> SyncCoroutine<'a, A, Y, R, S> {
+    /// Locks the coroutine, waiting for any resume in progress to return.
+    /// A panic propagated from the coroutine leaves it completed, so there is
+    /// nothing to recover from if the lock is poisoned.
+    fn lock(&self) -> MutexGuard<'_, SendCoroutine<'a, A, Y, R, S>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// See `Coroutine::state()`. Waits for any resume in progress.
+    pub fn state(&self) -> CoroutineState {
+        self.lock().state()
+    }
+
+    /// See `Coroutine::is_finished()`. Waits for any resume in progress.
+    pub fn is_finished(&self) -> bool {
+        self.lock().is_finished()
+    }
+
+    /// See `Coroutine::resume_with()`. Waits for any resume in progress on
+    /// another thread first.
+    pub fn resume_with(&self, arg: A) -> GeneratorState<Y, R> {
+        Pin::new(&mut *self.lock()).resume_with(arg)
+    }
+
+    /// See `Coroutine::try_resume_with()`. Waits for any resume in progress
+    /// on another thread first.
+    pub fn try_resume_with(&self, arg: A) -> Result<GeneratorState<Y, R>, CoroutineError> {
+        Pin::new(&mut *self.lock()).try_resume_with(arg)
+    }
+
+    /// See `Coroutine::poll_resume()`. Waits for any resume in progress on
+    /// another thread first.
+    pub fn poll_resume(&self, arg: A, cx: &mut Context<'_>) -> ResumeState<Y, R> {
+        Pin::new(&mut *self.lock()).poll_resume(arg, cx)
+    }
+
+    /// Pins the coroutine to the current thread. See
+    /// `SendCoroutine::pin_to_current_thread()`.
+    pub fn pin_to_current_thread(&self) {
+        self.lock().pin_to_current_thread()
+    }
+
+    /// See `Coroutine::cancel()`.
+    pub fn cancel(self) -> CancelOutcome {
+        self.into_inner().cancel()
+    }
+
+    /// Unwraps the coroutine. It can no longer be shared between threads.
+    pub fn into_inner(self) -> SendCoroutine<'a, A, Y, R, S> {
+        self.0.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, Y, R, S> SyncCoroutine<'a, (), Y, R, S> {
+    /// See `Generator::resume()`. Waits for any resume in progress on
+    /// another thread first.
+    pub fn resume(&self) -> GeneratorState<Y, R> {
+        self.resume_with(())
+    }
+
+    /// See `Coroutine::try_resume()`. Waits for any resume in progress on
+    /// another thread first.
+    pub fn try_resume(&self) -> Result<GeneratorState<Y, R>, CoroutineError> {
+        self.try_resume_with(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A, Y, R, S> From<SendCoroutine<'a, A, Y, R, S>> for SyncCoroutine<'a, A, Y, R, S> {
+    fn from(coro: SendCoroutine<'a, A, Y, R, S>) -> Self {
+        SyncCoroutine(Mutex::new(coro))
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::{HeapStack, STACK_MINIMUM};
+    #[cfg(feature = "std")]
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn resume_on_other_thread() {
+        let stack = HeapStack::new(STACK_MINIMUM * 16);
+
+        let mut coro = unsafe {
+            SendCoroutine::new(stack, |c, a: u32| {
+                let (c, b) = c.r#yield(a + 1)?;
+                let (c, d) = c.r#yield(b * 2)?;
+                c.done(d)
+            })
+        };
+
+        assert_eq!(
+            Pin::new(&mut coro).resume_with(1),
+            GeneratorState::Yielded(2)
+        );
+
+        let mut coro = thread::spawn(move || {
+            assert_eq!(
+                Pin::new(&mut coro).resume_with(5),
+                GeneratorState::Yielded(10)
+            );
+            coro
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(
+            Pin::new(&mut coro).resume_with(7),
+            GeneratorState::Complete(7)
+        );
+        assert!(coro.is_finished());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn pinned_to_thread() {
+        let stack = HeapStack::new(STACK_MINIMUM * 16);
+
+        let mut coro = unsafe {
+            SendCoroutine::new(stack, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done(2)
+            })
+        };
+        coro.pin_to_current_thread();
+
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Yielded(1));
+
+        let mut coro = thread::spawn(move || {
+            match Pin::new(&mut coro).try_resume_with(()) {
+                Err(CoroutineError::WrongThread) => {}
+                _ => panic!("unexpected return from try_resume_with"),
+            }
+            coro
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(coro.state(), CoroutineState::Suspended);
+        assert_eq!(Pin::new(&mut coro).resume(), GeneratorState::Complete(2));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn resume_from_pool() {
+        let stack = HeapStack::new(STACK_MINIMUM * 16);
+
+        let coro = unsafe {
+            SyncCoroutine::new(stack, |mut c, ()| {
+                for i in 0..100 {
+                    c = c.r#yield(i)?.0;
+                }
+                c.done(())
+            })
+        };
+        let coro = Arc::new(coro);
+
+        // Each worker resumes the coroutine until it has completed, keeping
+        // what it gets.
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let coro = coro.clone();
+                thread::spawn(move || {
+                    let mut got = Vec::new();
+                    while let Ok(GeneratorState::Yielded(i)) = coro.try_resume() {
+                        got.push(i);
+                    }
+                    got
+                })
+            })
+            .collect();
+
+        let mut got: Vec<_> = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        got.sort_unstable();
+        assert_eq!(got, (0..100).collect::<Vec<_>>());
+        assert!(coro.is_finished());
+    }
+}
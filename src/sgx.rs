@@ -0,0 +1,108 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Enclaves can't make system calls, take signals or execute a number of
+//! instructions (`syscall`, `int`, `cpuid`, `rdtsc` and others), and the
+//! fs and gs bases belong to the thread control structure (TCS) the enclave
+//! was entered with. The `sgx` feature holds the context switch to that:
+//!
+//! * it always uses the x86_64 assembly backend (`src/jump/x86_64.S`, or
+//!   `src/asm.rs` with the `asm` feature), which only moves general-purpose
+//!   registers and the stack pointer, and saves the floating-point control
+//!   state with `stmxcsr`/`fnstcw`, all of which enclaves may execute;
+//! * it never reads or writes fs or gs, so a coroutine sees the thread-local
+//!   variables of whichever TCS resumes it;
+//! * `build.rs` refuses the features which would break this: `cet` (shadow
+//!   stacks are set up with system calls), `guard` (signals), `valgrind`,
+//!   `tsan` and `threads`, as well as other targets.
+//!
+//! Spawning, resuming and dropping coroutines on borrowed stacks don't make
+//! system calls either. `ProtectedStack` and `StackPool` map memory, and
+//! need whatever the enclave runtime offers for that.
+//!
+//! There is no SGX hardware to test on in CI, so the test below stands in
+//! for an enclave: it runs coroutines in a child process which may only
+//! `read`, `write` and `exit` (seccomp strict mode), faults on `rdtsc` and,
+//! where the CPU can, on `cpuid`.
+
+#[cfg(all(test, feature = "std", target_os = "linux", not(miri)))]
+mod tests {
+    use crate::{Coroutine, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    const ARCH_SET_CPUID: libc::c_long = 0x1012;
+
+    /// Runs `f` in a child process set up like an enclave, and returns
+    /// whether it exited with `true`.
+    fn enclave(f: fn() -> bool) -> bool {
+        unsafe {
+            match libc::fork() {
+                -1 => panic!("fork failed"),
+
+                0 => {
+                    // Nothing here may allocate: the allocator may need a
+                    // system call, or a lock another thread held at the fork.
+                    let _ = libc::prctl(libc::PR_SET_TSC, libc::PR_TSC_SIGSEGV);
+                    let _ = libc::syscall(libc::SYS_arch_prctl, ARCH_SET_CPUID, 0);
+                    if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_STRICT) != 0 {
+                        libc::_exit(2);
+                    }
+
+                    // exit_group(), which _exit() calls, isn't allowed.
+                    let _ = libc::syscall(libc::SYS_exit, !f() as libc::c_long);
+                    unreachable!();
+                }
+
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                    assert!(
+                        !libc::WIFSIGNALED(status),
+                        "killed by signal {}",
+                        libc::WTERMSIG(status)
+                    );
+                    libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn switch() {
+        assert!(enclave(|| {
+            let mut stack = [1u8; STACK_MINIMUM];
+
+            let mut coro = Coroutine::new(&mut stack, |c, a: u32| {
+                let (c, b) = c.r#yield(a + 1)?;
+                let (c, d) = c.r#yield(b + 1)?;
+                c.done(d + 1)
+            });
+
+            let mut coro = Pin::new(&mut coro);
+            matches!(coro.as_mut().resume_with(1), GeneratorState::Yielded(2))
+                && matches!(coro.as_mut().resume_with(3), GeneratorState::Yielded(4))
+                && matches!(coro.as_mut().resume_with(5), GeneratorState::Complete(6))
+        }));
+    }
+
+    #[test]
+    fn denied() {
+        // The stand-in catches what an enclave would.
+        assert!(std::panic::catch_unwind(|| enclave(|| unsafe {
+            let _ = core::arch::x86_64::_rdtsc();
+            true
+        }))
+        .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In an AMD SEV-SNP guest, the hypervisor can't look into the guest's
+//! registers, so every instruction it intercepts (`cpuid`, `rdtsc`, `in`,
+//! `out`, `rdmsr`, `wrmsr`, `vmmcall`, ...) raises a #VC exception instead,
+//! which the guest kernel handles by talking to the hypervisor, or fails to.
+//!
+//! With the `snp` feature, `build.rs` makes sure the context switch is the
+//! x86_64 assembly backend without CET, and the test below decodes its three
+//! functions and fails on any instruction outside this list:
+//!
+//! * `mov`, `lea`, `push`, `pop`, and `add`, `sub`, `xor` and `test` on
+//!   general-purpose registers;
+//! * `stmxcsr`, `ldmxcsr`, `fnstcw` and `fldcw`, which save and restore the
+//!   floating-point control state;
+//! * `jmp`, `jz`, `call`, `ret`, and the `nop`s and `endbr64` which may pad
+//!   or start them.
+//!
+//! None of them is ever intercepted. The decoder only knows the encodings
+//! assemblers use for these, so whatever else appears fails the test until it
+//! is checked and added.
+
+#[cfg(all(test, not(miri)))]
+mod tests {
+    use crate::{jump_init, jump_swap, JumpBuffer};
+
+    extern "C" {
+        fn jump_into(into: *mut JumpBuffer) -> !;
+    }
+
+    /// Walks the instructions from `code` to the first `ret` or `jmp`, and
+    /// returns the offset of the first one not in the list, if any.
+    unsafe fn audit(code: *const u8) -> Result<(), usize> {
+        let mut at = 0;
+
+        loop {
+            let start = at;
+            let byte = |i: usize| *code.add(i);
+
+            // endbr64
+            if byte(at) == 0xf3 && [byte(at + 1), byte(at + 2), byte(at + 3)] == [0x0f, 0x1e, 0xfa]
+            {
+                at += 4;
+                continue;
+            }
+
+            // A REX prefix.
+            if byte(at) & 0xf0 == 0x40 {
+                at += 1;
+            }
+
+            // The length of a ModRM operand, and the reg field.
+            let modrm = |i: usize| -> (usize, u8) {
+                let m = byte(i);
+                let len = match (m >> 6, m & 7) {
+                    (3, _) => 1,
+                    (0, 4) if byte(i + 1) & 7 == 5 => 6,
+                    (0, 4) => 2,
+                    (0, 5) => 5,
+                    (0, _) => 1,
+                    (1, 4) => 3,
+                    (1, _) => 2,
+                    (2, 4) => 6,
+                    _ => 5,
+                };
+                (len, (m >> 3) & 7)
+            };
+            let memory = |i: usize| byte(i) >> 6 != 3;
+
+            let op = byte(at);
+            at += 1;
+            match op {
+                0x50..=0x5f | 0x90 => {} // push, pop, nop
+                0x01 | 0x03 | 0x29 | 0x2b | 0x31 | 0x33 | 0x85 | 0x89 | 0x8b => {
+                    at += modrm(at).0; // add, sub, xor, test, mov
+                }
+                0x8d if memory(at) => at += modrm(at).0, // lea
+                0x83 => match modrm(at) {
+                    (len, 0) | (len, 5) => at += len + 1, // add, sub imm8
+                    _ => return Err(start),
+                },
+                0x81 => match modrm(at) {
+                    (len, 0) | (len, 5) => at += len + 4, // add, sub imm32
+                    _ => return Err(start),
+                },
+                0xd9 if memory(at) => match modrm(at) {
+                    (len, 5) | (len, 7) => at += len, // fldcw, fnstcw
+                    _ => return Err(start),
+                },
+                0x0f => {
+                    let op = byte(at);
+                    at += 1;
+                    match op {
+                        0xae if memory(at) => match modrm(at) {
+                            (len, 2) | (len, 3) => at += len, // ldmxcsr, stmxcsr
+                            _ => return Err(start),
+                        },
+                        0x1f => at += modrm(at).0, // nop
+                        0x84 => at += 4,           // jz rel32
+                        _ => return Err(start),
+                    }
+                }
+                0x74 => at += 1, // jz rel8
+                0xe8 => at += 4, // call rel32
+                0xff => match modrm(at) {
+                    (len, 2) => at += len, // call
+                    _ => return Err(start),
+                },
+                0xc3 | 0xe9 | 0xeb => return Ok(()), // ret, jmp
+                _ => return Err(start),
+            }
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        let functions = [
+            ("jump_swap", jump_swap as *const u8),
+            ("jump_into", jump_into as *const u8),
+            ("jump_init", jump_init as *const u8),
+        ];
+
+        for (name, f) in functions.iter() {
+            if let Err(offset) = unsafe { audit(*f) } {
+                panic!(
+                    "{}+{:#x}: not an instruction the snp feature allows",
+                    name, offset
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decoder() {
+        // cpuid, rdtsc, and rdsspq (which the cet feature would add).
+        for code in [
+            &[0x0f, 0xa2][..],
+            &[0x0f, 0x31],
+            &[0xf3, 0x48, 0x0f, 0x1e, 0xc8],
+        ]
+        .iter()
+        {
+            assert_eq!(unsafe { audit(code.as_ptr()) }, Err(0));
+        }
+
+        // push rbp; mov rax, [rdi + 8]; stmxcsr [rsp]; ret
+        let code = [0x55, 0x48, 0x8b, 0x47, 0x08, 0x0f, 0xae, 0x1c, 0x24, 0xc3];
+        assert_eq!(unsafe { audit(code.as_ptr()) }, Ok(()));
+    }
+}
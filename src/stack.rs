@@ -0,0 +1,952 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(has_const_generics)]
+pub use self::aligned::{AlignedStack, StaticStack};
+#[cfg(feature = "alloc")]
+pub use self::heap::HeapStack;
+#[cfg(feature = "std")]
+pub use self::protected::ProtectedStack;
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
+/// A region of memory a coroutine can run on.
+///
+/// `Coroutine::new()` takes ownership of a `Stack` for the lifetime of the
+/// coroutine. Mutable references to byte buffers (arrays, slices, vectors,
+/// ...) are stacks, as are owned allocations such as `ProtectedStack`.
+///
+/// # Safety
+///
+/// The region described by `bottom()` and `len()` must be valid for reads and
+/// writes and must not be accessed through any other path while the `Stack` is
+/// alive. It also must not move when the `Stack` value itself is moved, since
+/// the coroutine's frames live inside it.
+#[allow(clippy::len_without_is_empty)]
+pub unsafe trait Stack {
+    /// Returns a pointer to the lowest address of the stack.
+    fn bottom(&mut self) -> *mut u8;
+
+    /// Returns the size of the stack in bytes.
+    fn len(&self) -> usize;
+}
+
+/// A stack in memory allocated by other means, described by a pointer and a
+/// length.
+///
+/// This is for memory Rust doesn't know about, such as a region mapped by a
+/// loader. The `RawStack` neither owns nor frees it.
+pub struct RawStack {
+    bottom: *mut u8,
+    len: usize,
+}
+
+impl RawStack {
+    /// Describes the stack of `len` bytes at `bottom`.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for reads and writes for as long as the
+    /// `RawStack` (and any coroutine running on it) is alive, and must not be
+    /// accessed through any other path in the meantime.
+    pub unsafe fn new(bottom: *mut u8, len: usize) -> Self {
+        RawStack { bottom, len }
+    }
+}
+
+unsafe impl Stack for RawStack {
+    fn bottom(&mut self) -> *mut u8 {
+        self.bottom
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+unsafe impl<T: AsMut<[u8]> + AsRef<[u8]> + ?Sized> Stack for &mut T {
+    fn bottom(&mut self) -> *mut u8 {
+        (**self).as_mut().as_mut_ptr()
+    }
+
+    fn len(&self) -> usize {
+        (**self).as_ref().len()
+    }
+}
+
+// Moving the box doesn't move the stack in it, whatever kind it is.
+#[cfg(feature = "alloc")]
+unsafe impl<T: Stack + ?Sized> Stack for Box<T> {
+    fn bottom(&mut self) -> *mut u8 {
+        (**self).bottom()
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+}
+
+#[cfg(has_const_generics)]
+mod aligned {
+    use crate::STACK_MINIMUM;
+    use core::cell::UnsafeCell;
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    /// A stack of `N` bytes, aligned to `STACK_ALIGNMENT`.
+    ///
+    /// It can live anywhere a byte array can, and is passed to
+    /// `Coroutine::new()` by mutable reference. `N` must be at least
+    /// `STACK_MINIMUM`, which is checked at compile time. Requires Rust 1.57
+    /// or later.
+    ///
+    /// ```
+    /// # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+    /// use frenetic::{AlignedStack, Coroutine, STACK_MINIMUM};
+    ///
+    /// let mut stack = AlignedStack::<{ STACK_MINIMUM * 8 }>::new();
+    /// let coro = Coroutine::new(&mut stack, |c, ()| c.done(1));
+    /// # let _: Coroutine<'_, (), (), i32, _> = coro;
+    /// ```
+    #[repr(C, align(16))] // STACK_ALIGNMENT
+    pub struct AlignedStack<const N: usize>([u8; N]);
+
+    impl<const N: usize> AlignedStack<N> {
+        const CHECK: () = assert!(
+            N >= STACK_MINIMUM,
+            "the stack is smaller than STACK_MINIMUM"
+        );
+
+        /// Creates a zeroed stack.
+        pub fn new() -> Self {
+            let () = Self::CHECK;
+            AlignedStack([0; N])
+        }
+    }
+
+    impl<const N: usize> Default for AlignedStack<N> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<const N: usize> Deref for AlignedStack<N> {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    impl<const N: usize> DerefMut for AlignedStack<N> {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.0
+        }
+    }
+
+    impl<const N: usize> AsRef<[u8]> for AlignedStack<N> {
+        fn as_ref(&self) -> &[u8] {
+            self
+        }
+    }
+
+    impl<const N: usize> AsMut<[u8]> for AlignedStack<N> {
+        fn as_mut(&mut self) -> &mut [u8] {
+            self
+        }
+    }
+
+    /// A stack of `N` bytes in a `static`, which can be taken once.
+    ///
+    /// Without an allocator, coroutines which outlive the function spawning
+    /// them need stacks with a `'static` lifetime. `take()` returns the stack
+    /// the first time it is called, and `None` after that, so no two
+    /// coroutines ever share it. Requires Rust 1.57 or later.
+    ///
+    /// ```
+    /// # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+    /// use frenetic::{Coroutine, StaticStack, STACK_MINIMUM};
+    ///
+    /// static STACK: StaticStack<{ STACK_MINIMUM * 2 }> = StaticStack::new();
+    ///
+    /// let coro = Coroutine::new(STACK.take().unwrap(), |c, ()| c.done(1));
+    /// assert!(STACK.take().is_none());
+    /// # let _: Coroutine<'static, (), (), i32, _> = coro;
+    /// ```
+    pub struct StaticStack<const N: usize> {
+        taken: AtomicBool,
+        stack: UnsafeCell<AlignedStack<N>>,
+    }
+
+    // The stack is only reachable through the one reference `take()` returns.
+    unsafe impl<const N: usize> Sync for StaticStack<N> {}
+
+    impl<const N: usize> StaticStack<N> {
+        /// Creates a zeroed stack, which has not been taken yet.
+        pub const fn new() -> Self {
+            let () = AlignedStack::<N>::CHECK;
+            StaticStack {
+                taken: AtomicBool::new(false),
+                stack: UnsafeCell::new(AlignedStack([0; N])),
+            }
+        }
+
+        /// Returns the stack, unless it has been taken before.
+        #[allow(clippy::mut_from_ref)] // Only ever once, see `taken`.
+        pub fn take(&'static self) -> Option<&'static mut AlignedStack<N>> {
+            if self.taken.swap(true, Ordering::Acquire) {
+                return None;
+            }
+
+            Some(unsafe { &mut *self.stack.get() })
+        }
+    }
+
+    impl<const N: usize> Default for StaticStack<N> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+mod heap {
+    use super::Stack;
+    use crate::STACK_ALIGNMENT;
+    use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+    use core::ops::{Deref, DerefMut};
+    use core::slice;
+
+    /// A stack allocated on the heap.
+    ///
+    /// The buffer is zeroed, aligned to `STACK_ALIGNMENT` and released when
+    /// the `HeapStack` is dropped.
+    pub struct HeapStack {
+        base: *mut u8,
+        layout: Layout,
+    }
+
+    // The allocation is exclusively owned by the `HeapStack`.
+    unsafe impl Send for HeapStack {}
+    unsafe impl Sync for HeapStack {}
+
+    impl HeapStack {
+        /// Allocates a new stack on the heap.
+        ///
+        /// # Arguments
+        ///
+        /// * `size` - The size of the stack. This is rounded up to a multiple
+        ///   of `STACK_ALIGNMENT`.
+        pub fn new(size: usize) -> Self {
+            let size = size
+                .max(1)
+                .checked_add(STACK_ALIGNMENT - 1)
+                .map(|s| s & !(STACK_ALIGNMENT - 1))
+                .expect("stack size overflow");
+
+            let layout =
+                Layout::from_size_align(size, STACK_ALIGNMENT).expect("invalid stack size");
+
+            // Zeroed, as the bytes can be read through `Deref`.
+            let base = unsafe { alloc_zeroed(layout) };
+            if base.is_null() {
+                handle_alloc_error(layout);
+            }
+
+            HeapStack { base, layout }
+        }
+    }
+
+    impl Drop for HeapStack {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.base, self.layout) }
+        }
+    }
+
+    impl Deref for HeapStack {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.base, self.layout.size()) }
+        }
+    }
+
+    impl DerefMut for HeapStack {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.base, self.layout.size()) }
+        }
+    }
+
+    unsafe impl Stack for HeapStack {
+        fn bottom(&mut self) -> *mut u8 {
+            self.base
+        }
+
+        fn len(&self) -> usize {
+            self.layout.size()
+        }
+    }
+
+    impl AsRef<[u8]> for HeapStack {
+        fn as_ref(&self) -> &[u8] {
+            self
+        }
+    }
+
+    impl AsMut<[u8]> for HeapStack {
+        fn as_mut(&mut self) -> &mut [u8] {
+            self
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod protected {
+    use super::Stack;
+    use crate::HugePages;
+    use core::mem::forget;
+    use core::ops::{Deref, DerefMut};
+    use core::slice;
+    use std::io::{Error, ErrorKind, Result};
+
+    /// A stack with an inaccessible guard page below it.
+    ///
+    /// Overflowing a `ProtectedStack` faults on the guard page instead of
+    /// silently corrupting the memory next to the stack. The memory is
+    /// released when the `ProtectedStack` is dropped.
+    pub struct ProtectedStack {
+        base: *mut u8,
+        size: usize,
+        guard: usize,
+    }
+
+    // The mapping is exclusively owned by the `ProtectedStack`.
+    unsafe impl Send for ProtectedStack {}
+    unsafe impl Sync for ProtectedStack {}
+
+    impl ProtectedStack {
+        /// Allocates a new stack with a guard page below it.
+        ///
+        /// # Arguments
+        ///
+        /// * `size` - The usable size of the stack. This is rounded up to a
+        ///   multiple of the page size. The guard page is allocated in
+        ///   addition to this size.
+        pub fn new(size: usize) -> Result<Self> {
+            Self::map(size, HugePages::No, false)
+        }
+
+        /// Allocates a new stack with a guard page below it, backed by huge
+        /// pages if the OS has them.
+        ///
+        /// With thousands of coroutines running hot loops, the TLB misses on
+        /// their stacks' small pages add up. On Linux, the stack is aligned to
+        /// a huge page, and its usable size is rounded up to a multiple of the
+        /// huge page size. `HugePages::Transparent` then asks for transparent
+        /// huge pages (`madvise(MADV_HUGEPAGE)`), which the kernel backs the
+        /// stack with when it can. `HugePages::Explicit` maps the stack from
+        /// the pool of huge pages reserved by the administrator
+        /// (`MAP_HUGETLB`), and falls back to transparent huge pages if the
+        /// pool is empty. Elsewhere, and with `HugePages::No`, this is `new()`.
+        pub fn with_huge_pages(size: usize, huge_pages: HugePages) -> Result<Self> {
+            Self::map(size, huge_pages, false)
+        }
+
+        /// Maps a stack, with huge pages if `huge_pages` asks for them, and
+        /// marked as growing down (`MAP_GROWSDOWN`, on Linux) if `grows_down`.
+        ///
+        /// Where the OS has it, the stack is mapped with `MAP_STACK`.
+        pub(crate) fn map(size: usize, huge_pages: HugePages, grows_down: bool) -> Result<Self> {
+            let guard = sys::page_size();
+            let align = sys::huge_page_size(huge_pages).unwrap_or(guard);
+            let size =
+                mapping(size, guard, align).ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+            let grows_down = grows_down && cfg!(any(target_os = "linux", target_os = "android"));
+
+            unsafe {
+                if align > guard || grows_down {
+                    let base =
+                        sys::map_aligned(size - guard, guard, align, huge_pages, grows_down)?;
+                    return Ok(ProtectedStack { base, size, guard });
+                }
+
+                let base = sys::map(size)?;
+
+                // The stack grows down, so the guard page goes at the bottom.
+                if let Err(e) = sys::protect(base, guard) {
+                    sys::unmap(base, size);
+                    return Err(e);
+                }
+
+                Ok(ProtectedStack { base, size, guard })
+            }
+        }
+
+        /// Tells the OS that the contents of the lowest `len` bytes of the
+        /// stack, rounded down to whole pages, are no longer needed.
+        ///
+        /// The memory stays mapped, but its physical pages may be released.
+        /// Their contents are unspecified afterwards.
+        pub(crate) fn discard(&mut self, len: usize) {
+            let len = len.min(self.size - self.guard) & !(self.guard - 1);
+            if len > 0 {
+                unsafe { sys::discard(self.base.add(self.guard), len) }
+            }
+        }
+
+        /// Gives up ownership of the mapping, and returns the bottom of the
+        /// stack. See `from_raw()`.
+        pub(crate) fn into_raw(mut self) -> *mut u8 {
+            let bottom = Stack::bottom(&mut self);
+            forget(self);
+            bottom
+        }
+
+        /// Takes back the ownership of a stack given up by `into_raw()`,
+        /// which was created by `with_huge_pages()` with the same `size` and
+        /// `huge_pages`.
+        pub(crate) unsafe fn from_raw(bottom: *mut u8, size: usize, huge_pages: HugePages) -> Self {
+            let guard = sys::page_size();
+            let align = sys::huge_page_size(huge_pages).unwrap_or(guard);
+            let size = mapping(size, guard, align).unwrap();
+            let base = bottom.sub(guard);
+            ProtectedStack { base, size, guard }
+        }
+    }
+
+    /// Returns the size of the mapping for a stack of `size` bytes: `size`
+    /// rounded up to a multiple of `align`, plus the guard page.
+    fn mapping(size: usize, guard: usize, align: usize) -> Option<usize> {
+        size.checked_add(align - 1)
+            .map(|s| s & !(align - 1))
+            .and_then(|s| s.checked_add(guard))
+    }
+
+    impl Drop for ProtectedStack {
+        fn drop(&mut self) {
+            unsafe { sys::unmap(self.base, self.size) }
+        }
+    }
+
+    impl Deref for ProtectedStack {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.base.add(self.guard), self.size - self.guard) }
+        }
+    }
+
+    impl DerefMut for ProtectedStack {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            unsafe { slice::from_raw_parts_mut(self.base.add(self.guard), self.size - self.guard) }
+        }
+    }
+
+    unsafe impl Stack for ProtectedStack {
+        fn bottom(&mut self) -> *mut u8 {
+            unsafe { self.base.add(self.guard) }
+        }
+
+        fn len(&self) -> usize {
+            self.size - self.guard
+        }
+    }
+
+    impl AsRef<[u8]> for ProtectedStack {
+        fn as_ref(&self) -> &[u8] {
+            self
+        }
+    }
+
+    impl AsMut<[u8]> for ProtectedStack {
+        fn as_mut(&mut self) -> &mut [u8] {
+            self
+        }
+    }
+
+    #[cfg(unix)]
+    mod sys {
+        use crate::HugePages;
+        use core::ptr::null_mut;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use std::io::{Error, ErrorKind, Result};
+
+        pub(super) fn page_size() -> usize {
+            unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+        }
+
+        pub(super) unsafe fn map(size: usize) -> Result<*mut u8> {
+            let ptr = libc::mmap(
+                null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON | STACK,
+                -1,
+                0,
+            );
+
+            if ptr == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(ptr as *mut u8)
+        }
+
+        // OpenBSD requires the stack pointer to point into memory mapped with
+        // MAP_STACK. Elsewhere it is a hint, if it exists at all.
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly"
+        ))]
+        const STACK: libc::c_int = libc::MAP_STACK;
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly"
+        )))]
+        const STACK: libc::c_int = 0;
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        const GROWSDOWN: libc::c_int = libc::MAP_GROWSDOWN;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        const GROWSDOWN: libc::c_int = 0;
+
+        /// Returns the size of the huge pages `huge_pages` asks for, if any.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        pub(super) fn huge_page_size(huge_pages: HugePages) -> Option<usize> {
+            // 0 until it's been read, 1 if there are none.
+            static SIZE: AtomicUsize = AtomicUsize::new(0);
+
+            if huge_pages == HugePages::No {
+                return None;
+            }
+
+            let mut size = SIZE.load(Ordering::Relaxed);
+            if size == 0 {
+                size = read_huge_page_size()
+                    .filter(|s| s.is_power_of_two() && *s > page_size())
+                    .unwrap_or(1);
+                SIZE.store(size, Ordering::Relaxed);
+            }
+
+            Some(size).filter(|s| *s > 1)
+        }
+
+        /// Reads the size of the transparent huge pages, or else the default
+        /// size of the huge pages in the pool ("Hugepagesize:    2048 kB").
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        fn read_huge_page_size() -> Option<usize> {
+            use std::fs::read_to_string;
+
+            if let Ok(size) = read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size") {
+                return size.trim().parse().ok();
+            }
+
+            let info = read_to_string("/proc/meminfo").ok()?;
+            let line = info.lines().find(|l| l.starts_with("Hugepagesize:"))?;
+            let kb = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+            kb.checked_mul(1024)
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        pub(super) fn huge_page_size(_huge_pages: HugePages) -> Option<usize> {
+            None
+        }
+
+        /// Maps `len` bytes at an address aligned to `align`, with a guard
+        /// page of `guard` bytes below them, and returns the address of the
+        /// guard page. `HugePages::Explicit` maps them from the huge page
+        /// pool if possible, and otherwise any huge pages are transparent
+        /// ones. With `grows_down`, the kernel keeps other mappings out of the
+        /// gap below them (`vm.stack_guard_gap`), as it does for the stack of
+        /// the main thread.
+        pub(super) unsafe fn map_aligned(
+            len: usize,
+            guard: usize,
+            align: usize,
+            huge_pages: HugePages,
+            grows_down: bool,
+        ) -> Result<*mut u8> {
+            // Reserve enough address space to align the stack within it, and
+            // give back what is left over on either side.
+            let reserve = len
+                .checked_add(align)
+                .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+            let start = libc::mmap(
+                null_mut(),
+                reserve,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+            if start == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+
+            let start = start as usize;
+            let bottom = (start + guard + align - 1) & !(align - 1);
+            let (base, end) = (bottom - guard, bottom + len);
+            if base > start {
+                unmap(start as *mut u8, base - start);
+            }
+            if start + reserve > end {
+                unmap(end as *mut u8, start + reserve - end);
+            }
+
+            // The guard page stays as it was reserved: inaccessible.
+            let flags = STACK | if grows_down { GROWSDOWN } else { 0 };
+            let explicit = huge_pages == HugePages::Explicit;
+            if !(explicit && fixed(bottom, len, flags | HUGETLB).is_ok()) {
+                if let Err(e) = fixed(bottom, len, flags) {
+                    unmap(base as *mut u8, guard + len);
+                    return Err(e);
+                }
+
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                {
+                    if huge_pages != HugePages::No {
+                        let _ = libc::madvise(bottom as _, len, libc::MADV_HUGEPAGE);
+                    }
+                }
+            }
+
+            Ok(base as *mut u8)
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        const HUGETLB: libc::c_int = libc::MAP_HUGETLB;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        const HUGETLB: libc::c_int = 0;
+
+        /// Maps `len` bytes of memory at `addr`, over whatever was there.
+        unsafe fn fixed(addr: usize, len: usize, flags: libc::c_int) -> Result<()> {
+            let ptr = libc::mmap(
+                addr as _,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_FIXED | flags,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(())
+        }
+
+        pub(super) unsafe fn protect(ptr: *mut u8, size: usize) -> Result<()> {
+            if libc::mprotect(ptr as _, size, libc::PROT_NONE) != 0 {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(())
+        }
+
+        pub(super) unsafe fn discard(ptr: *mut u8, size: usize) {
+            let _ = libc::madvise(ptr as _, size, libc::MADV_DONTNEED);
+        }
+
+        pub(super) unsafe fn unmap(ptr: *mut u8, size: usize) {
+            let _ = libc::munmap(ptr as _, size);
+        }
+    }
+
+    #[cfg(windows)]
+    mod sys {
+        use crate::HugePages;
+        use core::mem::MaybeUninit;
+        use core::ptr::null_mut;
+        use std::io::{Error, ErrorKind, Result};
+        use winapi::um::memoryapi::{VirtualAlloc, VirtualFree, VirtualProtect};
+        use winapi::um::sysinfoapi::GetSystemInfo;
+        use winapi::um::winnt::{MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, MEM_RESET};
+        use winapi::um::winnt::{PAGE_NOACCESS, PAGE_READWRITE};
+
+        pub(super) fn page_size() -> usize {
+            unsafe {
+                let mut info = MaybeUninit::uninit();
+                GetSystemInfo(info.as_mut_ptr());
+                info.assume_init().dwPageSize as usize
+            }
+        }
+
+        pub(super) unsafe fn map(size: usize) -> Result<*mut u8> {
+            let ptr = VirtualAlloc(null_mut(), size, MEM_RESERVE | MEM_COMMIT, PAGE_READWRITE);
+
+            if ptr.is_null() {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(ptr as *mut u8)
+        }
+
+        // Large pages need a privilege most processes don't have, and can't
+        // be mixed with small ones, as the guard page is.
+        pub(super) fn huge_page_size(_huge_pages: HugePages) -> Option<usize> {
+            None
+        }
+
+        pub(super) unsafe fn map_aligned(
+            _len: usize,
+            _guard: usize,
+            _align: usize,
+            _huge_pages: HugePages,
+            _grows_down: bool,
+        ) -> Result<*mut u8> {
+            Err(Error::from(ErrorKind::InvalidInput))
+        }
+
+        pub(super) unsafe fn protect(ptr: *mut u8, size: usize) -> Result<()> {
+            let mut old = 0;
+            if VirtualProtect(ptr as _, size, PAGE_NOACCESS, &mut old) == 0 {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(())
+        }
+
+        pub(super) unsafe fn discard(ptr: *mut u8, size: usize) {
+            let _ = VirtualAlloc(ptr as _, size, MEM_RESET, PAGE_READWRITE);
+        }
+
+        pub(super) unsafe fn unmap(ptr: *mut u8, _size: usize) {
+            let _ = VirtualFree(ptr as _, 0, MEM_RELEASE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "alloc")]
+    use super::*;
+    #[cfg(feature = "alloc")]
+    use crate::STACK_ALIGNMENT;
+    use crate::{Coroutine, Generator, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    #[test]
+    fn slice() {
+        let mut stack = vec![1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack[..], |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[test]
+    fn raw() {
+        let mut stack = vec![1u8; STACK_MINIMUM];
+        let (ptr, len) = (stack.as_mut_ptr(), stack.len());
+
+        let mut coro = unsafe {
+            Coroutine::new_raw(ptr, len, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done("foo")
+            })
+        };
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        drop(coro);
+        drop(stack);
+    }
+
+    #[cfg(has_const_generics)]
+    #[test]
+    fn aligned() {
+        use crate::{AlignedStack, STACK_ALIGNMENT};
+
+        let mut stack = AlignedStack::<{ STACK_MINIMUM * 2 }>::new();
+        assert_eq!(stack.as_ptr() as usize % STACK_ALIGNMENT, 0);
+        assert_eq!(stack.len(), STACK_MINIMUM * 2);
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[cfg(has_const_generics)]
+    #[test]
+    fn r#static() {
+        use crate::{StaticStack, STACK_ALIGNMENT};
+
+        static STACK: StaticStack<{ STACK_MINIMUM * 2 }> = StaticStack::new();
+
+        let stack = STACK.take().unwrap();
+        assert_eq!(stack.as_ptr() as usize % STACK_ALIGNMENT, 0);
+        assert!(STACK.take().is_none());
+
+        let mut coro: Coroutine<'static, (), i32, &str, _> = Coroutine::new(stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn heap() {
+        let stack = HeapStack::new(STACK_MINIMUM + 1);
+        assert_eq!(Stack::len(&stack) % STACK_ALIGNMENT, 0);
+        assert_eq!(stack.as_ptr() as usize % STACK_ALIGNMENT, 0);
+        assert!(stack.iter().all(|&b| b == 0));
+
+        let mut coro = Coroutine::new(stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn owned() {
+        fn spawn() -> Coroutine<'static, (), i32, &'static str, ProtectedStack> {
+            let stack = ProtectedStack::new(STACK_MINIMUM).unwrap();
+
+            Coroutine::new(stack, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done("foo")
+            })
+        }
+
+        // Move the coroutine (and its stack) around before resuming it.
+        let mut coro = Box::new(spawn());
+
+        match Pin::new(&mut *coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut *coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mprotect().
+    fn protected() {
+        let mut stack = ProtectedStack::new(STACK_MINIMUM + 1).unwrap();
+        assert!(Stack::len(&stack) > STACK_MINIMUM);
+        assert_eq!(Stack::len(&stack) % STACK_MINIMUM, 0);
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done("foo")
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(1) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete("foo") => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri can't call mmap().
+    fn huge_pages() {
+        use crate::HugePages;
+
+        for &huge in &[HugePages::Transparent, HugePages::Explicit] {
+            let mut stack = ProtectedStack::with_huge_pages(STACK_MINIMUM + 1, huge).unwrap();
+            assert!(Stack::len(&stack) > STACK_MINIMUM);
+            assert_eq!(Stack::len(&stack) % STACK_MINIMUM, 0);
+
+            let mut coro = Coroutine::new(&mut stack, |c, ()| {
+                let (c, ()) = c.r#yield(1)?;
+                c.done("foo")
+            });
+
+            match Pin::new(&mut coro).resume() {
+                GeneratorState::Yielded(1) => {}
+                _ => panic!("unexpected return from resume"),
+            }
+
+            match Pin::new(&mut coro).resume() {
+                GeneratorState::Complete("foo") => {}
+                _ => panic!("unexpected return from resume"),
+            }
+
+            drop(coro);
+            stack.discard(STACK_MINIMUM);
+        }
+    }
+}
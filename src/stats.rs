@@ -0,0 +1,151 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! With the `counters` feature, every thread counts the context switches it
+//! makes, so that benchmarks and tests can tell how many a piece of code
+//! costs. Every coroutine also counts how many times it has been resumed,
+//! and how long it has run, timing each resume with the monotonic clock.
+//!
+//! Without it, `count_switch()` is an empty function, `Stats` an empty type
+//! and all of this compiles away.
+
+#[cfg(feature = "counters")]
+mod imp {
+    use core::cell::Cell;
+    use core::time::Duration;
+    use std::time::Instant;
+
+    thread_local! {
+        static SWITCHES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Returns the number of context switches the current thread has made.
+    ///
+    /// Starting or resuming a coroutine is one switch, and so is every yield,
+    /// transfer or return back out of it. A coroutine canceled before it
+    /// starts is dropped without any. With the thread backend, the switches
+    /// out of a coroutine are made by the thread it runs on.
+    ///
+    /// Requires the `counters` feature.
+    pub fn switches() -> usize {
+        SWITCHES.with(Cell::get)
+    }
+
+    #[inline(always)]
+    pub(crate) fn count_switch() {
+        SWITCHES.with(|n| n.set(n.get().wrapping_add(1)));
+    }
+
+    /// How much a coroutine has run so far. See `Coroutine::stats()`.
+    ///
+    /// Requires the `counters` feature.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct CoroutineStats {
+        /// The number of times it has been resumed, including the last time,
+        /// when a coroutine which has started is canceled.
+        pub resumes: u64,
+
+        /// The time it has spent running, from switching into it until it
+        /// switched back. This includes the time spent in coroutines it
+        /// resumed, and with the thread backend, waking its thread up.
+        pub run_time: Duration,
+    }
+
+    /// The statistics of a coroutine.
+    pub(crate) struct Stats(CoroutineStats);
+
+    impl Stats {
+        pub(crate) fn new() -> Self {
+            Stats(CoroutineStats::default())
+        }
+
+        /// Samples the clock, right before the coroutine is resumed.
+        #[inline(always)]
+        pub(crate) fn start(&self) -> Started {
+            Started(Instant::now())
+        }
+
+        /// Adds a resume, which started at `started`, once the coroutine has
+        /// switched back.
+        #[inline(always)]
+        pub(crate) fn stop(&mut self, started: Started) {
+            self.0.resumes += 1;
+            self.0.run_time += started.0.elapsed();
+        }
+
+        pub(crate) fn get(&self) -> CoroutineStats {
+            self.0
+        }
+    }
+
+    /// When the coroutine was resumed.
+    pub(crate) struct Started(Instant);
+}
+
+#[cfg(not(feature = "counters"))]
+mod imp {
+    #[inline(always)]
+    pub(crate) fn count_switch() {}
+
+    pub(crate) struct Stats;
+
+    impl Stats {
+        #[inline(always)]
+        pub(crate) fn new() -> Self {
+            Stats
+        }
+
+        #[inline(always)]
+        pub(crate) fn start(&self) -> Started {
+            Started
+        }
+
+        #[inline(always)]
+        pub(crate) fn stop(&mut self, _started: Started) {}
+    }
+
+    pub(crate) struct Started;
+}
+
+pub(crate) use self::imp::{count_switch, Stats};
+#[cfg(feature = "counters")]
+pub use self::imp::{switches, CoroutineStats};
+
+#[cfg(all(test, feature = "counters"))]
+mod tests {
+    use crate::{Coroutine, Generator, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    #[test]
+    fn stats() {
+        let mut stack = [1u8; STACK_MINIMUM];
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            std::thread::sleep(core::time::Duration::from_millis(10));
+            let (c, ()) = c.r#yield(2)?;
+            c.done(3)
+        });
+
+        assert_eq!(coro.stats().resumes, 0);
+
+        let _ = Pin::new(&mut coro).resume();
+        let _ = Pin::new(&mut coro).resume();
+        let stats = coro.stats();
+        assert_eq!(stats.resumes, 2);
+        assert!(stats.run_time.as_millis() >= 10);
+
+        let _ = Pin::new(&mut coro).resume();
+        assert_eq!(coro.stats().resumes, 3);
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Coroutine, ResumeState};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_core::{FusedStream, Stream};
+
+/// A coroutine driven as a `Stream` of the values it yields.
+///
+/// Every poll resumes the coroutine, and every value it yields is the next
+/// item. While it waits for an event (see `Control::yield_pending()`), polling
+/// returns `Poll::Pending`. The stream ends when the coroutine completes. A
+/// coroutine returning `Result<(), E>` makes a stream of `Result<Y, E>`, whose
+/// last item is the error it returned, if any. See `Coroutine::into_stream()`.
+pub struct CoroutineStream<'a, Y, R, S = &'a mut [u8]>(Coroutine<'a, (), Y, R, S>);
+
+impl<'a, Y, R, S> Coroutine<'a, (), Y, R, S> {
+    /// Turns this coroutine into a `Stream` of the values it yields.
+    ///
+    /// As with `into_future()`, the waker of the polling task is available
+    /// to the coroutine through `Control::waker()`.
+    pub fn into_stream(self) -> CoroutineStream<'a, Y, R, S> {
+        CoroutineStream(self)
+    }
+}
+
+impl<'a, Y, S> Stream for CoroutineStream<'a, Y, (), S> {
+    type Item = Y;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Y>> {
+        let coroutine = &mut self.get_mut().0;
+        if coroutine.is_finished() {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(coroutine).poll_resume((), cx) {
+            ResumeState::Yielded(y) => Poll::Ready(Some(y)),
+            ResumeState::Pending => Poll::Pending,
+            ResumeState::Complete(()) => Poll::Ready(None),
+        }
+    }
+}
+
+impl<'a, Y, S> FusedStream for CoroutineStream<'a, Y, (), S> {
+    fn is_terminated(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+impl<'a, Y, E, S> Stream for CoroutineStream<'a, Y, Result<(), E>, S> {
+    type Item = Result<Y, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Y, E>>> {
+        let coroutine = &mut self.get_mut().0;
+        if coroutine.is_finished() {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(coroutine).poll_resume((), cx) {
+            ResumeState::Yielded(y) => Poll::Ready(Some(Ok(y))),
+            ResumeState::Pending => Poll::Pending,
+            ResumeState::Complete(Ok(())) => Poll::Ready(None),
+            ResumeState::Complete(Err(e)) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl<'a, Y, E, S> FusedStream for CoroutineStream<'a, Y, Result<(), E>, S> {
+    fn is_terminated(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::future::noop_waker;
+    use crate::STACK_MINIMUM;
+
+    fn collect<T: Stream + Unpin>(mut stream: T) -> Vec<T::Item> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut items = Vec::new();
+
+        while let Poll::Ready(Some(item)) = Pin::new(&mut stream).poll_next(&mut cx) {
+            items.push(item);
+        }
+
+        items
+    }
+
+    #[test]
+    fn stream() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut stream = Coroutine::new(&mut stack, |c, ()| {
+            assert!(c.waker().is_some());
+            let (c, ()) = c.r#yield(1)?;
+            let (c, ()) = c.r#yield(2)?;
+            c.done(())
+        })
+        .into_stream();
+
+        assert!(!stream.is_terminated());
+        assert_eq!(collect(&mut stream), [1, 2]);
+        assert!(stream.is_terminated());
+        assert_eq!(collect(&mut stream), []);
+    }
+
+    #[test]
+    fn result() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let stream = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(1)?;
+            c.done(Err("foo"))
+        })
+        .into_stream();
+
+        assert_eq!(collect(stream), [Ok(1), Err("foo")]);
+    }
+}
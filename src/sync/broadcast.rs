@@ -0,0 +1,454 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bounded multi-producer, multi-consumer channel for coroutines, on which
+//! every receiver sees every value.
+//!
+//! A value stays in the channel until all receivers which were subscribed
+//! when it was sent have received it. A coroutine sending while the channel
+//! is full is suspended until the slowest receiver catches up, and one
+//! receiving when it has seen everything is suspended until the next value is
+//! sent.
+//!
+//! ```
+//! # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+//! use frenetic::scheduler::Executor;
+//! use frenetic::sync::broadcast::channel;
+//! use frenetic::{Coroutine, GeneratorState, STACK_MINIMUM};
+//!
+//! let (tx, rx) = channel(1);
+//! let rx2 = tx.subscribe();
+//! let mut a = [0u8; STACK_MINIMUM * 8];
+//! let mut b = [0u8; STACK_MINIMUM * 8];
+//! let mut d = [0u8; STACK_MINIMUM * 8];
+//! let mut executor = Executor::<(), i32, _>::new();
+//!
+//! let _ = executor.spawn(Coroutine::new(&mut a, move |mut c, ()| {
+//!     for i in 1..=3 {
+//!         c = tx.send(c, i)?.0;
+//!     }
+//!     c.done(0)
+//! }));
+//!
+//! for (stack, mut rx) in [(&mut b, rx), (&mut d, rx2)] {
+//!     let _ = executor.spawn(Coroutine::new(stack, move |mut c, ()| {
+//!         let mut sum = 0;
+//!         loop {
+//!             match rx.recv(c)? {
+//!                 (next, Ok(i)) => { sum += i; c = next; }
+//!                 (next, Err(_)) => return next.done(sum),
+//!             }
+//!         }
+//!     }));
+//! }
+//!
+//! let mut results = Vec::new();
+//! executor.run(|_, state| results.push(state));
+//! assert_eq!(results.iter().filter(|&s| *s == GeneratorState::Complete(6)).count(), 2);
+//! ```
+
+pub use super::mpsc::{RecvError, SendError, TryRecvError, TrySendError};
+
+use super::{wait_in, WaitResult, Waiters};
+use crate::Control;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A value in the channel, with how many receivers are yet to receive it.
+struct Slot<T> {
+    value: T,
+    remaining: usize,
+}
+
+struct State<T> {
+    queue: VecDeque<Slot<T>>,
+    /// The position of the value at the front of `queue` in the sequence of
+    /// all values sent.
+    head: u64,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    sending: Waiters,
+    receiving: Waiters,
+}
+
+impl<T> State<T> {
+    /// Drops the values every receiver has received, making room for more.
+    fn release(&mut self) {
+        while self.queue.front().map(|s| s.remaining) == Some(0) {
+            let _ = self.queue.pop_front();
+            self.head += 1;
+            self.sending.wake_one();
+        }
+    }
+
+    /// Adds a value to the channel for every receiver to receive.
+    fn push(&mut self, value: T) {
+        let remaining = self.receivers;
+        self.queue.push_back(Slot { value, remaining });
+        self.receiving.wake_all();
+    }
+
+    /// Takes the value at position `next` for a receiver, and moves it on to
+    /// the one after. The last receiver to take a value gets it rather than a
+    /// clone.
+    fn take(&mut self, next: &mut u64) -> Result<T, TryRecvError>
+    where
+        T: Clone,
+    {
+        let index = (*next - self.head) as usize;
+        if index == self.queue.len() {
+            return Err(if self.senders == 0 {
+                TryRecvError::Disconnected
+            } else {
+                TryRecvError::Empty
+            });
+        }
+
+        *next += 1;
+        let slot = &mut self.queue[index];
+        slot.remaining -= 1;
+        if slot.remaining > 0 {
+            return Ok(slot.value.clone());
+        }
+
+        // Every receiver which has to take this value has taken the ones in
+        // front of it too, so it is the first.
+        let slot = self.queue.pop_front().unwrap();
+        self.head += 1;
+        self.sending.wake_one();
+        Ok(slot.value)
+    }
+
+    /// Registers a receiver, which receives the values sent from now on.
+    fn subscribe(&mut self) -> u64 {
+        self.receivers += 1;
+        self.head + self.queue.len() as u64
+    }
+}
+
+struct Shared<T>(Mutex<State<T>>);
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Creates a channel which holds up to `capacity` values, with a single
+/// receiver to start with. More subscribe with `Sender::subscribe()`.
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+
+    let shared = Arc::new(Shared(Mutex::new(State {
+        queue: VecDeque::with_capacity(capacity),
+        head: 0,
+        capacity,
+        senders: 1,
+        receivers: 1,
+        sending: Waiters::default(),
+        receiving: Waiters::default(),
+    })));
+
+    let rx = Receiver {
+        shared: shared.clone(),
+        next: 0,
+    };
+    (Sender(shared), rx)
+}
+
+/// The sending half of a channel. It can be cloned to send from several
+/// coroutines.
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to every receiver, suspending the coroutine while the
+    /// channel is full.
+    ///
+    /// Hands back the `Control`, along with the value if there is no
+    /// receiver left.
+    pub fn send<'a, Y, R>(
+        &self,
+        mut c: Control<'a, (), Y, R>,
+        value: T,
+    ) -> WaitResult<'a, Y, R, Result<(), SendError<T>>> {
+        loop {
+            let waiter = {
+                let mut state = self.0.lock();
+
+                if state.receivers == 0 {
+                    return Ok((c, Err(SendError(value))));
+                }
+
+                if state.queue.len() < state.capacity {
+                    state.push(value);
+                    return Ok((c, Ok(())));
+                }
+
+                state.sending.push(&c)
+            };
+
+            c = wait_in(c, &(self.0).0, |s| &mut s.sending, waiter)?;
+        }
+    }
+
+    /// Sends `value` to every receiver if the channel has room for it,
+    /// without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.0.lock();
+
+        if state.receivers == 0 {
+            return Err(TrySendError::Disconnected(value));
+        }
+
+        if state.queue.len() == state.capacity {
+            return Err(TrySendError::Full(value));
+        }
+
+        state.push(value);
+        Ok(())
+    }
+
+    /// Creates a new receiver, which receives every value sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = self.0.lock().subscribe();
+        Receiver {
+            shared: self.0.clone(),
+            next,
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.receiving.wake_all();
+        }
+    }
+}
+
+/// The receiving half of a channel. Each receiver receives every value sent
+/// while it is subscribed.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+
+    /// The position of the next value to receive in the sequence of all
+    /// values sent.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receives the next value, suspending the coroutine until there is one.
+    ///
+    /// Hands back the `Control`, along with the value, or an error once this
+    /// receiver has received every value and all senders have been dropped.
+    pub fn recv<'a, Y, R>(
+        &mut self,
+        mut c: Control<'a, (), Y, R>,
+    ) -> WaitResult<'a, Y, R, Result<T, RecvError>> {
+        loop {
+            let waiter = {
+                let mut state = self.shared.lock();
+
+                match state.take(&mut self.next) {
+                    Ok(value) => return Ok((c, Ok(value))),
+                    Err(TryRecvError::Disconnected) => return Ok((c, Err(RecvError))),
+                    Err(TryRecvError::Empty) => state.receiving.push(&c),
+                }
+            };
+
+            c = wait_in(c, &self.shared.0, |s| &mut s.receiving, waiter)?;
+        }
+    }
+
+    /// Receives the next value if there is one, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared.lock().take(&mut self.next)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+
+        // Nobody waits for this receiver to receive the rest any more.
+        let index = (self.next - state.head) as usize;
+        for slot in state.queue.iter_mut().skip(index) {
+            slot.remaining -= 1;
+        }
+        state.receivers -= 1;
+        state.release();
+
+        if state.receivers == 0 {
+            state.sending.wake_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::sync::tests::Flag;
+    use crate::{Control, Coroutine, GeneratorState, ResumeState, STACK_MINIMUM};
+    use core::pin::Pin;
+    use core::task::{Context, Waker};
+    use std::cell::RefCell;
+
+    #[test]
+    fn every_receiver() {
+        let log = RefCell::new(Vec::new());
+        let (tx, rx) = channel(2);
+        let rx2 = tx.subscribe();
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut d = [1u8; STACK_MINIMUM * 4];
+        let mut executor = Executor::<(), (), _>::new();
+
+        let _ = executor.spawn(Coroutine::new(&mut a, |mut c, ()| {
+            for i in 0..5 {
+                c = tx.send(c, i)?.0;
+                log.borrow_mut().push(("sent", i));
+            }
+            drop(tx);
+            c.done(())
+        }));
+
+        for (stack, mut rx, name) in [(&mut b, rx, "b"), (&mut d, rx2, "d")] {
+            let log = &log;
+            let _ = executor.spawn(Coroutine::new(stack, move |mut c, ()| loop {
+                match rx.recv(c)? {
+                    (next, Ok(i)) => {
+                        log.borrow_mut().push((name, i));
+                        c = next;
+                    }
+                    (next, Err(RecvError)) => return next.done(()),
+                }
+            }));
+        }
+
+        executor.run(|_, state| assert_eq!(state, GeneratorState::Complete(())));
+        assert!(executor.is_empty());
+
+        for name in ["b", "d"].iter() {
+            let received: Vec<_> = log
+                .borrow()
+                .iter()
+                .filter(|&&(what, _)| what == *name)
+                .map(|&(_, i)| i)
+                .collect();
+            assert_eq!(received, [0, 1, 2, 3, 4]);
+
+            // The sender never gets more than two values ahead of either.
+            let mut ahead = 0i32;
+            for &(what, _) in log.borrow().iter() {
+                if what == "sent" {
+                    ahead += 1;
+                } else if what == *name {
+                    ahead -= 1;
+                }
+                assert!(ahead <= 2);
+            }
+        }
+    }
+
+    #[test]
+    fn try_send_recv() {
+        let (tx, mut rx) = channel(2);
+
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(tx.try_send(1), Ok(()));
+
+        // A late subscriber misses what was sent before.
+        let mut late = tx.subscribe();
+        assert_eq!(tx.try_send(2), Ok(()));
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(late.try_recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Ok(2));
+
+        // Values stay until every receiver has them.
+        assert_eq!(tx.try_send(3), Ok(()));
+        assert_eq!(tx.try_send(4), Ok(()));
+        assert_eq!(rx.try_recv(), Ok(3));
+        assert_eq!(rx.try_recv(), Ok(4));
+        assert_eq!(tx.try_send(5), Err(TrySendError::Full(5)));
+
+        // Dropping a receiver which lags behind makes room.
+        drop(late);
+        assert_eq!(tx.try_send(5), Ok(()));
+
+        drop(tx);
+        assert_eq!(rx.try_recv(), Ok(5));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (tx, rx) = channel(1);
+        drop(rx);
+        assert_eq!(tx.try_send(1), Err(TrySendError::Disconnected(1)));
+    }
+
+    #[test]
+    fn cancel_waiting() {
+        let (tx, mut rx) = channel(1);
+        assert_eq!(tx.try_send(0), Ok(()));
+
+        let sender = |c: Control<'_, (), (), ()>, ()| {
+            let (c, sent) = tx.send(c, 1)?;
+            assert_eq!(sent, Ok(()));
+            c.done(())
+        };
+
+        let mut a = [1u8; STACK_MINIMUM * 16];
+        let mut b = [1u8; STACK_MINIMUM * 16];
+        let mut first = Coroutine::new(&mut a, sender);
+        let mut second = Coroutine::new(&mut b, sender);
+
+        let flags = [Arc::new(Flag::default()), Arc::new(Flag::default())];
+        let wakers = [Waker::from(flags[0].clone()), Waker::from(flags[1].clone())];
+        let mut cx = [
+            Context::from_waker(&wakers[0]),
+            Context::from_waker(&wakers[1]),
+        ];
+
+        let first_state = Pin::new(&mut first).poll_resume((), &mut cx[0]);
+        assert_eq!(first_state, ResumeState::Pending);
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Pending);
+
+        // The first is canceled while it waits, and leaves the queue.
+        drop(first);
+        assert!(!flags[0].is_set());
+
+        // So making room wakes the second, which sends.
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert!(flags[1].is_set());
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Complete(()));
+        assert_eq!(rx.try_recv(), Ok(1));
+    }
+}
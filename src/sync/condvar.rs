@@ -0,0 +1,177 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{wait_in, Mutex, MutexGuard, WaitResult, Waiters};
+use crate::Control;
+use core::fmt;
+use std::sync::{Mutex as StdMutex, MutexGuard as StdMutexGuard};
+
+/// A condition variable for coroutines, used along with a `sync::Mutex`.
+///
+/// A coroutine waiting on it is suspended, not the thread it runs on, until
+/// another one notifies it.
+#[derive(Default)]
+pub struct Condvar(StdMutex<Waiters>);
+
+impl Condvar {
+    /// Creates a condition variable nobody waits on.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn waiting(&self) -> StdMutexGuard<'_, Waiters> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Unlocks the mutex `guard` holds and suspends the coroutine until it is
+    /// notified, then locks the mutex again.
+    ///
+    /// Hands back the `Control`, along with the guard. Like its `std::sync`
+    /// counterpart, it may return without having been notified, so check the
+    /// condition waited for in a loop.
+    pub fn wait<'a, 'm, Y, R, T: ?Sized>(
+        &self,
+        c: Control<'a, (), Y, R>,
+        guard: MutexGuard<'m, T>,
+    ) -> WaitResult<'a, Y, R, MutexGuard<'m, T>> {
+        let waiter = self.waiting().push(&c);
+
+        let mutex: &'m Mutex<T> = guard.0;
+        drop(guard);
+
+        // Should it be canceled after having been notified, the notification
+        // goes to the next coroutine waiting instead.
+        let c = wait_in(c, &self.0, |w| w, waiter)?;
+        mutex.lock(c)
+    }
+
+    /// Wakes the coroutine which has waited longest, if any.
+    pub fn notify_one(&self) {
+        self.waiting().wake_one();
+    }
+
+    /// Wakes all the waiting coroutines.
+    pub fn notify_all(&self) {
+        self.waiting().wake_all();
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Condvar { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::sync::tests::Flag;
+    use crate::{Coroutine, GeneratorState, ResumeState, STACK_MINIMUM};
+    use core::pin::Pin;
+    use core::task::{Context, Waker};
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    #[test]
+    fn producer_consumer() {
+        let queue = Mutex::new(VecDeque::new());
+        let ready = Condvar::new();
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut executor = Executor::new();
+
+        // The consumer runs first, and has to wait.
+        let _ = executor.spawn(Coroutine::new(&mut a, |mut c, ()| {
+            let mut sum = 0;
+            loop {
+                let (next, mut guard) = queue.lock(c)?;
+                c = next;
+
+                while guard.is_empty() {
+                    let (next, g) = ready.wait(c, guard)?;
+                    c = next;
+                    guard = g;
+                }
+
+                match guard.pop_front().unwrap() {
+                    0 => return c.done(sum),
+                    i => sum += i,
+                }
+            }
+        }));
+
+        let _ = executor.spawn(Coroutine::new(&mut b, |mut c, ()| {
+            for i in [1, 2, 3, 0].iter() {
+                let (next, mut guard) = queue.lock(c)?;
+                guard.push_back(*i);
+                drop(guard);
+                ready.notify_one();
+                c = next.r#yield(())?.0;
+            }
+            c.done(0)
+        }));
+
+        let mut results = Vec::new();
+        executor.run(|_, state| {
+            if let GeneratorState::Complete(r) = state {
+                results.push(r)
+            }
+        });
+
+        assert!(executor.is_empty());
+        assert!(results.contains(&6));
+    }
+
+    #[test]
+    fn cancel_notified() {
+        let mutex = Mutex::new(());
+        let ready = Condvar::new();
+
+        let waiter = |c: Control<'_, (), (), ()>, ()| {
+            let (c, guard) = mutex.lock(c)?;
+            let (c, _guard) = ready.wait(c, guard)?;
+            c.done(())
+        };
+
+        let mut a = [1u8; STACK_MINIMUM * 16];
+        let mut b = [1u8; STACK_MINIMUM * 16];
+        let mut first = Coroutine::new(&mut a, waiter);
+        let mut second = Coroutine::new(&mut b, waiter);
+
+        let flags = [Arc::new(Flag::default()), Arc::new(Flag::default())];
+        let wakers = [Waker::from(flags[0].clone()), Waker::from(flags[1].clone())];
+        let mut cx = [
+            Context::from_waker(&wakers[0]),
+            Context::from_waker(&wakers[1]),
+        ];
+
+        let first_state = Pin::new(&mut first).poll_resume((), &mut cx[0]);
+        assert_eq!(first_state, ResumeState::Pending);
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Pending);
+
+        // The first is notified, but canceled before it runs again.
+        ready.notify_one();
+        assert!(flags[0].is_set());
+        assert!(!flags[1].is_set());
+        drop(first);
+
+        // The notification goes to the second instead.
+        assert!(flags[1].is_set());
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Complete(()));
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Synchronization primitives for coroutines.
+//!
+//! Where their `std::sync` counterparts block the thread, these suspend the
+//! coroutine waiting on them instead, like `Control::yield_pending()` does,
+//! until whoever it waits for wakes it. They take the coroutine's `Control`
+//! to do so, and hand it back. The coroutine must be driven by a task, such
+//! as the `scheduler::Executor` (see `Coroutine::poll_resume()`).
+
+pub mod broadcast;
+mod condvar;
+pub mod mpsc;
+mod mutex;
+pub mod oneshot;
+mod semaphore;
+
+pub use self::condvar::Condvar;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::semaphore::{Semaphore, SemaphorePermit};
+
+use crate::{Control, CoroutineError, ResumeState};
+use core::task::Waker;
+use std::collections::VecDeque;
+use std::sync::Mutex as StdMutex;
+
+/// What a primitive which may suspend a coroutine returns: the coroutine's
+/// `Control`, handed back, and the outcome. Fails like `Control::r#yield()`
+/// if the coroutine is canceled while it waits.
+pub type WaitResult<'a, Y, R, T> = Result<(Control<'a, (), Y, R>, T), CoroutineError>;
+
+/// The wakers of the coroutines waiting for something, in the order they
+/// started waiting.
+#[derive(Default)]
+struct Waiters {
+    next: usize,
+    queue: VecDeque<(usize, Waker)>,
+}
+
+/// A coroutine's place in `Waiters`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Waiter(usize);
+
+impl Waiters {
+    /// Adds the waker of the task driving `c`, if any.
+    fn push<Y, R>(&mut self, c: &Control<'_, (), Y, R>) -> Option<Waiter> {
+        let waker = c.waker()?.clone();
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.queue.push_back((id, waker));
+        Some(Waiter(id))
+    }
+
+    /// Takes a coroutine which has stopped waiting out of the queue. Returns
+    /// `true` if it wasn't there any more, because it had been woken.
+    fn remove(&mut self, waiter: Option<Waiter>) -> bool {
+        let id = match waiter {
+            Some(Waiter(id)) => id,
+            None => return false,
+        };
+
+        match self.queue.iter().position(|&(i, _)| i == id) {
+            Some(i) => self.queue.remove(i).is_none(),
+            None => true,
+        }
+    }
+
+    /// Wakes the coroutine which has waited longest.
+    fn wake_one(&mut self) {
+        let _ = self.wake_next();
+    }
+
+    /// Wakes the coroutine which has waited longest, and returns which one
+    /// it was.
+    fn wake_next(&mut self) -> Option<Waiter> {
+        let (id, waker) = self.queue.pop_front()?;
+        waker.wake();
+        Some(Waiter(id))
+    }
+
+    /// Wakes all the waiting coroutines.
+    fn wake_all(&mut self) {
+        for (_, waker) in self.queue.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Suspends the coroutine until it is woken.
+fn wait<'a, Y, R>(c: Control<'a, (), Y, R>) -> Result<Control<'a, (), Y, R>, CoroutineError> {
+    Ok(c.suspend(ResumeState::Pending)?.0)
+}
+
+/// Suspends the coroutine until it is woken, like `wait()`, once it has been
+/// queued as `waiter` in the `Waiters` which `waiters` picks out of the state
+/// behind `lock`. It is taken out of the queue again when it is resumed.
+///
+/// Should it be canceled instead after having been woken, whether its stack
+/// is unwound or not, the next coroutine in the queue is woken in its place,
+/// so that whatever it was woken for isn't lost.
+fn wait_in<'a, Y, R, S>(
+    c: Control<'a, (), Y, R>,
+    lock: &StdMutex<S>,
+    waiters: fn(&mut S) -> &mut Waiters,
+    waiter: Option<Waiter>,
+) -> Result<Control<'a, (), Y, R>, CoroutineError> {
+    wait_queued(c, lock, waiters, waiter, None)
+}
+
+/// Suspends the coroutine like `wait_in()`, for a primitive which hands what
+/// it was woken for straight to the coroutine it wakes. Should it be canceled
+/// after having been woken, `pass_on` passes it on in its place.
+fn wait_for_handover<'a, Y, R, S>(
+    c: Control<'a, (), Y, R>,
+    lock: &StdMutex<S>,
+    waiters: fn(&mut S) -> &mut Waiters,
+    waiter: Option<Waiter>,
+    pass_on: fn(&mut S),
+) -> Result<Control<'a, (), Y, R>, CoroutineError> {
+    wait_queued(c, lock, waiters, waiter, Some(pass_on))
+}
+
+fn wait_queued<'a, Y, R, S>(
+    c: Control<'a, (), Y, R>,
+    lock: &StdMutex<S>,
+    waiters: fn(&mut S) -> &mut Waiters,
+    waiter: Option<Waiter>,
+    pass_on: Option<fn(&mut S)>,
+) -> Result<Control<'a, (), Y, R>, CoroutineError> {
+    struct Leave<'l, S> {
+        lock: &'l StdMutex<S>,
+        waiters: fn(&mut S) -> &mut Waiters,
+        waiter: Option<Waiter>,
+        pass_on: Option<fn(&mut S)>,
+        canceled: bool,
+    }
+
+    impl<S> Drop for Leave<'_, S> {
+        fn drop(&mut self) {
+            let mut state = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+            let woken = (self.waiters)(&mut state).remove(self.waiter.take());
+            if woken && self.canceled {
+                match self.pass_on {
+                    Some(pass_on) => pass_on(&mut state),
+                    None => (self.waiters)(&mut state).wake_one(),
+                }
+            }
+        }
+    }
+
+    let mut leave = Leave {
+        lock,
+        waiters,
+        waiter,
+        pass_on,
+        canceled: true,
+    };
+
+    let c = wait(c)?;
+    leave.canceled = false;
+    Ok(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    /// A waker which remembers having been woken.
+    #[derive(Default)]
+    pub(super) struct Flag(AtomicBool);
+
+    impl Flag {
+        pub(super) fn is_set(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bounded multi-producer, single-consumer channel for coroutines.
+//!
+//! A coroutine sending on a full channel, or receiving from an empty one,
+//! is suspended until the other side makes room or sends something, so a
+//! pipeline of coroutines never runs further ahead than the channel allows.
+//!
+//! ```
+//! # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+//! use frenetic::scheduler::Executor;
+//! use frenetic::sync::mpsc::channel;
+//! use frenetic::{Coroutine, GeneratorState, STACK_MINIMUM};
+//!
+//! let (tx, rx) = channel(1);
+//! let mut a = [0u8; STACK_MINIMUM * 8];
+//! let mut b = [0u8; STACK_MINIMUM * 8];
+//! let mut executor = Executor::<(), i32, _>::new();
+//!
+//! let _ = executor.spawn(Coroutine::new(&mut a, move |mut c, ()| {
+//!     for i in 0..3 {
+//!         c = tx.send(c, i)?.0;
+//!     }
+//!     c.done(0)
+//! }));
+//!
+//! let _ = executor.spawn(Coroutine::new(&mut b, move |mut c, ()| {
+//!     let mut sum = 0;
+//!     loop {
+//!         match rx.recv(c)? {
+//!             (next, Ok(i)) => { sum += i; c = next; }
+//!             (next, Err(_)) => return next.done(sum),
+//!         }
+//!     }
+//! }));
+//!
+//! let mut results = Vec::new();
+//! executor.run(|_, state| results.push(state));
+//! assert!(results.contains(&GeneratorState::Complete(3)));
+//! ```
+
+use super::{wait_in, WaitResult, Waiters};
+use crate::Control;
+use core::fmt;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver: bool,
+    sending: Waiters,
+    receiving: Waiters,
+}
+
+struct Shared<T>(Mutex<State<T>>);
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Creates a channel which holds up to `capacity` values.
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+
+    let shared = Arc::new(Shared(Mutex::new(State {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receiver: true,
+        sending: Waiters::default(),
+        receiving: Waiters::default(),
+    })));
+
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+/// The sending half of a channel. It can be cloned to send from several
+/// coroutines.
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T> Sender<T> {
+    /// Sends `value`, suspending the coroutine while the channel is full.
+    ///
+    /// Hands back the `Control`, along with the value if the receiver has
+    /// been dropped.
+    pub fn send<'a, Y, R>(
+        &self,
+        mut c: Control<'a, (), Y, R>,
+        value: T,
+    ) -> WaitResult<'a, Y, R, Result<(), SendError<T>>> {
+        loop {
+            let waiter = {
+                let mut state = self.0.lock();
+
+                if !state.receiver {
+                    return Ok((c, Err(SendError(value))));
+                }
+
+                if state.queue.len() < state.capacity {
+                    state.queue.push_back(value);
+                    state.receiving.wake_one();
+                    return Ok((c, Ok(())));
+                }
+
+                state.sending.push(&c)
+            };
+
+            c = wait_in(c, &(self.0).0, |s| &mut s.sending, waiter)?;
+        }
+    }
+
+    /// Sends `value` if the channel has room for it, without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.0.lock();
+
+        if !state.receiver {
+            return Err(TrySendError::Disconnected(value));
+        }
+
+        if state.queue.len() == state.capacity {
+            return Err(TrySendError::Full(value));
+        }
+
+        state.queue.push_back(value);
+        state.receiving.wake_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.receiving.wake_all();
+        }
+    }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T> Receiver<T> {
+    /// Receives the next value, suspending the coroutine while the channel is
+    /// empty.
+    ///
+    /// Hands back the `Control`, along with the value, or an error once the
+    /// channel is empty and all senders have been dropped.
+    pub fn recv<'a, Y, R>(
+        &self,
+        mut c: Control<'a, (), Y, R>,
+    ) -> WaitResult<'a, Y, R, Result<T, RecvError>> {
+        loop {
+            let waiter = {
+                let mut state = self.0.lock();
+
+                if let Some(value) = state.queue.pop_front() {
+                    state.sending.wake_one();
+                    return Ok((c, Ok(value)));
+                }
+
+                if state.senders == 0 {
+                    return Ok((c, Err(RecvError)));
+                }
+
+                state.receiving.push(&c)
+            };
+
+            c = wait_in(c, &(self.0).0, |s| &mut s.receiving, waiter)?;
+        }
+    }
+
+    /// Receives the next value if there is one, without waiting.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.0.lock();
+
+        match state.queue.pop_front() {
+            Some(value) => {
+                state.sending.wake_one();
+                Ok(value)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.receiver = false;
+        state.sending.wake_all();
+    }
+}
+
+/// The receiver has been dropped. Holds the value which could not be sent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+
+/// Why `Sender::try_send()` failed. Holds the value which could not be sent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+
+    /// The receiver has been dropped.
+    Disconnected(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Disconnected(_) => write!(f, "sending on a closed channel"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: fmt::Debug> std::error::Error for TrySendError<T> {}
+
+/// The channel is empty, and all senders have been dropped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiving on a closed channel")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RecvError {}
+
+/// Why `Receiver::try_recv()` failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TryRecvError {
+    /// The channel is empty.
+    Empty,
+
+    /// The channel is empty, and all senders have been dropped.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Disconnected => write!(f, "receiving on a closed channel"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TryRecvError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::sync::tests::Flag;
+    use crate::{Control, Coroutine, GeneratorState, ResumeState, STACK_MINIMUM};
+    use core::pin::Pin;
+    use core::task::{Context, Waker};
+    use std::cell::RefCell;
+
+    #[test]
+    fn backpressure() {
+        let log = RefCell::new(Vec::new());
+        let (tx, rx) = channel(2);
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut executor = Executor::<(), (), _>::new();
+
+        let _ = executor.spawn(Coroutine::new(&mut a, |mut c, ()| {
+            for i in 0..5 {
+                c = tx.send(c, i)?.0;
+                log.borrow_mut().push(("sent", i));
+            }
+            drop(tx);
+            c.done(())
+        }));
+
+        let _ = executor.spawn(Coroutine::new(&mut b, |mut c, ()| loop {
+            match rx.recv(c)? {
+                (next, Ok(i)) => {
+                    log.borrow_mut().push(("received", i));
+                    c = next;
+                }
+                (next, Err(RecvError)) => return next.done(()),
+            }
+        }));
+
+        executor.run(|_, state| assert_eq!(state, GeneratorState::Complete(())));
+        assert!(executor.is_empty());
+
+        // The sender never gets more than two values ahead.
+        let mut ahead = 0i32;
+        for &(what, _) in log.borrow().iter() {
+            ahead += if what == "sent" { 1 } else { -1 };
+            assert!(ahead <= 2);
+        }
+
+        let received: Vec<_> = log
+            .borrow()
+            .iter()
+            .filter(|&&(what, _)| what == "received")
+            .map(|&(_, i)| i)
+            .collect();
+        assert_eq!(received, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn try_send_recv() {
+        let (tx, rx) = channel(1);
+
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+        assert_eq!(rx.try_recv(), Ok(1));
+
+        drop(tx);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (tx, rx) = channel(1);
+        drop(rx);
+        assert_eq!(tx.try_send(1), Err(TrySendError::Disconnected(1)));
+    }
+
+    #[test]
+    fn cancel_waiting() {
+        let (tx, rx) = channel(1);
+        assert_eq!(tx.try_send(0), Ok(()));
+
+        let sender = |c: Control<'_, (), (), ()>, ()| {
+            let (c, sent) = tx.send(c, 1)?;
+            assert_eq!(sent, Ok(()));
+            c.done(())
+        };
+
+        let mut a = [1u8; STACK_MINIMUM * 16];
+        let mut b = [1u8; STACK_MINIMUM * 16];
+        let mut first = Coroutine::new(&mut a, sender);
+        let mut second = Coroutine::new(&mut b, sender);
+
+        let flags = [Arc::new(Flag::default()), Arc::new(Flag::default())];
+        let wakers = [Waker::from(flags[0].clone()), Waker::from(flags[1].clone())];
+        let mut cx = [
+            Context::from_waker(&wakers[0]),
+            Context::from_waker(&wakers[1]),
+        ];
+
+        let first_state = Pin::new(&mut first).poll_resume((), &mut cx[0]);
+        assert_eq!(first_state, ResumeState::Pending);
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Pending);
+
+        // The first is canceled while it waits, and leaves the queue.
+        drop(first);
+        assert!(!flags[0].is_set());
+
+        // So making room wakes the second, which sends.
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert!(flags[1].is_set());
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Complete(()));
+        assert_eq!(rx.try_recv(), Ok(1));
+    }
+}
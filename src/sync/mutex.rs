@@ -0,0 +1,297 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{wait_for_handover, WaitResult, Waiter, Waiters};
+use crate::Control;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use std::sync::{Mutex as StdMutex, MutexGuard as StdMutexGuard};
+
+struct State {
+    locked: bool,
+    /// The coroutine the mutex was handed to on unlocking, which has yet to
+    /// take it.
+    owner: Option<Waiter>,
+    waiting: Waiters,
+}
+
+impl State {
+    /// Hands the mutex to the coroutine which has waited longest, keeping it
+    /// locked for it, or unlocks it if there is none.
+    fn unlock(&mut self) {
+        self.owner = self.waiting.wake_next();
+        self.locked = self.owner.is_some();
+    }
+}
+
+/// A mutual exclusion lock for coroutines.
+///
+/// A coroutine may hold the lock across a yield. Others trying to lock it
+/// meanwhile are suspended, not the thread they run on, until it is
+/// unlocked.
+pub struct Mutex<T: ?Sized> {
+    state: StdMutex<State>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex holding `value`.
+    pub fn new(value: T) -> Self {
+        Mutex {
+            state: StdMutex::new(State {
+                locked: false,
+                owner: None,
+                waiting: Waiters::default(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn state(&self) -> StdMutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Locks the mutex, suspending the coroutine while another one holds it.
+    /// Coroutines waiting for it get it in the order they started waiting.
+    ///
+    /// Hands back the `Control`, along with a guard which unlocks the mutex
+    /// when dropped.
+    pub fn lock<'a, Y, R>(
+        &self,
+        mut c: Control<'a, (), Y, R>,
+    ) -> WaitResult<'a, Y, R, MutexGuard<'_, T>> {
+        let mut queued = None;
+        loop {
+            let waiter = {
+                let mut state = self.state();
+                if queued.is_some() && state.owner == queued {
+                    state.owner = None;
+                    return Ok((c, MutexGuard(self)));
+                }
+
+                if !state.locked {
+                    state.locked = true;
+                    return Ok((c, MutexGuard(self)));
+                }
+
+                state.waiting.push(&c)
+            };
+
+            // The mutex stays locked while it is handed over, so that nobody
+            // else takes it first. Should the coroutine be canceled once it
+            // has been handed the mutex, it goes to the next one waiting.
+            queued = waiter;
+            c = wait_for_handover(c, &self.state, |s| &mut s.waiting, waiter, State::unlock)?;
+        }
+    }
+
+    /// Locks the mutex if nobody holds it, without waiting.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state();
+        if state.locked {
+            return None;
+        }
+
+        state.locked = true;
+        Some(MutexGuard(self))
+    }
+
+    /// Returns a mutable reference to the value. No locking is needed, since
+    /// the mutex is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value.get() }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_tuple("Mutex").field(&&*guard).finish(),
+            None => f.write_str("Mutex(<locked>)"),
+        }
+    }
+}
+
+/// Holds a `Mutex` locked, and gives access to its value. When dropped, hands
+/// the mutex to the coroutine which has waited for it longest, or unlocks it.
+pub struct MutexGuard<'m, T: ?Sized>(pub(super) &'m Mutex<T>);
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.0.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.0.state().unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::sync::tests::Flag;
+    use crate::{Coroutine, ResumeState, STACK_MINIMUM};
+    use core::pin::Pin;
+    use core::task::{Context, Waker};
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    #[test]
+    fn contention() {
+        let mutex = Mutex::new(Vec::new());
+        let log = RefCell::new(Vec::new());
+
+        let worker = |name| {
+            let (mutex, log) = (&mutex, &log);
+            move |c: Control<'_, (), (), ()>, ()| {
+                log.borrow_mut().push(name);
+                let (c, mut guard) = mutex.lock(c)?;
+
+                // Hold the lock across a yield.
+                guard.push(name);
+                let (c, ()) = c.r#yield(())?;
+                guard.push(name);
+                drop(guard);
+
+                c.done(())
+            }
+        };
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+
+        let mut executor = Executor::new();
+        let _ = executor.spawn(Coroutine::new(&mut a, worker("a")));
+        let _ = executor.spawn(Coroutine::new(&mut b, worker("b")));
+        executor.run(|_, _| {});
+        assert!(executor.is_empty());
+        drop(executor);
+
+        // b tried to lock while a held the lock, and had to wait.
+        assert_eq!(*log.borrow(), ["a", "b"]);
+        assert_eq!(mutex.into_inner(), ["a", "a", "b", "b"]);
+    }
+
+    #[test]
+    fn cancel_woken() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock().unwrap();
+
+        let waiter = |c: Control<'_, (), (), ()>, ()| {
+            let (c, _guard) = mutex.lock(c)?;
+            c.done(())
+        };
+
+        let mut a = [1u8; STACK_MINIMUM * 16];
+        let mut b = [1u8; STACK_MINIMUM * 16];
+        let mut first = Coroutine::new(&mut a, waiter);
+        let mut second = Coroutine::new(&mut b, waiter);
+
+        let flags = [Arc::new(Flag::default()), Arc::new(Flag::default())];
+        let wakers = [Waker::from(flags[0].clone()), Waker::from(flags[1].clone())];
+        let mut cx = [
+            Context::from_waker(&wakers[0]),
+            Context::from_waker(&wakers[1]),
+        ];
+
+        let first_state = Pin::new(&mut first).poll_resume((), &mut cx[0]);
+        assert_eq!(first_state, ResumeState::Pending);
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Pending);
+
+        // Unlocking wakes the first, which is canceled before it runs again.
+        drop(guard);
+        assert!(flags[0].is_set());
+        assert!(!flags[1].is_set());
+        drop(first);
+
+        // The second is woken in its place, and gets the lock.
+        assert!(flags[1].is_set());
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Complete(()));
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn handover() {
+        let mutex = Mutex::new(0);
+        let guard = mutex.try_lock().unwrap();
+
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+        let mut waiter = Coroutine::new(&mut stack, |c: Control<'_, (), (), ()>, ()| {
+            let (c, mut guard) = mutex.lock(c)?;
+            *guard += 1;
+            c.done(())
+        });
+
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let state = Pin::new(&mut waiter).poll_resume((), &mut cx);
+        assert_eq!(state, ResumeState::Pending);
+
+        // Unlocking hands the mutex to the waiter, so nobody else can take it
+        // before the waiter runs again.
+        drop(guard);
+        assert!(flag.is_set());
+        assert!(mutex.try_lock().is_none());
+
+        let state = Pin::new(&mut waiter).poll_resume((), &mut cx);
+        assert_eq!(state, ResumeState::Complete(()));
+        assert_eq!(*mutex.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn try_lock() {
+        let mut mutex = Mutex::new(1);
+
+        let guard = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        assert_eq!(format!("{:?}", mutex), "Mutex(<locked>)");
+        drop(guard);
+
+        *mutex.get_mut() += 1;
+        assert_eq!(format!("{:?}", mutex), "Mutex(2)");
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A channel for a single value, such as the result of a request.
+//!
+//! A coroutine receiving before the value has been sent is suspended until it
+//! is, or until the sender is dropped without sending one. Sending never
+//! waits.
+//!
+//! ```
+//! # #![cfg_attr(has_generator_trait, feature(generator_trait))]
+//! use frenetic::scheduler::Executor;
+//! use frenetic::sync::oneshot::channel;
+//! use frenetic::{Coroutine, GeneratorState, STACK_MINIMUM};
+//!
+//! let (tx, rx) = channel();
+//! let mut a = [0u8; STACK_MINIMUM * 8];
+//! let mut b = [0u8; STACK_MINIMUM * 8];
+//! let mut executor = Executor::<(), i32, _>::new();
+//!
+//! let _ = executor.spawn(Coroutine::new(&mut a, move |c, ()| {
+//!     let (c, value) = rx.recv(c)?;
+//!     c.done(value.unwrap_or(0))
+//! }));
+//!
+//! let _ = executor.spawn(Coroutine::new(&mut b, move |c, ()| {
+//!     let _ = tx.send(42);
+//!     c.done(0)
+//! }));
+//!
+//! let mut results = Vec::new();
+//! executor.run(|_, state| results.push(state));
+//! assert!(results.contains(&GeneratorState::Complete(42)));
+//! ```
+
+pub use super::mpsc::{RecvError, SendError, TryRecvError};
+
+use super::{wait_in, WaitResult, Waiters};
+use crate::Control;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct State<T> {
+    value: Option<T>,
+    sender: bool,
+    receiver: bool,
+    receiving: Waiters,
+}
+
+struct Shared<T>(Mutex<State<T>>);
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Creates a channel for a single value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared(Mutex::new(State {
+        value: None,
+        sender: true,
+        receiver: true,
+        receiving: Waiters::default(),
+    })));
+
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+/// The sending half of a channel, used up by sending.
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T> Sender<T> {
+    /// Sends `value`, and wakes the receiver if it is waiting for it.
+    ///
+    /// Gives the value back if the receiver has been dropped.
+    pub fn send(self, value: T) -> Result<(), SendError<T>> {
+        let mut state = self.0.lock();
+
+        if !state.receiver {
+            return Err(SendError(value));
+        }
+
+        state.value = Some(value);
+        state.receiving.wake_all();
+        Ok(())
+    }
+
+    /// Returns whether the receiver has been dropped, so that nobody is left
+    /// to send to.
+    pub fn is_closed(&self) -> bool {
+        !self.0.lock().receiver
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.sender = false;
+        state.receiving.wake_all();
+    }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T> Receiver<T> {
+    /// Receives the value, suspending the coroutine until it has been sent.
+    ///
+    /// Hands back the `Control`, along with the value, or an error if the
+    /// sender has been dropped without sending it.
+    pub fn recv<'a, Y, R>(
+        self,
+        mut c: Control<'a, (), Y, R>,
+    ) -> WaitResult<'a, Y, R, Result<T, RecvError>> {
+        loop {
+            let waiter = {
+                let mut state = self.0.lock();
+
+                if let Some(value) = state.value.take() {
+                    return Ok((c, Ok(value)));
+                }
+
+                if !state.sender {
+                    return Ok((c, Err(RecvError)));
+                }
+
+                state.receiving.push(&c)
+            };
+
+            c = wait_in(c, &(self.0).0, |s| &mut s.receiving, waiter)?;
+        }
+    }
+
+    /// Receives the value if it has been sent, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.0.lock();
+
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if !state.sender => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0.lock().receiver = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::{Coroutine, GeneratorState, STACK_MINIMUM};
+
+    #[test]
+    fn send_recv() {
+        let (tx, rx) = channel();
+        let (tx2, rx2) = channel::<i32>();
+
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+        let mut executor = Executor::<(), Result<i32, RecvError>, _>::new();
+
+        // Waits for a value which comes, then for one which never does.
+        let _ = executor.spawn(Coroutine::new(&mut a, move |c, ()| {
+            let (c, value) = rx.recv(c)?;
+            assert_eq!(value, Ok(1));
+            let (c, value) = rx2.recv(c)?;
+            c.done(value)
+        }));
+
+        let _ = executor.spawn(Coroutine::new(&mut b, move |c, ()| {
+            assert!(!tx.is_closed());
+            assert_eq!(tx.send(1), Ok(()));
+            drop(tx2);
+            c.done(Ok(0))
+        }));
+
+        let mut results = Vec::new();
+        executor.run(|_, state| results.push(state));
+        assert!(results.contains(&GeneratorState::Complete(Ok(0))));
+        assert!(results.contains(&GeneratorState::Complete(Err(RecvError))));
+    }
+
+    #[test]
+    fn try_recv() {
+        let (tx, mut rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(tx.send(1), Ok(()));
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (tx, rx) = channel();
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{wait_in, WaitResult, Waiters};
+use crate::Control;
+use core::fmt;
+use core::mem;
+use std::sync::{Mutex as StdMutex, MutexGuard as StdMutexGuard};
+
+struct State {
+    permits: usize,
+    waiting: Waiters,
+}
+
+/// A counting semaphore for coroutines, to limit how many of them do
+/// something at once.
+///
+/// A coroutine acquiring a permit while none is available is suspended, not
+/// the thread it runs on, until another one releases a permit.
+pub struct Semaphore(StdMutex<State>);
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` permits available.
+    pub fn new(permits: usize) -> Self {
+        Semaphore(StdMutex::new(State {
+            permits,
+            waiting: Waiters::default(),
+        }))
+    }
+
+    fn state(&self) -> StdMutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes a permit, suspending the coroutine while none is available.
+    ///
+    /// Hands back the `Control`, along with the permit, which is given back
+    /// when dropped.
+    pub fn acquire<'a, Y, R>(
+        &self,
+        mut c: Control<'a, (), Y, R>,
+    ) -> WaitResult<'a, Y, R, SemaphorePermit<'_>> {
+        loop {
+            let waiter = {
+                let mut state = self.state();
+                if state.permits > 0 {
+                    state.permits -= 1;
+                    return Ok((c, SemaphorePermit(self)));
+                }
+
+                state.waiting.push(&c)
+            };
+
+            // Should it be canceled after a permit was released for it, the
+            // next coroutine waiting gets the chance to take it instead.
+            c = wait_in(c, &self.0, |s| &mut s.waiting, waiter)?;
+        }
+    }
+
+    /// Takes a permit if one is available, without waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state();
+        if state.permits == 0 {
+            return None;
+        }
+
+        state.permits -= 1;
+        Some(SemaphorePermit(self))
+    }
+
+    /// Adds a permit, such as one kept with `SemaphorePermit::forget()`, and
+    /// wakes the coroutine which has waited for one longest.
+    pub fn release(&self) {
+        let mut state = self.state();
+        state.permits += 1;
+        state.waiting.wake_one();
+    }
+
+    /// Returns the number of permits available.
+    pub fn available(&self) -> usize {
+        self.state().permits
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+/// A permit taken from a `Semaphore`. Gives it back when dropped, and wakes
+/// the coroutine which has waited for one longest.
+pub struct SemaphorePermit<'s>(&'s Semaphore);
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken, without giving it back. See
+    /// `Semaphore::release()`.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SemaphorePermit { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::sync::tests::Flag;
+    use crate::{Coroutine, ResumeState, STACK_MINIMUM};
+    use core::cell::Cell;
+    use core::pin::Pin;
+    use core::task::{Context, Waker};
+    use std::sync::Arc;
+
+    #[test]
+    fn limit() {
+        let semaphore = Semaphore::new(2);
+        let running = Cell::new(0);
+        let most = Cell::new(0);
+
+        let worker = || {
+            let (semaphore, running, most) = (&semaphore, &running, &most);
+            move |c: Control<'_, (), (), ()>, ()| {
+                let (mut c, permit) = semaphore.acquire(c)?;
+                running.set(running.get() + 1);
+                most.set(most.get().max(running.get()));
+
+                for _ in 0..3 {
+                    c = c.r#yield(())?.0;
+                }
+
+                running.set(running.get() - 1);
+                drop(permit);
+                c.done(())
+            }
+        };
+
+        let mut stacks = [[1u8; STACK_MINIMUM * 4]; 4];
+        let mut executor = Executor::new();
+        for stack in stacks.iter_mut() {
+            let _ = executor.spawn(Coroutine::new(&mut stack[..], worker()));
+        }
+
+        executor.run(|_, _| {});
+        assert!(executor.is_empty());
+        assert_eq!(most.get(), 2);
+        assert_eq!(semaphore.available(), 2);
+    }
+
+    #[test]
+    fn try_acquire() {
+        let semaphore = Semaphore::new(1);
+
+        let permit = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        drop(permit);
+        assert_eq!(semaphore.available(), 1);
+
+        // A forgotten permit stays taken until it is released.
+        semaphore.try_acquire().unwrap().forget();
+        assert_eq!(semaphore.available(), 0);
+        semaphore.release();
+        assert_eq!(semaphore.available(), 1);
+    }
+
+    #[test]
+    fn cancel_woken() {
+        let semaphore = Semaphore::new(1);
+        let permit = semaphore.try_acquire().unwrap();
+
+        let waiter = |c: Control<'_, (), (), ()>, ()| {
+            let (c, _permit) = semaphore.acquire(c)?;
+            c.done(())
+        };
+
+        let mut a = [1u8; STACK_MINIMUM * 16];
+        let mut b = [1u8; STACK_MINIMUM * 16];
+        let mut first = Coroutine::new(&mut a, waiter);
+        let mut second = Coroutine::new(&mut b, waiter);
+
+        let flags = [Arc::new(Flag::default()), Arc::new(Flag::default())];
+        let wakers = [Waker::from(flags[0].clone()), Waker::from(flags[1].clone())];
+        let mut cx = [
+            Context::from_waker(&wakers[0]),
+            Context::from_waker(&wakers[1]),
+        ];
+
+        let first_state = Pin::new(&mut first).poll_resume((), &mut cx[0]);
+        assert_eq!(first_state, ResumeState::Pending);
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Pending);
+
+        // Releasing the permit wakes the first, which is canceled before it
+        // runs again.
+        drop(permit);
+        assert!(flags[0].is_set());
+        assert!(!flags[1].is_set());
+        drop(first);
+
+        // The second is woken in its place, and takes the permit.
+        assert!(flags[1].is_set());
+        let second_state = Pin::new(&mut second).poll_resume((), &mut cx[1]);
+        assert_eq!(second_state, ResumeState::Complete(()));
+        assert_eq!(semaphore.available(), 1);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A portable backend which runs every coroutine on its own OS thread.
+//!
+//! It provides the same primitives as the native backends, but a context is
+//! a parked thread rather than a saved set of registers. The first slot of a
+//! jump buffer points to a `Waiter` on the stack of the thread suspended into
+//! it. Only one of the threads taking part in a coroutine runs at a time;
+//! switching wakes the target thread and parks the current one.
+
+use crate::{JumpBuffer, JUMP_BUFFER};
+use core::ffi::c_void;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Thread};
+
+struct Waiter {
+    thread: Thread,
+    ready: AtomicBool,
+}
+
+impl Waiter {
+    fn new() -> Self {
+        Waiter {
+            thread: thread::current(),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Parks the current thread until `wake()` is called on the buffer it
+    /// was suspended into.
+    fn wait(&self) {
+        while !self.ready.load(Ordering::Acquire) {
+            thread::park();
+        }
+    }
+}
+
+/// Wakes the thread suspended into `into`.
+unsafe fn wake(into: *mut JumpBuffer) {
+    let waiter = &*((*into)[0] as *const Waiter);
+
+    // The waiter lives on the stack of the thread being woken. It may be gone
+    // as soon as `ready` is set, so take the handle out first.
+    let thread = waiter.thread.clone();
+    waiter.ready.store(true, Ordering::Release);
+    thread.unpark();
+}
+
+/// Suspends the current context into `from` and resumes `into`.
+pub(crate) unsafe fn jump_swap(from: *mut JumpBuffer, into: *mut JumpBuffer) {
+    let waiter = Waiter::new();
+    (*from)[0] = &waiter as *const Waiter as *mut c_void;
+
+    wake(into);
+    waiter.wait();
+}
+
+/// Raw pointers which are handed over to the coroutine's thread.
+struct Start {
+    parent: *mut JumpBuffer,
+    ctx: *mut c_void,
+    fnc: *mut c_void,
+    func: unsafe extern "C" fn(
+        parent: *mut JumpBuffer,
+        ctx: *mut c_void,
+        fnc: *mut c_void,
+    ) -> *mut JumpBuffer,
+}
+
+// Only one thread uses these pointers at a time. See `jump_init()`.
+unsafe impl Send for Start {}
+
+/// Spawns a thread which calls `func(buffer, ctx, fnc)` and returns once
+/// `buffer` is resumed.
+///
+/// The coroutine runs on the thread's own stack, so `stack`, `limit` and
+/// `shadow` are not used. When `func` returns, the context it returns is
+/// resumed and the thread exits.
+pub(crate) unsafe fn jump_init(
+    _stack: *mut u8,
+    ctx: *mut c_void,
+    fnc: *mut c_void,
+    func: unsafe extern "C" fn(
+        parent: *mut JumpBuffer,
+        ctx: *mut c_void,
+        fnc: *mut c_void,
+    ) -> *mut JumpBuffer,
+    _limit: *mut u8,
+    _shadow: *mut u8,
+) {
+    let waiter = Waiter::new();
+    let mut buffer = [null_mut(); JUMP_BUFFER];
+    buffer[0] = &waiter as *const Waiter as *mut c_void;
+
+    let start = Start {
+        parent: &mut buffer,
+        ctx,
+        fnc,
+        func,
+    };
+
+    let _ = thread::Builder::new()
+        .name("frenetic".into())
+        .spawn(move || {
+            let into = (start.func)(start.parent, start.ctx, start.fnc);
+            wake(into);
+        })
+        .expect("unable to spawn a coroutine thread");
+
+    waiter.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Coroutine, Generator, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+    use std::thread;
+
+    #[test]
+    fn thread() {
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let (c, ()) = c.r#yield(thread::current().id())?;
+            c.done(thread::current().name() == Some("frenetic"))
+        });
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Yielded(id) => assert_ne!(id, thread::current().id()),
+            _ => panic!("unexpected return from resume"),
+        }
+
+        match Pin::new(&mut coro).resume() {
+            GeneratorState::Complete(true) => {}
+            _ => panic!("unexpected return from resume"),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Suspending coroutines for a while.
+//!
+//! A coroutine which sleeps registers its waker with a timer wheel, and waits
+//! like `Control::yield_pending()` does. A thread started on first use
+//! advances the wheel and wakes the coroutines whose deadlines have passed, so
+//! that the thread driving them never blocks. The wheel has a resolution of
+//! one millisecond; a coroutine may sleep up to that much longer than asked.
+
+use crate::sync::WaitResult;
+use crate::{Control, ResumeState};
+use core::convert::TryFrom;
+use core::mem::take;
+use core::task::Waker;
+use core::time::Duration;
+use std::sync::{Condvar, Mutex, Once};
+use std::thread;
+use std::time::Instant;
+
+const TICK: Duration = Duration::from_millis(1);
+const SLOTS: usize = 256;
+
+struct Entry {
+    tick: u64,
+    waker: Waker,
+}
+
+/// A hashed timer wheel: a timer expiring at tick `t` goes in slot
+/// `t % SLOTS`, along with those expiring a multiple of `SLOTS` ticks later.
+struct Wheel {
+    origin: Option<Instant>,
+    /// The last tick processed.
+    now: u64,
+    len: usize,
+    slots: [Vec<Entry>; SLOTS],
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Entry> = Vec::new();
+
+        Wheel {
+            origin: None,
+            now: 0,
+            len: 0,
+            slots: [EMPTY; SLOTS],
+        }
+    }
+
+    /// Returns the time since the wheel started turning, in nanoseconds.
+    fn elapsed(&mut self, instant: Instant) -> u128 {
+        let origin = *self.origin.get_or_insert(instant);
+        instant.saturating_duration_since(origin).as_nanos()
+    }
+
+    /// Adds a timer, unless `deadline` has been reached already, in which
+    /// case the waker is handed back.
+    fn insert(&mut self, deadline: Instant, waker: Waker) -> Option<Waker> {
+        let tick = (self.elapsed(deadline) + TICK.as_nanos() - 1) / TICK.as_nanos();
+        let tick = u64::try_from(tick).unwrap_or(u64::MAX);
+        if tick <= self.now {
+            return Some(waker);
+        }
+
+        self.slots[tick as usize % SLOTS].push(Entry { tick, waker });
+        self.len += 1;
+        None
+    }
+
+    /// Processes the ticks up to `instant`, and returns the wakers of the
+    /// timers which have expired.
+    fn advance(&mut self, instant: Instant) -> Vec<Waker> {
+        let to = (self.elapsed(instant) / TICK.as_nanos()) as u64;
+        let mut expired = Vec::new();
+
+        let ticks = (to.saturating_sub(self.now) as usize).min(SLOTS);
+        for t in self.now + 1..=self.now + ticks as u64 {
+            let slot = &mut self.slots[t as usize % SLOTS];
+            for entry in take(slot) {
+                if entry.tick <= to {
+                    expired.push(entry.waker);
+                } else {
+                    slot.push(entry);
+                }
+            }
+        }
+
+        self.now = self.now.max(to);
+        self.len -= expired.len();
+        expired
+    }
+
+    /// Returns when the earliest timer expires, if there is one, and it
+    /// expires at an `Instant` which can be represented at all.
+    fn next(&self) -> Option<Instant> {
+        let tick = self.slots.iter().flatten().map(|e| e.tick).min()?;
+        let nanos = TICK.as_nanos() * u128::from(tick);
+        let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+        let since = Duration::new(secs, (nanos % 1_000_000_000) as u32);
+        self.origin?.checked_add(since)
+    }
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+static TICKED: Condvar = Condvar::new();
+
+/// Advances the wheel as time passes, sleeping until the earliest timer
+/// expires, or while there are none.
+fn run() {
+    let mut wheel = WHEEL.lock().unwrap();
+    loop {
+        let next = match wheel.next() {
+            Some(next) => next,
+            None => {
+                wheel = TICKED.wait(wheel).unwrap();
+                continue;
+            }
+        };
+
+        let now = Instant::now();
+        if now < next {
+            wheel = TICKED.wait_timeout(wheel, next - now).unwrap().0;
+            continue;
+        }
+
+        let expired = wheel.advance(now);
+        drop(wheel);
+        for waker in expired {
+            waker.wake();
+        }
+        wheel = WHEEL.lock().unwrap();
+    }
+}
+
+/// Arranges for `waker` to be woken once `deadline` has passed.
+fn register(deadline: Instant, waker: Waker) {
+    static START: Once = Once::new();
+    START.call_once(|| {
+        let _ = thread::Builder::new()
+            .name("frenetic-timer".into())
+            .spawn(run)
+            .expect("failed to start the timer thread");
+    });
+
+    let expired = WHEEL.lock().unwrap().insert(deadline, waker);
+    match expired {
+        Some(waker) => waker.wake(),
+        None => TICKED.notify_one(),
+    }
+}
+
+/// Suspends the coroutine for at least `duration`, without blocking the
+/// thread. Hands back the `Control`.
+///
+/// Like the primitives in `sync`, this needs the coroutine to be driven by a
+/// task, such as the `scheduler::Executor`, which then resumes other
+/// coroutines in the meantime.
+///
+/// A `duration` too long for the deadline to be represented, such as
+/// `Duration::MAX`, never passes.
+pub fn sleep<'a, Y, R>(c: Control<'a, (), Y, R>, duration: Duration) -> WaitResult<'a, Y, R, ()> {
+    wait_until(c, Instant::now().checked_add(duration))
+}
+
+/// Suspends the coroutine until `deadline` has passed. See `sleep()`.
+pub fn sleep_until<'a, Y, R>(
+    c: Control<'a, (), Y, R>,
+    deadline: Instant,
+) -> WaitResult<'a, Y, R, ()> {
+    wait_until(c, Some(deadline))
+}
+
+/// Suspends the coroutine until `deadline` has passed, or for good if there
+/// is none.
+fn wait_until<'a, Y, R>(
+    mut c: Control<'a, (), Y, R>,
+    deadline: Option<Instant>,
+) -> WaitResult<'a, Y, R, ()> {
+    while deadline.map_or(true, |d| Instant::now() < d) {
+        if let (Some(deadline), Some(waker)) = (deadline, c.waker()) {
+            register(deadline, waker.clone());
+        }
+
+        c = c.suspend(ResumeState::Pending)?.0;
+    }
+
+    Ok((c, ()))
+}
+
+/// Yields `value`, like `Control::r#yield()`, then suspends the coroutine
+/// until `duration` has passed since. See `sleep()`.
+///
+/// A coroutine yielding this way yields at most once every `duration`, such
+/// as one producing readings at a steady rate, however often it is resumed.
+pub fn yield_for<'a, Y, R>(
+    c: Control<'a, (), Y, R>,
+    value: Y,
+    duration: Duration,
+) -> WaitResult<'a, Y, R, ()> {
+    let deadline = Instant::now().checked_add(duration);
+    let (c, ()) = c.r#yield(value)?;
+    wait_until(c, deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Executor;
+    use crate::{Coroutine, GeneratorState, STACK_MINIMUM};
+    use core::pin::Pin;
+    use core::task::Context;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri reports the timer thread as leaked.
+    fn sleep() {
+        let mut a = [1u8; STACK_MINIMUM * 4];
+        let mut b = [1u8; STACK_MINIMUM * 4];
+
+        let nap = |ms| {
+            move |c: Control<'_, (), (), bool>, ()| {
+                let start = Instant::now();
+                let (c, ()) = super::sleep(c, Duration::from_millis(ms))?;
+                c.done(start.elapsed() >= Duration::from_millis(ms))
+            }
+        };
+
+        let mut executor = Executor::new();
+        let _ = executor.spawn(Coroutine::new(&mut a, nap(30)));
+        let _ = executor.spawn(Coroutine::new(&mut b, nap(10)));
+
+        let mut done = Vec::new();
+        while !executor.is_empty() {
+            executor.run(|_, state| {
+                if let GeneratorState::Complete(slept) = state {
+                    done.push(slept);
+                }
+            });
+            executor.wait();
+        }
+
+        assert_eq!(done, [true, true]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri reports the timer thread as leaked.
+    fn yield_for() {
+        let mut stack = [1u8; STACK_MINIMUM * 4];
+
+        let mut executor = Executor::new();
+        let _ = executor.spawn(Coroutine::new(&mut stack, |mut c, ()| {
+            for i in 0..3 {
+                c = super::yield_for(c, i, Duration::from_millis(10))?.0;
+            }
+            c.done(3)
+        }));
+
+        let start = Instant::now();
+        let mut states = Vec::new();
+        while !executor.is_empty() {
+            executor.run(|_, state| states.push(state));
+            executor.wait();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(
+            states,
+            [
+                GeneratorState::Yielded(0),
+                GeneratorState::Yielded(1),
+                GeneratorState::Yielded(2),
+                GeneratorState::Complete(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn sleep_forever() {
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), (), ()>, ()| {
+            let (c, ()) = super::sleep(c, Duration::MAX)?;
+            c.done(())
+        });
+
+        let waker = Waker::from(Arc::new(Noop));
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..2 {
+            let state = Pin::new(&mut coro).poll_resume((), &mut cx);
+            assert_eq!(state, ResumeState::Pending);
+        }
+    }
+
+    #[test]
+    fn wheel() {
+        let waker = Waker::from(Arc::new(Noop));
+        let origin = Instant::now();
+        let mut wheel = Wheel::new();
+        let _ = wheel.elapsed(origin);
+
+        // One expires in the current turn of the wheel, one a turn later.
+        let ms = |n| origin + Duration::from_millis(n);
+        assert!(wheel.insert(ms(5), waker.clone()).is_none());
+        assert!(wheel.insert(ms(5 + SLOTS as u64), waker.clone()).is_none());
+        assert!(wheel.insert(origin, waker).is_some());
+        assert_eq!(wheel.next(), Some(ms(5)));
+
+        assert_eq!(wheel.advance(ms(4) + TICK / 2).len(), 0);
+        assert_eq!(wheel.advance(ms(5)).len(), 1);
+        assert_eq!(wheel.next(), Some(ms(5 + SLOTS as u64)));
+        assert_eq!(wheel.advance(ms(4 + SLOTS as u64)).len(), 0);
+        assert_eq!(wheel.advance(ms(10 * SLOTS as u64)).len(), 1);
+        assert_eq!(wheel.len, 0);
+        assert_eq!(wheel.next(), None);
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! With the `tracing` feature, every coroutine gets a `tracing` span named
+//! "coroutine", with the coroutine's name as its `name` field. The span is
+//! entered while the coroutine runs, from the moment it is resumed until it
+//! yields or completes, so that events recorded by the coroutine nest inside
+//! it. The span also gets events of its own: `spawn`, `resume`, `yield`,
+//! `complete` and `cancel`, at the `TRACE` level for the ones emitted on
+//! every switch and the `DEBUG` level for the others.
+//!
+//! With the `threads` backend, the coroutine's code runs on a thread of its
+//! own, on which the span isn't entered. Events recorded there don't nest.
+//!
+//! Without it, `Trace` is an empty type and all of this compiles away.
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use crate::CancelOutcome;
+    use tracing::field::Empty;
+    use tracing::span::EnteredSpan;
+    use tracing::{debug, debug_span, trace, Span};
+
+    /// The span of a coroutine.
+    pub(crate) struct Trace(Span);
+
+    impl Trace {
+        pub(crate) fn new() -> Self {
+            let span = debug_span!("coroutine", name = Empty);
+            debug!(parent: &span, "spawn");
+            Trace(span)
+        }
+
+        #[cfg(feature = "alloc")]
+        pub(crate) fn set_name(&self, name: Option<&str>) {
+            if let Some(name) = name {
+                let _ = self.0.record("name", name);
+            }
+        }
+
+        /// Enters the span until the returned value is dropped.
+        pub(crate) fn enter(&self) -> Entered {
+            Entered(self.0.clone().entered())
+        }
+
+        /// Enters the span, like `enter()`, and records that the coroutine
+        /// is resumed.
+        pub(crate) fn resume(&self) -> Entered {
+            let entered = self.enter();
+            trace!("resume");
+            entered
+        }
+
+        pub(crate) fn yielded(&self, pending: bool) {
+            trace!(parent: &self.0, pending, "yield");
+        }
+
+        pub(crate) fn completed(&self, panicked: bool) {
+            debug!(parent: &self.0, panicked, "complete");
+        }
+
+        pub(crate) fn canceled(&self, outcome: CancelOutcome) {
+            debug!(parent: &self.0, ?outcome, "cancel");
+        }
+    }
+
+    /// Exits the span of a coroutine when dropped.
+    pub(crate) struct Entered(#[allow(dead_code)] EnteredSpan);
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use crate::CancelOutcome;
+
+    pub(crate) struct Trace;
+
+    impl Trace {
+        #[inline(always)]
+        pub(crate) fn new() -> Self {
+            Trace
+        }
+
+        #[cfg(feature = "alloc")]
+        #[inline(always)]
+        pub(crate) fn set_name(&self, _name: Option<&str>) {}
+
+        #[inline(always)]
+        pub(crate) fn enter(&self) -> Entered {
+            Entered
+        }
+
+        #[inline(always)]
+        pub(crate) fn resume(&self) -> Entered {
+            Entered
+        }
+
+        #[inline(always)]
+        pub(crate) fn yielded(&self, _pending: bool) {}
+
+        #[inline(always)]
+        pub(crate) fn completed(&self, _panicked: bool) {}
+
+        #[inline(always)]
+        pub(crate) fn canceled(&self, _outcome: CancelOutcome) {}
+    }
+
+    pub(crate) struct Entered;
+}
+
+pub(crate) use self::imp::Trace;
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{CoroutineBuilder, Generator, STACK_MINIMUM};
+    use core::fmt::Debug;
+    use core::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records the spans entered and exited, and the events, as strings.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Message<'a>(&'a mut String);
+
+    impl Visit for Message<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            if field.name() == "message" {
+                self.0.push_str(&format!("{:?}", value));
+            } else {
+                self.0.push_str(&format!(" {}={:?}", field.name(), value));
+            }
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            let mut line = String::from("record");
+            values.record(&mut Message(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut line = String::new();
+            event.record(&mut Message(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn enter(&self, _: &Id) {
+            self.0.lock().unwrap().push("enter".into());
+        }
+
+        fn exit(&self, _: &Id) {
+            self.0.lock().unwrap().push("exit".into());
+        }
+    }
+
+    #[test]
+    fn events() {
+        let recorder = Recorder::default();
+        let lines = recorder.0.clone();
+
+        tracing::subscriber::with_default(recorder, || {
+            let mut stack = [1u8; STACK_MINIMUM * 4];
+            let mut coro =
+                CoroutineBuilder::new()
+                    .name("parser")
+                    .spawn_on(&mut stack[..], |c, ()| {
+                        let (c, ()) = c.r#yield(1)?;
+                        c.done(2)
+                    });
+
+            let _ = Pin::new(&mut coro).resume();
+            let _ = coro.cancel();
+        });
+
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "spawn",
+                "record name=\"parser\"",
+                "enter",
+                "resume",
+                "exit",
+                "yield pending=false",
+                "enter",
+                "exit",
+                "cancel outcome=Acknowledged",
+            ]
+        );
+    }
+}
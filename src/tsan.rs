@@ -0,0 +1,109 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ThreadSanitizer keeps a shadow call stack and a happens-before clock per
+//! thread. A stack switch it doesn't know about corrupts the former and makes
+//! it report races between a coroutine and its parent. Its fiber API lets us
+//! tell it about every switch, just before making it.
+//!
+//! The shadow call stack is per fiber, so no instrumented function may return
+//! between telling the sanitizer about a switch and making it. That is why
+//! `Fiber::switch()` must be inlined, and why a completing coroutine doesn't
+//! switch fibers itself: its callback still has to return to the backend. The
+//! coroutine it returns to switches back to its own fiber instead.
+//!
+//! Without the `tsan` feature (or with the `threads` backend, where each
+//! coroutine really is a thread) `Fiber` is an empty type and all of this
+//! compiles away.
+
+#[cfg(all(feature = "tsan", not(any(feature = "threads", miri))))]
+mod imp {
+    use core::ffi::c_void;
+    use core::ptr::null_mut;
+
+    extern "C" {
+        fn __tsan_get_current_fiber() -> *mut c_void;
+        fn __tsan_create_fiber(flags: u32) -> *mut c_void;
+        fn __tsan_destroy_fiber(fiber: *mut c_void);
+        fn __tsan_switch_to_fiber(fiber: *mut c_void, flags: u32);
+    }
+
+    /// A ThreadSanitizer fiber: the sanitizer's view of a stack.
+    #[derive(Clone, Copy)]
+    pub(crate) struct Fiber(*mut c_void);
+
+    impl Fiber {
+        /// No fiber at all.
+        pub(crate) fn null() -> Self {
+            Fiber(null_mut())
+        }
+
+        /// Creates a fiber for a new coroutine.
+        pub(crate) fn new() -> Self {
+            Fiber(unsafe { __tsan_create_fiber(0) })
+        }
+
+        /// Returns the fiber we are running on.
+        pub(crate) fn current() -> Self {
+            Fiber(unsafe { __tsan_get_current_fiber() })
+        }
+
+        /// Tells the sanitizer we are about to switch to this fiber's stack.
+        /// The switch synchronizes the two fibers.
+        #[inline(always)]
+        pub(crate) unsafe fn switch(self) {
+            __tsan_switch_to_fiber(self.0, 0);
+        }
+
+        /// Destroys a fiber created by `Fiber::new()`, if it hasn't been
+        /// destroyed already. We must not be running on it.
+        pub(crate) unsafe fn destroy(&mut self) {
+            if !self.0.is_null() {
+                __tsan_destroy_fiber(self.0);
+                self.0 = null_mut();
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature = "tsan", not(any(feature = "threads", miri)))))]
+mod imp {
+    #[derive(Clone, Copy)]
+    pub(crate) struct Fiber;
+
+    impl Fiber {
+        #[inline(always)]
+        pub(crate) fn null() -> Self {
+            Fiber
+        }
+
+        #[inline(always)]
+        pub(crate) fn new() -> Self {
+            Fiber
+        }
+
+        #[inline(always)]
+        pub(crate) fn current() -> Self {
+            Fiber
+        }
+
+        #[inline(always)]
+        pub(crate) unsafe fn switch(self) {}
+
+        #[inline(always)]
+        pub(crate) unsafe fn destroy(&mut self) {}
+    }
+}
+
+pub(crate) use self::imp::Fiber;
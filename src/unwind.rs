@@ -0,0 +1,146 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A panic inside a coroutine can't unwind past the bottom of its stack. It
+//! is caught there, and its payload handed to the parent, which resumes
+//! unwinding from wherever it resumed the coroutine. Canceling a coroutine
+//! unwinds its stack the same way, with a payload of its own.
+//!
+//! All of this needs the standard library. Without the `std` feature,
+//! nothing is caught: a panic inside a coroutine aborts the process when it
+//! reaches the bottom of the stack (`no_std` targets usually abort on panic
+//! anyway), `Payload` is an empty type, and a canceled coroutine gets
+//! `CoroutineError::Canceled` from its next yield.
+//!
+//! Either way, a panic never unwinds out of the code at the bottom of the
+//! stack: a `Bomb` there turns one that gets past `catch()` into an abort.
+//! A coroutine whose `PanicPolicy` is `Abort` aborts on a caught panic too,
+//! rather than handing it to the parent.
+
+/// What happens to a panic which reaches the bottom of a coroutine's stack.
+/// See `CoroutineBuilder::panic_policy()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PanicPolicy {
+    /// The panic resumes unwinding in whoever resumed the coroutine. This is
+    /// the default. It needs the `std` feature: without it, nothing is
+    /// caught, and every panic aborts.
+    Propagate,
+
+    /// The process aborts, once the coroutine's stack has been unwound.
+    Abort,
+}
+
+/// Aborts the process if dropped. Code which must not be unwound out of
+/// holds one, and defuses it on the way out: it is only dropped by a panic
+/// unwinding past it, and panicking again while unwinding aborts.
+pub(crate) struct Bomb;
+
+impl Bomb {
+    /// Lets the code holding the bomb return.
+    pub(crate) fn defuse(self) {
+        core::mem::forget(self)
+    }
+}
+
+impl Drop for Bomb {
+    fn drop(&mut self) {
+        panic!("a panic unwound to the bottom of a coroutine stack");
+    }
+}
+
+#[cfg(feature = "std")]
+mod imp {
+    use core::any::Any;
+    use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+    /// The payload of a panic.
+    pub(crate) type Payload = Box<dyn Any + Send>;
+
+    /// The payload of the unwind started by `cancel()`.
+    struct Unwind;
+
+    /// Whether a coroutine can be canceled by unwinding its stack.
+    #[cfg(has_cfg_panic)]
+    pub(crate) const UNWIND: bool = cfg!(panic = "unwind");
+    #[cfg(not(has_cfg_panic))]
+    pub(crate) const UNWIND: bool = true;
+
+    /// Runs `f`, catching a panic.
+    pub(crate) fn catch<T>(f: impl FnOnce() -> T) -> Result<T, Payload> {
+        catch_unwind(AssertUnwindSafe(f))
+    }
+
+    /// Resumes unwinding with the payload of a caught panic.
+    pub(crate) fn resume(payload: Payload) -> ! {
+        resume_unwind(payload)
+    }
+
+    /// Unwinds the stack of a canceled coroutine. See `is_cancel()`.
+    pub(crate) fn cancel() -> ! {
+        resume_unwind(Box::new(Unwind))
+    }
+
+    /// Whether a caught panic is the unwind started by `cancel()`.
+    pub(crate) fn is_cancel(payload: &Payload) -> bool {
+        payload.is::<Unwind>()
+    }
+
+    /// Whether the current thread is unwinding.
+    pub(crate) fn panicking() -> bool {
+        std::thread::panicking()
+    }
+
+    /// Aborts the process on a caught panic. See `PanicPolicy::Abort`.
+    pub(crate) fn abort(_payload: Payload) -> ! {
+        std::process::abort()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    pub(crate) enum Payload {}
+
+    pub(crate) const UNWIND: bool = false;
+
+    #[inline(always)]
+    pub(crate) fn catch<T>(f: impl FnOnce() -> T) -> Result<T, Payload> {
+        Ok(f())
+    }
+
+    #[inline(always)]
+    pub(crate) fn resume(payload: Payload) -> ! {
+        match payload {}
+    }
+
+    pub(crate) fn cancel() -> ! {
+        unreachable!("no unwinding without the std feature")
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_cancel(payload: &Payload) -> bool {
+        match *payload {}
+    }
+
+    #[inline(always)]
+    pub(crate) fn panicking() -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub(crate) fn abort(payload: Payload) -> ! {
+        match payload {}
+    }
+}
+
+pub(crate) use self::imp::{abort, cancel, catch, is_cancel, panicking, resume, Payload, UNWIND};
@@ -0,0 +1,72 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Valgrind tracks the stack pointer to tell stack memory apart from the
+//! rest. When it jumps to memory it doesn't know as a stack, Valgrind assumes
+//! the program has gone wrong ("client switching stacks?") and its checks of
+//! the new stack become unreliable. Registering each coroutine stack for as
+//! long as a coroutine runs on it avoids this.
+//!
+//! Without the `valgrind` feature (or with the `threads` backend, where every
+//! coroutine runs on an ordinary thread stack) `StackId` is an empty type and
+//! all of this compiles away.
+
+#[cfg(all(feature = "valgrind", not(any(feature = "threads", miri))))]
+mod imp {
+    extern "C" {
+        fn valgrind_request(args: *const usize, dflt: usize) -> usize;
+    }
+
+    // See valgrind.h.
+    const STACK_REGISTER: usize = 0x1501;
+    const STACK_DEREGISTER: usize = 0x1502;
+
+    /// A stack registered with Valgrind.
+    pub(crate) struct StackId(Option<usize>);
+
+    impl StackId {
+        /// Registers the memory from `bottom` up to (but excluding) `top` as
+        /// a stack.
+        pub(crate) unsafe fn register(bottom: *mut u8, top: *mut u8) -> Self {
+            let args = [STACK_REGISTER, bottom as usize, top as usize - 1, 0, 0, 0];
+            StackId(Some(valgrind_request(args.as_ptr(), 0)))
+        }
+
+        /// Deregisters the stack, if it hasn't been deregistered already.
+        /// Nothing may run on it any more.
+        pub(crate) unsafe fn deregister(&mut self) {
+            if let Some(id) = self.0.take() {
+                let args = [STACK_DEREGISTER, id, 0, 0, 0, 0];
+                let _ = valgrind_request(args.as_ptr(), 0);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature = "valgrind", not(any(feature = "threads", miri)))))]
+mod imp {
+    pub(crate) struct StackId;
+
+    impl StackId {
+        #[inline(always)]
+        pub(crate) unsafe fn register(_bottom: *mut u8, _top: *mut u8) -> Self {
+            StackId
+        }
+
+        #[inline(always)]
+        pub(crate) unsafe fn deregister(&mut self) {}
+    }
+}
+
+pub(crate) use self::imp::StackId;
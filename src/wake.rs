@@ -0,0 +1,171 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Suspending a coroutine until an event occurs, on behalf of a task.
+
+#[cfg(feature = "std")]
+use crate::{Control, CoroutineError};
+use crate::{Coroutine, ResumeState};
+use core::pin::Pin;
+use core::ptr::null;
+use core::task::{Context, Waker};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+
+impl<'a, A, Y, R, S> Coroutine<'a, A, Y, R, S> {
+    /// Resumes a paused coroutine on behalf of the task polling `cx`, passing
+    /// it an argument.
+    ///
+    /// This is like `resume_with()`, except that the coroutine may also wait
+    /// for an event with `Control::yield_pending()`, which returns
+    /// `ResumeState::Pending`. The waker of `cx` is available to the coroutine
+    /// through `Control::waker()` for the duration of the resume, and is
+    /// woken once the coroutine should be resumed again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the coroutine has already completed, or is pinned to another
+    /// thread. If the coroutine itself panics, the panic is propagated to the
+    /// caller and the coroutine is considered completed.
+    pub fn poll_resume(
+        mut self: Pin<&mut Self>,
+        arg: A,
+        cx: &mut Context<'_>,
+    ) -> ResumeState<Y, R> {
+        // Lend the waker to the coroutine for the duration of the resume.
+        if let Some(ctx) = self.ctx {
+            unsafe { (&mut (*ctx.as_ptr()).waker as *mut *const Waker).write_volatile(cx.waker()) };
+        }
+
+        let state = self.as_mut().resume_state(arg);
+
+        if let Some(ctx) = self.ctx {
+            unsafe { (&mut (*ctx.as_ptr()).waker as *mut *const Waker).write_volatile(null()) };
+        }
+
+        match state {
+            Ok(state) => state,
+            Err(err) => self.fail(err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, A, Y, R> Control<'a, A, Y, R> {
+    /// Suspends this coroutine until an event occurs, without yielding a
+    /// value. Returns the argument the parent resumes us with.
+    ///
+    /// The waker of the task driving the coroutine (see `Control::waker()`)
+    /// is registered in `slot`, whose owner wakes it once the event occurs.
+    /// The parent sees `ResumeState::Pending`, so the coroutine must be driven
+    /// by `Coroutine::poll_resume()`, `into_future()` or `into_stream()`:
+    /// `resume_with()` panics instead. Canceling the coroutine works just
+    /// like with `r#yield()`.
+    #[cfg_attr(feature = "registry", track_caller)]
+    pub fn yield_pending(self, slot: &WakerSlot) -> Result<(Self, A), CoroutineError> {
+        if let Some(waker) = self.waker() {
+            slot.register(waker);
+        }
+
+        self.suspend(ResumeState::Pending)
+    }
+}
+
+/// A slot for the waker of a task waiting on an event, which whoever raises
+/// the event wakes. See `Control::yield_pending()`. Requires the `std`
+/// feature.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct WakerSlot(Mutex<Option<Waker>>);
+
+#[cfg(feature = "std")]
+impl WakerSlot {
+    /// Creates an empty slot.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `waker` in the slot, replacing the one in it, if any.
+    pub fn register(&self, waker: &Waker) {
+        let mut slot = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match &*slot {
+            Some(old) if old.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Wakes the waker in the slot, if any, and empties the slot.
+    pub fn wake(&self) {
+        let waker = self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{Generator, STACK_MINIMUM};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    #[test]
+    fn yield_pending() {
+        struct Counter(AtomicUsize);
+
+        impl Wake for Counter {
+            fn wake(self: Arc<Self>) {
+                let _ = self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let slot = WakerSlot::new();
+        let mut stack = [1u8; STACK_MINIMUM];
+
+        let mut coro = Coroutine::new(&mut stack, |c, a: i32| {
+            let (c, b) = c.yield_pending(&slot)?;
+            c.done(a + b)
+        });
+
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let state = Pin::new(&mut coro).poll_resume(1, &mut cx);
+        assert_eq!(state, ResumeState::<(), _>::Pending);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        slot.wake();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        let state = Pin::new(&mut coro).poll_resume(2, &mut cx);
+        assert_eq!(state, ResumeState::Complete(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "waiting for an event")]
+    fn pending_resumed() {
+        let slot = WakerSlot::new();
+        let mut stack = [1u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), (), ()>, ()| {
+            let (c, ()) = c.yield_pending(&slot)?;
+            c.done(())
+        });
+
+        let _ = Pin::new(&mut coro).resume();
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2019 Red Hat
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A coroutine leaves whatever its frames held on its stack, and the stack
+//! goes back to the caller (or to the allocator) once the coroutine is gone.
+//! With the `zeroize` feature, the stack is zeroed when the coroutine is
+//! spawned, and the part of it the coroutine has used is zeroed again once it
+//! has completed or been canceled.
+//!
+//! The stack grows down, so the used part starts at the lowest byte which
+//! isn't zero any more: the high-water mark. Everything from there up is
+//! wiped, which is usually far less than the whole stack. The canary which
+//! debug builds keep at the lower end (see `Canary`) doesn't count, and is
+//! wiped on its own.
+//!
+//! Without it, `Zeroize` is an empty type and all of this compiles away.
+
+#[cfg(feature = "zeroize")]
+mod imp {
+    use crate::canary::Canary;
+    use core::ptr;
+    use core::slice;
+
+    /// The stack of a coroutine, until it has been wiped.
+    pub(crate) struct Zeroize(Option<(*mut u8, usize)>);
+
+    impl Zeroize {
+        /// Zeroes the `len` bytes of the stack at `bottom`.
+        pub(crate) unsafe fn new(bottom: *mut u8, len: usize) -> Self {
+            ptr::write_bytes(bottom, 0, len);
+            Zeroize(Some((bottom, len)))
+        }
+
+        /// Zeroes the part of the stack the coroutine has used, if it hasn't
+        /// been wiped already. Nothing may run on the stack any more.
+        pub(crate) unsafe fn wipe(&mut self) {
+            if let (Some(mark), Some((bottom, len))) = (self.mark(), self.0.take()) {
+                // Volatile, so that the writes aren't elided even if the
+                // stack is freed right afterwards.
+                for i in (0..Canary::SIZE).chain(mark..len) {
+                    bottom.add(i).write_volatile(0);
+                }
+            }
+        }
+
+        /// Returns the offset of the high-water mark from the lower end of
+        /// the stack, above the canary, unless the stack has been wiped. It
+        /// is the length of the stack if nothing has been written to it.
+        pub(crate) unsafe fn mark(&self) -> Option<usize> {
+            self.0.map(|(bottom, len)| {
+                let stack = slice::from_raw_parts(bottom, len);
+                let used = stack[Canary::SIZE..].iter().position(|&b| b != 0);
+                used.map(|i| i + Canary::SIZE).unwrap_or(len)
+            })
+        }
+
+        /// Wipes the stack, like `wipe()`, for another coroutine to start on
+        /// it. Its whole stack is zero again, so there is nothing else to do.
+        pub(crate) unsafe fn reset(&mut self, bottom: *mut u8, len: usize) {
+            self.wipe();
+            self.0 = Some((bottom, len));
+        }
+    }
+}
+
+#[cfg(not(feature = "zeroize"))]
+mod imp {
+    pub(crate) struct Zeroize;
+
+    impl Zeroize {
+        #[inline(always)]
+        pub(crate) unsafe fn new(_bottom: *mut u8, _len: usize) -> Self {
+            Zeroize
+        }
+
+        #[inline(always)]
+        pub(crate) unsafe fn wipe(&mut self) {}
+
+        #[inline(always)]
+        pub(crate) unsafe fn reset(&mut self, _bottom: *mut u8, _len: usize) {}
+    }
+}
+
+pub(crate) use self::imp::Zeroize;
+
+#[cfg(all(test, feature = "zeroize"))]
+mod tests {
+    use crate::{Control, Coroutine, Generator, STACK_MINIMUM};
+    use core::pin::Pin;
+
+    const SECRET: [u8; 64] = [0x5a; 64];
+
+    #[test]
+    fn completed() {
+        let mut stack = [0xa5u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let secret = SECRET;
+            let (c, ()) = c.r#yield(&secret as *const _ as usize)?;
+            c.done(unsafe { (&secret as *const [u8; 64]).read_volatile()[0] })
+        });
+
+        let _ = Pin::new(&mut coro).resume();
+        let _ = Pin::new(&mut coro).resume();
+        assert!(coro.into_stack().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn canary() {
+        let mut stack = [0xa5u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |c: Control<'_, (), (), ()>, ()| c.done(()));
+        let _ = Pin::new(&mut coro).resume();
+
+        // The canary at the lower end doesn't make the whole stack look
+        // used: only the top is wiped.
+        let mark = unsafe { coro.zeroize.mark() }.unwrap();
+        assert!(mark > STACK_MINIMUM * 8);
+        assert!(coro.into_stack().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn canceled() {
+        let mut stack = [0xa5u8; STACK_MINIMUM * 16];
+
+        let mut coro = Coroutine::new(&mut stack, |c, ()| {
+            let secret = SECRET;
+            let (c, ()) = c.r#yield(&secret as *const _ as usize)?;
+            c.done(unsafe { (&secret as *const [u8; 64]).read_volatile()[0] })
+        });
+
+        let _ = Pin::new(&mut coro).resume();
+        drop(coro);
+        assert!(stack.iter().all(|&b| b == 0));
+    }
+}